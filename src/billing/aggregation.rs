@@ -0,0 +1,501 @@
+//! # Privacy-preserving neighbourhood aggregation
+//!
+//! The grid operator (aggregator) learns the total consumption of a group of households for each slot without learning any individual reading.
+//!
+//! Each household sends the aggregator the commitment for the slot, with a Merkle proof that it is in a batch signed by the meter, together with a masked opening (cons + r, a + s).
+//! The masks are derived from seeds shared pairwise between households so that, for each slot, the masks of all households sum to zero modulo the group order.
+//! The masked openings therefore reveal nothing individually but their sum is an opening of the product of the signed commitments.
+//! The aggregator checks this against the meter signatures, so a household cannot lie about its contribution without the check failing.
+//!
+//! Slots are hours of the week, so the masks also depend on an aggregation round (e.g. the week number). Otherwise a household would use the same mask in the same slot every week
+//! and the aggregator could subtract two weeks' contributions to learn how its consumption changed. The aggregator only accepts contributions for the current round,
+//! with readings newer than any the household contributed in an earlier round, so an old signed reading cannot be replayed into a later total.
+//!
+//! The pairwise seeds would be agreed with Diffie-Hellman between households in a real deployment. gen_pairwise_masks does this with a trusted dealer, which is fine for tests and the demo.
+//! The aggregator must never see the seeds.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use super::common::{read_line_blocking, from_hex};
+use super::merkle;
+use super::range_proof::Group;
+use proj_crypto::asymmetric::{sign, commitments};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes;
+use gmp::mpz::Mpz;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::str::FromStr;
+
+/// Number of bytes in each pairwise seed
+const SEED_BYTES: usize = 32;
+
+/// The secrets one household uses to mask its contributions
+pub struct AggregationMasks {
+    /// Index of this household in the group
+    my_index: usize,
+    /// (index of the other household, seed shared with it)
+    pair_seeds: Vec<(usize, Vec<u8>)>,
+}
+
+fn hash_to_mpz(seed: &[u8], label: &str, round: u64, other: u64, modulus: &Mpz) -> Mpz {
+    // use enough hash output that the bias introduced by reducing mod modulus is negligible
+    let blocks = (modulus.bit_length() + 64) / 256 + 1;
+    let mut hex = String::new();
+
+    for block in 0..blocks {
+        let mut input = Vec::from(seed);
+        input.extend_from_slice(format!(" {} {} {} {}", label, round, other, block).as_bytes());
+        let sha256::Digest(digest) = sha256::hash(&input);
+        for byte in digest.iter() {
+            hex += &format!("{:02x}", byte);
+        }
+    }
+
+    Mpz::from_str_radix(&hex, 16).unwrap().modulus(modulus)
+}
+
+impl AggregationMasks {
+    /// The masks (for cons, for a) used for the slot other in the aggregation round round. Summing these over every household in the group gives zero modulo q.
+    /// other repeats every week, so the round keeps a household's masks for the same slot in different weeks unrelated.
+    pub fn masks_for(&self, round: u64, other: u64, q: &Mpz) -> (Mpz, Mpz) {
+        let mut cons_mask = Mpz::zero();
+        let mut a_mask = Mpz::zero();
+
+        for &(their_index, ref seed) in &self.pair_seeds {
+            let cons_part = hash_to_mpz(seed, "cons", round, other, q);
+            let a_part = hash_to_mpz(seed, "a", round, other, q);
+
+            // the lower index adds and the higher index subtracts so the pair cancels
+            if self.my_index < their_index {
+                cons_mask = cons_mask + cons_part;
+                a_mask = a_mask + a_part;
+            } else {
+                cons_mask = cons_mask - cons_part;
+                a_mask = a_mask - a_part;
+            }
+        }
+
+        (cons_mask.modulus(q), a_mask.modulus(q))
+    }
+}
+
+/// Trusted dealer setup of the pairwise seeds for a group of n households. Element i should be given to household i.
+pub fn gen_pairwise_masks(n: usize) -> Vec<AggregationMasks> {
+    let mut ret: Vec<AggregationMasks> = (0..n).map(|i| AggregationMasks { my_index: i, pair_seeds: Vec::new() }).collect();
+
+    for i in 0..n {
+        for j in (i+1)..n {
+            let seed = randombytes::randombytes(SEED_BYTES);
+            ret[i].pair_seeds.push((j, seed.clone()));
+            ret[j].pair_seeds.push((i, seed));
+        }
+    }
+
+    ret
+}
+
+/// Why a contribution was refused or a slot total could not be worked out (see AggregatorState)
+#[derive(Clone, Debug, PartialEq)]
+pub enum AggregationError {
+    /// There is no household with this index in the group
+    UnknownHousehold(usize),
+    /// The contribution is malformed, for the reason given
+    Malformed(String),
+    /// The contribution is masked for this round rather than the aggregator's current round
+    WrongRound(u64),
+    /// The reading is not in a batch signed by the household's meter, for the reason given
+    NotSigned(String),
+    /// The signed reading is for this slot rather than the slot the contribution is for
+    WrongSlot(u64),
+    /// The reading with this counter is not newer than the readings the household contributed in earlier rounds
+    OldReading(u64),
+    /// The contributions for this slot do not add up to an opening of the product of the meter-signed commitments
+    BadTotal(u64),
+}
+
+impl fmt::Display for AggregationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AggregationError::UnknownHousehold(household) => write!(f, "There is no household {} in the group", household),
+            AggregationError::Malformed(ref why) => write!(f, "The contribution is malformed: {}", why),
+            AggregationError::WrongRound(round) => write!(f, "The contribution is for round {}, which is not the current round", round),
+            AggregationError::NotSigned(ref why) => write!(f, "The reading is not signed by the household's meter: {}", why),
+            AggregationError::WrongSlot(other) => write!(f, "The signed reading is for slot {}", other),
+            AggregationError::OldReading(counter) => write!(f, "Reading {} is from before the current round", counter),
+            AggregationError::BadTotal(other) => write!(f, "The aggregate for slot {} does not match the commitments signed by the meters", other),
+        }
+    }
+}
+
+struct Contribution {
+    commitment: Mpz,
+    masked_cons: Mpz,
+    masked_a: Mpz,
+}
+
+// the next line of a contribution
+fn read_field<R: Read>(channel: &mut R, what: &str) -> Result<String, AggregationError> {
+    match String::from_utf8(read_line_blocking(channel)) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(AggregationError::Malformed(format!("the {} is not text", what))),
+    }
+}
+
+fn parse_field<T: FromStr>(text: &str, what: &str) -> Result<T, AggregationError> {
+    match text.parse::<T>() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(AggregationError::Malformed(format!("could not parse the {} {:?}", what, text))),
+    }
+}
+
+fn parse_hex_number(text: &str, what: &str) -> Result<Mpz, AggregationError> {
+    match Mpz::from_str_radix(text, 16) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(AggregationError::Malformed(format!("could not parse the {} {:?}", what, text))),
+    }
+}
+
+fn parse_hash(text: &str) -> Result<merkle::Hash, AggregationError> {
+    match from_hex(text) {
+        Some(ref bytes) if bytes.len() == sha256::DIGESTBYTES => {
+            let mut hash: merkle::Hash = [0; sha256::DIGESTBYTES];
+            hash.copy_from_slice(bytes);
+            Ok(hash)
+        },
+        _ => Err(AggregationError::Malformed(format!("could not parse the hash {:?}", text))),
+    }
+}
+
+/// State associated with the aggregator (e.g. the distribution network operator)
+///
+/// Aggregation happens in rounds (e.g. one each week), numbered in increasing order. Contributions are only accepted for the current round,
+/// and only with readings newer than the ones each household contributed in earlier rounds, so a signed reading cannot be counted in two rounds.
+pub struct AggregatorState {
+    /// Public keys of the meters in the group, indexed by household
+    meter_keys: Vec<sign::PublicKey>,
+    /// The group the commitments are in
+    group: Group,
+    /// The current round
+    round: u64,
+    /// For each household, the lowest reading counter accepted in the current round
+    min_counters: Vec<u64>,
+    /// For each household, one more than the highest reading counter accepted so far
+    next_counters: Vec<u64>,
+    /// Contributions received in the current round, indexed by slot and then household
+    contributions: HashMap<u64, Vec<Option<Contribution>>>,
+}
+
+impl AggregatorState {
+    /// Create a new AggregatorState for the group of households with these meters, starting with the aggregation round round
+    pub fn new(meter_keys: Vec<sign::PublicKey>, params: commitments::DHParams, round: u64) -> AggregatorState {
+        let num_households = meter_keys.len();
+
+        AggregatorState {
+            meter_keys: meter_keys,
+            group: Group::from_params(&params),
+            round: round,
+            min_counters: vec!(0; num_households),
+            next_counters: vec!(0; num_households),
+            contributions: HashMap::new(),
+        }
+    }
+
+    /// Start the aggregation round round, forgetting any contributions to the current round. Panics if round is not after the current round.
+    pub fn start_round(&mut self, round: u64) {
+        assert!(round > self.round, "aggregation round {} does not come after round {}", round, self.round);
+
+        self.round = round;
+        self.min_counters = self.next_counters.clone();
+        self.contributions.clear();
+    }
+
+    /// Read one contribution (as written by CustomerState::send_aggregation_contribution) from the household with index household
+    pub fn receive_contribution<R: Read>(&mut self, channel: &mut R, household: usize) -> Result<(), AggregationError> {
+        if household >= self.meter_keys.len() {
+            return Err(AggregationError::UnknownHousehold(household));
+        }
+
+        // Message format: "round\nother\nmasked_cons\nmasked_a\nleaf\nindex_in_batch\nproof\nsigned_root\n"
+        // every line is read before anything is checked so that a refused contribution is not left half read
+        let round_str = read_field(channel, "round");
+        let other_str = read_field(channel, "slot");
+        let cons_str = read_field(channel, "masked consumption");
+        let a_str = read_field(channel, "masked opening");
+        let leaf = read_field(channel, "reading");
+        let index_str = read_field(channel, "index in the batch");
+        let proof_str = read_field(channel, "proof");
+        let signed_root = read_field(channel, "batch signature");
+
+        let round: u64 = try!(parse_field(&try!(round_str), "round"));
+        if round != self.round {
+            return Err(AggregationError::WrongRound(round));
+        }
+
+        let other: u64 = try!(parse_field(&try!(other_str), "slot"));
+        let masked_cons = try!(parse_hex_number(&try!(cons_str), "masked consumption"));
+        let masked_a = try!(parse_hex_number(&try!(a_str), "masked opening"));
+        let leaf = try!(leaf);
+        let index: usize = try!(parse_field(&try!(index_str), "index in the batch"));
+
+        let mut proof = Vec::new();
+        for hash_str in try!(proof_str).split_whitespace() {
+            proof.push(try!(parse_hash(hash_str)));
+        }
+
+        // the leaf must be part of a batch the meter signed
+        let batch = match merkle::verify_signed_root(&try!(signed_root), &self.meter_keys[household]) {
+            Ok(b) => b,
+            Err(why) => return Err(AggregationError::NotSigned(why)),
+        };
+        let root = try!(parse_hash(&batch.root));
+        if !merkle::verify_proof(leaf.as_bytes(), index, batch.count, &proof, &root) {
+            return Err(AggregationError::NotSigned(String::from("the Merkle proof does not lead to the signed root")));
+        }
+
+        // the household must not use a reading from a different slot, or one which could have been counted in an earlier round
+        let (commitment, signed_other, counter) = match merkle::parse_leaf(&leaf) {
+            Ok(l) => l,
+            Err(why) => return Err(AggregationError::Malformed(why)),
+        };
+        if signed_other != other {
+            return Err(AggregationError::WrongSlot(signed_other));
+        }
+        if counter < self.min_counters[household] {
+            return Err(AggregationError::OldReading(counter));
+        }
+
+        let contribution = Contribution {
+            commitment: commitment,
+            masked_cons: masked_cons,
+            masked_a: masked_a,
+        };
+
+        if counter >= self.next_counters[household] {
+            self.next_counters[household] = counter + 1;
+        }
+
+        let num_households = self.meter_keys.len();
+        let slot = self.contributions.entry(other).or_insert_with(|| (0..num_households).map(|_| None).collect());
+        slot[household] = Some(contribution);
+
+        Ok(())
+    }
+
+    /// The total consumption of the group in slot other, or None if some households have not contributed to the current round yet.
+    /// Returns an error if the contributions do not open the product of the meter-signed commitments.
+    pub fn slot_total(&self, other: u64) -> Result<Option<i64>, AggregationError> {
+        let slot = match self.contributions.get(&other) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+
+        let p = &self.group.p;
+        let q = &self.group.q;
+        let mut total_cons = Mpz::zero();
+        let mut total_a = Mpz::zero();
+        let mut calculated_commit = Mpz::one();
+
+        for contribution in slot.iter() {
+            let contribution = match *contribution {
+                Some(ref c) => c,
+                None => return Ok(None),
+            };
+
+            total_cons = (total_cons + contribution.masked_cons.clone()).modulus(q);
+            total_a = (total_a + contribution.masked_a.clone()).modulus(q);
+            calculated_commit = (calculated_commit * contribution.commitment.clone()).modulus(p);
+        }
+
+        if self.group.commit(&total_cons, &total_a) != calculated_commit {
+            return Err(AggregationError::BadTotal(other));
+        }
+
+        // the readings are i32s, so a total which does not fit in an i64 cannot be their sum
+        match i64::from_str_radix(&total_cons.to_str_radix(10), 10) {
+            Ok(total) => Ok(Some(total)),
+            Err(_) => Err(AggregationError::BadTotal(other)),
+        }
+    }
+
+    /// Forget the contributions for a slot once its total has been used
+    pub fn clear_slot(&mut self, other: u64) {
+        self.contributions.remove(&other);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::three_party::{MeterState, CustomerState, read_or_gen_params, DEFAULT_PARAMS_PATH};
+    use super::super::consumption::integer_consumption::IntegerConsumption;
+    use super::super::consumption::Consumption;
+    use super::super::tests::{random_positive_i32, random_hour_of_week};
+    use sodiumoxide;
+    use std::os::unix::net::UnixStream;
+
+    struct Household {
+        meter: MeterState<UnixStream>,
+        customer: CustomerState<UnixStream, UnixStream>,
+        meter_key: sign::PublicKey,
+    }
+
+    fn household(params: &commitments::DHParams) -> Household {
+        let (pk, sk) = sign::gen_keypair();
+        let (meter_end, customer_end) = UnixStream::pair().unwrap();
+        customer_end.set_nonblocking(true).unwrap();
+        let (provider_end, _) = UnixStream::pair().unwrap();
+        let (_, provider_pk) = sign::gen_keypair();
+
+        Household {
+            meter: MeterState::new(meter_end, sk, params.clone()),
+            customer: CustomerState::new(customer_end, provider_end, [1; 24*7], provider_pk, pk.clone(), params.clone()),
+            meter_key: pk,
+        }
+    }
+
+    // the meter signs a batch with units in the slot other, followed by a reading in the next slot
+    fn consume(household: &mut Household, units: i32, other: u64) {
+        household.meter.consume(&IntegerConsumption::new(units, other));
+        household.meter.consume(&IntegerConsumption::new(1, (other + 1) % (24*7)));
+        household.meter.end_batch();
+        household.customer.read_meter_messages();
+    }
+
+    fn contribution(household: &Household, round: u64, other: u64, masks: &AggregationMasks) -> Vec<u8> {
+        let mut message: Vec<u8> = Vec::new();
+        assert!(household.customer.send_aggregation_contribution(&mut message, round, other, masks));
+        message
+    }
+
+    // the masked consumption in a contribution
+    fn masked_cons(message: &[u8]) -> Mpz {
+        let text = String::from_utf8(Vec::from(message)).unwrap();
+        Mpz::from_str_radix(text.lines().nth(2).unwrap(), 16).unwrap()
+    }
+
+    #[test]
+    fn neighbourhood_total() {
+        sodiumoxide::init();
+        let num_households = 3;
+        let round = 1;
+        let other = random_hour_of_week() as u64;
+        let params = read_or_gen_params(DEFAULT_PARAMS_PATH);
+        let masks = gen_pairwise_masks(num_households);
+
+        let mut households: Vec<Household> = (0..num_households).map(|_| household(&params)).collect();
+        let mut messages = Vec::new();
+        let mut expected_total = 0 as i64;
+
+        for i in 0..num_households {
+            let units = random_positive_i32() >> 8;
+            expected_total += units as i64;
+            consume(&mut households[i], units, other);
+
+            messages.push(contribution(&households[i], round, other, &masks[i]));
+            assert!(!households[i].customer.send_aggregation_contribution(&mut Vec::new(), round, (other + 2) % (24*7), &masks[i]));
+        }
+
+        let mut aggregator = AggregatorState::new(households.iter().map(|h| h.meter_key.clone()).collect(), params, round);
+
+        for i in 0..num_households {
+            assert_eq!(aggregator.slot_total(other), Ok(None));
+            aggregator.receive_contribution(&mut messages[i].as_slice(), i).unwrap();
+        }
+
+        assert_eq!(aggregator.slot_total(other), Ok(Some(expected_total)));
+    }
+
+    #[test]
+    fn rounds() {
+        sodiumoxide::init();
+        let other = random_hour_of_week() as u64;
+        let params = read_or_gen_params(DEFAULT_PARAMS_PATH);
+        let q = params.1.clone();
+        let masks = gen_pairwise_masks(2);
+        let mut households = vec!(household(&params), household(&params));
+        let mut aggregator = AggregatorState::new(households.iter().map(|h| h.meter_key.clone()).collect(), params, 1);
+
+        // the first week
+        let first_week = [random_positive_i32() >> 8, random_positive_i32() >> 8];
+        let mut first_messages = Vec::new();
+        for i in 0..2 {
+            consume(&mut households[i], first_week[i], other);
+            first_messages.push(contribution(&households[i], 1, other, &masks[i]));
+            aggregator.receive_contribution(&mut first_messages[i].as_slice(), i).unwrap();
+        }
+        assert_eq!(aggregator.slot_total(other), Ok(Some(first_week[0] as i64 + first_week[1] as i64)));
+
+        // the same slot a week later is masked differently
+        assert!(masks[0].masks_for(1, other, &q) != masks[0].masks_for(2, other, &q));
+        aggregator.start_round(2);
+
+        // last week's reading and last week's masks are both refused
+        let replayed = contribution(&households[0], 2, other, &masks[0]);
+        assert_eq!(aggregator.receive_contribution(&mut replayed.as_slice(), 0), Err(AggregationError::OldReading(0)));
+        assert_eq!(aggregator.receive_contribution(&mut first_messages[1].as_slice(), 1), Err(AggregationError::WrongRound(1)));
+
+        let second_week = [random_positive_i32() >> 8, random_positive_i32() >> 8];
+        let mut second_messages = Vec::new();
+        for i in 0..2 {
+            consume(&mut households[i], second_week[i], other);
+            second_messages.push(contribution(&households[i], 2, other, &masks[i]));
+            aggregator.receive_contribution(&mut second_messages[i].as_slice(), i).unwrap();
+        }
+        assert_eq!(aggregator.slot_total(other), Ok(Some(second_week[0] as i64 + second_week[1] as i64)));
+
+        // subtracting a household's contributions from the two weeks does not give its change in consumption
+        let change = (Mpz::from(second_week[0]) - Mpz::from(first_week[0])).modulus(&q);
+        let masked_change = (masked_cons(&second_messages[0]) - masked_cons(&first_messages[0])).modulus(&q);
+        assert!(change != masked_change);
+    }
+
+    #[test]
+    fn bad_contributions() {
+        sodiumoxide::init();
+        let other = random_hour_of_week() as u64;
+        let params = read_or_gen_params(DEFAULT_PARAMS_PATH);
+        let masks = gen_pairwise_masks(2);
+        let mut households = vec!(household(&params), household(&params));
+        let mut aggregator = AggregatorState::new(households.iter().map(|h| h.meter_key.clone()).collect(), params, 1);
+
+        for i in 0..2 {
+            consume(&mut households[i], 5, other);
+        }
+        let message = contribution(&households[0], 1, other, &masks[0]);
+
+        assert_eq!(aggregator.receive_contribution(&mut message.as_slice(), 2), Err(AggregationError::UnknownHousehold(2)));
+        match aggregator.receive_contribution(&mut message.as_slice(), 1) {
+            Err(AggregationError::NotSigned(_)) => (),
+            r => panic!("accepted a reading signed by another household's meter: {:?}", r),
+        }
+        match aggregator.receive_contribution(&mut &b"1\nnot a slot\n0\n0\n0\n0\n\n0\n"[..], 0) {
+            Err(AggregationError::Malformed(_)) => (),
+            r => panic!("accepted a malformed contribution: {:?}", r),
+        }
+
+        // claiming the signed reading is for another slot
+        let text = String::from_utf8(message.clone()).unwrap();
+        let mut lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+        lines[1] = ((other + 1) % (24*7)).to_string();
+        let moved = lines.join("\n") + "\n";
+        assert_eq!(aggregator.receive_contribution(&mut moved.as_bytes(), 0), Err(AggregationError::WrongSlot(other)));
+
+        // household 1 uses masks which do not cancel household 0's, so the total does not open the commitments
+        aggregator.receive_contribution(&mut message.as_slice(), 0).unwrap();
+        let unmasked = contribution(&households[1], 1, other, &gen_pairwise_masks(2).remove(1));
+        aggregator.receive_contribution(&mut unmasked.as_slice(), 1).unwrap();
+        assert_eq!(aggregator.slot_total(other), Err(AggregationError::BadTotal(other)));
+    }
+}
@@ -15,68 +15,168 @@ use num::cast::NumCast;
 use proj_crypto::asymmetric::sign;
 use std::io::{Read, Write, ErrorKind};
 use super::consumption::Consumption;
+use super::regulator::{TariffPolicy, TariffEvidence, tariff_acceptable};
 use std::time::SystemTime;
 use std::vec::Vec;
 use std::mem::{size_of, transmute};
+use std::thread;
+use std::time::Duration;
+use sodiumoxide::crypto::hash::sha256;
 
-// only works for 4-byte wide Cons (see the transmute)
-pub fn check_for_new_prices<T: Read + Write, Cons: Sized, Other: NumCast, C: Consumption<Cons, Other>>(channel: &mut T, their_pk: &sign::PublicKey) -> Option<C::Prices> {
-    const BUF_LEN: usize = 4 * 7 * 24 + sign::SIGNATUREBYTES + size_of::<SystemTime>(); 
-    let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
-    let mut ret = None;
-
-    loop { // in case several messages have been sent
-        match channel.read(&mut buf) {
-            Ok(s) => { assert_eq!(s, buf.len()) },
-            Err(e) => match e.kind() {
-                ErrorKind::WouldBlock => break,
-                _ => panic!("Device read failed with error {}", e),
-            },
+/// Length of the message sent by change_prices
+pub const PRICES_MESSAGE_LEN: usize = 4 * 7 * 24 + sign::SIGNATUREBYTES + size_of::<SystemTime>() + size_of::<u64>() + sha256::DIGESTBYTES;
+
+/// How long after changing the prices a provider still accepts bills calculated with the tariff before: long enough for a bill sent just before the new tariff arrived to be received,
+/// but not so long that the customer can bill readings taken since at the old prices
+pub const PREVIOUS_TARIFF_GRACE_SECS: u64 = 60;
+
+/// The position of a signed tariff in the provider's chain of tariffs
+pub struct TariffLink {
+    /// Sequence number of the tariff
+    pub seq: u64,
+    /// Hash of the previous tariff
+    pub prev_hash: [u8; sha256::DIGESTBYTES],
+    /// Hash of this tariff
+    pub hash: [u8; sha256::DIGESTBYTES],
+}
+
+/// The latest tariff in a chain. Each signed tariff includes a sequence number and the hash of the tariff before it, so there is only ever one tariff in force for each sequence number.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TariffChain {
+    /// Sequence number of the latest tariff. The prices both parties start with are number 0.
+    pub seq: u64,
+    /// Hash of the latest tariff
+    pub hash: [u8; sha256::DIGESTBYTES],
+}
+
+impl TariffChain {
+    /// The start of the chain. Tariff number 0 is whatever prices the parties were created with, so it has no hash.
+    pub fn genesis() -> TariffChain {
+        TariffChain {
+            seq: 0,
+            hash: [0; sha256::DIGESTBYTES],
         }
+    }
+
+    /// Does the tariff directly follow the latest one? A tariff which does not is a fork (or one was missed) and should be refused.
+    pub fn follows(&self, link: &TariffLink) -> bool {
+        link.seq == self.seq + 1 && link.prev_hash == self.hash
+    }
+
+    /// Move on to the tariff. Panics if it does not follow the latest one.
+    pub fn advance(&mut self, link: &TariffLink) {
+        assert!(self.follows(link));
+        self.seq = link.seq;
+        self.hash = link.hash;
+    }
 
-        let mut time_buf = match sign::verify(&buf, their_pk) {
-            Ok(b) => b,
-            Err(_) => { panic!("Verification of new pricing strategy failed") },
-        };
+    /// Hex hash of the latest tariff, used to say which tariff a bill was calculated with
+    pub fn hex_hash(&self) -> String {
+        to_hex(&self.hash)
+    }
+}
 
-        // split timestamp and prices
-        let data_buf = time_buf.split_off(size_of::<SystemTime>());
+// only works for 4-byte wide Cons (see the transmute)
+/// Check a signed prices message, and the countersignature sent with it if any, and if it is acceptable move chain on to it and return the prices and the evidence for them.
+/// Tariffs which do not meet the policy or do not follow on from chain are refused.
+pub fn accept_prices<Cons: Sized + PartialOrd, Other: NumCast, C: Consumption<Cons, Other>>(signed_prices: &[u8], countersignature: Option<&[u8]>, their_pk: &sign::PublicKey,
+                                                                                         policy: Option<&TariffPolicy<C::Prices>>, chain: &mut TariffChain)
+                                                                                         -> Option<(C::Prices, TariffEvidence)> {
+    let (prices, link) = verify_prices::<Cons, Other, C>(signed_prices, their_pk);
 
-        // check timestamp
-        let mut timestamp_bytes: [u8; size_of::<SystemTime>()] = [0; size_of::<SystemTime>()];
-        for i in 0..size_of::<SystemTime>() {
-            timestamp_bytes[i] = time_buf[i];
+    if !chain.follows(&link) {
+        println!("Refused tariff number {} because it does not follow on from tariff number {}", link.seq, chain.seq);
+        return None;
+    }
+
+    if let Some(p) = policy {
+        if !tariff_acceptable::<Cons, Other, C>(p, signed_prices, countersignature, &prices) {
+            println!("Rejected a tariff which does not meet the regulator's requirements");
+            return None;
         }
-        let timestamp: SystemTime = unsafe {
-            transmute::<[u8; size_of::<SystemTime>()], SystemTime>(timestamp_bytes)
-        };
-        let time_difference = SystemTime::now().duration_since(timestamp).unwrap();
-        // expect new prices every 2 months
-        if time_difference.as_secs() > (2 * 31 * 24 * 60 * 60) {
-            panic!("Time difference is too large");
-        } // else everything's good
-
-        let mut new_prices: C::Prices = C::null_prices();
-
-        for i in 0..C::prices_len() {
-            let buf_i = i * 4;
-            let mut these_bytes = [0; 4];
-
-            for i in 0..4 {
-                these_bytes[i] = data_buf[buf_i + i]
-            }
+    }
+
+    chain.advance(&link);
+    Some((prices, TariffEvidence {
+        signed_prices: Vec::from(signed_prices),
+        countersignature: countersignature.map(|c| Vec::from(c)),
+    }))
+}
+
+/// Read exactly one signed prices message (as produced by sign_prices), waiting for it if necessary. The signature is not checked.
+pub fn read_signed_prices<T: Read>(channel: &mut T) -> Vec<u8> {
+    let mut buf = vec!(0 as u8; PRICES_MESSAGE_LEN);
+    read_exact_blocking(channel, &mut buf);
+    buf
+}
+
+/// Checks the signature and timestamp on a message from sign_prices and returns the prices and the position of the tariff in the chain.
+/// Whether the tariff follows on from the last one is up to the caller (see TariffChain::follows).
+pub fn verify_prices<Cons: Sized, Other: NumCast, C: Consumption<Cons, Other>>(buf: &[u8], their_pk: &sign::PublicKey) -> (C::Prices, TariffLink) {
+    let mut time_buf = match sign::verify(buf, their_pk) {
+        Ok(b) => b,
+        Err(_) => { panic!("Verification of new pricing strategy failed") },
+    };
+    let hash = sha256::hash(&time_buf).0;
 
-            let new_price = C::cons_from_bytes(&these_bytes);
-            C::set_price(&mut new_prices, Other::from(i).unwrap(), new_price);
+    // split timestamp, sequence number, previous hash and prices
+    let mut seq_buf = time_buf.split_off(size_of::<SystemTime>());
+    let mut prev_hash_buf = seq_buf.split_off(size_of::<u64>());
+    let data_buf = prev_hash_buf.split_off(sha256::DIGESTBYTES);
+
+    let mut seq_bytes = [0 as u8; 8];
+    seq_bytes.copy_from_slice(&seq_buf);
+    let seq = unsafe { transmute::<[u8; 8], u64>(seq_bytes) };
+
+    let mut prev_hash = [0 as u8; sha256::DIGESTBYTES];
+    prev_hash.copy_from_slice(&prev_hash_buf);
+
+    // check timestamp
+    let mut timestamp_bytes: [u8; size_of::<SystemTime>()] = [0; size_of::<SystemTime>()];
+    for i in 0..size_of::<SystemTime>() {
+        timestamp_bytes[i] = time_buf[i];
+    }
+    let timestamp: SystemTime = unsafe {
+        transmute::<[u8; size_of::<SystemTime>()], SystemTime>(timestamp_bytes)
+    };
+    let time_difference = SystemTime::now().duration_since(timestamp).unwrap();
+    // expect new prices every 2 months
+    if time_difference.as_secs() > (2 * 31 * 24 * 60 * 60) {
+        panic!("Time difference is too large");
+    } // else everything's good
+
+    let mut new_prices: C::Prices = C::null_prices();
+
+    for i in 0..C::prices_len() {
+        let buf_i = i * 4;
+        let mut these_bytes = [0; 4];
+
+        for i in 0..4 {
+            these_bytes[i] = data_buf[buf_i + i]
         }
 
-        ret = Some(new_prices);
+        let new_price = C::cons_from_bytes(&these_bytes);
+        C::set_price(&mut new_prices, Other::from(i).unwrap(), new_price);
     }
 
-    ret
+    (new_prices, TariffLink {
+        seq: seq,
+        prev_hash: prev_hash,
+        hash: hash,
+    })
 }
 
-pub fn change_prices<T: Write, Cons, Other, C: Consumption<Cons, Other>>(channel: &mut T, sk: &sign::SecretKey, prices: &C::Prices) {
+pub fn change_prices<T: Write, Cons, Other, C: Consumption<Cons, Other>>(channel: &mut T, sk: &sign::SecretKey, prices: &C::Prices, chain: &mut TariffChain) {
+    let sbuf = sign_prices::<Cons, Other, C>(sk, prices, chain);
+
+    match channel.write(&sbuf) {
+        Ok(s) => assert_eq!(s, sbuf.len()),
+        Err(e) => panic!("Failed to write the new prices error {}", e),
+    };
+}
+
+/// The signed prices message written by change_prices. The new tariff is added to the end of chain.
+pub fn sign_prices<Cons, Other, C: Consumption<Cons, Other>>(sk: &sign::SecretKey, prices: &C::Prices, chain: &mut TariffChain) -> Vec<u8> {
     // get timestamp
     let now = SystemTime::now();
     let time_buf = unsafe {
@@ -86,7 +186,13 @@ pub fn change_prices<T: Write, Cons, Other, C: Consumption<Cons, Other>>(channel
     // get prices
     let mut price_buf = C::prices_to_bytes(prices);
 
-    // timestamp, prices
+    // position in the chain
+    let seq = chain.seq + 1;
+    let seq_buf = unsafe {
+        transmute::<u64, [u8; 8]>(seq)
+    };
+
+    // timestamp, seq, previous hash, prices
     let mut buf: Vec<u8> = Vec::new();
     // to get the lengths equal
     for _ in 0..size_of::<SystemTime>() {
@@ -94,13 +200,153 @@ pub fn change_prices<T: Write, Cons, Other, C: Consumption<Cons, Other>>(channel
     }
 
     buf.copy_from_slice(&time_buf);
+    buf.extend_from_slice(&seq_buf);
+    buf.extend_from_slice(&chain.hash);
     buf.append(&mut price_buf);
 
-    let sbuf = sign::sign(&buf, sk);
+    chain.seq = seq;
+    chain.hash = sha256::hash(&buf).0;
 
-    match channel.write(&sbuf) {
-        Ok(s) => assert_eq!(s, sbuf.len()),
-        Err(e) => panic!("Failed to write the new prices error {}", e),
+    sign::sign(&buf, sk)
+}
+
+pub fn stringify_bytes(bytes: &[u8]) -> String {
+    let mut ret = String::new();
+
+    for byte in bytes {
+        ret += &format!("{} ", byte);
+    }
+
+    ret
+}
+
+pub fn unstringify_bytes(string: &str) -> Vec<u8> {
+    match try_unstringify_bytes(string) {
+        Some(bytes) => bytes,
+        None => panic!("Malformed bytes: {}", string),
+    }
+}
+
+// unstringify_bytes for bytes from the other party: None if string is malformed
+pub fn try_unstringify_bytes(string: &str) -> Option<Vec<u8>> {
+    let mut ret = Vec::new();
+
+    for str in string.split_whitespace() {
+        match u8::from_str_radix(str, 10) {
+            Ok(byte) => ret.push(byte),
+            Err(_) => return None,
+        }
+    }
+
+    Some(ret)
+}
+
+// non-blocking channels and channels with a read timeout give these when there is nothing to read yet
+fn should_retry(kind: ErrorKind) -> bool {
+    match kind {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => true,
+        _ => false,
+    }
+}
+
+/// Fill buf from channel, waiting for more input if the channel is non-blocking
+pub fn read_exact_blocking<R: Read>(channel: &mut R, buf: &mut [u8]) {
+    let mut done = 0;
+
+    while done < buf.len() {
+        match channel.read(&mut buf[done..]) {
+            Ok(0) => panic!("The channel was closed part way through a message"),
+            Ok(s) => done += s,
+            Err(e) => {
+                if should_retry(e.kind()) {
+                    thread::sleep(Duration::from_millis(1));
+                } else {
+                    panic!("Device read failed with error {}", e);
+                }
+            },
+        }
+    }
+}
+
+/// Read one line (without the newline), waiting for more input if the channel is non-blocking.
+/// Unlike a BufReader this never reads past the end of the line, so the rest of the channel is left for the next message.
+pub fn read_line_blocking<R: Read>(channel: &mut R) -> Vec<u8> {
+    let mut ret = Vec::new();
+    let mut byte = [0 as u8; 1];
+
+    loop {
+        read_exact_blocking(channel, &mut byte);
+        if byte[0] == b'\n' {
+            return ret;
+        }
+        ret.push(byte[0]);
+    }
+}
+
+/// Like read_line_blocking but returns None if no message has started arriving yet
+pub fn try_read_line<R: Read>(channel: &mut R) -> Option<Vec<u8>> {
+    let mut byte = [0 as u8; 1];
+
+    match channel.read(&mut byte) {
+        Ok(0) => return None,
+        Ok(_) => {},
+        Err(e) => {
+            if should_retry(e.kind()) {
+                return None;
+            }
+            panic!("Device read failed with error {}", e);
+        },
+    };
+
+    if byte[0] == b'\n' {
+        return Some(Vec::new());
+    }
+
+    let mut ret = vec!(byte[0]);
+    ret.append(&mut read_line_blocking(channel));
+    Some(ret)
+}
+
+/// Read a line which should be a message header and panic if it is not the expected one
+pub fn expect_header<R: Read>(channel: &mut R, expected: &str) {
+    let header = String::from_utf8(read_line_blocking(channel)).unwrap();
+    if header != expected {
+        panic!("Expected a {} message but received {}", expected, header);
+    }
+}
+
+/// Write all of message or panic with a message mentioning what
+pub fn write_message<W: Write>(channel: &mut W, message: &[u8], what: &str) {
+    match channel.write(message) {
+        Ok(s) => assert_eq!(s, message.len()),
+        Err(e) => panic!("Failed to send the {}. The error was {}", what, e),
     };
 }
 
+/// Lower case hexadecimal representation of bytes
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut ret = String::new();
+
+    for byte in bytes {
+        ret += &format!("{:02x}", byte);
+    }
+
+    ret
+}
+
+/// Reverses to_hex
+pub fn from_hex(string: &str) -> Option<Vec<u8>> {
+    if string.len() % 2 != 0 {
+        return None;
+    }
+
+    let mut ret = Vec::new();
+    for i in 0..(string.len() / 2) {
+        match u8::from_str_radix(&string[(2*i)..(2*i + 2)], 16) {
+            Ok(b) => ret.push(b),
+            Err(_) => return None,
+        }
+    }
+
+    Some(ret)
+}
@@ -0,0 +1,252 @@
+//! Load profiles
+//!
+//! A load profile is a CSV file of timestamped readings, one per line: "timestamp,units". The timestamp is the start of the interval in which the units were consumed,
+//! either in seconds since the Unix epoch or as a UTC date and time "YYYY-MM-DD HH:MM[:SS]" (a T may separate the date and time and a Z may follow).
+//! Readings for intervals shorter than an hour (e.g. the half hourly readings in most smart meter exports) are added up into hourly readings, which can be replayed through a meter.
+//!
+//! A header line is skipped, as are blank lines and lines starting with #.
+//!
+//! A reading only records its hour of the week, so a replay may cover at most a week (see check_one_week). Readings a week apart would otherwise be billed as if they were in the same hour.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use super::Consumption;
+use super::integer_consumption::IntegerConsumption;
+use std::io::BufRead;
+
+/// The number of seconds in an hour
+pub const SECONDS_PER_HOUR: u64 = 60*60;
+
+// 1970-01-01 was a Thursday, which is 3 days after the start of the week
+const EPOCH_HOUR_OF_WEEK: u64 = 3*24;
+
+/// The units consumed in one hour
+#[derive(Debug, PartialEq)]
+pub struct HourlyReading {
+    /// Hours since the Unix epoch
+    pub hour: u64,
+    /// The number of units consumed during the hour
+    pub units: i32,
+}
+
+impl HourlyReading {
+    /// The reading as the meter sends it. Hours of the week start at midnight (UTC) on Monday.
+    pub fn to_consumption(&self) -> IntegerConsumption {
+        IntegerConsumption::new(self.units, hour_of_week(self.hour * SECONDS_PER_HOUR))
+    }
+}
+
+/// The most hours one replay may cover
+pub const MAX_REPLAY_HOURS: u64 = 24*7;
+
+/// Check that readings (in time order, as from read_csv) cover at most MAX_REPLAY_HOURS, so that no two of them are for the same hour of the week.
+pub fn check_one_week(readings: &[HourlyReading]) -> Result<(), String> {
+    if readings.is_empty() {
+        return Ok(());
+    }
+
+    let hours = readings[readings.len() - 1].hour - readings[0].hour + 1;
+    if hours > MAX_REPLAY_HOURS {
+        Err(format!("the readings cover {} hours but only {} can be replayed at once, because a reading only records its hour of the week. Split the load profile into weeks and bill each week before replaying the next", hours, MAX_REPLAY_HOURS))
+    } else {
+        Ok(())
+    }
+}
+
+/// The hour of the week containing timestamp (seconds since the Unix epoch). Hours of the week start at midnight (UTC) on Monday.
+pub fn hour_of_week(timestamp: u64) -> u64 {
+    (timestamp / SECONDS_PER_HOUR + EPOCH_HOUR_OF_WEEK) % (24*7)
+}
+
+// days between 1970-01-01 and year-month-day. See http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 => if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 { 29 } else { 28 },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parse a timestamp in seconds since the Unix epoch or a UTC date and time "YYYY-MM-DD HH:MM[:SS]". Returns None if text is neither or is before 1970.
+pub fn parse_timestamp(text: &str) -> Option<u64> {
+    let text = text.trim();
+    if let Ok(t) = text.parse::<u64>() {
+        return Some(t);
+    }
+
+    let text = text.trim_right_matches('Z');
+    let (date, time) = match text.find(|c: char| c == ' ' || c == 'T') {
+        Some(i) => (&text[..i], &text[i+1..]),
+        None => return None,
+    };
+
+    let mut numbers = Vec::new();
+    for part in date.split('-').chain(time.split(':')) {
+        match part.parse::<u64>() {
+            Ok(n) => numbers.push(n),
+            Err(_) => return None,
+        }
+    }
+    if numbers.len() == 5 {
+        numbers.push(0); // no seconds
+    }
+    if numbers.len() != 6 {
+        return None;
+    }
+
+    let (year, month, day, hour, minute, second) = (numbers[0], numbers[1], numbers[2], numbers[3], numbers[4], numbers[5]);
+    if year < 1970 || month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 24 * SECONDS_PER_HOUR + hour * SECONDS_PER_HOUR + minute * 60 + second)
+}
+
+/// Read a load profile (see the module documentation) and add it up into hourly readings. The readings must be in time order.
+/// Returns a description of the first problem found if the file cannot be read or is malformed.
+pub fn read_csv<R: BufRead>(input: R) -> Result<Vec<HourlyReading>, String> {
+    let mut readings: Vec<HourlyReading> = Vec::new();
+    let mut last_timestamp = None;
+    let mut first = true;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if fields.len() != 2 {
+            return Err(format!("line {}: there should be two columns: timestamp and units", i + 1));
+        }
+
+        let timestamp = match parse_timestamp(fields[0]) {
+            Some(t) => t,
+            None if first => { // header
+                first = false;
+                continue;
+            },
+            None => return Err(format!("line {}: could not parse the timestamp {}", i + 1, fields[0])),
+        };
+        first = false;
+
+        let units = match fields[1].parse::<i32>() {
+            Ok(u) if u >= 0 => u,
+            _ => return Err(format!("line {}: the units should be a non-negative integer", i + 1)),
+        };
+
+        if let Some(last) = last_timestamp {
+            if timestamp <= last {
+                return Err(format!("line {}: the readings are not in time order", i + 1));
+            }
+        }
+        last_timestamp = Some(timestamp);
+
+        let hour = timestamp / SECONDS_PER_HOUR;
+        if let Some(reading) = readings.last_mut() {
+            if reading.hour == hour {
+                reading.units = match reading.units.checked_add(units) {
+                    Some(u) => u,
+                    None => return Err(format!("line {}: too many units in one hour", i + 1)),
+                };
+                continue;
+            }
+        }
+
+        readings.push(HourlyReading {
+            hour: hour,
+            units: units,
+        });
+    }
+
+    Ok(readings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_timestamp("0"), Some(0));
+        assert_eq!(parse_timestamp("1970-01-01 00:00"), Some(0));
+        assert_eq!(parse_timestamp("2017-06-01T12:30:15Z"), Some(1496320215));
+        assert_eq!(parse_timestamp("2016-02-29 23:59:59"), Some(1456790399));
+        assert_eq!(parse_timestamp("2017-02-29 00:00"), None);
+        assert_eq!(parse_timestamp("2017-06-01"), None);
+        assert_eq!(parse_timestamp("1969-12-31 23:00"), None);
+        assert_eq!(parse_timestamp("timestamp"), None);
+
+        // 1970-01-05 was a Monday
+        assert_eq!(hour_of_week(parse_timestamp("1970-01-05 00:00").unwrap()), 0);
+        assert_eq!(hour_of_week(parse_timestamp("2017-06-06 07:59").unwrap()), 24 + 7);
+        assert_eq!(hour_of_week(parse_timestamp("2017-06-11 23:00").unwrap()), 24*7 - 1);
+    }
+
+    #[test]
+    fn csv() {
+        let text = "timestamp,kWh\n\
+                    2017-06-05 00:00,1\n\
+                    2017-06-05 00:30,2\n\
+                    # a gap\n\
+                    \n\
+                    2017-06-05 02:00,4\n";
+        let readings = read_csv(text.as_bytes()).unwrap();
+        let start = parse_timestamp("2017-06-05 00:00").unwrap() / SECONDS_PER_HOUR;
+        assert_eq!(readings, vec!(HourlyReading { hour: start, units: 3 }, HourlyReading { hour: start + 2, units: 4 }));
+        assert_eq!(readings[1].to_consumption().hour_of_week, 2);
+
+        assert!(read_csv("2017-06-05 01:00,1\n2017-06-05 00:00,1\n".as_bytes()).is_err());
+        assert!(read_csv("2017-06-05 01:00,-1\n".as_bytes()).is_err());
+        assert!(read_csv("2017-06-05 01:00,1\nnonsense,1\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn more_than_a_week() {
+        // a week starting on a Wednesday wraps around to the start of the week without repeating an hour
+        let mut text = String::new();
+        let start = parse_timestamp("2017-06-07 00:00").unwrap();
+        for hour in 0..MAX_REPLAY_HOURS {
+            text += &format!("{},1\n", start + hour * SECONDS_PER_HOUR);
+        }
+        let readings = read_csv(text.as_bytes()).unwrap();
+        assert_eq!(check_one_week(&readings), Ok(()));
+        let mut hours: Vec<u64> = readings.iter().map(|r| r.to_consumption().hour_of_week).collect();
+        hours.sort();
+        hours.dedup();
+        assert_eq!(hours.len() as u64, MAX_REPLAY_HOURS);
+
+        // one more hour would be billed in the same hour of the week as the first reading
+        text += &format!("{},1\n", start + MAX_REPLAY_HOURS * SECONDS_PER_HOUR);
+        let readings = read_csv(text.as_bytes()).unwrap();
+        assert_eq!(readings[0].to_consumption().hour_of_week, readings[readings.len() - 1].to_consumption().hour_of_week);
+        assert!(check_one_week(&readings).is_err());
+
+        assert_eq!(check_one_week(&[]), Ok(()));
+    }
+}
@@ -14,6 +14,8 @@
 
 pub mod floating_consumption;
 pub mod integer_consumption;
+pub mod load_profile;
+pub mod tariff;
 
 /// Note that Cons doubles as the type of the price per cons, just to keep things simple
 pub trait Consumption<Cons, Other> {
@@ -0,0 +1,233 @@
+//! Tariff files
+//!
+//! A tariff gives a price for each of the 24*7 hours of the week (see integer_consumption::Prices). Hours of the week start at midnight on Monday.
+//!
+//! In CSV, each line is "hours,price" where hours is one hour of the week or an inclusive range "first-last". Every hour must be given exactly once.
+//! A header line is skipped, as are blank lines and lines starting with #.
+//!
+//! In JSON, the tariff is either a list of 24*7 integer prices or an object with such a list under "prices".
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use super::integer_consumption::Prices;
+use serde_json;
+use std::io::BufRead;
+
+const HOURS: usize = 24*7;
+const DAYS: [&'static str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// A change to the price of one hour
+#[derive(Debug, PartialEq)]
+pub struct Change {
+    /// Hour of the week
+    pub hour: usize,
+    /// The price before
+    pub old: i32,
+    /// The price after
+    pub new: i32,
+}
+
+/// A day of the week as e.g. "Tue". Day 0 is Monday.
+pub fn day_name(day: usize) -> &'static str {
+    DAYS[day % 7]
+}
+
+/// An hour of the week as e.g. "Tue 07:00"
+pub fn slot_name(hour: usize) -> String {
+    format!("{} {:02}:00", day_name(hour / 24), hour % 24)
+}
+
+// "5" or "0-23"
+fn parse_hours(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.splitn(2, '-');
+    let first = match parts.next().map(|p| p.trim().parse::<usize>()) {
+        Some(Ok(f)) => f,
+        _ => return None,
+    };
+    let last = match parts.next().map(|p| p.trim().parse::<usize>()) {
+        None => first,
+        Some(Ok(l)) => l,
+        Some(Err(_)) => return None,
+    };
+
+    if first <= last && last < HOURS {
+        Some((first, last))
+    } else {
+        None
+    }
+}
+
+/// Read a tariff in CSV (see the module documentation). Returns a description of the first problem found.
+pub fn read_csv<R: BufRead>(input: R) -> Result<Prices, String> {
+    let mut prices = [0; HOURS];
+    let mut given = [false; HOURS];
+    let mut first = true;
+
+    for (i, line) in input.lines().enumerate() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if fields.len() != 2 {
+            return Err(format!("line {}: there should be two columns: hours and price", i + 1));
+        }
+
+        let price = fields[1].parse::<i32>();
+        let (start, end) = match (parse_hours(fields[0]), price.is_ok()) {
+            (Some(h), true) => h,
+            _ if first => { // header
+                first = false;
+                continue;
+            },
+            (None, _) => return Err(format!("line {}: {} is not an hour of the week (0 to {}) or a range of them", i + 1, fields[0], HOURS - 1)),
+            (Some(_), false) => return Err(format!("line {}: the price should be a 32 bit signed integer", i + 1)),
+        };
+        first = false;
+        let price = price.unwrap();
+
+        for hour in start..(end + 1) {
+            if given[hour] {
+                return Err(format!("line {}: the price for hour {} was already given", i + 1, hour));
+            }
+            given[hour] = true;
+            prices[hour] = price;
+        }
+    }
+
+    match given.iter().position(|g| !g) {
+        Some(hour) => Err(format!("there is no price for hour {} ({})", hour, slot_name(hour))),
+        None => Ok(prices),
+    }
+}
+
+/// Read a tariff in JSON (see the module documentation). Returns a description of the problem if it is malformed.
+pub fn from_json(text: &str) -> Result<Prices, String> {
+    let value: serde_json::Value = match serde_json::from_str(text) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("not valid JSON: {}", e)),
+    };
+
+    let list = match value.get("prices").unwrap_or(&value).as_array() {
+        Some(l) => l,
+        None => return Err(String::from("expected a list of prices or an object with a list under \"prices\"")),
+    };
+
+    if list.len() != HOURS {
+        return Err(format!("there should be one price for each of the {} hours of the week, not {}", HOURS, list.len()));
+    }
+
+    let mut prices = [0; HOURS];
+    for (hour, v) in list.iter().enumerate() {
+        prices[hour] = match v.as_i64() {
+            Some(p) if p >= i32::min_value() as i64 && p <= i32::max_value() as i64 => p as i32,
+            _ => return Err(format!("the price for hour {} should be a 32 bit signed integer", hour)),
+        };
+    }
+
+    Ok(prices)
+}
+
+/// The hours whose price is different in new
+pub fn diff(old: &Prices, new: &Prices) -> Vec<Change> {
+    (0..HOURS).filter(|h| old[*h] != new[*h]).map(|h| Change { hour: h, old: old[h], new: new[h] }).collect()
+}
+
+/// The prices as runs of hours with the same price, e.g. "0-6: 5, 7-167: 10"
+pub fn runs(prices: &Prices) -> String {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for hour in 1..(HOURS + 1) {
+        if hour == HOURS || prices[hour] != prices[start] {
+            runs.push(format!("{}-{}: {}", start, hour - 1, prices[start]));
+            start = hour;
+        }
+    }
+
+    runs.join(", ")
+}
+
+/// The prices as a table with a row for each day and a column for each hour
+pub fn table(prices: &Prices) -> String {
+    let width = prices.iter().map(|p| p.to_string().len()).max().unwrap_or(1);
+
+    let mut text = String::from("   ");
+    for hour in 0..24 {
+        text += &format!(" {:>width$}", hour, width = width);
+    }
+    text += "\n";
+
+    for (day, name) in DAYS.iter().enumerate() {
+        text += name;
+        for hour in 0..24 {
+            text += &format!(" {:>width$}", prices[day * 24 + hour], width = width);
+        }
+        text += "\n";
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv() {
+        let text = "hours,price\n\
+                    0-6,5\n\
+                    # the rest of the week\n\
+                    7-166,10\n\
+                    167,2\n";
+        let prices = read_csv(text.as_bytes()).unwrap();
+        assert_eq!(prices[6], 5);
+        assert_eq!(prices[7], 10);
+        assert_eq!(prices[167], 2);
+        assert_eq!(runs(&prices), "0-6: 5, 7-166: 10, 167-167: 2");
+
+        assert!(read_csv("0-100,1\n".as_bytes()).is_err()); // missing hours
+        assert!(read_csv("0-167,1\n5,2\n".as_bytes()).is_err()); // hour 5 twice
+        assert!(read_csv("0-168,1\n".as_bytes()).is_err());
+        assert!(read_csv("0-167,1\nnonsense,1\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn json() {
+        let list: Vec<String> = (0..(24*7)).map(|h| (h % 3).to_string()).collect();
+        let text = format!("[{}]", list.join(", "));
+        let prices = from_json(&text).unwrap();
+        assert_eq!(prices[4], 1);
+        assert_eq!(from_json(&format!("{{\"prices\": {}}}", text)).unwrap()[4], 1);
+
+        assert!(from_json("[1, 2, 3]").is_err());
+        assert!(from_json("{\"price\": 1}").is_err());
+        assert!(from_json(&text.replace("[0", "[0.5")).is_err());
+    }
+
+    #[test]
+    fn differences() {
+        let old = [1; 24*7];
+        let mut new = old;
+        new[3] = 4;
+        new[30] = 0;
+        assert_eq!(diff(&old, &new), vec!(Change { hour: 3, old: 1, new: 4 }, Change { hour: 30, old: 1, new: 0 }));
+        assert_eq!(slot_name(30), "Tue 06:00");
+        assert!(table(&new).lines().nth(2).unwrap().starts_with("Tue 1 1 1 1 1 1 0"));
+    }
+}
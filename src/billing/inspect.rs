@@ -0,0 +1,585 @@
+//! # Message inspector
+//!
+//! Decodes a captured stream of three party protocol messages from one sender and checks everything that can be checked with the keys available, without panicking on malformed input.
+//! This is for working out why a bill failed verification.
+//!
+//! The supported messages are
+//!
+//! * from the meter to the customer: readings and batch signatures
+//! * from the customer to the provider: bills
+//! * from the provider to the customer: prices, countersigned prices and receipts
+//!
+//! The link between the customer and the provider is encrypted by proj_net, so that stream has to be captured after decryption.
+//! Decoding stops at the first message which cannot be decoded, because there is no way to tell where the next message starts.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use super::common;
+use super::common::PRICES_MESSAGE_LEN;
+use super::consumption::Consumption;
+use super::consumption::integer_consumption::IntegerConsumption;
+use super::consumption::tariff;
+use super::merkle;
+use super::range_proof::Group;
+use super::regulator::{COUNTERSIGNATURE_LEN, verify_countersignature};
+use proj_crypto::asymmetric::{commitments, sign};
+use sodiumoxide::crypto::hash::sha256;
+use gmp::mpz::Mpz;
+use serde_json::{Map, Value};
+use std::io::BufRead;
+use std::mem::{size_of, transmute};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Who sent the captured stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sender {
+    /// The meter, to the customer
+    Meter,
+    /// The customer, to the provider
+    Customer,
+    /// The provider, to the customer
+    Provider,
+}
+
+/// What the inspector can check against. Checks which need something missing are reported as not checked.
+pub struct InspectKeys {
+    /// The meter's signing key
+    pub meter: Option<sign::PublicKey>,
+    /// The provider's signing key
+    pub provider: Option<sign::PublicKey>,
+    /// The regulator's signing key
+    pub regulator: Option<sign::PublicKey>,
+    /// The commitment parameters
+    pub params: Option<commitments::DHParams>,
+}
+
+/// One decoded message
+pub struct Message {
+    /// The message header (e.g. "reading"), or "error" if the message could not be decoded
+    pub kind: String,
+    /// (name, value) for each field, in the order they were sent
+    pub fields: Vec<(String, String)>,
+    /// (description, result) for each check. The result is None if the check needs a key which was not given.
+    pub checks: Vec<(String, Option<bool>)>,
+}
+
+impl Message {
+    fn new(kind: &str) -> Message {
+        Message {
+            kind: kind.to_string(),
+            fields: Vec::new(),
+            checks: Vec::new(),
+        }
+    }
+
+    fn error(problem: String) -> Message {
+        let mut message = Message::new("error");
+        message.field("problem", problem);
+        message.checks.push((String::from("message decoded"), Some(false)));
+        message
+    }
+
+    fn field<S: ToString>(&mut self, name: &str, value: S) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+
+    fn check(&mut self, description: &str, result: Option<bool>) {
+        self.checks.push((description.to_string(), result));
+    }
+
+    /// Did every check which was made pass?
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|&(_, result)| result != Some(false))
+    }
+
+    /// A human readable breakdown, one line per field or check
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\n", self.kind);
+        for &(ref name, ref value) in &self.fields {
+            text += &format!("    {}: {}\n", name, value);
+        }
+        for &(ref description, result) in &self.checks {
+            let result = match result {
+                Some(true) => "pass",
+                Some(false) => "FAIL",
+                None => "not checked",
+            };
+            text += &format!("    [{}] {}\n", result, description);
+        }
+
+        text
+    }
+
+    /// The fields as one JSON object of name: value
+    pub fn fields_json(&self) -> Value {
+        let mut fields = Map::new();
+        for &(ref name, ref value) in &self.fields {
+            fields.insert(name.clone(), Value::String(value.clone()));
+        }
+
+        Value::Object(fields)
+    }
+
+    /// The checks as a JSON list of {"check": description, "result": "pass", "fail" or "not checked"}
+    pub fn checks_json(&self) -> Value {
+        Value::Array(self.checks.iter().map(|&(ref description, result)| {
+            let result = match result {
+                Some(true) => "pass",
+                Some(false) => "fail",
+                None => "not checked",
+            };
+
+            let mut check = Map::new();
+            check.insert(String::from("check"), Value::String(description.clone()));
+            check.insert(String::from("result"), Value::String(String::from(result)));
+            Value::Object(check)
+        }).collect())
+    }
+}
+
+// what has been seen so far in the stream
+struct State {
+    // leaves of readings which have not been batched yet
+    pending: Vec<String>,
+    // the counter the next reading should have
+    next_counter: Option<u64>,
+    // hash of the last batch
+    last_batch: Option<String>,
+    // sequence number and hash of the last tariff
+    last_tariff: Option<(u64, [u8; sha256::DIGESTBYTES])>,
+}
+
+/// Decode every message in input, which was sent by sender. If a message cannot be decoded it is reported as an "error" message and decoding stops.
+pub fn inspect<R: BufRead>(mut input: R, sender: Sender, keys: &InspectKeys) -> Vec<Message> {
+    let mut state = State {
+        pending: Vec::new(),
+        next_counter: None,
+        last_batch: None,
+        last_tariff: None,
+    };
+    let group = keys.params.as_ref().map(|p| Group::from_params(p));
+    let mut messages = Vec::new();
+
+    loop {
+        let header = match next_line(&mut input) {
+            Ok(Some(h)) => h,
+            Ok(None) => break,
+            Err(e) => {
+                messages.push(Message::error(e));
+                break;
+            },
+        };
+
+        let result = match (sender, header.as_str()) {
+            (Sender::Meter, "reading") => decode_reading(&mut input, group.as_ref(), &mut state),
+            (Sender::Meter, "batch") => decode_meter_batch(&mut input, keys, &mut state),
+            (Sender::Customer, "bill") => decode_bill(&mut input, keys, &mut state),
+            (Sender::Provider, "prices") => decode_prices(&mut input, keys, &mut state, false),
+            (Sender::Provider, "countersigned_prices") => decode_prices(&mut input, keys, &mut state, true),
+            (Sender::Provider, "receipt") => decode_receipt(&mut input, keys),
+            _ => Err(format!("{:?} is not a message the {:?} sends (or the capture does not start at the beginning of a message)", header, sender)),
+        };
+
+        match result {
+            Ok(m) => messages.push(m),
+            Err(e) => {
+                messages.push(Message::error(e));
+                break;
+            },
+        }
+    }
+
+    messages
+}
+
+// the next line without the newline, or None at the end of the input
+fn next_line<R: BufRead>(input: &mut R) -> Result<Option<String>, String> {
+    let mut buf = Vec::new();
+    match input.read_until(b'\n', &mut buf) {
+        Ok(0) => Ok(None),
+        Ok(_) => {
+            if buf.pop() != Some(b'\n') {
+                return Err(String::from("the capture ends part way through a line"));
+            }
+            match String::from_utf8(buf) {
+                Ok(s) => Ok(Some(s)),
+                Err(_) => Err(String::from("a line is not valid UTF-8")),
+            }
+        },
+        Err(e) => Err(format!("error reading the capture: {}", e)),
+    }
+}
+
+// the next line of a message which has already started
+fn line<R: BufRead>(input: &mut R) -> Result<String, String> {
+    match try!(next_line(input)) {
+        Some(l) => Ok(l),
+        None => Err(String::from("the capture ends part way through a message")),
+    }
+}
+
+fn parse<T: ::std::str::FromStr>(text: &str, what: &str) -> Result<T, String> {
+    match text.parse() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("could not parse the {} {:?}", what, text)),
+    }
+}
+
+fn parse_hex_number(text: &str, what: &str) -> Result<Mpz, String> {
+    match Mpz::from_str_radix(text, 16) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("could not parse the {} {:?}", what, text)),
+    }
+}
+
+// reverses common::stringify_bytes
+fn parse_bytes(text: &str) -> Result<Vec<u8>, String> {
+    let mut ret = Vec::new();
+    for part in text.split_whitespace() {
+        ret.push(try!(parse::<u8>(part, "signed byte")));
+    }
+
+    Ok(ret)
+}
+
+// the contents of a signed message and whether the signature is valid (None if there is no key to check it with)
+fn open_signed(signed: &[u8], key: Option<&sign::PublicKey>) -> Result<(Vec<u8>, Option<bool>), String> {
+    if signed.len() < sign::SIGNATUREBYTES {
+        return Err(String::from("a signed message is shorter than a signature"));
+    }
+    let contents = Vec::from(&signed[sign::SIGNATUREBYTES..]);
+
+    match key {
+        Some(k) => match sign::verify(signed, k) {
+            Ok(m) => Ok((m, Some(true))),
+            Err(_) => Ok((contents, Some(false))),
+        },
+        None => Ok((contents, None)),
+    }
+}
+
+// "commitment other counter"
+fn parse_leaf(leaf: &str) -> Result<(Mpz, u64, u64), String> {
+    let parts: Vec<&str> = leaf.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Err(format!("the reading {:?} should be \"commitment other counter\"", leaf));
+    }
+
+    Ok((try!(parse_hex_number(parts[0], "commitment")), try!(parse(parts[1], "slot")), try!(parse(parts[2], "counter"))))
+}
+
+fn decode_reading<R: BufRead>(input: &mut R, group: Option<&Group>, state: &mut State) -> Result<Message, String> {
+    let opening = try!(line(input));
+    let leaf = try!(line(input));
+
+    let parts: Vec<&str> = opening.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err(format!("the opening {:?} should be \"cons a\"", opening));
+    }
+    let cons: i32 = try!(parse(parts[0], "units"));
+    let a = try!(parse_hex_number(parts[1], "opening"));
+    let (commitment, other, counter) = try!(parse_leaf(&leaf));
+
+    let mut message = Message::new("reading");
+    message.field("units", cons);
+    message.field("opening", parts[1]);
+    message.field("commitment", commitment.to_str_radix(16));
+    message.field("slot", other);
+    message.field("counter", counter);
+
+    message.check("the units are not negative", Some(cons >= 0));
+    message.check("the slot is an hour of the week", Some(other < 24*7));
+    let opens = match group {
+        Some(g) if cons >= 0 => Some(g.commit(&Mpz::from(cons), &a) == commitment),
+        Some(_) => Some(false),
+        None => None,
+    };
+    message.check("the commitment opens to the units", opens);
+    message.check("the counter follows the previous reading", state.next_counter.map(|c| c == counter));
+
+    state.next_counter = Some(counter + 1);
+    state.pending.push(leaf);
+
+    Ok(message)
+}
+
+// check a signed batch root against the leaves it should cover, adding the fields and checks to message with prefix
+fn check_batch(message: &mut Message, prefix: &str, signed_root_str: &str, leaves: &[String], keys: &InspectKeys, state: &mut State) -> Result<(), String> {
+    let signed = try!(parse_bytes(signed_root_str));
+    let (text, signature) = try!(open_signed(&signed, keys.meter.as_ref()));
+    let text = match String::from_utf8(text) {
+        Ok(t) => t,
+        Err(_) => return Err(String::from("the signed batch is not valid UTF-8")),
+    };
+
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    if parts.len() != 4 {
+        return Err(format!("the signed batch {:?} should be \"root count first_counter prev_hash\"", text));
+    }
+    let count: usize = try!(parse(parts[1], "batch size"));
+    let first_counter: u64 = try!(parse(parts[2], "first counter"));
+    let hash = merkle::chain_hash(&text);
+
+    message.field(&format!("{}root", prefix), parts[0]);
+    message.field(&format!("{}readings", prefix), count);
+    message.field(&format!("{}first counter", prefix), first_counter);
+    message.field(&format!("{}previous batch", prefix), parts[3]);
+    message.field(&format!("{}hash", prefix), &hash);
+
+    message.check(&format!("{}signed by the meter", prefix), signature);
+    message.check(&format!("{}covers the readings sent", prefix), Some(count == leaves.len()));
+    let root_matches = !leaves.is_empty() && common::to_hex(&merkle::root(leaves)) == parts[0];
+    message.check(&format!("{}the Merkle root matches the readings", prefix), Some(root_matches));
+
+    let mut consecutive = true;
+    for (i, leaf) in leaves.iter().enumerate() {
+        let (_, _, counter) = try!(parse_leaf(leaf));
+        consecutive = consecutive && counter == first_counter + i as u64;
+    }
+    message.check(&format!("{}the readings are numbered from the first counter", prefix), Some(consecutive));
+
+    // the chain can only be checked if the capture includes the batch before, or this is the first batch
+    let follows = if parts[3] == merkle::GENESIS_HASH {
+        Some(first_counter == 0)
+    } else {
+        state.last_batch.as_ref().map(|h| h == parts[3])
+    };
+    message.check(&format!("{}follows the previous batch", prefix), follows);
+
+    state.last_batch = Some(hash);
+    Ok(())
+}
+
+fn decode_meter_batch<R: BufRead>(input: &mut R, keys: &InspectKeys, state: &mut State) -> Result<Message, String> {
+    let signed_root_str = try!(line(input));
+    let leaves = state.pending.split_off(0);
+
+    let mut message = Message::new("batch");
+    try!(check_batch(&mut message, "", &signed_root_str, &leaves, keys, state));
+    Ok(message)
+}
+
+fn decode_bill<R: BufRead>(input: &mut R, keys: &InspectKeys, state: &mut State) -> Result<Message, String> {
+    let bill: i64 = try!(parse(&try!(line(input)), "bill"));
+    let a = try!(line(input));
+    try!(parse_hex_number(&a, "opening"));
+    let tariff_hash = try!(line(input));
+    let num_batches: usize = try!(parse(&try!(line(input)), "number of batches"));
+
+    let mut message = Message::new("bill");
+    message.field("amount", bill);
+    message.field("opening", &a);
+    message.field("tariff hash", &tariff_hash);
+    message.field("batches", num_batches);
+
+    let mut others = Vec::new();
+    for i in 0..num_batches {
+        let signed_root_str = try!(line(input));
+        let len: usize = try!(parse(&try!(line(input)), "batch length"));
+
+        let mut leaves = Vec::new();
+        for _ in 0..len {
+            let leaf = try!(line(input));
+            let (_, other, _) = try!(parse_leaf(&leaf));
+            others.push(other);
+            leaves.push(leaf);
+        }
+
+        try!(check_batch(&mut message, &format!("batch {} ", i), &signed_root_str, &leaves, keys, state));
+    }
+
+    message.field("readings", others.len());
+    if let (Some(first), Some(last)) = (others.iter().min(), others.iter().max()) {
+        message.field("slots", format!("{} to {}", first, last));
+    }
+    message.check("an empty bill is for nothing", if num_batches == 0 { Some(bill == 0) } else { None });
+    message.check("the bill matches the readings (needs the tariff, which is only known to the provider)", None);
+
+    Ok(message)
+}
+
+fn decode_prices<R: BufRead>(input: &mut R, keys: &InspectKeys, state: &mut State, countersigned: bool) -> Result<Message, String> {
+    let mut signed = vec!(0 as u8; PRICES_MESSAGE_LEN);
+    if input.read_exact(&mut signed).is_err() {
+        return Err(String::from("the capture ends part way through a prices message"));
+    }
+    let mut countersignature = vec!(0 as u8; COUNTERSIGNATURE_LEN);
+    if countersigned && input.read_exact(&mut countersignature).is_err() {
+        return Err(String::from("the capture ends part way through a countersignature"));
+    }
+
+    let (contents, signature) = try!(open_signed(&signed, keys.provider.as_ref()));
+    if contents.len() != PRICES_MESSAGE_LEN - sign::SIGNATUREBYTES {
+        return Err(String::from("the signed prices are the wrong length"));
+    }
+    let hash = sha256::hash(&contents).0;
+
+    // timestamp, sequence number, previous hash and prices (see common::sign_prices)
+    let (time_bytes, rest) = contents.split_at(size_of::<SystemTime>());
+    let (seq_bytes, rest) = rest.split_at(size_of::<u64>());
+    let (prev_hash, price_bytes) = rest.split_at(sha256::DIGESTBYTES);
+
+    let mut timestamp_bytes = [0 as u8; size_of::<SystemTime>()];
+    timestamp_bytes.copy_from_slice(time_bytes);
+    let timestamp = unsafe { transmute::<[u8; size_of::<SystemTime>()], SystemTime>(timestamp_bytes) };
+
+    let mut seq_array = [0 as u8; 8];
+    seq_array.copy_from_slice(seq_bytes);
+    let seq = unsafe { transmute::<[u8; 8], u64>(seq_array) };
+
+    let mut prices = IntegerConsumption::null_prices();
+    for (i, bytes) in price_bytes.chunks(4).enumerate() {
+        prices[i] = IntegerConsumption::cons_from_bytes(bytes);
+    }
+
+    let mut message = Message::new(if countersigned { "countersigned_prices" } else { "prices" });
+    message.field("tariff number", seq);
+    match timestamp.duration_since(UNIX_EPOCH) {
+        Ok(d) => message.field("signed at", format!("{} seconds after the Unix epoch", d.as_secs())),
+        Err(_) => message.field("signed at", "before the Unix epoch"),
+    }
+    message.field("previous tariff", common::to_hex(prev_hash));
+    message.field("tariff hash", common::to_hex(&hash));
+    message.field("prices", tariff::runs(&prices));
+
+    message.check("signed by the provider", signature);
+    message.check("not signed in the future", Some(SystemTime::now().duration_since(timestamp).is_ok()));
+    let follows = match state.last_tariff {
+        Some((last_seq, ref last_hash)) => Some(seq == last_seq + 1 && prev_hash == &last_hash[..]),
+        None if seq == 1 => Some(prev_hash.iter().all(|b| *b == 0)),
+        None => None,
+    };
+    message.check("follows the previous tariff", follows);
+
+    if countersigned {
+        message.field("countersignature", common::to_hex(&countersignature));
+        message.check("countersigned by the regulator", keys.regulator.as_ref().map(|k| verify_countersignature(&signed, &countersignature, k)));
+    }
+
+    state.last_tariff = Some((seq, hash));
+    Ok(message)
+}
+
+fn decode_receipt<R: BufRead>(input: &mut R, keys: &InspectKeys) -> Result<Message, String> {
+    let signed = try!(parse_bytes(&try!(line(input))));
+    let (text, signature) = try!(open_signed(&signed, keys.provider.as_ref()));
+    let text = match String::from_utf8(text) {
+        Ok(t) => t,
+        Err(_) => return Err(String::from("the receipt is not valid UTF-8")),
+    };
+
+    // "bill_number period_start period_end amount commitments_hash tariff_hash"
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    if parts.len() != 6 {
+        return Err(format!("the receipt {:?} should have six fields", text));
+    }
+
+    let mut message = Message::new("receipt");
+    message.field("bill number", try!(parse::<usize>(parts[0], "bill number")));
+    message.field("first slot", try!(parse::<u64>(parts[1], "first slot")));
+    message.field("last slot", try!(parse::<u64>(parts[2], "last slot")));
+    message.field("amount", try!(parse::<i64>(parts[3], "amount")));
+    message.field("commitments hash", parts[4]);
+    message.field("tariff hash", parts[5]);
+    message.check("signed by the provider", signature);
+
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::three_party::{MeterState, ProviderState, read_or_gen_params, DEFAULT_PARAMS_PATH};
+    use super::super::consumption::Consumption;
+    use super::super::consumption::integer_consumption::IntegerConsumption;
+    use super::super::Keys;
+    use proj_crypto::asymmetric::sign;
+    use sodiumoxide;
+    use std::io::Read;
+    use std::os::unix::net::UnixStream;
+
+    // everything written to the other end of the channel after the writer is done with it
+    fn capture<F: FnOnce(UnixStream)>(write: F) -> Vec<u8> {
+        let (writer, mut reader) = UnixStream::pair().unwrap();
+        write(writer);
+        let mut ret = Vec::new();
+        reader.read_to_end(&mut ret).unwrap();
+        ret
+    }
+
+    #[test]
+    fn meter_stream() {
+        sodiumoxide::init();
+        let (pk, sk) = sign::gen_keypair();
+        let params = read_or_gen_params(DEFAULT_PARAMS_PATH);
+
+        let meter_params = params.clone();
+        let stream = capture(move |channel| {
+            let mut meter = MeterState::new(channel, sk, meter_params);
+            meter.consume(&IntegerConsumption::new(5, 0));
+            meter.consume(&IntegerConsumption::new(7, 1));
+            meter.end_batch();
+        });
+
+        let keys = InspectKeys { meter: Some(pk), provider: None, regulator: None, params: Some(params) };
+        let messages = inspect(&stream[..], Sender::Meter, &keys);
+        assert_eq!(messages.iter().map(|m| m.kind.as_str()).collect::<Vec<&str>>(), vec!("reading", "reading", "batch"));
+        assert!(messages.iter().all(|m| m.passed()));
+        assert!(messages[2].checks.iter().all(|&(_, r)| r == Some(true)));
+
+        // change the units in the first reading
+        let text = String::from_utf8(stream.clone()).unwrap().replacen("reading\n5 ", "reading\n6 ", 1);
+        let messages = inspect(text.as_bytes(), Sender::Meter, &keys);
+        assert!(!messages[0].passed());
+        assert!(messages[1].passed());
+
+        // the wrong key
+        let (other_pk, _) = sign::gen_keypair();
+        let keys = InspectKeys { meter: Some(other_pk), provider: None, regulator: None, params: None };
+        let messages = inspect(&stream[..], Sender::Meter, &keys);
+        assert!(messages[0].passed());
+        assert!(!messages[2].passed());
+
+        // a truncated capture
+        let messages = inspect(&stream[..(stream.len() - 10)], Sender::Meter, &keys);
+        assert_eq!(messages.last().unwrap().kind, "error");
+    }
+
+    #[test]
+    fn provider_stream() {
+        sodiumoxide::init();
+        let (pk, sk) = sign::gen_keypair();
+        let (meter_pk, _) = sign::gen_keypair();
+
+        let stream = capture(move |channel| {
+            let mut provider = ProviderState::new(channel, [1; 24*7], Keys { my_sk: sk, their_pk: meter_pk }, read_or_gen_params(DEFAULT_PARAMS_PATH));
+            let mut prices = [3; 24*7];
+            prices[0] = 5;
+            provider.change_prices(&prices);
+            provider.change_prices(&[2; 24*7]);
+        });
+
+        let keys = InspectKeys { meter: None, provider: Some(pk), regulator: None, params: None };
+        let messages = inspect(&stream[..], Sender::Provider, &keys);
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|m| m.passed()));
+        assert!(messages[0].fields.contains(&(String::from("prices"), String::from("0-0: 5, 1-167: 3"))));
+        assert_eq!(messages[1].fields_json()["tariff number"], Value::from("2"));
+        assert_eq!(messages[1].checks_json()[0]["result"], Value::from("pass"));
+
+        // a meter stream is not a provider stream
+        let messages = inspect(&stream[..], Sender::Meter, &keys);
+        assert_eq!(messages.len(), 1);
+        assert!(!messages[0].passed());
+    }
+}
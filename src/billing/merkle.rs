@@ -0,0 +1,218 @@
+//! Merkle trees over batches of meter readings, so that the meter only has to sign one root per batch
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use sodiumoxide::crypto::hash::sha256;
+use proj_crypto::asymmetric::sign;
+use gmp::mpz::Mpz;
+use super::common::{try_unstringify_bytes, to_hex};
+
+pub type Hash = [u8; sha256::DIGESTBYTES];
+
+// the prefixes stop a leaf being passed off as an interior node
+fn hash_leaf(leaf: &[u8]) -> Hash {
+    let mut buf = vec!(0 as u8);
+    buf.extend_from_slice(leaf);
+    sha256::hash(&buf).0
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    let mut buf = vec!(1 as u8);
+    buf.extend_from_slice(left);
+    buf.extend_from_slice(right);
+    sha256::hash(&buf).0
+}
+
+// the hashes of the next level up. An odd node out is carried up unchanged.
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    let mut ret = Vec::new();
+
+    for pair in level.chunks(2) {
+        if pair.len() == 2 {
+            ret.push(hash_node(&pair[0], &pair[1]));
+        } else {
+            ret.push(pair[0]);
+        }
+    }
+
+    ret
+}
+
+/// The root of the tree with these leaves. Panics if there are no leaves.
+pub fn root<T: AsRef<[u8]>>(leaves: &[T]) -> Hash {
+    assert!(leaves.len() > 0);
+    let mut level: Vec<Hash> = leaves.iter().map(|l| hash_leaf(l.as_ref())).collect();
+
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    level[0]
+}
+
+/// The sibling hashes needed to get from leaf index to the root, starting at the bottom of the tree
+pub fn proof<T: AsRef<[u8]>>(leaves: &[T], index: usize) -> Vec<Hash> {
+    assert!(index < leaves.len());
+    let mut level: Vec<Hash> = leaves.iter().map(|l| hash_leaf(l.as_ref())).collect();
+    let mut index = index;
+    let mut ret = Vec::new();
+
+    while level.len() > 1 {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            ret.push(level[sibling]);
+        }
+        level = next_level(&level);
+        index = index / 2;
+    }
+
+    ret
+}
+
+/// Check that leaf is at position index of a tree with count leaves and the given root
+pub fn verify_proof(leaf: &[u8], index: usize, count: usize, proof: &[Hash], root: &Hash) -> bool {
+    if index >= count {
+        return false;
+    }
+
+    let mut hash = hash_leaf(leaf);
+    let mut index = index;
+    let mut level_len = count;
+    let mut proof_iter = proof.iter();
+
+    while level_len > 1 {
+        let sibling = index ^ 1;
+        if sibling < level_len {
+            let sibling_hash = match proof_iter.next() {
+                Some(h) => h,
+                None => return false,
+            };
+
+            hash = if index % 2 == 0 {
+                hash_node(&hash, sibling_hash)
+            } else {
+                hash_node(sibling_hash, &hash)
+            };
+        } // else we are the odd one out and get carried up
+
+        index = index / 2;
+        level_len = (level_len + 1) / 2;
+    }
+
+    proof_iter.next().is_none() && hash == *root
+}
+
+/// Hash linking a batch to the one before the first batch the meter signs
+pub const GENESIS_HASH: &'static str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The contents of a batch signature from the meter
+pub struct SignedRoot {
+    /// Hex Merkle root of the leaves in the batch
+    pub root: String,
+    /// Number of leaves in the batch
+    pub count: usize,
+    /// The counter of the first reading in the batch. The readings are numbered consecutively.
+    pub first_counter: u64,
+    /// The hash of the previous signed batch (or GENESIS_HASH)
+    pub prev_hash: String,
+    /// The hash of this signed batch, which the next batch should refer to
+    pub hash: String,
+}
+
+/// The text the meter signs for a batch: "root count first_counter prev_hash"
+pub fn signed_root_text(root: &Hash, count: usize, first_counter: u64, prev_hash: &str) -> String {
+    format!("{} {} {} {}", to_hex(root), count, first_counter, prev_hash)
+}
+
+/// The hash used to chain batches together
+pub fn chain_hash(signed_root_text: &str) -> String {
+    to_hex(&sha256::hash(signed_root_text.as_bytes()).0)
+}
+
+/// Check the meter's signature on the root of a batch and parse it. Returns why if the signature is bad or the batch is malformed.
+pub fn verify_signed_root(signed_root_str: &str, meter_key: &sign::PublicKey) -> Result<SignedRoot, String> {
+    let signed_root = match try_unstringify_bytes(signed_root_str) {
+        Some(b) => b,
+        None => return Err(String::from("the batch signature is malformed")),
+    };
+    let text_bytes = match sign::verify(&signed_root, meter_key) {
+        Ok(b) => b,
+        Err(_) => return Err(String::from("verification of the meter's signature on a batch failed")),
+    };
+    let text = match String::from_utf8(text_bytes) {
+        Ok(t) => t,
+        Err(_) => return Err(String::from("the signed batch is not text")),
+    };
+
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    if fields.len() != 4 {
+        return Err(format!("the signed batch {:?} should be \"root count first_counter prev_hash\"", text));
+    }
+    let count = match usize::from_str_radix(fields[1], 10) {
+        Ok(c) => c,
+        Err(_) => return Err(format!("could not parse the batch length {:?}", fields[1])),
+    };
+    let first_counter = match u64::from_str_radix(fields[2], 10) {
+        Ok(c) => c,
+        Err(_) => return Err(format!("could not parse the counter {:?}", fields[2])),
+    };
+
+    Ok(SignedRoot {
+        root: fields[0].to_string(),
+        count: count,
+        first_counter: first_counter,
+        prev_hash: fields[3].to_string(),
+        hash: chain_hash(&text),
+    })
+}
+
+/// Leaves look like "commitment other counter". Returns (commitment, other, counter), or why the leaf is malformed.
+pub fn parse_leaf(leaf: &str) -> Result<(Mpz, u64, u64), String> {
+    let fields: Vec<&str> = leaf.split_whitespace().collect();
+    if fields.len() != 3 {
+        return Err(format!("the reading {:?} should be \"commitment other counter\"", leaf));
+    }
+
+    let commitment = match Mpz::from_str_radix(fields[0], 16) {
+        Ok(c) => c,
+        Err(_) => return Err(format!("could not parse the commitment {:?}", fields[0])),
+    };
+    let other = match u64::from_str_radix(fields[1], 10) {
+        Ok(o) => o,
+        Err(_) => return Err(format!("could not parse the slot {:?}", fields[1])),
+    };
+    let counter = match u64::from_str_radix(fields[2], 10) {
+        Ok(c) => c,
+        Err(_) => return Err(format!("could not parse the counter {:?}", fields[2])),
+    };
+
+    Ok((commitment, other, counter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs() {
+        let leaves: Vec<String> = (0..7).map(|i| format!("leaf {}", i)).collect();
+        let tree_root = root(&leaves);
+
+        for i in 0..leaves.len() {
+            let path = proof(&leaves, i);
+            assert!(verify_proof(leaves[i].as_bytes(), i, leaves.len(), &path, &tree_root));
+            assert!(!verify_proof(b"something else", i, leaves.len(), &path, &tree_root));
+            assert!(!verify_proof(leaves[(i + 1) % leaves.len()].as_bytes(), i, leaves.len(), &path, &tree_root));
+        }
+    }
+}
@@ -110,7 +110,7 @@
 use std::io::prelude::*;
 use proj_crypto::asymmetric::sign;
 
-/// Cryptographic Keys
+/// Cryptographic Keys. The secret key wipes itself when it is dropped (sodiumoxide does this).
 pub struct Keys {
     /// Secret key and public key
     pub my_sk: sign::SecretKey,
@@ -124,6 +124,8 @@ pub enum MeterKeys {
     SignOnMeter(Keys),
     /// (meter secret key, meter public key, provider public key)
     ThreeParty(sign::SecretKey, sign::PublicKey, sign::PublicKey),
+    /// (meter secret key, meter public key, provider public key)
+    Paillier(sign::SecretKey, sign::PublicKey, sign::PublicKey),
 }
 
 /// Functionality which all billing protocols must provide.
@@ -164,12 +166,23 @@ pub trait BillingProtocol<T: Read + Write, B> {
 pub mod sign_on_meter;
 pub mod consumption;
 pub mod three_party;
+pub mod aggregation;
+pub mod regulator;
+pub mod paillier;
+pub mod split;
+pub mod inspect;
 mod common;
+mod merkle;
+mod range_proof;
+mod secrets;
+
+pub use self::common::{to_hex, from_hex};
 
 #[cfg(test)]
 mod tests {
     use super::sign_on_meter::SignOnMeter;
     use super::three_party::tests::ThreeParty;
+    use super::paillier::Paillier;
     use super::BillingProtocol;
     use super::consumption::Consumption;
     use sodiumoxide;
@@ -388,5 +401,44 @@ mod tests {
 
         assert_eq!(res, expected_bill);
     }
-}
 
+    #[test]
+    fn paillier() {
+        sodiumoxide::init();
+        let num_cons = 1 + randombytes::randombytes(1)[0] % 9;
+
+        let mut prices = [0 as i32; 24*7];
+
+        for i in 0..(24*7) {
+            prices[i as usize] = random_positive_i32() >> 8;
+        }
+
+        let mut consumption = LinkedList::new();
+        let mut expected_bill = 0 as i64;
+
+        for _ in 0..num_cons {
+            let units = random_positive_i32() >> 20;
+            let hour = random_hour_of_week() as u64;
+
+            let cons = <Paillier<UnixStream> as BillingProtocol<UnixStream, i64>>::Consumption::new(units, hour);
+            consumption.push_back(cons);
+
+            expected_bill += prices[hour as usize] as i64 * units as i64;
+        }
+
+        let socket_path = "./paillier_test_socket".to_string();
+
+        let (m_pk, m_sk) = sign::gen_keypair();
+        let (s_pk, s_sk) = sign::gen_keypair();
+
+        let s_keys = super::Keys {
+            my_sk: s_sk,
+            their_pk: m_pk.clone(),
+        };
+
+        let res = test_billing_protocol::<Paillier<UnixStream>, String, i64>(prices, consumption, socket_path,
+                                                                super::MeterKeys::Paillier(m_sk, m_pk, s_pk), s_keys);
+
+        assert_eq!(res, expected_bill);
+    }
+}
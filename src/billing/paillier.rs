@@ -0,0 +1,841 @@
+//! # Paillier
+//!
+//! Time of use billing using the additively homomorphic Paillier cryptosystem instead of commitments.
+//!
+//! The customer generates a Paillier keypair and gives the public key to their meter. The meter encrypts each reading under this key and signs the ciphertext, along with the time of use, a counter and the hash of the public key, so that the customer can neither change nor drop readings.
+//!
+//! At the end of the billing period the customer sends the signed ciphertexts to the provider, who computes the encrypted bill by raising each ciphertext to the price for its time of use and multiplying the results together. The customer decrypts the same encrypted bill and proves that the decryption is correct by revealing the randomness r such that the encrypted bill is (1 + bill n) r^n mod n^2. This reveals the bill but nothing about the individual readings.
+//!
+//! The provider signs a receipt for each bill it accepts. The customer keeps the readings until the receipt arrives, so that a bill the provider rejects for using an out of date tariff can be sent again.
+//!
+//! Prices are signed and chained exactly as in the other protocols. The meter and the customer are separate parties but in this implementation they run in the same process: readings are passed from the meter to the customer with PaillierCustomer::receive_reading.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use super::BillingProtocol;
+use super::consumption::integer_consumption::{IntegerConsumption, Prices};
+use super::consumption::Consumption;
+use super::common::{self, TariffChain, stringify_bytes, read_line_blocking};
+use super::secrets;
+use proj_crypto::asymmetric::sign;
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::randombytes;
+use gmp::mpz::Mpz;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the modulus n in bits
+pub const DEFAULT_KEY_BITS: usize = 2048;
+
+/// How long the client waits for the provider's receipt after sending a bill (see BillingProtocol::send_billing_information)
+pub const RECEIPT_TIMEOUT_SECS: u64 = 10;
+
+/// Paillier public key. The generator is always n + 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublicKey {
+    /// The modulus
+    pub n: Mpz,
+    n_squared: Mpz,
+}
+
+impl PublicKey {
+    /// Public key with modulus n
+    pub fn from_modulus(n: Mpz) -> PublicKey {
+        let n_squared = n.clone() * n.clone();
+        PublicKey {
+            n: n,
+            n_squared: n_squared,
+        }
+    }
+
+    /// Hex encoded sha256 of the modulus, which the meter includes in everything it signs
+    pub fn hash(&self) -> String {
+        common::to_hex(&sha256::hash(self.n.to_str_radix(16).as_bytes()).0)
+    }
+
+    /// Encrypt m using randomness r: (1 + mn) r^n mod n^2
+    pub fn encrypt_with(&self, m: &Mpz, r: &Mpz) -> Mpz {
+        let gm = (Mpz::one() + m.modulus(&self.n) * self.n.clone()).modulus(&self.n_squared);
+        (gm * r.powm(&self.n, &self.n_squared)).modulus(&self.n_squared)
+    }
+
+    /// Encrypt m using fresh randomness
+    pub fn encrypt(&self, m: &Mpz) -> Mpz {
+        let mut r = self.random_unit();
+        let ret = self.encrypt_with(m, &r);
+        secrets::wipe_mpz(&mut r);
+        ret
+    }
+
+    /// Is c a possible ciphertext?
+    pub fn valid_ciphertext(&self, c: &Mpz) -> bool {
+        *c > Mpz::zero() && *c < self.n_squared && c.gcd(&self.n) == Mpz::one()
+    }
+
+    /// The encryption of the sum of the weighted plaintexts, where each reading is (ciphertext, other)
+    pub fn weighted_sum(&self, readings: &[(Mpz, u64)], prices: &Prices) -> Mpz {
+        let mut ret = Mpz::one();
+
+        for &(ref c, other) in readings {
+            let price = IntegerConsumption::get_price(prices, other) as i64;
+            let term = if price >= 0 {
+                c.powm(&Mpz::from(price), &self.n_squared)
+            } else {
+                c.invert(&self.n_squared).unwrap().powm(&Mpz::from(-price), &self.n_squared)
+            };
+            ret = (ret * term).modulus(&self.n_squared);
+        }
+
+        ret
+    }
+
+    /// Does r prove that c decrypts to m?
+    pub fn verify_decryption(&self, c: &Mpz, m: i64, r: &Mpz) -> bool {
+        if *r <= Mpz::zero() || *r >= self.n || r.gcd(&self.n) != Mpz::one() {
+            return false;
+        }
+
+        self.encrypt_with(&Mpz::from(m), r) == *c
+    }
+
+    // uniformly random element of the multiplicative group mod n
+    fn random_unit(&self) -> Mpz {
+        loop {
+            let r = random_mpz(self.n.bit_length() + 128).modulus(&self.n);
+            if r.gcd(&self.n) == Mpz::one() {
+                return r;
+            }
+        }
+    }
+}
+
+/// Paillier secret key. This is wiped when it is dropped.
+pub struct SecretKey {
+    /// The corresponding public key
+    pub public: PublicKey,
+    lambda: Mpz,
+    mu: Mpz,
+    // n^-1 mod lambda: used to recover the randomness from a ciphertext
+    n_inv: Mpz,
+}
+
+impl SecretKey {
+    /// Generate a new keypair with a modulus of bits bits
+    pub fn generate(bits: usize) -> SecretKey {
+        assert!(bits % 16 == 0);
+
+        let mut p = random_prime(bits / 2);
+        let mut q = random_prime(bits / 2);
+        while p == q {
+            q = random_prime(bits / 2);
+        }
+
+        let public = PublicKey::from_modulus(p.clone() * q.clone());
+        let mut p_minus_one = p.clone() - Mpz::one();
+        let mut q_minus_one = q.clone() - Mpz::one();
+        let lambda = p_minus_one.lcm(&q_minus_one);
+        // with generator n + 1, L(g^lambda mod n^2) = lambda mod n
+        let mu = lambda.invert(&public.n).unwrap();
+        let n_inv = public.n.invert(&lambda).unwrap();
+
+        secrets::wipe_mpz(&mut p);
+        secrets::wipe_mpz(&mut q);
+        secrets::wipe_mpz(&mut p_minus_one);
+        secrets::wipe_mpz(&mut q_minus_one);
+
+        SecretKey {
+            public: public,
+            lambda: lambda,
+            mu: mu,
+            n_inv: n_inv,
+        }
+    }
+
+    /// Decrypt c. Plaintexts above n/2 are taken to be negative.
+    pub fn decrypt(&self, c: &Mpz) -> i64 {
+        let n = &self.public.n;
+        let x = c.powm(&self.lambda, &self.public.n_squared);
+        let l = (x - Mpz::one()) / n.clone();
+        let mut m = (l * self.mu.clone()).modulus(n);
+
+        if m > n.clone() / Mpz::from(2 as u64) {
+            m = m - n.clone();
+        }
+
+        i64::from_str_radix(&m.to_str_radix(10), 10).unwrap()
+    }
+
+    /// Decrypt c and return the randomness r which proves the decryption (see PublicKey::verify_decryption)
+    pub fn decrypt_with_proof(&self, c: &Mpz) -> (i64, Mpz) {
+        let m = self.decrypt(c);
+        // c = r^n mod n so this is r^(n n_inv) = r mod n
+        let r = c.modulus(&self.public.n).powm(&self.n_inv, &self.public.n);
+        (m, r)
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        secrets::wipe_mpz(&mut self.lambda);
+        secrets::wipe_mpz(&mut self.mu);
+        secrets::wipe_mpz(&mut self.n_inv);
+    }
+}
+
+fn random_mpz(bits: usize) -> Mpz {
+    let bytes = randombytes::randombytes((bits + 7) / 8);
+    Mpz::from_str_radix(&common::to_hex(&bytes), 16).unwrap()
+}
+
+fn random_prime(bits: usize) -> Mpz {
+    let mut x = random_mpz(bits);
+    x.setbit(bits - 1); // so that n has exactly the right number of bits
+    x.setbit(bits - 2);
+    x.nextprime()
+}
+
+// the text signed by the meter for each reading
+fn reading_text(key_hash: &str, counter: u64, c: &Mpz, other: u64) -> String {
+    format!("{} {} {} {}", key_hash, counter, c.to_str_radix(16), other)
+}
+
+/// Verify a reading signed by the meter and return (counter, ciphertext, other). Returns why if the signature is bad, the reading is malformed or it was encrypted under some other key.
+pub fn verify_reading(signed: &[u8], meter_key: &sign::PublicKey, customer_key: &PublicKey) -> Result<(u64, Mpz, u64), String> {
+    let text = match sign::verify(signed, meter_key).map(String::from_utf8) {
+        Ok(Ok(t)) => t,
+        _ => return Err(String::from("the signature on a reading did not verify")),
+    };
+
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    if fields.len() != 4 {
+        return Err(format!("the reading {:?} should be \"key_hash counter ciphertext other\"", text));
+    }
+    if fields[0] != customer_key.hash() {
+        return Err(String::from("the reading was encrypted under another key"));
+    }
+
+    let counter = match u64::from_str_radix(fields[1], 10) {
+        Ok(c) => c,
+        Err(_) => return Err(format!("could not parse the counter {:?}", fields[1])),
+    };
+    let c = try!(parse_hex_number(fields[2], "ciphertext"));
+    let other = match u64::from_str_radix(fields[3], 10) {
+        Ok(o) => o,
+        Err(_) => return Err(format!("could not parse the slot {:?}", fields[3])),
+    };
+
+    if !customer_key.valid_ciphertext(&c) {
+        return Err(String::from("the reading is not a valid ciphertext"));
+    }
+
+    Ok((counter, c, other))
+}
+
+fn parse_hex_number(text: &str, what: &str) -> Result<Mpz, String> {
+    match Mpz::from_str_radix(text, 16) {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("could not parse the {} {:?}", what, text)),
+    }
+}
+
+// the next line from the other party. Anything which is not text fails to parse later.
+fn read_text<R: Read>(channel: &mut R) -> String {
+    String::from_utf8_lossy(&read_line_blocking(channel)).into_owned()
+}
+
+// the hex hash of the signed reading lines in a bill, which receipts refer to
+fn readings_hash(lines: &[String]) -> String {
+    let mut buf = Vec::new();
+    for line in lines {
+        buf.extend_from_slice(line.as_bytes());
+        buf.push(b'\n');
+    }
+
+    common::to_hex(&sha256::hash(&buf).0)
+}
+
+// the text the provider signs to receipt a bill
+fn receipt_text(readings_hash: &str, bill: i64, tariff_hash: &str) -> String {
+    format!("paillier receipt {} {} {}", readings_hash, bill, tariff_hash)
+}
+
+/// State associated with the meter
+pub struct PaillierMeter {
+    sk: sign::SecretKey,
+    customer_key: PublicKey,
+    counter: u64,
+}
+
+impl PaillierMeter {
+    /// Create a new meter encrypting readings under customer_key
+    pub fn new(sk: sign::SecretKey, customer_key: PublicKey) -> PaillierMeter {
+        PaillierMeter {
+            sk: sk,
+            customer_key: customer_key,
+            counter: 0,
+        }
+    }
+
+    /// Encrypt and sign a reading, to be given to the customer
+    pub fn consume(&mut self, consumption: &IntegerConsumption) -> Vec<u8> {
+        assert!(consumption.is_valid());
+
+        let c = self.customer_key.encrypt(&Mpz::from(consumption.units_consumed as i64));
+        let text = reading_text(&self.customer_key.hash(), self.counter, &c, consumption.hour_of_week);
+        self.counter += 1;
+
+        sign::sign(text.as_bytes(), &self.sk)
+    }
+}
+
+// a bill which has been sent but not receipted yet
+struct PendingBill {
+    /// The number of readings (from the start of PaillierCustomer::readings) in the bill
+    readings: usize,
+    amount: i64,
+    readings_hash: String,
+    tariff_hash: String,
+}
+
+/// State associated with the customer
+pub struct PaillierCustomer<T: Read + Write> {
+    channel: T,
+    key: SecretKey,
+    meter_key: sign::PublicKey,
+    provider_key: sign::PublicKey,
+    /// Signed readings not yet receipted. The first of them are in the pending bill, if there is one.
+    readings: Vec<Vec<u8>>,
+    /// The bill waiting for a receipt
+    pending_bill: Option<PendingBill>,
+    prices: Prices,
+    tariff_chain: TariffChain,
+}
+
+impl<T: Read + Write> PaillierCustomer<T> {
+    /// Create a new customer. key is the customer's Paillier keypair; its public part should be given to the meter.
+    pub fn new(channel: T, key: SecretKey, meter_key: sign::PublicKey, provider_key: sign::PublicKey) -> PaillierCustomer<T> {
+        PaillierCustomer {
+            channel: channel,
+            key: key,
+            meter_key: meter_key,
+            provider_key: provider_key,
+            readings: Vec::new(),
+            pending_bill: None,
+            prices: IntegerConsumption::null_prices(),
+            tariff_chain: TariffChain::genesis(),
+        }
+    }
+
+    /// The public key the meter should encrypt readings under
+    pub fn public_key(&self) -> &PublicKey {
+        &self.key.public
+    }
+
+    /// Read the messages the provider has sent: new prices, receipts and rejected bills. A bill rejected because it used an old tariff is sent again with the latest tariff.
+    /// Returns a description of each message which rejected a bill or was not acted on.
+    pub fn read_provider_messages(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        // in case several messages have been sent
+        while let Some(header) = common::try_read_line(&mut self.channel) {
+            let header = String::from_utf8_lossy(&header).into_owned();
+            let problem = match header.as_str() {
+                "prices" => self.receive_prices(),
+                "receipt" => self.receive_receipt(),
+                "bill_rejected" => self.receive_bill_rejection(),
+                _ => Some(format!("Unrecognised message from the provider: {}", header)),
+            };
+            problems.extend(problem);
+        }
+
+        problems
+    }
+
+    fn receive_prices(&mut self) -> Option<String> {
+        let signed_prices = common::read_signed_prices(&mut self.channel);
+        let (prices, link) = common::verify_prices::<i32, u64, IntegerConsumption>(&signed_prices, &self.provider_key);
+
+        if !self.tariff_chain.follows(&link) {
+            return Some(format!("Refused tariff number {} because it does not follow on from tariff number {}", link.seq, self.tariff_chain.seq));
+        }
+
+        self.tariff_chain.advance(&link);
+        self.prices = prices;
+        None
+    }
+
+    // the readings in the bill are only forgotten once the provider has signed a receipt for exactly the bill which was sent
+    fn receive_receipt(&mut self) -> Option<String> {
+        let signed = common::try_unstringify_bytes(&read_text(&mut self.channel));
+
+        let expected = match self.pending_bill {
+            Some(ref pending) => receipt_text(&pending.readings_hash, pending.amount, &pending.tariff_hash),
+            None => return Some(String::from("Rejected a receipt: no bill is waiting for one")),
+        };
+        let genuine = match signed {
+            Some(ref s) => sign::verify(s, &self.provider_key).map(|text| text == expected.as_bytes()).unwrap_or(false),
+            None => false,
+        };
+        if !genuine {
+            return Some(String::from("Rejected a receipt which is not the provider's signature on the bill which was sent"));
+        }
+
+        let pending = self.pending_bill.take().unwrap();
+        let remaining = self.readings.split_off(pending.readings);
+        self.readings = remaining;
+        None
+    }
+
+    // the provider rejected our bill because it used an old tariff: the newer tariffs arrived before the rejection so bill again with the latest
+    fn receive_bill_rejection(&mut self) -> Option<String> {
+        let why = read_text(&mut self.channel);
+
+        if self.pending_bill.is_none() {
+            return Some(String::from("Ignored a bill rejection: no bill is waiting for a receipt"));
+        }
+
+        self.send_bill();
+        Some(format!("The provider rejected the bill ({}), so it was sent again with the latest tariff", why))
+    }
+
+    /// Accept a signed reading from the meter. Panics if it is not valid.
+    pub fn receive_reading(&mut self, signed: Vec<u8>) {
+        if let Err(why) = verify_reading(&signed, &self.meter_key, &self.key.public) {
+            panic!("Refused a reading from the meter: {}", why);
+        }
+        self.readings.push(signed);
+    }
+
+    /// Send the readings, the bill and the proof that the bill is the decryption of the encrypted bill to the provider.
+    /// The readings are kept until the provider's receipt arrives (see read_provider_messages), so that the bill can be sent again if the provider rejects it.
+    pub fn send_billing_information(&mut self) {
+        for problem in self.read_provider_messages() {
+            println!("{}", problem);
+        }
+
+        if self.pending_bill.is_some() {
+            println!("The last bill has not been receipted yet!");
+            return;
+        }
+
+        self.pending_bill = Some(PendingBill {
+            readings: self.readings.len(),
+            amount: 0,
+            readings_hash: String::new(),
+            tariff_hash: String::new(),
+        });
+        self.send_bill();
+    }
+
+    // calculate the pending bill with the latest tariff and send it
+    fn send_bill(&mut self) {
+        let num_readings = match self.pending_bill {
+            Some(ref pending) => pending.readings,
+            None => panic!("There is no bill to send"),
+        };
+
+        let mut ciphertexts = Vec::new();
+        let mut lines = Vec::new();
+        for signed in &self.readings[..num_readings] {
+            // checked by receive_reading
+            let (_, c, other) = verify_reading(signed, &self.meter_key, &self.key.public).unwrap();
+            ciphertexts.push((c, other));
+            lines.push(stringify_bytes(signed));
+        }
+
+        let encrypted_bill = self.key.public.weighted_sum(&ciphertexts, &self.prices);
+        let (bill, r) = self.key.decrypt_with_proof(&encrypted_bill);
+
+        let mut message = format!("bill\n{}\n{}\n{}\n", self.tariff_chain.hex_hash(), self.key.public.n.to_str_radix(16), num_readings);
+        for line in &lines {
+            message += line;
+            message += "\n";
+        }
+        message += &format!("{}\n{}\n", bill, r.to_str_radix(16));
+
+        common::write_message(&mut self.channel, message.as_bytes(), "billing information");
+
+        self.pending_bill = Some(PendingBill {
+            readings: num_readings,
+            amount: bill,
+            readings_hash: readings_hash(&lines),
+            tariff_hash: self.tariff_chain.hex_hash(),
+        });
+    }
+
+    /// Is a bill waiting for a receipt?
+    pub fn awaiting_receipt(&self) -> bool {
+        self.pending_bill.is_some()
+    }
+
+    /// Keep reading the provider's messages until the receipt for the last bill arrives or timeout has passed. Returns the problems with the messages read (see read_provider_messages).
+    pub fn wait_for_receipt(&mut self, timeout: Duration) -> Vec<String> {
+        let start = Instant::now();
+        let mut problems = Vec::new();
+
+        while self.pending_bill.is_some() {
+            problems.append(&mut self.read_provider_messages());
+
+            if start.elapsed() >= timeout {
+                problems.push(String::from("The provider has not sent a receipt for the last bill"));
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        problems
+    }
+}
+
+/// State associated with the provider
+pub struct PaillierProvider<T: Read + Write> {
+    channel: T,
+    /// my_sk signs prices, their_pk is the meter's key
+    keys: super::Keys,
+    prices: Prices,
+    tariff_chain: TariffChain,
+    /// The hex hash and prices of the tariff before the latest, for bills sent before the customer received the latest tariff, and when it was replaced
+    previous_tariff: Option<(String, Prices, Instant)>,
+    /// How long after it is replaced bills calculated with the previous tariff are accepted
+    tariff_grace: Duration,
+    /// The counter expected on the next reading
+    next_counter: u64,
+}
+
+impl<T: Read + Write> PaillierProvider<T> {
+    /// Create a new provider
+    pub fn new(channel: T, keys: super::Keys, prices: &Prices) -> PaillierProvider<T> {
+        PaillierProvider {
+            channel: channel,
+            keys: keys,
+            prices: prices.clone(),
+            tariff_chain: TariffChain::genesis(),
+            previous_tariff: None,
+            tariff_grace: Duration::from_secs(common::PREVIOUS_TARIFF_GRACE_SECS),
+            next_counter: 0,
+        }
+    }
+
+    /// Accept bills calculated with the previous tariff for grace after the prices change, instead of PREVIOUS_TARIFF_GRACE_SECS
+    pub fn set_tariff_grace(&mut self, grace: Duration) {
+        self.tariff_grace = grace;
+    }
+
+    /// Sign and send new prices
+    pub fn change_prices(&mut self, prices: &Prices) {
+        self.previous_tariff = Some((self.tariff_chain.hex_hash(), self.prices.clone(), Instant::now()));
+        common::write_message(&mut self.channel, b"prices\n", "prices header");
+        common::change_prices::<T, i32, u64, IntegerConsumption>(&mut self.channel, &self.keys.my_sk, prices, &mut self.tariff_chain);
+        self.prices = prices.clone();
+    }
+
+    /// Block until billing information arrives from the customer, check it, send the customer a receipt and return the bill.
+    /// The bill may use the latest tariff or, if it arrives within the grace period after the prices changed (see set_tariff_grace) because the customer sent it before receiving the latest tariff,
+    /// the one before. A bill using any other tariff is rejected
+    /// and the customer is told, so that it bills the same readings again with the latest tariff.
+    /// A bill whose readings were not signed by the meter or do not follow on from the last bill, or whose decryption proof is wrong, is refused without a reply.
+    /// Returns why the bill was rejected or refused.
+    pub fn receive_billing_information(&mut self) -> Result<i64, String> {
+        common::expect_header(&mut self.channel, "bill");
+
+        // read the whole bill before checking any of it so that a refused bill is not left half read
+        let tariff_hash = read_text(&mut self.channel);
+        let n_str = read_text(&mut self.channel);
+        let num_readings_str = read_text(&mut self.channel);
+        let num_readings = match usize::from_str_radix(&num_readings_str, 10) {
+            Ok(n) => n,
+            Err(_) => return Err(format!("could not parse the number of readings {:?}", num_readings_str)),
+        };
+        let lines: Vec<String> = (0..num_readings).map(|_| read_text(&mut self.channel)).collect();
+        let bill_str = read_text(&mut self.channel);
+        let r_str = read_text(&mut self.channel);
+
+        let customer_key = PublicKey::from_modulus(try!(parse_hex_number(&n_str, "public key")));
+        let bill = match i64::from_str_radix(&bill_str, 10) {
+            Ok(b) => b,
+            Err(_) => return Err(format!("could not parse the bill {:?}", bill_str)),
+        };
+        let r = try!(parse_hex_number(&r_str, "decryption proof"));
+
+        let mut ciphertexts = Vec::new();
+        let mut next_counter = self.next_counter;
+        for line in &lines {
+            let signed = match common::try_unstringify_bytes(line) {
+                Some(s) => s,
+                None => return Err(String::from("a signed reading is malformed")),
+            };
+            let (counter, c, other) = try!(verify_reading(&signed, &self.keys.their_pk, &customer_key));
+            if counter != next_counter {
+                return Err(format!("expected reading {} but received reading {}", next_counter, counter));
+            }
+            next_counter += 1;
+            ciphertexts.push((c, other));
+        }
+
+        // the bill must use the latest tariff or, during the grace period, the one before it
+        let prices = if tariff_hash == self.tariff_chain.hex_hash() {
+            self.prices.clone()
+        } else {
+            match self.previous_tariff {
+                Some((ref hash, ref prices, replaced)) if *hash == tariff_hash && replaced.elapsed() < self.tariff_grace => prices.clone(),
+                _ => {
+                    let why = format!("the bill was calculated with an out of date tariff. Bill again with tariff number {}", self.tariff_chain.seq);
+
+                    // Message format: "bill_rejected\nREASON\n"
+                    let message = format!("bill_rejected\n{}\n", why);
+                    common::write_message(&mut self.channel, message.as_bytes(), "bill rejection");
+                    return Err(why);
+                },
+            }
+        };
+
+        let encrypted_bill = customer_key.weighted_sum(&ciphertexts, &prices);
+        if !customer_key.verify_decryption(&encrypted_bill, bill, &r) {
+            return Err(String::from("the bill is not the decryption of the encrypted bill"));
+        }
+
+        self.next_counter = next_counter;
+
+        // Message format: "receipt\nsigned receipt\n"
+        let signed_receipt = sign::sign(receipt_text(&readings_hash(&lines), bill, &tariff_hash).as_bytes(), &self.keys.my_sk);
+        let message = format!("receipt\n{}\n", stringify_bytes(&signed_receipt));
+        common::write_message(&mut self.channel, message.as_bytes(), "receipt");
+
+        Ok(bill)
+    }
+}
+
+/// The Paillier protocol as a BillingProtocol. The client runs both the meter and the customer.
+pub enum Paillier<T: Read + Write> {
+    /// Meter and customer
+    Client(PaillierMeter, PaillierCustomer<T>),
+    /// Provider
+    Server(PaillierProvider<T>),
+}
+
+impl<T: Read + Write> BillingProtocol<T, i64> for Paillier<T> {
+    type Consumption = IntegerConsumption;
+    type Prices = Prices;
+
+    fn null_prices() -> Prices {
+        IntegerConsumption::null_prices()
+    }
+
+    fn consume(&mut self, consumption: &IntegerConsumption) {
+        match *self {
+            Paillier::Client(ref mut meter, ref mut customer) => {
+                for problem in customer.read_provider_messages() {
+                    println!("{}", problem);
+                }
+                let signed = meter.consume(consumption);
+                customer.receive_reading(signed);
+            },
+            Paillier::Server(_) => panic!("consume must be run on the client"),
+        }
+    }
+
+    fn send_billing_information(&mut self) {
+        match *self {
+            Paillier::Client(_, ref mut customer) => {
+                customer.send_billing_information();
+                for problem in customer.wait_for_receipt(Duration::from_secs(RECEIPT_TIMEOUT_SECS)) {
+                    println!("{}", problem);
+                }
+            },
+            Paillier::Server(_) => panic!("send_billing_information must be run on the client"),
+        }
+    }
+
+    fn pay_bill(&mut self) -> i64 {
+        match *self {
+            Paillier::Server(ref mut provider) => loop {
+                match provider.receive_billing_information() {
+                    Ok(bill) => break bill,
+                    Err(why) => println!("Rejected a bill: {}", why),
+                }
+            },
+            Paillier::Client(_, _) => panic!("pay_bill must be run on the server"),
+        }
+    }
+
+    fn change_prices(&mut self, prices: &Prices) {
+        match *self {
+            Paillier::Server(ref mut provider) => provider.change_prices(prices),
+            Paillier::Client(_, _) => panic!("change_prices must be run on the server"),
+        }
+    }
+
+    fn new_meter(channel: T, _prices: &Prices, keys: super::MeterKeys) -> Paillier<T> {
+        let (m_sk, m_pk, p_pk) = match keys {
+            super::MeterKeys::Paillier(m_sk, m_pk, p_pk) => (m_sk, m_pk, p_pk),
+            _ => panic!("Wrong sort of MeterKeys"),
+        };
+
+        let customer_key = SecretKey::generate(DEFAULT_KEY_BITS);
+        let meter = PaillierMeter::new(m_sk, customer_key.public.clone());
+        let customer = PaillierCustomer::new(channel, customer_key, m_pk, p_pk);
+
+        Paillier::Client(meter, customer)
+    }
+
+    fn new_server(channel: T, keys: super::Keys, prices: &Prices) -> Paillier<T> {
+        Paillier::Server(PaillierProvider::new(channel, keys, prices))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gmp::mpz::Mpz;
+    use sodiumoxide;
+    use std::mem;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    #[test]
+    fn encryption() {
+        sodiumoxide::init();
+        let key = SecretKey::generate(512);
+
+        let c = key.public.encrypt(&Mpz::from(1234 as i64));
+        assert!(key.public.valid_ciphertext(&c));
+        assert_eq!(key.decrypt(&c), 1234);
+
+        // homomorphic weighted sums, including a negative price
+        let mut prices = [0; 24*7];
+        prices[1] = 3;
+        prices[2] = -5;
+        let readings = vec!((key.public.encrypt(&Mpz::from(10 as i64)), 1), (key.public.encrypt(&Mpz::from(4 as i64)), 2),
+                            (key.public.encrypt(&Mpz::from(7 as i64)), 1));
+        let sum = key.public.weighted_sum(&readings, &prices);
+
+        let (m, r) = key.decrypt_with_proof(&sum);
+        assert_eq!(m, 3*10 - 5*4 + 3*7);
+        assert!(key.public.verify_decryption(&sum, m, &r));
+        assert!(!key.public.verify_decryption(&sum, m + 1, &r));
+    }
+
+    #[test]
+    fn reading_for_other_key() {
+        sodiumoxide::init();
+        let (m_pk, m_sk) = sign::gen_keypair();
+        let key = SecretKey::generate(512);
+        let other_key = SecretKey::generate(512);
+
+        let mut meter = PaillierMeter::new(m_sk, other_key.public.clone());
+        let signed = meter.consume(&IntegerConsumption::new(5, 3));
+        assert!(verify_reading(&signed, &m_pk, &other_key.public).is_ok());
+        assert!(verify_reading(&signed, &m_pk, &key.public).is_err());
+    }
+
+    // a meter, a customer and a provider connected to the customer. The customer's end does not block.
+    fn parties(prices: &Prices) -> (PaillierMeter, PaillierCustomer<UnixStream>, PaillierProvider<UnixStream>) {
+        let (m_pk, m_sk) = sign::gen_keypair();
+        let (p_pk, p_sk) = sign::gen_keypair();
+        let (customer_end, provider_end) = UnixStream::pair().unwrap();
+        customer_end.set_nonblocking(true).unwrap();
+
+        let customer = PaillierCustomer::new(customer_end, SecretKey::generate(512), m_pk.clone(), p_pk);
+        let meter = PaillierMeter::new(m_sk, customer.public_key().clone());
+        let provider = PaillierProvider::new(provider_end, super::super::Keys { my_sk: p_sk, their_pk: m_pk }, prices);
+
+        (meter, customer, provider)
+    }
+
+    #[test]
+    fn stale_tariff() {
+        sodiumoxide::init();
+        let (mut meter, mut customer, mut provider) = parties(&[1; 24*7]);
+
+        provider.change_prices(&[2; 24*7]);
+        assert!(customer.read_provider_messages().is_empty());
+        customer.receive_reading(meter.consume(&IntegerConsumption::new(5, 3)));
+        customer.receive_reading(meter.consume(&IntegerConsumption::new(7, 4)));
+        customer.send_billing_information();
+
+        // two new tariffs before the provider reads the bill, so it is two tariffs out of date
+        provider.change_prices(&[3; 24*7]);
+        provider.change_prices(&[4; 24*7]);
+        assert!(provider.receive_billing_information().is_err());
+
+        // the customer still has the readings and bills them again with the latest tariff
+        assert!(customer.awaiting_receipt());
+        assert_eq!(customer.read_provider_messages().len(), 1);
+        assert_eq!(provider.receive_billing_information(), Ok(4 * (5 + 7)));
+
+        assert!(customer.read_provider_messages().is_empty());
+        assert!(!customer.awaiting_receipt());
+
+        // the next bill carries on from the receipted readings
+        customer.receive_reading(meter.consume(&IntegerConsumption::new(1, 5)));
+        customer.send_billing_information();
+        assert_eq!(provider.receive_billing_information(), Ok(4));
+    }
+
+    #[test]
+    fn previous_tariff_grace() {
+        sodiumoxide::init();
+        let (mut meter, mut customer, mut provider) = parties(&[1; 24*7]);
+
+        customer.receive_reading(meter.consume(&IntegerConsumption::new(5, 3)));
+        customer.send_billing_information();
+
+        // the bill was sent with the previous tariff but the grace period is already over
+        provider.set_tariff_grace(Duration::from_secs(0));
+        provider.change_prices(&[2; 24*7]);
+        assert!(provider.receive_billing_information().is_err());
+
+        assert_eq!(customer.read_provider_messages().len(), 1);
+        assert_eq!(provider.receive_billing_information(), Ok(10));
+        assert!(customer.read_provider_messages().is_empty());
+        assert!(!customer.awaiting_receipt());
+    }
+
+    #[test]
+    fn dishonest_bills() {
+        sodiumoxide::init();
+        let prices = [3; 24*7];
+        let (mut meter, mut customer, mut provider) = parties(&prices);
+        let (_, mut customer_end) = UnixStream::pair().unwrap();
+        mem::swap(&mut customer.channel, &mut customer_end);
+
+        let signed = meter.consume(&IntegerConsumption::new(5, 3));
+        let key = &customer.key;
+        let (_, c, other) = verify_reading(&signed, &customer.meter_key, &key.public).unwrap();
+        let (bill, r) = key.decrypt_with_proof(&key.public.weighted_sum(&[(c, other)], &prices));
+        let send = |channel: &mut UnixStream, signed: &[u8], bill: i64| {
+            let message = format!("bill\n{}\n{}\n1\n{}\n{}\n{}\n", TariffChain::genesis().hex_hash(), key.public.n.to_str_radix(16), stringify_bytes(signed),
+                                  bill, r.to_str_radix(16));
+            common::write_message(channel, message.as_bytes(), "billing information");
+        };
+
+        // the wrong bill, then a reading the meter did not sign
+        send(&mut customer_end, &signed, bill + 1);
+        assert!(provider.receive_billing_information().is_err());
+        let mut forged = signed.clone();
+        forged[0] ^= 1;
+        send(&mut customer_end, &forged, bill);
+        assert!(provider.receive_billing_information().is_err());
+
+        // a reading which does not follow on from the last one billed
+        let later = meter.consume(&IntegerConsumption::new(5, 3));
+        send(&mut customer_end, &later, bill);
+        assert!(provider.receive_billing_information().is_err());
+
+        // none of these moved the provider on, so the honest bill is still accepted
+        send(&mut customer_end, &signed, bill);
+        assert_eq!(provider.receive_billing_information(), Ok(15));
+    }
+}
@@ -0,0 +1,279 @@
+//! Zero knowledge proofs that a committed value lies in [0, 2^bits)
+//!
+//! The value is split into bits. Each bit gets its own commitment, with openings chosen so that the commitments multiply back up (with weights 2^i) to the original commitment,
+//! and an OR proof (Cramer, Damgard and Schoenmakers) shows that each bit commitment opens to 0 or to 1 without saying which. The proofs are made non-interactive with the Fiat-Shamir heuristic.
+//!
+//! Commitments are g^v h^a mod p where g and h generate the subgroup of order q. g and h are recovered from the commitment parameters by opening two commitments.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use super::common;
+use super::secrets;
+use proj_crypto::asymmetric::commitments;
+use sodiumoxide::crypto::hash::sha256;
+use gmp::mpz::Mpz;
+
+/// The group the commitments live in
+pub struct Group {
+    /// The modulus
+    pub p: Mpz,
+    /// The order of g and h
+    pub q: Mpz,
+    g: Mpz,
+    h: Mpz,
+}
+
+impl Group {
+    /// Recover the generators used by the commitments with parameters params
+    pub fn from_params(params: &commitments::DHParams) -> Group {
+        let open = |v: i64, a: i64| commitments::CommitmentContext::from_opening((Mpz::from(v), Mpz::from(a)), params.clone()).unwrap().to_commitment().x;
+
+        let p = params.0.clone();
+        let h = open(0, 1);
+        let g = (open(1, 1) * h.invert(&p).unwrap()).modulus(&p);
+
+        Group {
+            p: p,
+            q: params.1.clone(),
+            g: g,
+            h: h,
+        }
+    }
+
+    /// g^v h^a mod p
+    pub fn commit(&self, v: &Mpz, a: &Mpz) -> Mpz {
+        (self.g.powm(v, &self.p) * self.h.powm(a, &self.p)).modulus(&self.p)
+    }
+
+    /// The commitment to the difference of the values committed to by x and y
+    pub fn divide(&self, x: &Mpz, y: &Mpz) -> Mpz {
+        (x.clone() * y.invert(&self.p).unwrap()).modulus(&self.p)
+    }
+
+    // what every challenge in a proof about commitment is bound to: "context\np q g h commitment\n" with the numbers in hex
+    fn statement(&self, context: &[u8], commitment: &Mpz) -> Vec<u8> {
+        let text = format!("{} {} {} {} {}\n", self.p.to_str_radix(16), self.q.to_str_radix(16), self.g.to_str_radix(16), self.h.to_str_radix(16),
+                           commitment.modulus(&self.p).to_str_radix(16));
+        let mut buf = Vec::from(context);
+        buf.push(b'\n');
+        buf.extend_from_slice(text.as_bytes());
+        buf
+    }
+
+    // the Fiat-Shamir challenge for one bit of a proof of statement
+    fn challenge(&self, statement: &[u8], c: &Mpz, t0: &Mpz, t1: &Mpz) -> Mpz {
+        let text = format!("{} {} {}", c.to_str_radix(16), t0.to_str_radix(16), t1.to_str_radix(16));
+        let mut buf = Vec::from(statement);
+        buf.extend_from_slice(text.as_bytes());
+
+        Mpz::from_str_radix(&common::to_hex(&sha256::hash(&buf).0), 16).unwrap().modulus(&self.q)
+    }
+
+    // h^z y^-e mod p: the first message of a Schnorr proof for y = h^x which has challenge e and response z
+    fn simulate(&self, y: &Mpz, e: &Mpz, z: &Mpz) -> Mpz {
+        let y_e = y.powm(e, &self.p);
+        (self.h.powm(z, &self.p) * y_e.invert(&self.p).unwrap()).modulus(&self.p)
+    }
+}
+
+// proof that c opens to 0 or 1
+struct BitProof {
+    c: Mpz,
+    e0: Mpz,
+    z0: Mpz,
+    e1: Mpz,
+    z1: Mpz,
+}
+
+/// Proof that a commitment opens to a value in [0, 2^bits)
+pub struct RangeProof {
+    bits: Vec<BitProof>,
+}
+
+impl RangeProof {
+    /// Prove that the commitment to (v, a) opens to a value in [0, 2^bits). context, the group and the commitment are bound into the proof so that it cannot be reused elsewhere.
+    /// Panics if v is out of range.
+    pub fn prove(group: &Group, v: u64, a: &Mpz, bits: usize, context: &[u8]) -> RangeProof {
+        assert!(bits > 0 && bits < 64);
+        assert!(v >> bits == 0);
+
+        // openings for the bit commitments, chosen so that sum 2^i a_i = a mod q
+        let mut openings = Vec::new();
+        let mut a_0 = a.modulus(&group.q);
+        for i in 1..bits {
+            let a_i = commitments::random_a(&group.q);
+            a_0 = (a_0 - a_i.clone() * Mpz::from((1 as u64) << i)).modulus(&group.q);
+            openings.push(a_i);
+        }
+        openings.insert(0, a_0);
+
+        let statement = group.statement(context, &group.commit(&Mpz::from(v), a));
+        let mut proofs = Vec::new();
+        for i in 0..bits {
+            let bit = (v >> i) & 1;
+            let ref r = openings[i];
+            let c = group.commit(&Mpz::from(bit), r);
+            let c_over_g = group.divide(&c, &group.g);
+
+            // simulate the branch which is false and prove the true one
+            let mut w = commitments::random_a(&group.q);
+            let fake_e = commitments::random_a(&group.q);
+            let fake_z = commitments::random_a(&group.q);
+            let real_t = group.h.powm(&w, &group.p);
+
+            let proof = if bit == 0 {
+                let t1 = group.simulate(&c_over_g, &fake_e, &fake_z);
+                let e = group.challenge(&statement, &c, &real_t, &t1);
+                let e0 = (e - fake_e.clone()).modulus(&group.q);
+                let z0 = (w.clone() + e0.clone() * r.clone()).modulus(&group.q);
+                BitProof { c: c, e0: e0, z0: z0, e1: fake_e, z1: fake_z }
+            } else {
+                let t0 = group.simulate(&c, &fake_e, &fake_z);
+                let e = group.challenge(&statement, &c, &t0, &real_t);
+                let e1 = (e - fake_e.clone()).modulus(&group.q);
+                let z1 = (w.clone() + e1.clone() * r.clone()).modulus(&group.q);
+                BitProof { c: c, e0: fake_e, z0: fake_z, e1: e1, z1: z1 }
+            };
+
+            secrets::wipe_mpz(&mut w);
+            proofs.push(proof);
+        }
+
+        for opening in openings.iter_mut() {
+            secrets::wipe_mpz(opening);
+        }
+
+        RangeProof {
+            bits: proofs,
+        }
+    }
+
+    /// Does this prove that commitment opens to a value in [0, 2^bits)?
+    pub fn verify(&self, group: &Group, commitment: &Mpz, bits: usize, context: &[u8]) -> bool {
+        if self.bits.len() != bits {
+            return false;
+        }
+
+        // the bit commitments must multiply up to the commitment
+        let mut product = Mpz::one();
+        for (i, proof) in self.bits.iter().enumerate() {
+            product = (product * proof.c.powm(&Mpz::from((1 as u64) << i), &group.p)).modulus(&group.p);
+        }
+        if product != commitment.modulus(&group.p) {
+            return false;
+        }
+
+        let statement = group.statement(context, commitment);
+        for proof in &self.bits {
+            if proof.c <= Mpz::zero() || proof.c >= group.p {
+                return false;
+            }
+
+            let c_over_g = group.divide(&proof.c, &group.g);
+            let t0 = group.simulate(&proof.c, &proof.e0, &proof.z0);
+            let t1 = group.simulate(&c_over_g, &proof.e1, &proof.z1);
+            let e = group.challenge(&statement, &proof.c, &t0, &t1);
+
+            if (proof.e0.clone() + proof.e1.clone()).modulus(&group.q) != e {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Format: hex numbers separated by spaces, five for each bit
+    pub fn to_text(&self) -> String {
+        let mut parts = Vec::new();
+        for proof in &self.bits {
+            for x in &[&proof.c, &proof.e0, &proof.z0, &proof.e1, &proof.z1] {
+                parts.push(x.to_str_radix(16));
+            }
+        }
+
+        parts.join(" ")
+    }
+
+    /// Reverses to_text. Returns None if text is malformed.
+    pub fn from_text(text: &str) -> Option<RangeProof> {
+        let mut numbers = Vec::new();
+        for part in text.split_whitespace() {
+            match Mpz::from_str_radix(part, 16) {
+                Ok(x) => numbers.push(x),
+                Err(_) => return None,
+            }
+        }
+
+        if numbers.len() % 5 != 0 {
+            return None;
+        }
+
+        let mut bits = Vec::new();
+        let mut iter = numbers.into_iter();
+        while let Some(c) = iter.next() {
+            bits.push(BitProof {
+                c: c,
+                e0: iter.next().unwrap(),
+                z0: iter.next().unwrap(),
+                e1: iter.next().unwrap(),
+                z1: iter.next().unwrap(),
+            });
+        }
+
+        Some(RangeProof {
+            bits: bits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::three_party::{read_or_gen_params, DEFAULT_PARAMS_PATH};
+    use proj_crypto::asymmetric::commitments;
+    use gmp::mpz::Mpz;
+
+    #[test]
+    fn range_proofs() {
+        let params = read_or_gen_params(DEFAULT_PARAMS_PATH);
+        let group = Group::from_params(&params);
+
+        let a = commitments::random_a(&group.q);
+        assert_eq!(group.commit(&Mpz::from(37 as u64), &a),
+                   commitments::CommitmentContext::from_opening((Mpz::from(37 as u64), a.clone()), params.clone()).unwrap().to_commitment().x);
+
+        let commitment = group.commit(&Mpz::from(37 as u64), &a);
+        let proof = RangeProof::prove(&group, 37, &a, 8, b"test");
+        assert!(proof.verify(&group, &commitment, 8, b"test"));
+        assert!(!proof.verify(&group, &commitment, 8, b"other context"));
+        assert!(!proof.verify(&group, &group.commit(&Mpz::from(38 as u64), &a), 8, b"test"));
+
+        let proof = RangeProof::from_text(&proof.to_text()).unwrap();
+        assert!(proof.verify(&group, &commitment, 8, b"test"));
+        assert!(!proof.verify(&group, &commitment, 9, b"test"));
+
+        // a bit which is neither 0 nor 1 cannot be proven
+        let mut proof = RangeProof::prove(&group, 1, &a, 1, b"test");
+        let two = group.commit(&Mpz::from(2 as u64), &a);
+        proof.bits[0].c = two.clone();
+        assert!(!proof.verify(&group, &two, 1, b"test"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_range() {
+        let group = Group::from_params(&read_or_gen_params(DEFAULT_PARAMS_PATH));
+        let a = commitments::random_a(&group.q);
+        RangeProof::prove(&group, 256, &a, 8, b"test");
+    }
+}
@@ -0,0 +1,209 @@
+//! # Regulator
+//!
+//! An optional fourth party (e.g. the energy regulator or an auditor) which vouches for tariffs. It can
+//!
+//! 1. Countersign a particular tariff signed by the provider
+//! 2. Publish signed price caps which no tariff may exceed
+//!
+//! Customers and meters configured with a TariffPolicy reject tariffs which do not meet it, and keep the last accepted tariff (and its countersignature) as evidence of the prices they were charged.
+//!
+//! When countersignatures are required a countersigned prices message is the usual prices message from the provider followed by COUNTERSIGNATURE_LEN bytes of countersignature.
+//! The provider signs a tariff without sending it (ProviderState::sign_tariff), the regulator checks it with signed_tariff_prices and countersigns it, and then the provider sends both.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use super::common;
+use super::consumption::Consumption;
+use proj_crypto::asymmetric::sign;
+use sodiumoxide::crypto::hash::sha256;
+use num::cast::NumCast;
+
+// domain separation between the two things the regulator signs
+const COUNTERSIGNATURE_PREFIX: &'static [u8] = b"tariff ";
+const PRICE_CAP_PREFIX: &'static [u8] = b"cap ";
+
+// 7 is COUNTERSIGNATURE_PREFIX.len(), which is not a const fn (the countersignature_len test checks it)
+/// Length of a countersignature
+pub const COUNTERSIGNATURE_LEN: usize = sign::SIGNATUREBYTES + 7 + sha256::DIGESTBYTES;
+
+/// State associated with the regulator
+pub struct Regulator {
+    /// Signing key
+    sk: sign::SecretKey,
+}
+
+impl Regulator {
+    /// Create a new Regulator
+    pub fn new(sk: sign::SecretKey) -> Regulator {
+        Regulator {
+            sk: sk,
+        }
+    }
+
+    /// Countersign a prices message signed by the provider (as passed to the countersign function given to change_prices_countersigned)
+    pub fn countersign(&self, signed_prices: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::from(COUNTERSIGNATURE_PREFIX);
+        buf.extend_from_slice(&sha256::hash(signed_prices).0);
+        sign::sign(&buf, &self.sk)
+    }
+
+    /// Sign a price cap: the highest price allowed for each other
+    pub fn sign_price_cap<Cons, Other, C: Consumption<Cons, Other>>(&self, caps: &C::Prices) -> Vec<u8> {
+        let mut buf = Vec::from(PRICE_CAP_PREFIX);
+        buf.append(&mut C::prices_to_bytes(caps));
+        sign::sign(&buf, &self.sk)
+    }
+}
+
+/// What a customer or meter requires of new tariffs
+pub struct TariffPolicy<P> {
+    /// The regulator's public key
+    pub regulator_key: sign::PublicKey,
+    /// Reject tariffs without a valid countersignature from the regulator
+    pub require_countersignature: bool,
+    /// Reject tariffs with any price above these
+    pub caps: Option<P>,
+}
+
+impl<P> TariffPolicy<P> {
+    /// Create a policy with no price caps
+    pub fn new(regulator_key: sign::PublicKey, require_countersignature: bool) -> TariffPolicy<P> {
+        TariffPolicy {
+            regulator_key: regulator_key,
+            require_countersignature: require_countersignature,
+            caps: None,
+        }
+    }
+}
+
+/// Set the caps in policy from a price cap signed by the regulator. Returns false (leaving the policy unchanged) if the signature is not valid.
+pub fn set_price_cap<Cons, Other, C: Consumption<Cons, Other>>(policy: &mut TariffPolicy<C::Prices>, signed_caps: &[u8]) -> bool {
+    let buf = match sign::verify(signed_caps, &policy.regulator_key) {
+        Ok(b) => b,
+        Err(_) => return false,
+    };
+
+    if !buf.starts_with(PRICE_CAP_PREFIX) || buf.len() != PRICE_CAP_PREFIX.len() + C::prices_len() * 4 {
+        return false;
+    }
+
+    policy.caps = Some(C::prices_from_bytes(&buf[PRICE_CAP_PREFIX.len()..]));
+    true
+}
+
+/// The prices and tariff number in a signed prices message from the provider with the public key provider_key, so that the regulator can check them before countersigning.
+/// None if the message is not one signed by the provider.
+pub fn signed_tariff_prices<Cons: Sized, Other: NumCast, C: Consumption<Cons, Other>>(signed_prices: &[u8], provider_key: &sign::PublicKey) -> Option<(C::Prices, u64)> {
+    if signed_prices.len() != common::PRICES_MESSAGE_LEN || sign::verify(signed_prices, provider_key).is_err() {
+        return None;
+    }
+
+    let (prices, link) = common::verify_prices::<Cons, Other, C>(signed_prices, provider_key);
+    Some((prices, link.seq))
+}
+
+/// Is countersignature the regulator's countersignature on signed_prices?
+pub fn verify_countersignature(signed_prices: &[u8], countersignature: &[u8], regulator_key: &sign::PublicKey) -> bool {
+    let buf = match sign::verify(countersignature, regulator_key) {
+        Ok(b) => b,
+        Err(_) => return false,
+    };
+
+    let mut expected = Vec::from(COUNTERSIGNATURE_PREFIX);
+    expected.extend_from_slice(&sha256::hash(signed_prices).0);
+
+    buf == expected
+}
+
+/// Does the tariff meet the policy? prices should already have been verified as coming from signed_prices.
+pub fn tariff_acceptable<Cons: PartialOrd, Other: NumCast, C: Consumption<Cons, Other>>(policy: &TariffPolicy<C::Prices>, signed_prices: &[u8],
+                                                                                         countersignature: Option<&[u8]>, prices: &C::Prices) -> bool {
+    if policy.require_countersignature {
+        match countersignature {
+            Some(c) => if !verify_countersignature(signed_prices, c, &policy.regulator_key) {
+                return false;
+            },
+            None => return false,
+        }
+    }
+
+    if let Some(ref caps) = policy.caps {
+        for i in 0..C::prices_len() {
+            let other = Other::from(i).unwrap();
+            if C::get_price(prices, other) > C::get_price(caps, Other::from(i).unwrap()) {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// The last tariff accepted, kept as evidence of the prices which applied
+pub struct TariffEvidence {
+    /// The prices message as signed by the provider
+    pub signed_prices: Vec<u8>,
+    /// The regulator's countersignature on signed_prices, if there was one
+    pub countersignature: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::common;
+    use super::super::consumption::integer_consumption::{IntegerConsumption, Prices};
+    use sodiumoxide;
+
+    #[test]
+    fn countersignature_len() {
+        assert_eq!(COUNTERSIGNATURE_LEN, sign::SIGNATUREBYTES + COUNTERSIGNATURE_PREFIX.len() + sha256::DIGESTBYTES);
+    }
+
+    #[test]
+    fn policy() {
+        sodiumoxide::init();
+        let (provider_pk, provider_sk) = sign::gen_keypair();
+        let (regulator_pk, regulator_sk) = sign::gen_keypair();
+        let regulator = Regulator::new(regulator_sk);
+
+        let prices: Prices = [5; 24*7];
+        let mut chain = common::TariffChain::genesis();
+        let signed_prices = common::sign_prices::<i32, u64, IntegerConsumption>(&provider_sk, &prices, &mut chain);
+        let (verified_prices, _) = common::verify_prices::<i32, u64, IntegerConsumption>(&signed_prices, &provider_pk);
+        let countersignature = regulator.countersign(&signed_prices);
+        assert_eq!(countersignature.len(), COUNTERSIGNATURE_LEN);
+
+        let mut policy: TariffPolicy<Prices> = TariffPolicy::new(regulator_pk, true);
+        assert!(tariff_acceptable::<i32, u64, IntegerConsumption>(&policy, &signed_prices, Some(&countersignature), &verified_prices));
+        assert!(!tariff_acceptable::<i32, u64, IntegerConsumption>(&policy, &signed_prices, None, &verified_prices));
+
+        // a countersignature for some other tariff does not count
+        let other_signed_prices = common::sign_prices::<i32, u64, IntegerConsumption>(&provider_sk, &[6; 24*7], &mut chain);
+        let other_countersignature = regulator.countersign(&other_signed_prices);
+        assert!(!tariff_acceptable::<i32, u64, IntegerConsumption>(&policy, &signed_prices, Some(&other_countersignature), &verified_prices));
+
+        // what the regulator sees before countersigning
+        let (seen_prices, seq) = signed_tariff_prices::<i32, u64, IntegerConsumption>(&signed_prices, &provider_pk).unwrap();
+        assert_eq!(&seen_prices[..], &prices[..]);
+        assert_eq!(seq, 1);
+        assert!(signed_tariff_prices::<i32, u64, IntegerConsumption>(&signed_prices, &regulator_pk).is_none());
+        assert!(signed_tariff_prices::<i32, u64, IntegerConsumption>(&signed_prices[1..], &provider_pk).is_none());
+
+        // caps
+        let mut caps: Prices = [10; 24*7];
+        caps[3] = 4;
+        assert!(!set_price_cap::<i32, u64, IntegerConsumption>(&mut policy, &countersignature));
+        assert!(set_price_cap::<i32, u64, IntegerConsumption>(&mut policy, &regulator.sign_price_cap::<i32, u64, IntegerConsumption>(&caps)));
+        assert!(!tariff_acceptable::<i32, u64, IntegerConsumption>(&policy, &signed_prices, Some(&countersignature), &verified_prices));
+    }
+}
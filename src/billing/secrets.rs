@@ -0,0 +1,107 @@
+//! Wiping secret values (keys and commitment openings) from memory once they are no longer needed
+//!
+//! This is best effort: the compiler and allocator may already have left copies elsewhere. The aim is to keep secrets out of memory for as short a time as reasonably possible.
+//! Signing secret keys need nothing here because sodiumoxide wipes them when they are dropped.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use sodiumoxide::utils::memzero;
+use gmp::mpz::{Mpz, mpz_ptr, mpz_srcptr};
+use std::os::raw::c_long;
+use std::ptr;
+
+// not wrapped by rust-gmp
+#[link(name = "gmp")]
+extern "C" {
+    fn __gmpz_size(x: mpz_srcptr) -> usize;
+    fn __gmpz_limbs_write(x: mpz_ptr, n: c_long) -> *mut usize;
+}
+
+/// Overwrite the limbs holding the value of x and then set it to zero
+pub fn wipe_mpz(x: &mut Mpz) {
+    unsafe {
+        let size = __gmpz_size(x.inner());
+        if size > 0 {
+            // size limbs are already allocated so this does not reallocate
+            let limbs = __gmpz_limbs_write(x.inner_mut(), size as c_long);
+            for i in 0..size {
+                ptr::write_volatile(limbs.offset(i as isize), 0);
+            }
+        }
+    }
+
+    *x = Mpz::zero();
+}
+
+/// Overwrite and free a string which held a secret
+pub fn wipe_string(string: String) {
+    let mut bytes = string.into_bytes();
+    memzero(&mut bytes);
+}
+
+/// A byte buffer which is wiped when it is dropped.
+/// The capacity is fixed when it is created so that the contents are never left behind by a reallocation.
+pub struct SecretBuf {
+    buf: Vec<u8>,
+}
+
+impl SecretBuf {
+    /// Create an empty buffer which can hold capacity bytes
+    pub fn with_capacity(capacity: usize) -> SecretBuf {
+        SecretBuf {
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Append bytes. Panics if this would exceed the capacity.
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        assert!(self.buf.len() + bytes.len() <= self.buf.capacity());
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The contents of the buffer
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for SecretBuf {
+    fn drop(&mut self) {
+        memzero(&mut self.buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gmp::mpz::Mpz;
+
+    #[test]
+    fn wipe() {
+        let mut x = Mpz::from_str_radix("123456789abcdef0123456789abcdef0123456789abcdef", 16).unwrap();
+        wipe_mpz(&mut x);
+        assert_eq!(x, Mpz::zero());
+
+        let mut buf = SecretBuf::with_capacity(4);
+        buf.push_bytes(b"ab");
+        buf.push_bytes(b"cd");
+        assert_eq!(buf.as_slice(), b"abcd");
+    }
+
+    #[test]
+    #[should_panic]
+    fn secret_buf_capacity() {
+        let mut buf = SecretBuf::with_capacity(3);
+        buf.push_bytes(b"abcd");
+    }
+}
@@ -2,6 +2,8 @@
 //!
 //! The simplest smart meter billing protocol. This implements linear time of use billing under the assumption that everything is run on tamperproof hardware in the smart meter. This is very unrealistic.
 //! The privacy issues relating to time of use billing are overcome by calculating the bill total on the meter and then only sending this total to the utility company every billing period (e.g. one month).
+//!
+//! The server signs a receipt for each bill it accepts. The meter keeps the readings until the receipt arrives, so that a bill the server rejects for using an out of date tariff can be calculated again with the latest prices.
 
 /*  This file is part of project-billing.
     project-billing is free software: you can redistribute it and/or modify
@@ -19,12 +21,18 @@ use super::BillingProtocol;
 use super::consumption::floating_consumption::*;
 use super::consumption::Consumption;
 use super::common;
+use super::common::TariffChain;
+use super::regulator::{TariffPolicy, TariffEvidence, COUNTERSIGNATURE_LEN};
 use std::io::{Read, Write, ErrorKind};
 use proj_crypto::asymmetric::sign;
+use sodiumoxide::crypto::hash::sha256;
 use std::mem::transmute;
 use std::mem::drop;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// How long the meter waits for the server's receipt after sending a bill (see BillingProtocol::send_billing_information)
+pub const RECEIPT_TIMEOUT_SECS: u64 = 10;
 
 #[derive(PartialEq)]
 enum Role {
@@ -38,12 +46,172 @@ pub struct SignOnMeter<T: Read + Write> {
     role: Role,
     /// The channel along which we are ending data. This should probably be used with types in proj_net
     channel: T,
-    /// The running total of money still to be payed
+    /// The running total of money still to be payed (server only)
     running_total: f64,
     /// The prices currently used to calculate the bill
     prices: Prices,
     /// Cryptographic keys for signing responses
     keys: super::Keys,
+    /// Requirements for new tariffs (meter only)
+    tariff_policy: Option<TariffPolicy<Prices>>,
+    /// The last tariff accepted (meter only)
+    tariff_evidence: Option<TariffEvidence>,
+    /// The latest tariff signed (server) or accepted (meter)
+    tariff_chain: TariffChain,
+    /// Hash of the tariff before the latest, which a meter which has not seen the latest tariff yet bills with, and when it was replaced (server only)
+    previous_tariff_hash: Option<([u8; sha256::DIGESTBYTES], Instant)>,
+    /// (hour of the week, units consumed) for each reading which has not been billed or whose bill has not been receipted yet (meter only)
+    readings: Vec<(u8, f32)>,
+    /// The number of readings in the bill waiting for a receipt and the signed bill which was sent (meter only)
+    pending_bill: Option<(usize, Vec<u8>)>,
+}
+
+// what the server signs to say that it accepted a bill
+fn receipt_text(signed_bill: &[u8]) -> Vec<u8> {
+    let mut text = Vec::from(&b"sign on meter receipt "[..]);
+    text.extend_from_slice(&sha256::hash(signed_bill).0);
+    text
+}
+
+impl<T: Read + Write> SignOnMeter<T> {
+    /// Only accept tariffs which meet policy. If the policy requires countersignatures then the server must send prices using change_prices_countersigned.
+    pub fn set_tariff_policy(&mut self, policy: TariffPolicy<Prices>) {
+        assert!(self.role == Role::Meter);
+        self.tariff_policy = Some(policy);
+    }
+
+    /// The last tariff accepted by the meter, as evidence of the prices used
+    pub fn tariff_evidence(&self) -> Option<&TariffEvidence> {
+        self.tariff_evidence.as_ref()
+    }
+
+    /// Like change_prices but the signed prices are countersigned (by calling countersign, which should ask the regulator) and the countersignature is sent too
+    pub fn change_prices_countersigned<F: Fn(&[u8]) -> Vec<u8>>(&mut self, prices: &Prices, countersign: F) {
+        assert!(self.role == Role::Server);
+
+        self.previous_tariff_hash = Some((self.tariff_chain.hash, Instant::now()));
+        let mut sbuf = common::sign_prices::<f32, u8, FloatingConsumption>(&self.keys.my_sk, prices, &mut self.tariff_chain);
+        let mut countersignature = countersign(&sbuf);
+        assert_eq!(countersignature.len(), COUNTERSIGNATURE_LEN);
+        sbuf.append(&mut countersignature);
+
+        // Message format: "countersigned_prices\n" signed_prices countersignature
+        common::write_message(&mut self.channel, b"countersigned_prices\n", "prices header");
+        common::write_message(&mut self.channel, &sbuf, "countersigned prices");
+    }
+
+    /// Read the messages the server has sent: new prices, receipts and rejected bills. A rejected bill is calculated again with the latest prices and sent again (meter only).
+    pub fn read_server_messages(&mut self) {
+        assert!(self.role == Role::Meter);
+
+        // in case several messages have been sent
+        while let Some(header) = common::try_read_line(&mut self.channel) {
+            let header = String::from_utf8_lossy(&header).into_owned();
+            match header.as_str() {
+                "prices" => {
+                    let signed_prices = common::read_signed_prices(&mut self.channel);
+                    self.receive_prices(&signed_prices, None);
+                },
+                "countersigned_prices" => {
+                    let signed_prices = common::read_signed_prices(&mut self.channel);
+                    let mut countersignature = vec!(0 as u8; COUNTERSIGNATURE_LEN);
+                    common::read_exact_blocking(&mut self.channel, &mut countersignature);
+                    self.receive_prices(&signed_prices, Some(&countersignature));
+                },
+                "receipt" => self.receive_receipt(),
+                "bill_rejected" => self.receive_bill_rejection(),
+                h => println!("Unrecognised message from the server: {}", h),
+            }
+        }
+    }
+
+    /// Is a bill waiting for a receipt? (meter only)
+    pub fn awaiting_receipt(&self) -> bool {
+        self.pending_bill.is_some()
+    }
+
+    fn receive_prices(&mut self, signed_prices: &[u8], countersignature: Option<&[u8]>) {
+        if let Some((new_prices, evidence)) = common::accept_prices::<f32, u8, FloatingConsumption>(signed_prices, countersignature, &self.keys.their_pk, self.tariff_policy.as_ref(), &mut self.tariff_chain) {
+            self.prices = new_prices;
+            self.tariff_evidence = Some(evidence);
+        }
+    }
+
+    // the readings in the bill are only forgotten once the server has signed a receipt for exactly the bill which was sent
+    fn receive_receipt(&mut self) {
+        let signed = common::try_unstringify_bytes(&String::from_utf8_lossy(&common::read_line_blocking(&mut self.channel)));
+
+        let genuine = match (signed, self.pending_bill.as_ref()) {
+            (_, None) => {
+                println!("Ignored a receipt: no bill is waiting for one");
+                return;
+            },
+            (Some(s), Some(&(_, ref bill))) => match sign::verify(&s, &self.keys.their_pk) {
+                Ok(text) => text == receipt_text(bill),
+                Err(_) => false,
+            },
+            (None, Some(_)) => false,
+        };
+        if !genuine {
+            println!("Rejected a receipt which is not the server's signature on the bill which was sent");
+            return;
+        }
+
+        let (num_readings, _) = self.pending_bill.take().unwrap();
+        self.readings.drain(..num_readings);
+    }
+
+    // the server rejected the bill because it used an out of date tariff. The newer tariffs arrived before the rejection so bill the same readings again with the latest
+    fn receive_bill_rejection(&mut self) {
+        let why = String::from_utf8_lossy(&common::read_line_blocking(&mut self.channel)).into_owned();
+
+        if self.pending_bill.is_none() {
+            println!("Ignored a bill rejection: no bill is waiting for a receipt");
+            return;
+        }
+
+        println!("The server rejected the bill ({}), so it was sent again with the latest tariff", why);
+        self.send_bill();
+    }
+
+    // bill the readings so far unless the last bill is still waiting for a receipt. Returns whether a bill was sent
+    fn start_bill(&mut self) -> bool {
+        self.read_server_messages();
+
+        if self.pending_bill.is_some() {
+            println!("The last bill has not been receipted yet!");
+            return false;
+        }
+
+        self.pending_bill = Some((self.readings.len(), Vec::new()));
+        self.send_bill();
+        true
+    }
+
+    // calculate the pending bill with the latest prices and send it
+    fn send_bill(&mut self) {
+        let num_readings = match self.pending_bill {
+            Some((n, _)) => n,
+            None => panic!("There is no bill to send"),
+        };
+
+        let mut total = 0.0;
+        for &(hour, units) in &self.readings[..num_readings] {
+            total += (self.prices[hour as usize] as f64) * (units as f64);
+        }
+
+        // Message format: signed(total tariff_hash), with the total as the 8 bytes of an f64
+        let total_bytes = unsafe {
+            transmute::<f64, [u8; 8]>(total)
+        };
+        let mut buf = Vec::from(&total_bytes[..]);
+        buf.extend_from_slice(&self.tariff_chain.hash);
+
+        let sbuf = sign::sign(&buf, &self.keys.my_sk);
+        common::write_message(&mut self.channel, &sbuf, "billing information");
+
+        self.pending_bill = Some((num_readings, sbuf));
+    }
 }
 
 impl<T: Read + Write> BillingProtocol<T, f64> for SignOnMeter<T> {
@@ -58,37 +226,36 @@ impl<T: Read + Write> BillingProtocol<T, f64> for SignOnMeter<T> {
         assert!(self.role == Role::Meter);
         assert!(consumption.is_valid());
 
-        // check for new prices information
-        if let Some(new_prices) = common::check_for_new_prices::<T, f32, u8, FloatingConsumption>(&mut self.channel, &self.keys.their_pk) {
-            self.prices = new_prices;
-        }
+        // check for new prices and receipts
+        self.read_server_messages();
 
-        // now actually work out the price
-        let time = consumption.hour_of_week as usize;
-        self.running_total += (self.prices[time] as f64) * (consumption.units_consumed as f64);
+        // the bill is worked out when it is sent, with the latest prices
+        self.readings.push((consumption.hour_of_week, consumption.units_consumed));
     }
-        
+
+    // waits for the server to accept the bill (sending it again if it is rejected) so that the server's reply is not left unread
     fn send_billing_information(&mut self) {
         assert!(self.role == Role::Meter);
 
-        let buf = unsafe {
-            transmute::<f64, [u8; 8]>(self.running_total)
-        };
-
-        let sbuf = sign::sign(&buf, &self.keys.my_sk);
+        if !self.start_bill() {
+            return;
+        }
 
-        match self.channel.write(&sbuf) {
-            Ok(s) => assert_eq!(s, sbuf.len()),
-            Err(e) => panic!("Failed to write the billing information with error {}", e),
-        };
+        let start = Instant::now();
+        while self.awaiting_receipt() && start.elapsed() < Duration::from_secs(RECEIPT_TIMEOUT_SECS) {
+            thread::sleep(Duration::from_millis(10));
+            self.read_server_messages();
+        }
 
-        self.running_total = 0.0;
+        if self.awaiting_receipt() {
+            println!("The server has not receipted the bill yet, so the readings in it are kept");
+        }
     }
 
     fn pay_bill(&mut self) -> f64 {
         assert!(self.role == Role::Server);
 
-        const BUF_LEN: usize = 8 + sign::SIGNATUREBYTES; // size_of apparently doesn't output constants
+        const BUF_LEN: usize = 8 + sha256::DIGESTBYTES + sign::SIGNATUREBYTES; // size_of apparently doesn't output constants
         let mut buf: [u8; BUF_LEN] = [0; BUF_LEN];
 
         // check for any new bills that have been sent
@@ -106,6 +273,22 @@ impl<T: Read + Write> BillingProtocol<T, f64> for SignOnMeter<T> {
                 Err(_) => { drop(self); panic!("Verification of new bill failed") },
             };
 
+            // the meter must have used the latest tariff or, during the grace period after the prices changed, the one before if it has not seen the latest yet
+            let tariff_hash = &data_buf[8..];
+            let previous = match self.previous_tariff_hash {
+                Some((ref hash, replaced)) => &hash[..] == tariff_hash && replaced.elapsed() < Duration::from_secs(common::PREVIOUS_TARIFF_GRACE_SECS),
+                None => false,
+            };
+            if tariff_hash != &self.tariff_chain.hash[..] && !previous {
+                let why = format!("the bill was calculated with an out of date tariff. Bill again with tariff number {}", self.tariff_chain.seq);
+                println!("Rejected a bill: {}", why);
+
+                // the meter keeps the readings and sends the bill again. Message format: "bill_rejected\nREASON\n"
+                let message = format!("bill_rejected\n{}\n", why);
+                common::write_message(&mut self.channel, message.as_bytes(), "bill rejection");
+                continue;
+            }
+
             let mut new_bill_bytes = [0; 8];
 
             for i in 0..8 {
@@ -116,6 +299,11 @@ impl<T: Read + Write> BillingProtocol<T, f64> for SignOnMeter<T> {
                 transmute::<[u8; 8], f64>(new_bill_bytes)
             };
 
+            // Message format: "receipt\nsigned receipt\n"
+            let signed_receipt = sign::sign(&receipt_text(&buf), &self.keys.my_sk);
+            let message = format!("receipt\n{}\n", common::stringify_bytes(&signed_receipt));
+            common::write_message(&mut self.channel, message.as_bytes(), "receipt");
+
             self.running_total += new_bill;
             break;
         }
@@ -128,7 +316,9 @@ impl<T: Read + Write> BillingProtocol<T, f64> for SignOnMeter<T> {
     fn change_prices(&mut self, prices: &Self::Prices) {
         assert!(self.role == Role::Server);
 
-        common::change_prices::<T, f32, u8, FloatingConsumption>(&mut self.channel, &self.keys.my_sk, prices);
+        self.previous_tariff_hash = Some((self.tariff_chain.hash, Instant::now()));
+        common::write_message(&mut self.channel, b"prices\n", "prices header");
+        common::change_prices::<T, f32, u8, FloatingConsumption>(&mut self.channel, &self.keys.my_sk, prices, &mut self.tariff_chain);
     }
 
     fn new_meter(channel: T, prices: &Prices, meter_keys: super::MeterKeys) -> SignOnMeter<T> {
@@ -143,6 +333,12 @@ impl<T: Read + Write> BillingProtocol<T, f64> for SignOnMeter<T> {
             running_total: 0.0,
             prices: prices.clone(),
             keys: keys,
+            tariff_policy: None,
+            tariff_evidence: None,
+            tariff_chain: TariffChain::genesis(),
+            previous_tariff_hash: None,
+            readings: Vec::new(),
+            pending_bill: None,
         }
     }
 
@@ -157,6 +353,57 @@ impl<T: Read + Write> BillingProtocol<T, f64> for SignOnMeter<T> {
             running_total: 0.0,
             prices: prices_clone,
             keys: keys,
+            tariff_policy: None,
+            tariff_evidence: None,
+            tariff_chain: TariffChain::genesis(),
+            previous_tariff_hash: None,
+            readings: Vec::new(),
+            pending_bill: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::BillingProtocol;
+    use super::super::consumption::Consumption;
+    use super::super::consumption::floating_consumption::FloatingConsumption;
+    use proj_crypto::asymmetric::sign;
+    use sodiumoxide;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn out_of_date_tariff() {
+        sodiumoxide::init();
+        let (m_pk, m_sk) = sign::gen_keypair();
+        let (s_pk, s_sk) = sign::gen_keypair();
+        let (meter_end, server_end) = UnixStream::pair().unwrap();
+        meter_end.set_nonblocking(true).unwrap();
+
+        let m_keys = super::super::Keys { my_sk: m_sk, their_pk: s_pk };
+        let mut meter = SignOnMeter::new_meter(meter_end, &[1.0; 24*7], super::super::MeterKeys::SignOnMeter(m_keys));
+        let mut server = SignOnMeter::new_server(server_end, super::super::Keys { my_sk: s_sk, their_pk: m_pk }, &[1.0; 24*7]);
+
+        server.change_prices(&[2.0; 24*7]);
+        meter.consume(&FloatingConsumption::new(3.0, 5));
+        assert!(meter.start_bill());
+
+        // two new tariffs before the server reads the bill, so it is two tariffs out of date
+        let server_thread = thread::spawn(move || {
+            server.change_prices(&[3.0; 24*7]);
+            server.change_prices(&[4.0; 24*7]);
+            server.pay_bill()
+        });
+
+        // the meter kept the reading, so the bill is sent again with the latest prices
+        while meter.awaiting_receipt() {
+            thread::sleep(Duration::from_millis(10));
+            meter.read_server_messages();
         }
+        assert_eq!(server_thread.join().unwrap(), 12.0);
+        assert!(meter.readings.is_empty());
     }
 }
@@ -0,0 +1,305 @@
+//! # Bill splitting
+//!
+//! Houses in multiple occupancy share one meter. The tenants sign a split agreement saying what share of each slot's consumption each of them pays, either the same share for every slot or a different share for each slot (e.g. one tenant is only home in the evenings).
+//!
+//! Once a bill has been receipted the customer (the household's billing hardware) proves each tenant's amount to the provider as a linear statement over the readings in the bill, with weights price * share. The provider checks that the amounts add up to the bill it verified and signs a SplitReceipt listing them.
+//! The provider learns only the amounts. Each tenant can check the receipt against the agreement they signed.
+//!
+//! Shares are out of SHARE_DENOMINATOR in every slot, so the amounts are SHARE_DENOMINATOR times the currency unit of the bill.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use super::common;
+use super::three_party::Weights;
+use super::consumption::integer_consumption::Prices;
+use proj_crypto::asymmetric::sign;
+use sodiumoxide::crypto::hash::sha256;
+
+/// The shares of each slot add up to this
+pub const SHARE_DENOMINATOR: i32 = 100;
+
+// what each tenant signs, followed by the hash of the agreement
+const AGREEMENT_PREFIX: &'static str = "split agreement ";
+
+/// How a bill is split between the tenants
+#[derive(Clone, Debug, PartialEq)]
+pub struct SplitAgreement {
+    /// The tenants' signing keys
+    pub tenants: Vec<sign::PublicKey>,
+    /// shares[tenant][hour of the week] out of SHARE_DENOMINATOR
+    pub shares: Vec<Weights>,
+}
+
+impl SplitAgreement {
+    /// Each tenant pays the same share of every slot. Panics if the shares do not add up to SHARE_DENOMINATOR.
+    pub fn fixed(tenants: Vec<sign::PublicKey>, shares: &[i32]) -> SplitAgreement {
+        SplitAgreement::per_slot(tenants, shares.iter().map(|s| [*s; 24*7]).collect())
+    }
+
+    /// Each tenant pays shares[tenant][hour of the week] of each slot. Panics if the shares for any slot do not add up to SHARE_DENOMINATOR.
+    pub fn per_slot(tenants: Vec<sign::PublicKey>, shares: Vec<Weights>) -> SplitAgreement {
+        assert!(tenants.len() > 0);
+        assert_eq!(tenants.len(), shares.len());
+
+        for slot in 0..(24*7) {
+            let mut total = 0;
+            for tenant_shares in &shares {
+                assert!(tenant_shares[slot] >= 0);
+                total += tenant_shares[slot];
+            }
+            assert_eq!(total, SHARE_DENOMINATOR);
+        }
+
+        SplitAgreement {
+            tenants: tenants,
+            shares: shares,
+        }
+    }
+
+    /// Format: one line for each tenant: "public_key_hex share[0] ... share[24*7-1]"
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (key, shares) in self.tenants.iter().zip(self.shares.iter()) {
+            text += &common::to_hex(&key.0);
+            for share in shares.iter() {
+                text += &format!(" {}", share);
+            }
+            text += "\n";
+        }
+
+        text
+    }
+
+    /// Reverses to_text. Returns None if the text is malformed or the shares do not add up.
+    pub fn from_text(text: &str) -> Option<SplitAgreement> {
+        let mut tenants = Vec::new();
+        let mut shares = Vec::new();
+
+        for line in text.lines() {
+            let mut iter = line.split_whitespace();
+            let key = match iter.next().and_then(common::from_hex).and_then(|k| sign::PublicKey::from_slice(&k)) {
+                Some(k) => k,
+                None => return None,
+            };
+            tenants.push(key);
+
+            let mut tenant_shares: Weights = [0; 24*7];
+            for i in 0..tenant_shares.len() {
+                tenant_shares[i] = match iter.next().map(|s| i32::from_str_radix(s, 10)) {
+                    Some(Ok(share)) if share >= 0 => share,
+                    _ => return None,
+                };
+            }
+            if iter.next().is_some() {
+                return None;
+            }
+            shares.push(tenant_shares);
+        }
+
+        if tenants.is_empty() {
+            return None;
+        }
+        for slot in 0..(24*7) {
+            if shares.iter().map(|s| s[slot]).sum::<i32>() != SHARE_DENOMINATOR {
+                return None;
+            }
+        }
+
+        Some(SplitAgreement::per_slot(tenants, shares))
+    }
+
+    /// Hex SHA-256 of to_text
+    pub fn hash(&self) -> String {
+        common::to_hex(&sha256::hash(self.to_text().as_bytes()).0)
+    }
+
+    /// Sign the agreement as one of the tenants
+    pub fn sign(&self, sk: &sign::SecretKey) -> Vec<u8> {
+        sign::sign(format!("{}{}", AGREEMENT_PREFIX, self.hash()).as_bytes(), sk)
+    }
+
+    /// The weights which give tenant's amount for a bill calculated with prices: price * share for each slot. Panics on overflow.
+    pub fn weights(&self, tenant: usize, prices: &Prices) -> Weights {
+        let mut weights: Weights = [0; 24*7];
+        for i in 0..weights.len() {
+            weights[i] = match prices[i].checked_mul(self.shares[tenant][i]) {
+                Some(w) => w,
+                None => panic!("The price for slot {} is too large to split", i),
+            };
+        }
+
+        weights
+    }
+}
+
+/// A split agreement with a signature from every tenant, in the same order as the tenants
+pub struct SignedSplitAgreement {
+    /// The agreement
+    pub agreement: SplitAgreement,
+    /// signatures[i] is the signature of tenant i (see SplitAgreement::sign)
+    pub signatures: Vec<Vec<u8>>,
+}
+
+impl SignedSplitAgreement {
+    /// Has every tenant signed the agreement?
+    pub fn verify(&self) -> bool {
+        if self.signatures.len() != self.agreement.tenants.len() {
+            return false;
+        }
+
+        let expected = format!("{}{}", AGREEMENT_PREFIX, self.agreement.hash());
+        for (signature, key) in self.signatures.iter().zip(self.agreement.tenants.iter()) {
+            match sign::verify(signature, key) {
+                Ok(m) => if m != expected.as_bytes() {
+                    return false;
+                },
+                Err(_) => return false,
+            }
+        }
+
+        true
+    }
+
+    /// Format: "agreement_hex\nsignature_hex\n..." with one signature line for each tenant
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\n", common::to_hex(self.agreement.to_text().as_bytes()));
+        for signature in &self.signatures {
+            text += &format!("{}\n", common::to_hex(signature));
+        }
+
+        text
+    }
+}
+
+/// The provider's signed confirmation of how a bill was split
+pub struct SplitReceipt {
+    /// Which bill was split (counting from 0)
+    pub bill_number: usize,
+    /// Hash of the split agreement (see SplitAgreement::hash)
+    pub agreement_hash: String,
+    /// The amount of the bill
+    pub total: i64,
+    /// Each tenant's amount in units of 1 / SHARE_DENOMINATOR. These add up to total * SHARE_DENOMINATOR.
+    pub amounts: Vec<i64>,
+    /// The receipt as signed by the provider
+    pub signed: Vec<u8>,
+}
+
+impl SplitReceipt {
+    /// Format: "bill_number agreement_hash total amount amount ..."
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{} {} {}", self.bill_number, self.agreement_hash, self.total);
+        for amount in &self.amounts {
+            text += &format!(" {}", amount);
+        }
+
+        text
+    }
+
+    /// Check the provider's signature on signed and return the receipt. Returns None if the signature is not valid or the text is malformed.
+    pub fn verify(signed: Vec<u8>, provider_key: &sign::PublicKey) -> Option<SplitReceipt> {
+        let text = match sign::verify(&signed, provider_key) {
+            Ok(t) => match String::from_utf8(t) {
+                Ok(s) => s,
+                Err(_) => return None,
+            },
+            Err(_) => return None,
+        };
+
+        let parts: Vec<&str> = text.split_whitespace().collect();
+        if parts.len() < 4 {
+            return None;
+        }
+
+        let mut amounts = Vec::new();
+        for part in &parts[3..] {
+            match i64::from_str_radix(part, 10) {
+                Ok(a) => amounts.push(a),
+                Err(_) => return None,
+            }
+        }
+
+        match (usize::from_str_radix(parts[0], 10), i64::from_str_radix(parts[2], 10)) {
+            (Ok(bill_number), Ok(total)) => Some(SplitReceipt {
+                bill_number: bill_number,
+                agreement_hash: parts[1].to_string(),
+                total: total,
+                amounts: amounts,
+                signed: signed,
+            }),
+            _ => None,
+        }
+    }
+
+    /// For a tenant: is this a split of a bill according to agreement, with amounts which add up? Returns the amount for tenant if so.
+    pub fn share_for(&self, agreement: &SplitAgreement, tenant: usize) -> Option<i64> {
+        if self.agreement_hash != agreement.hash() || self.amounts.len() != agreement.tenants.len() {
+            return None;
+        }
+
+        let sum: i64 = self.amounts.iter().sum();
+        if sum != self.total * SHARE_DENOMINATOR as i64 {
+            return None;
+        }
+
+        self.amounts.get(tenant).map(|a| *a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proj_crypto::asymmetric::sign;
+    use sodiumoxide;
+
+    #[test]
+    fn agreement() {
+        sodiumoxide::init();
+        let (pk1, sk1) = sign::gen_keypair();
+        let (pk2, sk2) = sign::gen_keypair();
+
+        let agreement = SplitAgreement::fixed(vec!(pk1, pk2), &[60, 40]);
+        assert_eq!(SplitAgreement::from_text(&agreement.to_text()), Some(agreement.clone()));
+        assert_eq!(SplitAgreement::from_text(""), None);
+        assert_eq!(SplitAgreement::from_text(&agreement.to_text().replace(" 60", " 61")), None);
+        assert_eq!(SplitAgreement::from_text(&agreement.to_text().replace(" 40", " x")), None);
+        assert_eq!(SplitAgreement::from_text("not hex\n"), None);
+
+        let signed = SignedSplitAgreement {
+            agreement: agreement.clone(),
+            signatures: vec!(agreement.sign(&sk1), agreement.sign(&sk2)),
+        };
+        assert!(signed.verify());
+
+        // both tenants must sign
+        let signed = SignedSplitAgreement {
+            agreement: agreement.clone(),
+            signatures: vec!(agreement.sign(&sk1), agreement.sign(&sk1)),
+        };
+        assert!(!signed.verify());
+
+        let mut prices = [2; 24*7];
+        prices[5] = 7;
+        assert_eq!(agreement.weights(0, &prices)[5], 7 * 60);
+        assert_eq!(agreement.weights(1, &prices)[0], 2 * 40);
+    }
+
+    #[test]
+    #[should_panic]
+    fn shares_must_add_up() {
+        sodiumoxide::init();
+        let (pk1, _) = sign::gen_keypair();
+        let (pk2, _) = sign::gen_keypair();
+        SplitAgreement::fixed(vec!(pk1, pk2), &[60, 30]);
+    }
+}
@@ -6,6 +6,47 @@
 //!
 //! The meter signs and commits to readings. The consumer may then perform computations on them which can be blindly verified by the utility provider, without the provider having to know the individual readings.
 //!
+//! To keep bills small the meter does not sign every reading. Instead it signs the root of a Merkle tree over a batch of commitments (by default one day of readings). Bills contain whole batches, so the provider checks one signature per batch and still notices if a reading in the batch was dropped or altered.
+//!
+//! Every reading carries a counter and every signed batch carries the hash of the batch before it. The provider keeps the head of this chain for the meter between bills (see ChainHead), so a customer cannot leave out or reorder readings without the provider noticing.
+//!
+//! ## Several providers
+//!
+//! In many markets the same consumption is billed by more than one party, for example the energy supplier (with a time of use tariff) and the distribution network operator (with network use charges).
+//! A CustomerState can bill several providers (see CustomerState::add_provider). Each provider signs its own tariffs and has its own channel, and every provider is sent a bill over the same batches of commitments signed by the meter.
+//! Each ProviderState checks its bill against the meter's signatures on its own, so the providers do not need to trust each other or the customer. The readings are only moved out of the consumption table once every provider has receipted its bill.
+//!
+//! ## Sub-metering
+//!
+//! Appliances such as EV chargers or heat pumps may have their own sub-meters (MeterStates with their own signing keys) so that their consumption can be billed at a special tariff.
+//! After a bill has been receipted the customer sends the signed sub-meter readings for the slots in that bill, along with a zero knowledge proof for each slot that the main meter's reading minus the sub-meter readings is not negative.
+//! This uses the additive homomorphism of the commitments: the provider divides the main commitment by the sub-meter commitments and checks a range proof (see the range_proof module) on the result.
+//! The difference between the appliance tariff and the normal tariff is then settled like a linear statement, so the provider learns neither the breakdown nor the individual readings.
+//!
+//! ## Shared meters
+//!
+//! Tenants sharing one meter can have each receipted bill split between them according to an agreement they have all signed (see the split module and CustomerState::send_bill_split).
+//! Each tenant's amount is proven like a linear statement, so the provider learns only the amounts, and it signs a receipt for the split which each tenant can check.
+//!
+//! ## Linear statements
+//!
+//! The bill is one linear combination of the readings (weighted by the prices). The same check works for any weights, so once a bill has been receipted the provider may ask the customer to prove other linear statements about its readings,
+//! for example the total consumption, the consumption in peak hours (for demand-response rebates) or the carbon footprint given the carbon intensity of each hour. See ProviderState::request_linear_statement.
+//! The customer only proves the statements its StatementPolicy allows. Two statements whose weights differ in one hour reveal that hour's reading, so by default it only proves the total consumption in a bill.
+//!
+//! ## Threshold statements
+//!
+//! Some things depend on whether a total is below a threshold rather than on the total itself, for example eligibility for a social tariff with a cap on annual consumption.
+//! The provider may ask the customer to prove that its total consumption over a range of bills is below a threshold (see ProviderState::request_threshold_statement).
+//! The customer answers with a range proof for the commitment to threshold - 1 - total, which the provider computes from the commitments it checked when verifying the bills, so the provider learns nothing but the answer.
+//! Each answer narrows down the total, so a provider allowed to try many thresholds for the same bills could find the total by binary search. The customer only answers about as many thresholds for the same bills as its ThresholdPolicy allows.
+//!
+//! ## Retention of openings
+//!
+//! The customer is the only party which learns the commitment openings (cons, a). They are needed to answer disputes, so the customer keeps the openings for a bill after it has been receipted.
+//! Once DISPUTE_WINDOW_SECS have passed since the receipt arrived the provider can no longer dispute the bill, and the openings are wiped (see CustomerState::purge_expired_openings).
+//! Openings and signing keys are also wiped whenever they are dropped.
+//!
 //! For the implementation of BillingProtocol (for tests), the Customer and Smart Meter are handled together.
 
 /*  This file is part of project-billing.
@@ -23,14 +64,29 @@
 use super::consumption::integer_consumption::*;
 use super::consumption::Consumption;
 use super::common;
-use std::io::{Read, Write};
+use super::common::TariffChain;
+use super::common::{stringify_bytes, unstringify_bytes, read_line_blocking};
+use super::aggregation::AggregationMasks;
+use super::regulator::{TariffPolicy, TariffEvidence, COUNTERSIGNATURE_LEN, tariff_acceptable};
+use super::merkle;
+use super::range_proof::{Group, RangeProof};
+use super::split::{SplitAgreement, SignedSplitAgreement, SplitReceipt, SHARE_DENOMINATOR};
+use super::secrets;
+use super::secrets::SecretBuf;
+use std::io::{Read, Write, BufRead, BufReader};
+use std::io;
+use std::fs::File;
 use proj_crypto::asymmetric::{sign, commitments};
 use gmp::mpz::Mpz;
-use std::io;
 use std::path::Path;
-use std::io::BufReader;
-use std::io::BufRead;
 use std::iter::Iterator;
+use std::collections::BTreeMap;
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use std::fmt;
+use std::str::FromStr;
+use sodiumoxide::crypto::hash::sha256;
 
 /// The default file to store diffie-hellman parameters in
 pub static DEFAULT_PARAMS_PATH: &'static str = "dhparams.txt";
@@ -47,139 +103,375 @@ pub fn read_or_gen_params<P: AsRef<Path> + Clone>(path: P) -> commitments::DHPar
     }
 }
 
-/// State associated with the smart meter
-pub struct MeterState<T: Read + Write> {
-    /// Channel through which to communicate with the customer
-    channel: T,
-    /// Signing key
-    sk: sign::SecretKey,
-    /// Commitment parameters
-    params: commitments::DHParams,
+/// Number of readings covered by each signature from the meter: one day of hourly readings
+pub const DEFAULT_BATCH_SIZE: usize = 24;
+
+/// Weights for a linear statement about the readings in a bill, indexed by hour of the week like Prices
+pub type Weights = [i32; 24*7];
+
+// the weighted sum of the readings in rows and the matching sum of the openings. This is what the customer reveals to prove a linear statement.
+fn weighted_opening(rows: &[ConsumptionTableRow], weights: &Weights, modulus: &Mpz) -> (i64, Mpz) {
+    let mut value = 0 as i64;
+    let mut a = Mpz::zero();
+
+    for row in rows {
+        let weight = weights[(row.other % (24*7)) as usize] as i64;
+        value += row.cons as i64 * weight;
+        a = (a + row.a.clone() * weight).modulus(modulus);
+    }
+
+    (value, a)
 }
 
-fn stringify_bytes(bytes: &[u8]) -> String {
-    let mut ret = String::new();
+// check that (value, a) opens the weighted product of the commitments in readings
+fn verify_linear_combination(params: &commitments::DHParams, readings: &[(u64, commitments::Commitment)], weights: &Weights, value: i64, a: Mpz) -> bool {
+    if readings.len() == 0 {
+        return value == 0;
+    }
+
+    let expected_commit = commitments::CommitmentContext::from_opening(
+        (Mpz::from(value), a), params.clone()).unwrap().to_commitment();
 
-    for byte in bytes {
-        ret += &format!("{} ", byte);
+    let weight = |other: u64| Mpz::from(weights[(other % (24*7)) as usize]);
+    let mut calculated_commit = readings[0].1.clone() * weight(readings[0].0);
+    for i in 1..readings.len() {
+        calculated_commit = calculated_commit + (readings[i].1.clone() * weight(readings[i].0));
     }
 
-    ret
+    expected_commit == calculated_commit
 }
 
-fn unstringify_bytes(string: &str) -> Vec<u8> {
-    let mut ret = Vec::new();
+/// Sub-meter readings are proven to be at most the main meter's reading by proving that the difference is in [0, 2^SUBMETER_RANGE_BITS). Readings are non-negative i32s so this is enough.
+pub const SUBMETER_RANGE_BITS: usize = 31;
+
+/// Threshold statements prove that the threshold minus one minus the total consumption is in [0, 2^THRESHOLD_RANGE_BITS). This is enough for many years of readings.
+pub const THRESHOLD_RANGE_BITS: usize = 48;
 
-    for str in string.split_whitespace() {
-        ret.push(u8::from_str_radix(str, 10).unwrap());
+/// How long after a bill is receipted the provider may still dispute it: 90 days. After this the customer wipes the openings for the bill.
+pub const DISPUTE_WINDOW_SECS: u64 = 90 * 24 * 60 * 60;
+
+/// The position reached in the hash chain of a meter's signed batches
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainHead {
+    /// The counter the next reading should have
+    pub next_counter: u64,
+    /// The hash of the last signed batch
+    pub hash: String,
+}
+
+impl ChainHead {
+    /// The chain head before the meter has signed anything
+    pub fn genesis() -> ChainHead {
+        ChainHead {
+            next_counter: 0,
+            hash: merkle::GENESIS_HASH.to_string(),
+        }
     }
 
-    ret
+    /// Move the head on past batch. Fails, leaving the head where it was, if batch does not directly follow the head.
+    fn extend(&mut self, batch: &merkle::SignedRoot) -> Result<(), String> {
+        if batch.first_counter != self.next_counter || batch.prev_hash != self.hash {
+            return Err(format!("the batch starting at reading {} does not follow on from reading {}: some readings are missing or out of order",
+                               batch.first_counter, self.next_counter));
+        }
+
+        self.next_counter += batch.count as u64;
+        self.hash = batch.hash.clone();
+        Ok(())
+    }
 }
 
-fn read_up_to_newline<R: Read>(source: &mut io::Bytes<R>) -> Vec<u8> {
-    //let mut iterator = source.map(|x| x.unwrap());
+/// Read the chain head stored for meter_key in the file at path. Returns the genesis chain head if there is no such file or no entry for the meter.
+/// Each line of the file looks like "meter_public_key next_counter hash" (all but next_counter in hex).
+pub fn load_chain_head<P: AsRef<Path>>(path: P, meter_key: &sign::PublicKey) -> io::Result<ChainHead> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return match e.kind() {
+            io::ErrorKind::NotFound => Ok(ChainHead::genesis()),
+            _ => Err(e),
+        },
+    };
+
+    let key_str = common::to_hex(&meter_key.0);
 
-    let mut ret: Vec<u8> = Vec::new();
-    for result in source {
-        let byte = match result {
-            Ok(b) => b,
-            Err(_) => { let ret_clone = ret.clone(); panic!("Error unwrapping in read_up_to_newline. Input up to now was {:?} => {:?}", ret_clone, String::from_utf8(ret)) },
-        };
+    for line in BufReader::new(file).lines() {
+        let line = try!(line);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        if fields.len() != 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed chain head file"));
+        }
+
+        if fields[0] == key_str {
+            let next_counter = match u64::from_str_radix(fields[1], 10) {
+                Ok(c) => c,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed chain head file")),
+            };
 
-        if byte == b'\n' {
-            return ret;
+            return Ok(ChainHead {
+                next_counter: next_counter,
+                hash: fields[2].to_string(),
+            });
         }
-        
-        ret.push(byte);
     }
 
-    //let ret: Vec<u8> = iterator.by_ref().take_while(|x| *x != b'\n').collect();
+    Ok(ChainHead::genesis())
+}
+
+/// Store head as the chain head for meter_key in the file at path, keeping the entries for other meters
+pub fn save_chain_head<P: AsRef<Path>>(path: P, meter_key: &sign::PublicKey, head: &ChainHead) -> io::Result<()> {
+    let key_str = common::to_hex(&meter_key.0);
+    let mut contents = String::new();
+
+    match File::open(path.as_ref()) {
+        Ok(f) => {
+            for line in BufReader::new(f).lines() {
+                let line = try!(line);
+                if line.split_whitespace().next() != Some(key_str.as_str()) {
+                    contents = contents + &line + "\n";
+                }
+            }
+        },
+        Err(e) => if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        },
+    };
+
+    contents += &format!("{} {} {}\n", key_str, head.next_counter, head.hash);
+
+    let mut file = try!(File::create(path));
+    file.write_all(contents.as_bytes())
+}
 
-    panic!("left read_up_to_newline loop without finding a newline. ret = {:?}. If you are running the automated test then the client is probably not keeping up with this thread. Increase the thread sleep. Rust lies about the infinite read timeout.", ret);
+/// State associated with the smart meter
+pub struct MeterState<T: Read + Write> {
+    /// Channel through which to communicate with the customer
+    channel: T,
+    /// Signing key
+    sk: sign::SecretKey,
+    /// Commitment parameters
+    params: commitments::DHParams,
+    /// Merkle tree leaves for the readings sent since the last signed batch
+    batch_leaves: Vec<String>,
+    /// The number of readings in each signed batch
+    batch_size: usize,
+    /// The end of the hash chain of the batches signed so far
+    chain: ChainHead,
 }
 
-// separate function so I can test it more easily
-fn meter_consume<W: Write>(params: &commitments::DHParams, sk: &sign::SecretKey, channel: &mut W, consumption: &IntegerConsumption) {
+// separate function so I can test it more easily. Returns the Merkle tree leaf for the reading.
+fn meter_consume<W: Write>(params: &commitments::DHParams, channel: &mut W, consumption: &IntegerConsumption, counter: u64) -> String {
     assert!(consumption.is_valid());
 
     let cons_int = consumption.units_consumed;
 
-    let a = commitments::random_a(&params.1);
+    let mut a = commitments::random_a(&params.1);
     let a_str = a.to_str_radix(16);
+    let mut v = Mpz::from(cons_int);
+    let cons_str = cons_int.to_string();
+
+    // commit with Group rather than a CommitmentContext, which would keep a copy of the opening that cannot be wiped
+    let commitment_str = Group::from_params(params).commit(&v, &a).to_str_radix(16);
+    secrets::wipe_mpz(&mut a);
+    secrets::wipe_mpz(&mut v);
 
-    let commit_context = commitments::CommitmentContext::from_opening((Mpz::from(cons_int), a), params.clone()).unwrap();
-    let commitment = commit_context.to_commitment();
-    let commitment_str = commitment.x.to_str_radix(16);
+    // send (cons, a) + (commit, other, counter). (commit, other, counter) is signed later as a leaf of the batch's Merkle tree
 
-    // send (cons, a) + sign(commit, other)
+    let leaf = format!("{} {} {}", commitment_str, consumption.hour_of_week, counter);
 
-    let touple_str = format!("{} {}", cons_int, a_str);
-    let thing_to_sign = format!("{} {}", commitment_str, consumption.hour_of_week);
-    let signed_commitment = sign::sign(&thing_to_sign.as_bytes(), &sk);
+    // the opening is secret so build the message in a buffer which is wiped afterwards, rather than with format!
+    let header = b"reading\n";
+    let mut message = SecretBuf::with_capacity(header.len() + cons_str.len() + 1 + a_str.len() + 1 + leaf.len() + 1);
+    message.push_bytes(header);
+    message.push_bytes(cons_str.as_bytes());
+    message.push_bytes(b" ");
+    message.push_bytes(a_str.as_bytes());
+    message.push_bytes(b"\n");
+    message.push_bytes(leaf.as_bytes());
+    message.push_bytes(b"\n");
 
-    let message_str = touple_str + "\n" + &stringify_bytes(&signed_commitment) + "\n";
-    let message = message_str.as_bytes();
+    secrets::wipe_string(a_str);
+    secrets::wipe_string(cons_str);
 
     // actually send it
-    match channel.write(&message) {
-        Ok(s) => assert_eq!(s, message.len()),
+    match channel.write(message.as_slice()) {
+        Ok(s) => assert_eq!(s, message.as_slice().len()),
         Err(e) => panic!("Failed to send the consumption data. The error was {}", e),
     };
+
+    leaf
+}
+
+// separate function so I can test it more easily. Signs the root of the Merkle tree over the batch and links it to the chain.
+fn meter_end_batch<W: Write>(sk: &sign::SecretKey, channel: &mut W, leaves: &[String], chain: &mut ChainHead) {
+    let thing_to_sign = merkle::signed_root_text(&merkle::root(leaves), leaves.len(), chain.next_counter, &chain.hash);
+    let signed_root = sign::sign(&thing_to_sign.as_bytes(), &sk);
+
+    let message_str = format!("batch\n{}\n", stringify_bytes(&signed_root));
+    common::write_message(channel, message_str.as_bytes(), "batch signature");
+
+    chain.next_counter += leaves.len() as u64;
+    chain.hash = merkle::chain_hash(&thing_to_sign);
 }
 
 // separate function so that I can test it more easily
-fn customer_read_consumption<R: Read>(channel: &mut R, meter_key: &sign::PublicKey, table: &mut Vec<ConsumptionTableRow>) {
-    // read the two newline separated stringified signatures
-    let buf = BufReader::new(channel);
-    let mut lines = buf.lines();
-
-    loop {
-        // read touple str
-        let touple_str = match lines.next() {
-            Some(opt) => {
-                match opt {
-                    Ok(s) => s,
-                    Err(_) => return, // some readers give an empty string instead of a None when there is nothing to read
+fn customer_read_consumption<R: Read>(channel: &mut R, meter_key: &sign::PublicKey, unbatched: &mut Vec<ConsumptionTableRow>,
+                                      table: &mut Vec<ConsumptionTableRow>, batches: &mut Vec<SignedBatch>, chain: &mut Option<ChainHead>) {
+    // in case several messages have been sent
+    while let Some(header_bytes) = common::try_read_line(channel) {
+        match String::from_utf8(header_bytes).unwrap().as_str() {
+            "reading" => {
+                let touple_str = String::from_utf8(read_line_blocking(channel)).unwrap();
+                let leaf = String::from_utf8(read_line_blocking(channel)).unwrap();
+                let (_, other, _) = merkle::parse_leaf(&leaf).unwrap();
+
+                // touple looks like "cons a"
+                let (cons, a) = {
+                    let mut touple_iter = touple_str.split_whitespace();
+                    let cons_str = touple_iter.next().unwrap();
+                    let a_str = touple_iter.next().unwrap();
+                    assert_eq!(None, touple_iter.next());
+
+                    (i32::from_str_radix(&cons_str, 10).unwrap(), Mpz::from_str_radix(&a_str, 16).unwrap())
+                };
+                secrets::wipe_string(touple_str);
+
+                // we can't trust this until the meter has signed the batch
+                unbatched.push(ConsumptionTableRow {
+                    leaf: leaf,
+                    cons: cons,
+                    other: other,
+                    a: a,
+                });
+            },
+            "batch" => {
+                let signed_root = String::from_utf8(read_line_blocking(channel)).unwrap();
+                let batch = merkle::verify_signed_root(&signed_root, meter_key).unwrap();
+                assert_eq!(batch.count, unbatched.len());
+
+                let calculated_root = {
+                    let leaves: Vec<&str> = unbatched.iter().map(|r| r.leaf.as_str()).collect();
+                    common::to_hex(&merkle::root(&leaves))
+                };
+                assert_eq!(batch.root, calculated_root);
+                if let Err(why) = check_leaf_counters(unbatched.iter().map(|r| r.leaf.as_str()), &batch) {
+                    panic!("Refused a batch from the meter: {}", why);
                 }
+
+                // the first batch we see is trusted to start the chain. After that there should be no gaps.
+                let extended = match *chain {
+                    Some(ref mut head) => {
+                        if let Err(why) = head.extend(&batch) {
+                            panic!("Refused a batch from the meter: {}", why);
+                        }
+                        true
+                    },
+                    None => false,
+                };
+                if !extended {
+                    *chain = Some(ChainHead { next_counter: batch.first_counter + batch.count as u64, hash: batch.hash.clone() });
+                }
+
+                table.append(unbatched);
+                batches.push(SignedBatch {
+                    signed_root: signed_root,
+                    len: batch.count,
+                });
             },
-            None => return,
-        };
-        
-        // read the signed commitment
-        let signed_commitment_str = lines.next().unwrap().unwrap();
-        let signed_commitment_other = unstringify_bytes(&signed_commitment_str);
-    
-        // verify the signature on the commitment
-        let commitment_other_bytes = sign::verify(&signed_commitment_other, meter_key).unwrap();
-        let commit_other_str = String::from_utf8(commitment_other_bytes).unwrap();
-        let mut commit_other_iter = commit_other_str.split_whitespace();
-        let _ = commit_other_iter.next().unwrap();
-        let other_str = commit_other_iter.next().unwrap();
-        assert_eq!(None, commit_other_iter.next());
-    
-        // touple looks like "cons a"
-        let mut touple_iter = touple_str.split_whitespace();
-        let cons_str = touple_iter.next().unwrap();
-        let a_str = touple_iter.next().unwrap();
-        assert_eq!(None, touple_iter.next());
-    
-        let cons = i32::from_str_radix(&cons_str, 10).unwrap();
-        let other = u64::from_str_radix(&other_str, 10).unwrap();
-        let a = Mpz::from_str_radix(&a_str, 16).unwrap();
-    
-        let table_row = ConsumptionTableRow {
-            //signed_commitment: String::from_utf8(signed_commitment_str_bytes).unwrap(),
-            signed_commitment: signed_commitment_str,
-            cons: cons,
-            other: other,
-            a: a,
+            header => panic!("Unrecognised message from the meter: {}", header),
+        }
+    }
+}
+
+// Read num_batches batches signed by meter_key (in the format sent by batch_lines), checking them and extending chain_head.
+// The (other, commitment) pairs are appended to readings and the lines read to lines.
+// Fails if a batch is malformed, was not signed by meter_key or does not follow on from chain_head. chain_head may have been moved on past some of the batches by then,
+// so callers should pass a copy and only keep it once everything else has been checked.
+fn provider_read_batches<R: Read>(channel: &mut R, num_batches: usize, meter_key: &sign::PublicKey, params: &commitments::DHParams, chain_head: &mut ChainHead,
+                                  readings: &mut Vec<(u64, commitments::Commitment)>, lines: &mut Vec<String>) -> Result<(), String> {
+    // read every batch before checking any of them so that a refused bill is not left half read
+    let mut batches = Vec::new();
+    for _ in 0..num_batches {
+        let signed_root_str = String::from_utf8_lossy(&read_line_blocking(channel)).into_owned();
+        let len_str = String::from_utf8_lossy(&read_line_blocking(channel)).into_owned();
+        let len = match usize::from_str_radix(&len_str, 10) {
+            Ok(l) => l,
+            Err(_) => return Err(format!("could not parse the batch length {:?}", len_str)),
         };
-            
-        table.push(table_row);
+
+        let leaves: Vec<String> = (0..len).map(|_| String::from_utf8_lossy(&read_line_blocking(channel)).into_owned()).collect();
+        batches.push((signed_root_str, len_str, leaves));
+    }
+
+    for (signed_root_str, len_str, mut leaves) in batches {
+        let batch = try!(merkle::verify_signed_root(&signed_root_str, meter_key));
+        if leaves.len() != batch.count {
+            return Err(format!("the meter signed a batch of {} readings but {} were sent", batch.count, leaves.len()));
+        }
+
+        // a missing or reordered batch breaks the chain
+        try!(chain_head.extend(&batch));
+        lines.push(signed_root_str);
+        lines.push(len_str);
+
+        // a missing or altered reading changes the root
+        for leaf in &leaves {
+            let (commitment, other, _) = try!(merkle::parse_leaf(leaf));
+            readings.push((other, commitments::Commitment::from_parts(commitment, params.0.clone(), false).unwrap()));
+        }
+        if batch.root != common::to_hex(&merkle::root(&leaves)) {
+            return Err(format!("the readings in the batch starting at reading {} do not match the root the meter signed", batch.first_counter));
+        }
+        try!(check_leaf_counters(leaves.iter().map(|l| l.as_str()), &batch));
+
+        lines.append(&mut leaves);
+    }
+
+    Ok(())
+}
+
+// the lines sent to the provider for the signed batches covering table: "signed_root\nLEN\nleaf[0]\n...leaf[LEN-1]\n" for each batch
+fn batch_lines(table: &[ConsumptionTableRow], batches: &[SignedBatch]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut rows = table.iter();
+    for batch in batches {
+        lines.push(batch.signed_root.clone());
+        lines.push(batch.len.to_string());
+        for _ in 0..batch.len {
+            lines.push(rows.next().unwrap().leaf.clone());
+        }
     }
+
+    lines
 }
-    
+
+// the position of the only reading for the slot other in a bill, or the number of readings for the slot if there is not exactly one
+fn main_index<I: Iterator<Item=u64>>(bill_others: I, other: u64) -> Result<usize, usize> {
+    let matches: Vec<usize> = bill_others.enumerate().filter(|&(_, o)| o == other).map(|(i, _)| i).collect();
+    if matches.len() != 1 {
+        return Err(matches.len());
+    }
+
+    Ok(matches[0])
+}
+
+// bound into each sub-metering range proof so that it cannot be reused for another slot
+fn submetering_context(bill_number: usize, main_index: usize) -> String {
+    format!("submetering {} {}", bill_number, main_index)
+}
+
+// the readings in a batch should be numbered consecutively from the batch's first_counter
+fn check_leaf_counters<'a, I: Iterator<Item=&'a str>>(leaves: I, batch: &merkle::SignedRoot) -> Result<(), String> {
+    for (i, leaf) in leaves.enumerate() {
+        let (_, _, counter) = try!(merkle::parse_leaf(leaf));
+        if counter != batch.first_counter + i as u64 {
+            return Err(format!("reading {} of the batch starting at reading {} is numbered {}", i, batch.first_counter, counter));
+        }
+    }
+
+    Ok(())
+}
+
 impl<T: Read + Write> MeterState<T> {
     /// Create a new MeterState object
     pub fn new(channel: T, sk: sign::SecretKey, params: commitments::DHParams) -> MeterState<T> {
@@ -187,236 +479,2173 @@ impl<T: Read + Write> MeterState<T> {
         MeterState {
             channel: channel,
             sk: sk,
-            params: params
+            params: params,
+            batch_leaves: Vec::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            chain: ChainHead::genesis(),
         }
     }
 
-    /// Called once every hour with the consumption incurred in that hour
+    /// The public key corresponding to the meter's signing key
+    pub fn public_key(&self) -> sign::PublicKey {
+        // the second half of an Ed25519 secret key is the public key
+        sign::PublicKey::from_slice(&self.sk.0[32..]).unwrap()
+    }
+
+    /// The end of the hash chain of the batches signed so far. This should be stored so that the chain can be continued if the meter restarts.
+    pub fn chain_head(&self) -> &ChainHead {
+        &self.chain
+    }
+
+    /// Continue an existing hash chain (see chain_head). Panics if there are readings waiting to be signed.
+    pub fn set_chain_head(&mut self, head: ChainHead) {
+        assert!(self.batch_leaves.is_empty());
+        self.chain = head;
+    }
+
+    /// Change the number of readings covered by each signature
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        assert!(batch_size > 0);
+        self.batch_size = batch_size;
+    }
+
+    /// Called once every hour with the consumption incurred in that hour. The batch is signed automatically when it is full.
     pub fn consume(&mut self, consumption: &IntegerConsumption) {
-        meter_consume(&self.params, &self.sk, &mut self.channel, consumption);
+        let counter = self.chain.next_counter + self.batch_leaves.len() as u64;
+        let leaf = meter_consume(&self.params, &mut self.channel, consumption, counter);
+        self.batch_leaves.push(leaf);
+
+        if self.batch_leaves.len() >= self.batch_size {
+            self.end_batch();
+        }
+    }
+
+    /// Sign the readings sent since the last batch so that the customer can bill them. Does nothing if there are none.
+    pub fn end_batch(&mut self) {
+        if self.batch_leaves.is_empty() {
+            return;
+        }
+
+        meter_end_batch(&self.sk, &mut self.channel, &self.batch_leaves, &mut self.chain);
+        self.batch_leaves.clear();
     }
 }
 
 struct ConsumptionTableRow {
-    signed_commitment: String,
+    leaf: String,
     cons: i32,
     other: u64,
     a: Mpz,
 }
 
-/// State associated with the customer
-pub struct CustomerState<P: Read + Write, M: Read + Write> {
-    /// Channel through which to communicate with the meter
-    meter_channel: M,
-    /// Channel through which to communicate with the provider
-    provider_channel: P,
-    /// The stored consumptions since the last bill was paid
-    consumption_table: Vec<ConsumptionTableRow>,
-    /// The prices currently used to calculate the bill
-    pub prices: Prices,
-    /// Public key of the provider for the verification of their prices
-    provider_key: sign::PublicKey,
-    /// Public key of the meter for verification of consumption data
-    meter_key: sign::PublicKey,
-    /// Commitment parameters
-    params: commitments::DHParams,
+impl Drop for ConsumptionTableRow {
+    fn drop(&mut self) {
+        secrets::wipe_mpz(&mut self.a);
+        self.cons = 0;
+    }
 }
 
-impl<P: Read + Write, M: Read + Write> CustomerState<P, M> {
-    /// Create a new CustomerState
-    pub fn new(meter_channel: M, provider_channel: P, prices: Prices, provider_key: sign::PublicKey,
-               meter_key: sign::PublicKey, params: commitments::DHParams)
-               -> CustomerState<P, M> {
-        //assert!(commitments::verify_dh_params(&params));
-        CustomerState {
-            meter_channel: meter_channel,
-            provider_channel: provider_channel,
-            consumption_table: Vec::new(),
-            prices: prices,
-            provider_key: provider_key,
-            meter_key: meter_key,
-            params: params,
-        }
-    }
+// the rows of a receipted bill, kept so that disputes can be answered
+struct BilledTable {
+    rows: Vec<ConsumptionTableRow>,
+    receipted_at: SystemTime,
+}
 
-    /// For debugging and logging: outputs a human readable representation of the cons and other fields of the consumption table
-    pub fn readable_consumption_table(&self) -> String {
-        let mut out = String::new();
-        for row in &self.consumption_table {
-            let row = format!("cons: {}, other: {}\n", row.cons, row.other);
-            out = out + row.as_str();
-        }
-        out
+// a signature from the meter covering some consecutive rows of the consumption table
+struct SignedBatch {
+    signed_root: String,
+    len: usize,
+}
+
+/// Hash of the signed batches of commitments in a bill, in the order they were sent
+fn commitments_hash(lines: &[&str]) -> String {
+    let mut buf = Vec::new();
+    for line in lines {
+        buf.extend_from_slice(line.as_bytes());
+        buf.push(b'\n');
     }
 
-    /// Calculate the bill and send it to the provider and return the bill
-    pub fn send_billing_information(&mut self) -> i64 {
-        // calculate what we think that the bill will be and what we expect a to be
-        let mut bill = 0 as i64;
-        let mut a = Mpz::zero();
+    common::to_hex(&sha256::hash(&buf).0)
+}
 
-        // do nothing if there is no bill to send
-        if self.consumption_table.len() == 0 {
-            println!("I can't bill an empty consumption table!");
-            return 0;
-        }
+// what the customer expects the receipt for its last bill to say
+struct PendingBill {
+    bill_number: usize,
+    amount: i64,
+    period_start: u64,
+    period_end: u64,
+    commitments_hash: String,
+    tariff_hash: String,
+    prices: Prices,
+}
 
-        for row in &self.consumption_table {
-            let price = self.prices[(row.other % (24*7)) as usize] as i64;
-            bill += row.cons as i64 * price;
-            a = (a + row.a.clone() * price).modulus(&self.params.0);
-        }
+/// The provider's signed confirmation that a bill was accepted
+pub struct Receipt {
+    /// Which bill this is (counting from 0)
+    pub bill_number: usize,
+    /// The first slot (other) covered by the bill
+    pub period_start: u64,
+    /// The last slot (other) covered by the bill
+    pub period_end: u64,
+    /// The amount accepted
+    pub amount: i64,
+    /// Hex SHA-256 of the signed batches of commitments which the bill was calculated from
+    pub commitments_hash: String,
+    /// Hex hash of the tariff used to check the bill (see common::TariffChain)
+    pub tariff_hash: String,
+    /// The receipt as signed by the provider
+    pub signed: Vec<u8>,
+}
 
-        // Message format: "bill\na\ntable.len()\ntable[0]\n...\n\table[N]\n"
+impl Receipt {
+    // Format: "bill_number period_start period_end amount commitments_hash tariff_hash"
+    fn to_text(&self) -> String {
+        format!("{} {} {} {} {} {}", self.bill_number, self.period_start, self.period_end, self.amount, self.commitments_hash, self.tariff_hash)
+    }
 
-        let const_len_part_str = format!("{}\n{}\n{}\n", bill, a.to_str_radix(16), self.consumption_table.len());
-        let const_len_part = const_len_part_str.as_bytes();
-        match self.provider_channel.write(&const_len_part) {
-            Ok(s) => assert_eq!(s, const_len_part.len()),
-            Err(e) => panic!("Failed to send the constant part of the billing info. The error was {}", e),
-        };
+    // None if text is malformed
+    fn from_text(text: &str, signed: Vec<u8>) -> Option<Receipt> {
+        let parts: Vec<&str> = text.split_whitespace().collect();
+        if parts.len() != 6 {
+            return None;
+        }
 
-        // send the contents of the table
-        for row in &self.consumption_table {
-            let string = format!("{}\n", row.signed_commitment);
-            let bytes = string.as_bytes();
-            match self.provider_channel.write(&bytes) {
-                Ok(s) => assert_eq!(s, bytes.len()),
-                Err(e) => panic!("Failed to send a signed_commitment to the provider. The error was {}", e),
-            };
+        match (usize::from_str_radix(parts[0], 10), u64::from_str_radix(parts[1], 10), u64::from_str_radix(parts[2], 10), i64::from_str_radix(parts[3], 10)) {
+            (Ok(bill_number), Ok(period_start), Ok(period_end), Ok(amount)) => Some(Receipt {
+                bill_number: bill_number,
+                period_start: period_start,
+                period_end: period_end,
+                amount: amount,
+                commitments_hash: parts[4].to_string(),
+                tariff_hash: parts[5].to_string(),
+                signed: signed,
+            }),
+            _ => None,
         }
+    }
+}
 
-        //println!("consumption table = {}", self.readable_consumption_table());
-        //println!("prices = {:?}", self.prices.as_ref());
-        //println!("bill = {}", bill);
+/// A message from a provider which was not acted on (see CustomerState::read_provider_messages)
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProviderProblem {
+    /// The receipt was not signed by the provider, is malformed or does not match the bill which was sent. The bill is still waiting for a receipt.
+    BadReceipt(ProviderId, String),
+    /// No receipt arrived before the timeout given to CustomerState::wait_for_receipt
+    NoReceipt(ProviderId),
+    /// The provider rejected the bill for the reason given, because it used a tariff which is too old. The bill has been sent again with the latest tariff.
+    BillRejected(ProviderId, String),
+    /// The split receipt was not signed by the provider, is malformed or does not match the split which was sent
+    BadSplitReceipt(ProviderId, String),
+    /// The provider refused the bill split which was sent, for the reason given
+    SplitRejected(ProviderId, String),
+    /// A dispute, statement or threshold request, or a dispute transcript, was not signed by the provider or is malformed. It was not answered (a transcript is not countersigned).
+    BadRequest(ProviderId, String),
+}
 
-        // empty the table
-        self.consumption_table.clear();
-        bill
-    }
-    
-    /// check for new consumption messages from the meter
-    pub fn read_meter_messages(&mut self) {
-        customer_read_consumption(&mut self.meter_channel, &self.meter_key, &mut self.consumption_table);
+impl fmt::Display for ProviderProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProviderProblem::BadReceipt(provider, ref why) => write!(f, "Rejected a receipt from provider {}: {}", provider, why),
+            ProviderProblem::NoReceipt(provider) => write!(f, "Provider {} has not sent a receipt for the last bill", provider),
+            ProviderProblem::BillRejected(provider, ref why) => write!(f, "Provider {} rejected the bill ({}), so it was sent again with the latest tariff", provider, why),
+            ProviderProblem::BadSplitReceipt(provider, ref why) => write!(f, "Rejected a split receipt from provider {}: {}", provider, why),
+            ProviderProblem::SplitRejected(provider, ref why) => write!(f, "Provider {} rejected the bill split: {}", provider, why),
+            ProviderProblem::BadRequest(provider, ref why) => write!(f, "Did not answer a request from provider {}: {}", provider, why),
+        }
     }
+}
+
+/// Why sub-meter readings were not claimed (see CustomerState::send_sub_metering)
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubMeteringError {
+    /// The provider has not receipted a bill to attach the readings to
+    NoBill(ProviderId),
+    /// The readings for the provider's bill with this number have been purged
+    ReadingsUnavailable(ProviderId, usize),
+    /// A sub-meter reading is for a slot which appears more than once in the bill, so it cannot be matched to one main reading
+    RepeatedSlot(u64),
+    /// The sub-meters recorded more than the main meter in the slot
+    OverRead(u64),
+}
 
-    /// check for price changes from the provider
-    pub fn read_provider_messages(&mut self) {
-        // check for new prices information
-        if let Some(new_prices) = common::check_for_new_prices::<P, i32, u64, IntegerConsumption>(&mut self.provider_channel, &self.provider_key) {
-            self.prices = new_prices;
+impl fmt::Display for SubMeteringError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SubMeteringError::NoBill(provider) => write!(f, "There is no receipted bill from provider {} to attach the sub-meter readings to", provider),
+            SubMeteringError::ReadingsUnavailable(provider, bill) => write!(f, "The readings for bill {} from provider {} are no longer available", bill, provider),
+            SubMeteringError::RepeatedSlot(other) => write!(f, "Slot {} appears more than once in the bill. Sub-meter readings need exactly one main reading for their slot", other),
+            SubMeteringError::OverRead(other) => write!(f, "The sub-meters recorded more than the main meter in slot {}", other),
         }
     }
 }
 
-/// State associated with the provider
-pub struct ProviderState<T: Read + Write> {
-    /// Channel through which to communicate to the customer
-    channel: T,
-    /// The prices currently used to calculate the bill
-    pub prices: Prices,
-    /// Signing keys
-    keys: super::Keys,
-    /// Commitment parameters
-    params: commitments::DHParams,
-    /// Bill total
-    bill_total: i64,
+/// Why a bill split was not sent (see CustomerState::send_bill_split)
+#[derive(Clone, Debug, PartialEq)]
+pub enum SplitError {
+    /// The provider has not receipted a bill to split
+    NoBill(ProviderId),
+    /// There is no split agreement (see CustomerState::set_split_agreement)
+    NoAgreement,
+    /// The readings for the provider's bill with this number have been purged
+    ReadingsUnavailable(ProviderId, usize),
 }
 
-impl<T: Read + Write> ProviderState<T> {
-    /// create a new ProviderState
-    pub fn new(channel: T, prices: Prices, keys: super::Keys, params: commitments::DHParams) -> ProviderState<T> {
-        //assert!(commitments::verify_dh_params(&params));
-        ProviderState {
-            channel: channel,
-            prices: prices,
-            keys: keys,
-            params: params,
-            bill_total: 0,
+impl fmt::Display for SplitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SplitError::NoBill(provider) => write!(f, "There is no receipted bill from provider {} to split", provider),
+            SplitError::NoAgreement => write!(f, "There is no split agreement"),
+            SplitError::ReadingsUnavailable(provider, bill) => write!(f, "The readings for bill {} from provider {} are no longer available", bill, provider),
         }
     }
+}
 
-    /// for implementing BillingProtocol
-    pub fn pay_bill(&mut self) -> i64 {
-        let ret = self.bill_total;
-        self.bill_total = 0;
-        ret
+/// A reading revealed during a dispute
+pub struct DisputeOpening {
+    /// Position of the reading in the bill
+    pub index: usize,
+    /// The slot the reading was for (0 if the index is not in the bill)
+    pub other: u64,
+    /// The revealed consumption
+    pub cons: i32,
+    /// The revealed commitment randomness
+    pub a: Mpz,
+    /// Does (cons, a) open the meter-signed commitment?
+    pub valid: bool,
+}
+
+// what the customer signs to countersign a dispute transcript: this prefix followed by the SHA-256 of the transcript as signed by the provider
+const DISPUTE_COUNTERSIGNATURE_PREFIX: &'static [u8] = b"countersigned dispute transcript ";
+
+fn dispute_countersignature_text(signed: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::from(DISPUTE_COUNTERSIGNATURE_PREFIX);
+    buf.extend_from_slice(&sha256::hash(signed).0);
+    buf
+}
+
+// parse the next of the whitespace separated fields of a message from the provider
+fn next_field<'a, I: Iterator<Item=&'a str>, F: FromStr>(fields: &mut I, what: &str) -> Result<F, String> {
+    match fields.next() {
+        Some(field) => field.parse::<F>().map_err(|_| format!("could not parse the {} {:?}", what, field)),
+        None => Err(format!("the {} is missing", what)),
     }
+}
 
-    /// receive new billing information
-    pub fn receive_billing_information(&mut self) {
-        let buf = BufReader::new(&mut self.channel);
-        let mut iterator = buf.bytes();
+/// Record of what was revealed in a dispute. The provider signs this and sends it to the customer, who countersigns it if it agrees, so that both sides hold a transcript signed by the other.
+pub struct DisputeTranscript {
+    /// Identifies the dispute
+    pub dispute_id: u64,
+    /// Which bill was disputed (counting from 0)
+    pub bill_number: usize,
+    /// The readings which were opened
+    pub openings: Vec<DisputeOpening>,
+    /// Requested slots for which the customer did not open any reading
+    pub missing: Vec<u64>,
+    /// The transcript as signed by the provider
+    pub signed: Vec<u8>,
+    /// The customer's countersignature of signed. Empty if the customer did not countersign (it has no signing key or the transcript does not match what it opened), or, for the provider, if the countersignature could not be checked.
+    pub countersignature: Vec<u8>,
+}
 
-        // get the fixed-length part
-        //println!("reading bill");
-        let bill_bytes = read_up_to_newline(&mut iterator);
-        //println!("bill_bytes is {:?}", bill_bytes);
-        let a_bytes = read_up_to_newline(&mut iterator);
-        //println!("a bytes is {:?}", a_bytes);
-        let length_bytes = read_up_to_newline(&mut iterator);
-        //println!("length_bytes is {:?}", String::from_utf8(length_bytes.clone()));
-
-        let bill = i64::from_str_radix(&String::from_utf8(bill_bytes).unwrap(), 10).unwrap();
-        let a = Mpz::from_str_radix(&String::from_utf8(a_bytes).unwrap(), 16).unwrap();
-        let length = usize::from_str_radix(&String::from_utf8(length_bytes).unwrap(), 10).unwrap();
-
-        // get all of the signed commitments
-        let mut commitments = Vec::new();
-        let mut others = Vec::new();
-
-        if length == 0 {
-            assert_eq!(bill, 0);
-            return;
+impl DisputeTranscript {
+    /// Is countersignature a valid countersignature of this transcript by the customer with the public key customer_key?
+    pub fn countersigned_by(&self, customer_key: &sign::PublicKey) -> bool {
+        match sign::verify(&self.countersignature, customer_key) {
+            Ok(buf) => buf == dispute_countersignature_text(&self.signed),
+            Err(_) => false,
         }
+    }
 
-        for _ in 0..length {
-            let signed_commitment_bytes = read_up_to_newline(&mut iterator);
-            let signed_commitment = unstringify_bytes(&String::from_utf8(signed_commitment_bytes).unwrap());
-            let commitment_bytes = sign::verify(&signed_commitment, &self.keys.their_pk).unwrap();
-            let commit_other_str = String::from_utf8(commitment_bytes).unwrap();
+    // Format: "dispute_id bill_number\nopen index other cons a valid\n...\nmissing other other ...\n"
+    fn to_text(&self) -> String {
+        let mut text = format!("{} {}\n", self.dispute_id, self.bill_number);
 
-            let mut commit_other_iter = commit_other_str.split_whitespace();
-            let commit_str = commit_other_iter.next().unwrap();
-            let other_str = commit_other_iter.next().unwrap();
-            assert_eq!(None, commit_other_iter.next());
-            
-            let commitment = Mpz::from_str_radix(&commit_str, 16).unwrap();
-            commitments.push(commitments::Commitment::from_parts(commitment, self.params.0.clone(), false).unwrap());
+        for opening in &self.openings {
+            text += &format!("open {} {} {} {} {}\n", opening.index, opening.other, opening.cons, opening.a.to_str_radix(16), opening.valid as u8);
+        }
 
-            let other = u64::from_str_radix(&other_str, 10).unwrap();
-            others.push(other);
+        text += "missing";
+        for other in &self.missing {
+            text += &format!(" {}", other);
         }
+        text + "\n"
+    }
 
-        // check the bill
-        let expected_commit = commitments::CommitmentContext::from_opening(
-            (Mpz::from(bill), a), self.params.clone()).unwrap().to_commitment();
+    // Err if text is malformed
+    fn from_text(text: &str, signed: Vec<u8>) -> Result<DisputeTranscript, String> {
+        let mut lines = text.lines();
 
-        let mut calculated_commit = commitments[0].clone() * Mpz::from(self.prices[(others[0] % (24*7)) as usize]);
-        for i in 1..length {
-            calculated_commit = calculated_commit + (commitments[i].clone() * Mpz::from(self.prices[(others[i] % (24*7)) as usize]));
+        let mut first_line = match lines.next() {
+            Some(l) => l.split_whitespace(),
+            None => return Err(String::from("the dispute transcript is empty")),
+        };
+        let dispute_id = try!(next_field(&mut first_line, "dispute id"));
+        let bill_number = try!(next_field(&mut first_line, "bill number"));
+
+        let mut openings = Vec::new();
+        let mut missing = Vec::new();
+
+        for line in lines {
+            let mut iter = line.split_whitespace();
+            match iter.next() {
+                Some("open") => {
+                    let index = try!(next_field(&mut iter, "index of an opening"));
+                    let other = try!(next_field(&mut iter, "slot of an opening"));
+                    let cons = try!(next_field(&mut iter, "consumption of an opening"));
+                    let a = match iter.next().map(|a| Mpz::from_str_radix(a, 16)) {
+                        Some(Ok(a)) => a,
+                        _ => return Err(String::from("could not parse the randomness of an opening")),
+                    };
+                    let valid: u8 = try!(next_field(&mut iter, "validity of an opening"));
+
+                    openings.push(DisputeOpening {
+                        index: index,
+                        other: other,
+                        cons: cons,
+                        a: a,
+                        valid: valid == 1,
+                    });
+                },
+                Some("missing") => {
+                    missing = match iter.map(|o| o.parse()).collect::<Result<Vec<u64>, _>>() {
+                        Ok(m) => m,
+                        Err(_) => return Err(String::from("could not parse the missing slots")),
+                    };
+                },
+                _ => return Err(format!("unexpected line in the dispute transcript {:?}", line)),
+            }
         }
 
-        assert!(expected_commit == calculated_commit);
-
-        // it worked so trust it
-        self.bill_total += bill;
+        Ok(DisputeTranscript {
+            dispute_id: dispute_id,
+            bill_number: bill_number,
+            openings: openings,
+            missing: missing,
+            signed: signed,
+            countersignature: Vec::new(),
+        })
     }
-    
-    /// Store and send the new prices to the customer. Does not check if the prices have actually changed before sending.
-    pub fn change_prices(&mut self, prices: &Prices) {
-        // send them
-        common::change_prices::<T, i32, u64, IntegerConsumption>(&mut self.channel, &self.keys.my_sk, prices);
+}
 
-        // store the prices 
-        self.prices = *prices;
-    }
+/// The result of asking the customer to prove a linear statement about a bill
+pub struct LinearStatement {
+    /// Identifies the request
+    pub request_id: u64,
+    /// The bill the statement is about (counting from 0)
+    pub bill_number: usize,
+    /// The weights used
+    pub weights: Weights,
+    /// The proven value of the sum of weights[hour] * cons, or None if the customer could not prove it
+    pub value: Option<i64>,
+    /// Did the customer refuse because the weights are outside its StatementPolicy?
+    pub refused: bool,
 }
 
-/************************************** Small tests unique to this module ***********************************************/
-#[cfg(test)]
-pub mod tests {
-    use super::super::tests::{random_hour_of_week};
+/// Which linear statements the customer will prove (see CustomerState::set_statement_policy). Requests outside the policy are refused.
+pub struct StatementPolicy {
+    /// Refuse requests with fewer non-zero weights than this. This alone does not protect single readings: the difference between two allowed statements whose weights differ in one slot reveals that reading.
+    pub min_nonzero_weights: usize,
+    /// If set, only prove statements with exactly these weights. Weights chosen by the provider can single out readings even when many are non-zero (e.g. 1 and 1000000), so this is the stricter policy.
+    pub allowed: Option<Vec<Weights>>,
+}
+
+impl StatementPolicy {
+    /// Only prove the total consumption in a bill (every weight 1). A customer only ever proves one statement about each bill, so nothing can be learnt by differencing. This is the default policy.
+    pub fn totals_only() -> StatementPolicy {
+        StatementPolicy {
+            min_nonzero_weights: 0,
+            allowed: Some(vec!([1; 24*7])),
+        }
+    }
+
+    /// Accept any weights with at least min_nonzero_weights non-zero weights. Statements which differ in one slot can be differenced to reveal a reading (see min_nonzero_weights).
+    pub fn new(min_nonzero_weights: usize) -> StatementPolicy {
+        StatementPolicy {
+            min_nonzero_weights: min_nonzero_weights,
+            allowed: None,
+        }
+    }
+
+    /// Does the policy allow a statement with these weights?
+    pub fn allows(&self, weights: &Weights) -> bool {
+        if weights.iter().filter(|w| **w != 0).count() < self.min_nonzero_weights {
+            return false;
+        }
+
+        match self.allowed {
+            Some(ref allowed) => allowed.iter().any(|a| &a[..] == &weights[..]),
+            None => true,
+        }
+    }
+}
+
+/// The charge for one reading in a bill preview
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlotCharge {
+    /// The hour of the week of the reading
+    pub other: u64,
+    /// Units consumed
+    pub units: i64,
+    /// The price for the hour
+    pub price: i32,
+    /// units * price
+    pub amount: i64,
+}
+
+/// The charges for one day of the week in a bill preview
+#[derive(Clone, Debug, PartialEq)]
+pub struct DayCharge {
+    /// 0 is Monday
+    pub day: u64,
+    /// Units consumed on the day
+    pub units: i64,
+    /// The amount charged for the day
+    pub amount: i64,
+}
+
+/// What the next bill to a provider would be if it were sent now (see CustomerState::preview_bill)
+#[derive(Clone, Debug, PartialEq)]
+pub struct BillPreview {
+    /// The number of the tariff the bill is calculated with
+    pub tariff_number: u64,
+    /// Hex hash of the tariff
+    pub tariff_hash: String,
+    /// The charge for each reading in the bill, in the order they were measured
+    pub slots: Vec<SlotCharge>,
+    /// The charges for each day of the week with readings, Monday first
+    pub days: Vec<DayCharge>,
+    /// The amount of the bill
+    pub total: i64,
+    /// The total if the rest of a billing period of period_hours continues like the readings so far, or None if there are no readings
+    pub projected_total: Option<i64>,
+    /// Readings which are not included because the meter has not signed them yet
+    pub unsigned_readings: usize,
+}
+
+/// Identifies one of the providers which a customer bills. The provider given to CustomerState::new is 0 and the rest are numbered in the order they are added (see CustomerState::add_provider).
+pub type ProviderId = usize;
+
+// everything the customer keeps about one of the providers it bills
+struct ProviderLink<P: Read + Write> {
+    /// Channel through which to communicate with the provider
+    channel: P,
+    /// Public key of the provider for the verification of their prices, receipts and requests
+    key: sign::PublicKey,
+    /// The prices currently used to calculate this provider's bill
+    prices: Prices,
+    /// Requirements for new tariffs from the provider
+    tariff_policy: Option<TariffPolicy<Prices>>,
+    /// The last tariff accepted
+    tariff_evidence: Option<TariffEvidence>,
+    /// The latest tariff accepted. Tariffs which do not follow on from this are refused.
+    tariff_chain: TariffChain,
+    /// The bill which has been sent but not yet receipted
+    pending_bill: Option<PendingBill>,
+    /// Receipts signed by the provider, indexed by the provider's bill number
+    receipts: Vec<Receipt>,
+    /// Transcripts of disputes which the provider has signed
+    dispute_transcripts: Vec<DisputeTranscript>,
+    /// The prices each receipted bill was calculated with, indexed by the provider's bill number
+    billed_prices: Vec<Prices>,
+    /// (bill number, amounts) of the bill split which has been sent but not yet receipted
+    pending_split: Option<(usize, Vec<i64>)>,
+    /// The provider's receipts for bill splits
+    split_receipts: Vec<SplitReceipt>,
+    /// The index in billed_tables of this provider's first bill
+    first_bill: usize,
+}
+
+impl<P: Read + Write> ProviderLink<P> {
+    fn new(channel: P, prices: Prices, key: sign::PublicKey, first_bill: usize) -> ProviderLink<P> {
+        ProviderLink {
+            channel: channel,
+            key: key,
+            prices: prices,
+            tariff_policy: None,
+            tariff_evidence: None,
+            tariff_chain: TariffChain::genesis(),
+            pending_bill: None,
+            receipts: Vec::new(),
+            dispute_transcripts: Vec::new(),
+            billed_prices: Vec::new(),
+            pending_split: None,
+            split_receipts: Vec::new(),
+            first_bill: first_bill,
+        }
+    }
+}
+
+// readings from an appliance sub-meter (see CustomerState::add_sub_meter)
+struct SubMeterLink<M: Read + Write> {
+    /// Channel through which to communicate with the sub-meter
+    channel: M,
+    /// Public key of the sub-meter
+    key: sign::PublicKey,
+    /// The tariff for the appliance
+    prices: Prices,
+    /// Readings which the sub-meter has not signed yet
+    unbatched: Vec<ConsumptionTableRow>,
+    /// Signed readings which have not been sent to the provider yet
+    table: Vec<ConsumptionTableRow>,
+    /// The sub-meter's signatures covering table, in order
+    batches: Vec<SignedBatch>,
+    /// The end of the hash chain of the batches received from the sub-meter
+    chain: Option<ChainHead>,
+}
+
+// the part of the consumption table which has been billed to every provider but not yet receipted by all of them
+struct BillingRound {
+    rows: usize,
+    batches: usize,
+}
+
+/// The result of asking the customer to prove that its total consumption over some bills is below a threshold
+pub struct ThresholdStatement {
+    /// Identifies the request
+    pub request_id: u64,
+    /// The first bill in the period (counting from 0)
+    pub first_bill: usize,
+    /// The last bill in the period
+    pub last_bill: usize,
+    /// The threshold
+    pub threshold: i64,
+    /// Did the customer prove that the total consumption in the period is less than threshold?
+    pub proven: bool,
+    /// Did the customer refuse because the threshold is outside its ThresholdPolicy?
+    pub refused: bool,
+}
+
+/// The most thresholds the customer answers for the same bills unless told otherwise (see ThresholdPolicy). One answer reveals one bit about the total.
+pub const DEFAULT_MAX_THRESHOLDS: usize = 1;
+
+/// Which threshold statements the customer will prove (see CustomerState::set_threshold_policy). Requests outside the policy are refused.
+pub struct ThresholdPolicy {
+    /// The most different thresholds to answer for the same bills. Asking about the same threshold again reveals nothing new so it does not count.
+    pub max_thresholds: usize,
+    /// If set, only answer for these thresholds (e.g. the caps of the social tariffs on offer)
+    pub allowed: Option<Vec<i64>>,
+}
+
+impl ThresholdPolicy {
+    /// Answer for any thresholds, but at most max_thresholds for the same bills
+    pub fn new(max_thresholds: usize) -> ThresholdPolicy {
+        ThresholdPolicy {
+            max_thresholds: max_thresholds,
+            allowed: None,
+        }
+    }
+
+    /// Does the policy allow answering for threshold, given the thresholds already answered for the same bills?
+    pub fn allows(&self, threshold: i64, answered: &[i64]) -> bool {
+        if let Some(ref allowed) = self.allowed {
+            if !allowed.contains(&threshold) {
+                return false;
+            }
+        }
+
+        answered.contains(&threshold) || answered.len() < self.max_thresholds
+    }
+}
+
+// bound into each threshold range proof so that it cannot be reused for another request
+fn threshold_context(request_id: u64, first_bill: usize, last_bill: usize, threshold: i64) -> String {
+    format!("threshold {} {} {} {}", request_id, first_bill, last_bill, threshold)
+}
+
+/// State associated with the customer
+pub struct CustomerState<P: Read + Write, M: Read + Write> {
+    /// Channel through which to communicate with the meter
+    meter_channel: M,
+    /// The providers which bills are sent to, indexed by ProviderId
+    providers: Vec<ProviderLink<P>>,
+    /// Consumptions which the meter has not signed yet
+    unbatched: Vec<ConsumptionTableRow>,
+    /// The stored consumptions since the last bill was paid
+    consumption_table: Vec<ConsumptionTableRow>,
+    /// The meter's signatures covering consumption_table, in order
+    batches: Vec<SignedBatch>,
+    /// The end of the hash chain of the batches received from the meter
+    meter_chain: Option<ChainHead>,
+    /// Appliance sub-meters, indexed in the order they were added
+    sub_meters: Vec<SubMeterLink<M>>,
+    /// The consumptions which have already been billed, in the order they were billed. These are kept so that disputes can be answered.
+    billed_tables: Vec<BilledTable>,
+    /// The bills which have been sent but not yet receipted by every provider
+    billing_round: Option<BillingRound>,
+    /// How bills are split between tenants sharing the meter
+    split_agreement: Option<SignedSplitAgreement>,
+    /// Which linear statements to prove
+    statement_policy: StatementPolicy,
+    /// Which threshold statements to prove
+    threshold_policy: ThresholdPolicy,
+    /// The thresholds answered so far for each (provider, first bill, last bill)
+    thresholds_answered: BTreeMap<(ProviderId, usize, usize), Vec<i64>>,
+    /// Public key of the meter for verification of consumption data
+    meter_key: sign::PublicKey,
+    /// Key for countersigning dispute transcripts (see set_signing_key)
+    sign_sk: Option<sign::SecretKey>,
+    /// Commitment parameters
+    params: commitments::DHParams,
+}
+
+impl<P: Read + Write, M: Read + Write> CustomerState<P, M> {
+    /// Create a new CustomerState which bills one provider (with ProviderId 0)
+    pub fn new(meter_channel: M, provider_channel: P, prices: Prices, provider_key: sign::PublicKey,
+               meter_key: sign::PublicKey, params: commitments::DHParams)
+               -> CustomerState<P, M> {
+        //assert!(commitments::verify_dh_params(&params));
+        CustomerState {
+            meter_channel: meter_channel,
+            providers: vec!(ProviderLink::new(provider_channel, prices, provider_key, 0)),
+            unbatched: Vec::new(),
+            consumption_table: Vec::new(),
+            batches: Vec::new(),
+            meter_chain: None,
+            sub_meters: Vec::new(),
+            billed_tables: Vec::new(),
+            billing_round: None,
+            split_agreement: None,
+            statement_policy: StatementPolicy::totals_only(),
+            threshold_policy: ThresholdPolicy::new(DEFAULT_MAX_THRESHOLDS),
+            thresholds_answered: BTreeMap::new(),
+            meter_key: meter_key,
+            sign_sk: None,
+            params: params,
+        }
+    }
+
+    /// Countersign the transcripts of disputes with sk (see DisputeTranscript). Without a signing key the customer still answers disputes but the provider only holds transcripts it signed itself.
+    pub fn set_signing_key(&mut self, sk: sign::SecretKey) {
+        self.sign_sk = Some(sk);
+    }
+
+    /// Only prove the linear statements allowed by policy. The default policy only proves the total consumption in a bill (see StatementPolicy::totals_only).
+    pub fn set_statement_policy(&mut self, policy: StatementPolicy) {
+        self.statement_policy = policy;
+    }
+
+    /// Only prove the threshold statements allowed by policy. The default policy answers for one threshold for the same bills.
+    pub fn set_threshold_policy(&mut self, policy: ThresholdPolicy) {
+        self.threshold_policy = policy;
+    }
+
+    /// Also bill another provider (e.g. the network operator as well as the energy supplier) for the same readings, using its own signed tariffs.
+    /// Each provider checks its own bill against the meter's signatures so the providers do not need to trust each other.
+    /// The new provider's first bill is the next one sent. Panics if bills are waiting for receipts.
+    pub fn add_provider(&mut self, channel: P, prices: Prices, provider_key: sign::PublicKey) -> ProviderId {
+        assert!(self.billing_round.is_none());
+
+        let first_bill = self.billed_tables.len();
+        self.providers.push(ProviderLink::new(channel, prices, provider_key, first_bill));
+        self.providers.len() - 1
+    }
+
+    /// The number of providers which are billed
+    pub fn num_providers(&self) -> usize {
+        self.providers.len()
+    }
+
+    /// The prices currently used to calculate the bill for provider
+    pub fn prices(&self, provider: ProviderId) -> &Prices {
+        &self.providers[provider].prices
+    }
+
+    /// For debugging and logging: outputs a human readable representation of the cons and other fields of the consumption table
+    pub fn readable_consumption_table(&self) -> String {
+        let mut out = String::new();
+        for row in &self.consumption_table {
+            let row = format!("cons: {}, other: {}\n", row.cons, row.other);
+            out = out + row.as_str();
+        }
+        out
+    }
+
+    /// Calculate the bill which send_billing_information would send to provider, without sending anything.
+    /// The projection assumes the billing period lasts period_hours readings and that each hour still to come uses the average of the readings so far for the same hour of the week
+    /// (or of all the readings if there are none for that hour yet).
+    pub fn preview_bill(&self, provider: ProviderId, period_hours: u64) -> BillPreview {
+        let link = &self.providers[provider];
+        let price = |other: u64| link.prices[(other % (24*7)) as usize];
+
+        let slots: Vec<SlotCharge> = self.consumption_table.iter().map(|row| SlotCharge {
+            other: row.other,
+            units: row.cons as i64,
+            price: price(row.other),
+            amount: row.cons as i64 * price(row.other) as i64,
+        }).collect();
+
+        let mut days: Vec<DayCharge> = Vec::new();
+        for day in 0..7 {
+            let on_day: Vec<&SlotCharge> = slots.iter().filter(|s| (s.other % (24*7)) / 24 == day).collect();
+            if !on_day.is_empty() {
+                days.push(DayCharge {
+                    day: day,
+                    units: on_day.iter().map(|s| s.units).sum(),
+                    amount: on_day.iter().map(|s| s.amount).sum(),
+                });
+            }
+        }
+
+        let total: i64 = slots.iter().map(|s| s.amount).sum();
+
+        let projected_total = slots.last().map(|last| {
+            let mut by_hour = [(0 as i64, 0 as i64); 24*7]; // (units, readings)
+            for slot in &slots {
+                let hour = (slot.other % (24*7)) as usize;
+                by_hour[hour].0 += slot.units;
+                by_hour[hour].1 += 1;
+            }
+            let overall = slots.iter().map(|s| s.units).sum::<i64>() as f64 / slots.len() as f64;
+
+            let mut projection = 0.0;
+            let remaining = period_hours.saturating_sub(slots.len() as u64);
+            for i in 1..(remaining + 1) {
+                let other = last.other + i;
+                let (units, readings) = by_hour[(other % (24*7)) as usize];
+                let expected = if readings > 0 { units as f64 / readings as f64 } else { overall };
+                projection += expected * price(other) as f64;
+            }
+
+            total + projection.round() as i64
+        });
+
+        BillPreview {
+            tariff_number: link.tariff_chain.seq,
+            tariff_hash: link.tariff_chain.hex_hash(),
+            slots: slots,
+            days: days,
+            total: total,
+            projected_total: projected_total,
+            unsigned_readings: self.unbatched.len(),
+        }
+    }
+
+    /// Calculate a bill for every provider over the same readings, send each provider its bill and return the bills (indexed by ProviderId).
+    /// The consumption table is only emptied once every provider's receipt has arrived (see read_provider_messages).
+    pub fn send_billing_information(&mut self) -> Vec<i64> {
+        // do nothing if there is no bill to send
+        if self.consumption_table.len() == 0 {
+            println!("I can't bill an empty consumption table!");
+            return Vec::new();
+        }
+
+        if self.billing_round.is_some() {
+            println!("The last bills have not all been receipted yet!");
+            return Vec::new();
+        }
+
+        self.billing_round = Some(BillingRound {
+            rows: self.consumption_table.len(),
+            batches: self.batches.len(),
+        });
+
+        (0..self.providers.len()).map(|provider| self.send_bill(provider)).collect()
+    }
+
+    // calculate provider's bill for the readings in the billing round with its latest tariff and send it
+    fn send_bill(&mut self, provider: ProviderId) -> i64 {
+        let (rows, batches) = match self.billing_round {
+            Some(ref round) => (&self.consumption_table[..round.rows], &self.batches[..round.batches]),
+            None => panic!("There is no billing round to send a bill for"),
+        };
+
+        // every provider is sent the same signed batches
+        let body_lines = batch_lines(rows, batches);
+
+        let mut body = String::new();
+        for line in &body_lines {
+            body = body + line + "\n";
+        }
+
+        let lines: Vec<&str> = body_lines.iter().map(|l| l.as_str()).collect();
+        let hash = commitments_hash(&lines);
+        let period_start = rows.iter().map(|r| r.other).min().unwrap();
+        let period_end = rows.iter().map(|r| r.other).max().unwrap();
+
+        let link = &mut self.providers[provider];
+
+        // calculate what we think that the bill will be and what we expect a to be
+        let (bill, a) = weighted_opening(rows, &link.prices, &self.params.0);
+
+        // Message format: "bill\nBILL\nA\nTARIFF_HASH\nNUM_BATCHES\n" followed by "signed_root\nLEN\nleaf[0]\n...leaf[LEN-1]\n" for each batch
+        let const_len_part_str = format!("bill\n{}\n{}\n{}\n{}\n", bill, a.to_str_radix(16), link.tariff_chain.hex_hash(), batches.len());
+        let const_len_part = const_len_part_str.as_bytes();
+        match link.channel.write(&const_len_part) {
+            Ok(s) => assert_eq!(s, const_len_part.len()),
+            Err(e) => panic!("Failed to send the constant part of the billing info. The error was {}", e),
+        };
+        common::write_message(&mut link.channel, body.as_bytes(), "signed batches");
+
+        // remember what we sent so that we can check the receipt
+        link.pending_bill = Some(PendingBill {
+            bill_number: self.billed_tables.len() - link.first_bill,
+            amount: bill,
+            period_start: period_start,
+            period_end: period_end,
+            commitments_hash: hash,
+            tariff_hash: link.tariff_chain.hex_hash(),
+            prices: link.prices,
+        });
+
+        bill
+    }
+
+    // the provider rejected our bill because it used an old tariff: the newer tariffs arrived before the rejection so bill again with the latest
+    fn receive_bill_rejection(&mut self, provider: ProviderId) -> Option<ProviderProblem> {
+        let why = String::from_utf8_lossy(&read_line_blocking(&mut self.providers[provider].channel)).into_owned();
+
+        if self.providers[provider].pending_bill.is_none() {
+            return Some(ProviderProblem::BadReceipt(provider, "it rejects a bill when no bill is waiting for a receipt".to_string()));
+        }
+
+        self.send_bill(provider);
+        Some(ProviderProblem::BillRejected(provider, why))
+    }
+
+    /// Are bills waiting for receipts?
+    pub fn awaiting_receipt(&self) -> bool {
+        self.billing_round.is_some()
+    }
+
+    /// Keep reading messages from the providers until every receipt for the last bills has arrived, a receipt is rejected or timeout has passed.
+    /// Returns the problems with the messages read, including a NoReceipt for each provider whose receipt did not arrive in time. The bills are receipted if awaiting_receipt is false afterwards.
+    pub fn wait_for_receipt(&mut self, timeout: Duration) -> Vec<ProviderProblem> {
+        let start = Instant::now();
+        let mut problems = Vec::new();
+
+        while self.billing_round.is_some() {
+            problems.append(&mut self.read_provider_messages());
+            if problems.iter().any(|p| match *p { ProviderProblem::BadReceipt(_, _) => true, _ => false }) {
+                break;
+            }
+
+            if start.elapsed() >= timeout {
+                for (provider, link) in self.providers.iter().enumerate() {
+                    if link.pending_bill.is_some() {
+                        problems.push(ProviderProblem::NoReceipt(provider));
+                    }
+                }
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        problems
+    }
+
+    /// The receipts provider has sent for our bills
+    pub fn receipts(&self, provider: ProviderId) -> &[Receipt] {
+        &self.providers[provider].receipts
+    }
+
+    // a receipt which does not match the bill which was sent is rejected and the bill keeps waiting for one
+    fn receive_receipt(&mut self, provider: ProviderId) -> Option<ProviderProblem> {
+        {
+            let link = &mut self.providers[provider];
+            let problem = |why: &str| Some(ProviderProblem::BadReceipt(provider, why.to_string()));
+
+            let signed = unstringify_bytes(&String::from_utf8(read_line_blocking(&mut link.channel)).unwrap());
+            let text = match sign::verify(&signed, &link.key).map(String::from_utf8) {
+                Ok(Ok(t)) => t,
+                _ => return problem("it is not signed by the provider"),
+            };
+            let receipt = match Receipt::from_text(&text, signed) {
+                Some(r) => r,
+                None => return problem("it is malformed"),
+            };
+
+            // check that the provider accepted exactly what we sent
+            let matches = match link.pending_bill {
+                Some(ref pending) => receipt.bill_number == pending.bill_number && receipt.amount == pending.amount &&
+                    receipt.period_start == pending.period_start && receipt.period_end == pending.period_end &&
+                    receipt.commitments_hash == pending.commitments_hash && receipt.tariff_hash == pending.tariff_hash,
+                None => return problem("no bill is waiting for a receipt"),
+            };
+            if !matches {
+                return problem("it does not match the bill which was sent");
+            }
+
+            let pending = link.pending_bill.take().unwrap();
+            link.receipts.push(receipt);
+            link.billed_prices.push(pending.prices);
+        }
+
+        if self.providers.iter().all(|l| l.pending_bill.is_none()) {
+            self.finish_billing_round();
+        }
+
+        None
+    }
+
+    // every provider has receipted its bill so it is safe to empty the table, keeping the rows in case there is a dispute
+    fn finish_billing_round(&mut self) {
+        let round = self.billing_round.take().unwrap();
+
+        let remaining = self.consumption_table.split_off(round.rows);
+        let billed = mem::replace(&mut self.consumption_table, remaining);
+        self.billed_tables.push(BilledTable {
+            rows: billed,
+            receipted_at: SystemTime::now(),
+        });
+        let remaining_batches = self.batches.split_off(round.batches);
+        self.batches = remaining_batches;
+
+        self.purge_expired_openings(SystemTime::now());
+    }
+
+    // the rows of provider's bill number bill_number, or None if they are not known (or were purged)
+    fn billed_rows(&self, provider: ProviderId, bill_number: usize) -> Option<&[ConsumptionTableRow]> {
+        let index = self.providers[provider].first_bill + bill_number;
+
+        if let Some(billed) = self.billed_tables.get(index) {
+            return Some(&billed.rows);
+        }
+
+        // receipted by this provider but still waiting for the others
+        match self.billing_round {
+            Some(ref round) if index == self.billed_tables.len() && self.providers[provider].pending_bill.is_none() =>
+                Some(&self.consumption_table[..round.rows]),
+            _ => None,
+        }
+    }
+
+    /// Wipe the openings for bills which were receipted more than DISPUTE_WINDOW_SECS before now. Disputes about these bills will be answered with no openings.
+    /// This is done automatically whenever the receipts for a bill arrive and before the providers' messages are read (see read_provider_messages). Returns the number of readings wiped.
+    pub fn purge_expired_openings(&mut self, now: SystemTime) -> usize {
+        let mut purged = 0;
+
+        for billed in self.billed_tables.iter_mut() {
+            let expired = match now.duration_since(billed.receipted_at) {
+                Ok(age) => age.as_secs() > DISPUTE_WINDOW_SECS,
+                Err(_) => false, // receipted in the future?
+            };
+
+            if expired {
+                purged += billed.rows.len();
+                billed.rows.clear(); // the rows wipe themselves when dropped
+            }
+        }
+
+        purged
+    }
+    
+    /// check for new consumption messages from the meter and the sub-meters
+    pub fn read_meter_messages(&mut self) {
+        customer_read_consumption(&mut self.meter_channel, &self.meter_key, &mut self.unbatched, &mut self.consumption_table, &mut self.batches, &mut self.meter_chain);
+
+        for sub in self.sub_meters.iter_mut() {
+            customer_read_consumption(&mut sub.channel, &sub.key, &mut sub.unbatched, &mut sub.table, &mut sub.batches, &mut sub.chain);
+        }
+    }
+
+    /// Add an appliance sub-meter (e.g. an EV charger) whose consumption is billed at the appliance tariff prices. The sub-meter is a MeterState with its own signing key.
+    /// Returns the index of the sub-meter: the provider must add the same sub-meters in the same order (see ProviderState::add_sub_meter).
+    pub fn add_sub_meter(&mut self, channel: M, key: sign::PublicKey, prices: Prices) -> usize {
+        self.sub_meters.push(SubMeterLink {
+            channel: channel,
+            key: key,
+            prices: prices,
+            unbatched: Vec::new(),
+            table: Vec::new(),
+            batches: Vec::new(),
+            chain: None,
+        });
+        self.sub_meters.len() - 1
+    }
+
+    /// Claim the appliance tariffs from provider for the signed sub-meter readings received so far.
+    /// Readings are claimed against provider's last receipted bill, a whole batch at a time, up to the first batch with a reading for a slot outside that bill: those readings are kept for a later bill.
+    /// For each slot the customer proves that the sub-meter readings sum to at most the main meter's reading, without revealing either.
+    /// The adjustment for each sub-meter is the sum of (appliance price - price) * reading at the prices of the bill, which is proven like a linear statement. Returns the total adjustment to the bill.
+    /// Nothing is sent if there is an error.
+    pub fn send_sub_metering(&mut self, provider: ProviderId) -> Result<i64, SubMeteringError> {
+        let bill_number = match self.providers[provider].receipts.len() {
+            0 => return Err(SubMeteringError::NoBill(provider)),
+            n => n - 1,
+        };
+
+        let (message, adjustment, claimed) = {
+            let main_rows = match self.billed_rows(provider, bill_number) {
+                Some(rows) if rows.len() > 0 => rows,
+                _ => return Err(SubMeteringError::ReadingsUnavailable(provider, bill_number)),
+            };
+            let group = Group::from_params(&self.params);
+            let ref prices = self.providers[provider].billed_prices[bill_number];
+
+            // the number of batches and rows claimed from each sub-meter and the position of the main reading for each claimed row
+            let mut claimed = Vec::new();
+            let mut indices = Vec::new();
+            for sub in &self.sub_meters {
+                let mut rows = sub.table.iter();
+                let mut num_batches = 0;
+                let mut sub_indices = Vec::new();
+                'batches: for batch in &sub.batches {
+                    let mut batch_indices = Vec::new();
+                    for _ in 0..batch.len {
+                        let row = rows.next().unwrap();
+                        match main_index(main_rows.iter().map(|r| r.other), row.other) {
+                            Ok(index) => batch_indices.push(index),
+                            Err(0) => break 'batches, // taken after the bill
+                            Err(_) => return Err(SubMeteringError::RepeatedSlot(row.other)),
+                        }
+                    }
+                    num_batches += 1;
+                    sub_indices.append(&mut batch_indices);
+                }
+                claimed.push((num_batches, sub_indices.len()));
+                indices.push(sub_indices);
+            }
+
+            // Message format: "submetering\nBILL_NUMBER\nNUM_SUB_METERS\n"
+            // followed by "NUM_BATCHES\n" batches "VALUE\nA\n" for each sub-meter
+            // followed by "NUM_PROOFS\n" and "MAIN_INDEX\nrange proof\n" for each slot with sub-meter readings
+            let mut message = format!("submetering\n{}\n{}\n", bill_number, self.sub_meters.len());
+            let mut adjustment = 0;
+
+            // the sum of the sub-meter readings and openings in each slot, indexed by the position of the main reading for the slot
+            let mut slots: BTreeMap<usize, (i64, Mpz)> = BTreeMap::new();
+
+            for (sub, (&(num_batches, num_rows), sub_indices)) in self.sub_meters.iter().zip(claimed.iter().zip(indices.iter())) {
+                let table = &sub.table[..num_rows];
+                for (row, index) in table.iter().zip(sub_indices.iter()) {
+                    let entry = slots.entry(*index).or_insert((0, Mpz::zero()));
+                    entry.0 += row.cons as i64;
+                    entry.1 = (entry.1.clone() + row.a.clone()).modulus(&group.q);
+                }
+
+                let mut weights: Weights = [0; 24*7];
+                for i in 0..weights.len() {
+                    weights[i] = sub.prices[i] - prices[i];
+                }
+                let (value, a) = weighted_opening(table, &weights, &self.params.0);
+                adjustment += value;
+
+                message += &format!("{}\n", num_batches);
+                for line in batch_lines(table, &sub.batches[..num_batches]) {
+                    message = message + &line + "\n";
+                }
+                message += &format!("{}\n{}\n", value, a.to_str_radix(16));
+            }
+
+            // check every slot before proving anything
+            let mut over_read = None;
+            for (index, &(sub_cons, _)) in slots.iter() {
+                if (main_rows[*index].cons as i64) < sub_cons {
+                    over_read = Some(main_rows[*index].other);
+                    break;
+                }
+            }
+
+            if over_read.is_none() {
+                message += &format!("{}\n", slots.len());
+                for (index, &(sub_cons, ref sub_a)) in slots.iter() {
+                    let ref main_row = main_rows[*index];
+                    let remainder = main_row.cons as i64 - sub_cons;
+
+                    let mut a = (main_row.a.clone() - sub_a.clone()).modulus(&group.q);
+                    let proof = RangeProof::prove(&group, remainder as u64, &a, SUBMETER_RANGE_BITS, submetering_context(bill_number, *index).as_bytes());
+                    secrets::wipe_mpz(&mut a);
+
+                    message += &format!("{}\n{}\n", index, proof.to_text());
+                }
+            }
+
+            for (_, &mut (_, ref mut sub_a)) in slots.iter_mut() {
+                secrets::wipe_mpz(sub_a);
+            }
+
+            if let Some(other) = over_read {
+                return Err(SubMeteringError::OverRead(other));
+            }
+
+            (message, adjustment, claimed)
+        };
+
+        common::write_message(&mut self.providers[provider].channel, message.as_bytes(), "sub-metering proof");
+
+        // the provider's chain heads for the sub-meters have moved on so these readings cannot be claimed again
+        for (sub, (num_batches, num_rows)) in self.sub_meters.iter_mut().zip(claimed.into_iter()) {
+            sub.table.drain(..num_rows); // the rows wipe themselves when dropped
+            sub.batches.drain(..num_batches);
+        }
+
+        Ok(adjustment)
+    }
+
+    /// check for price changes and dispute requests from every provider. Dispute requests are answered immediately.
+    /// Returns the problems with any messages which were not acted on.
+    pub fn read_provider_messages(&mut self) -> Vec<ProviderProblem> {
+        let mut problems = Vec::new();
+
+        // expired openings must not be used to answer disputes and statements
+        self.purge_expired_openings(SystemTime::now());
+
+        for provider in 0..self.providers.len() {
+            // in case several messages have been sent
+            while let Some(header) = self.next_provider_header(provider) {
+                problems.extend(self.receive_provider_message(provider, &header));
+            }
+        }
+
+        problems
+    }
+
+    // handle the rest of a message from provider whose header has been read
+    fn receive_provider_message(&mut self, provider: ProviderId, header: &str) -> Option<ProviderProblem> {
+        match header {
+            "prices" => {
+                let signed_prices = common::read_signed_prices(&mut self.providers[provider].channel);
+                self.receive_prices(provider, signed_prices, None);
+                None
+            },
+            "countersigned_prices" => {
+                let signed_prices = common::read_signed_prices(&mut self.providers[provider].channel);
+                let mut countersignature = vec!(0 as u8; COUNTERSIGNATURE_LEN);
+                common::read_exact_blocking(&mut self.providers[provider].channel, &mut countersignature);
+                self.receive_prices(provider, signed_prices, Some(countersignature));
+                None
+            },
+            "receipt" => self.receive_receipt(provider),
+            "bill_rejected" => self.receive_bill_rejection(provider),
+            "dispute" => self.answer_dispute(provider).err().map(|why| ProviderProblem::BadRequest(provider, why)),
+            "statement" => self.answer_linear_statement(provider).err().map(|why| ProviderProblem::BadRequest(provider, why)),
+            "split_receipt" => self.receive_split_receipt(provider),
+            "split_rejected" => self.receive_split_rejection(provider),
+            "threshold" => self.answer_threshold_statement(provider).err().map(|why| ProviderProblem::BadRequest(provider, why)),
+            _ => panic!("Unrecognised message from provider {}: {}", provider, header),
+        }
+    }
+
+    fn next_provider_header(&mut self, provider: ProviderId) -> Option<String> {
+        common::try_read_line(&mut self.providers[provider].channel).map(|h| String::from_utf8(h).unwrap())
+    }
+
+    /// Only accept tariffs from provider which meet policy (see the regulator module)
+    pub fn set_tariff_policy(&mut self, provider: ProviderId, policy: TariffPolicy<Prices>) {
+        self.providers[provider].tariff_policy = Some(policy);
+    }
+
+    /// The last tariff accepted from provider, as evidence of the prices used. None if the prices have not changed since the provider was added.
+    pub fn tariff_evidence(&self, provider: ProviderId) -> Option<&TariffEvidence> {
+        self.providers[provider].tariff_evidence.as_ref()
+    }
+
+    fn receive_prices(&mut self, provider: ProviderId, signed_prices: Vec<u8>, countersignature: Option<Vec<u8>>) {
+        let link = &mut self.providers[provider];
+        let (prices, tariff_link) = common::verify_prices::<i32, u64, IntegerConsumption>(&signed_prices, &link.key);
+
+        // the provider must not sign two different tariffs with the same number
+        if !link.tariff_chain.follows(&tariff_link) {
+            println!("Refused tariff number {} because it does not follow on from tariff number {}", tariff_link.seq, link.tariff_chain.seq);
+            return;
+        }
+
+        if let Some(ref policy) = link.tariff_policy {
+            if !tariff_acceptable::<i32, u64, IntegerConsumption>(policy, &signed_prices, countersignature.as_ref().map(|c| c.as_slice()), &prices) {
+                println!("Rejected a tariff which does not meet the regulator's requirements");
+                return;
+            }
+        }
+
+        link.prices = prices;
+        link.tariff_chain.advance(&tariff_link);
+        link.tariff_evidence = Some(TariffEvidence {
+            signed_prices: signed_prices,
+            countersignature: countersignature,
+        });
+    }
+
+    /// Transcripts of the disputes with provider answered so far. These are signed by the provider and countersigned by us if we have a signing key and agreed with them.
+    pub fn dispute_transcripts(&self, provider: ProviderId) -> &[DisputeTranscript] {
+        &self.providers[provider].dispute_transcripts
+    }
+
+    // read a request from provider (a line containing something signed by the provider). Err if it is malformed or not signed by the provider.
+    fn read_signed_request(&mut self, provider: ProviderId, what: &str) -> Result<String, String> {
+        let link = &mut self.providers[provider];
+        let signed_request = match common::try_unstringify_bytes(&String::from_utf8_lossy(&read_line_blocking(&mut link.channel))) {
+            Some(s) => s,
+            None => return Err(format!("the {} is malformed", what)),
+        };
+        match sign::verify(&signed_request, &link.key) {
+            Ok(b) => String::from_utf8(b).map_err(|_| format!("the {} is not text", what)),
+            Err(_) => Err(format!("verification of the {} failed", what)),
+        }
+    }
+
+    // open the commitments the provider asked for
+    fn answer_dispute(&mut self, provider: ProviderId) -> Result<(), String> {
+        let request = try!(self.read_signed_request(provider, "dispute request"));
+
+        // request looks like "dispute_id bill_number other other ..."
+        let mut request_iter = request.split_whitespace();
+        let dispute_id: u64 = try!(next_field(&mut request_iter, "dispute id"));
+        let bill_number: usize = try!(next_field(&mut request_iter, "bill number"));
+        let others = match request_iter.map(|o| o.parse()).collect::<Result<Vec<u64>, _>>() {
+            Ok(o) => o,
+            Err(_) => return Err(String::from("could not parse the slots in the dispute request")),
+        };
+
+        // (index, cons, a) for each reading to open
+        let mut openings = Vec::new();
+        if let Some(rows) = self.billed_rows(provider, bill_number) {
+            for (index, row) in rows.iter().enumerate() {
+                if others.contains(&row.other) {
+                    openings.push((index.to_string(), row.cons.to_string(), row.a.to_str_radix(16)));
+                }
+            }
+        }
+
+        // Message format: "dispute_response\ndispute_id\nnum_openings\nindex cons a\n...\n"
+        // the openings are secret so build the message in a buffer which is wiped afterwards, rather than with format!
+        let header = format!("dispute_response\n{}\n{}\n", dispute_id, openings.len());
+        let len = header.len() + openings.iter().map(|&(ref index, ref cons, ref a)| index.len() + 1 + cons.len() + 1 + a.len() + 1).sum::<usize>();
+        let mut message = SecretBuf::with_capacity(len);
+        message.push_bytes(header.as_bytes());
+        for (index, cons, a) in openings {
+            message.push_bytes(index.as_bytes());
+            message.push_bytes(b" ");
+            message.push_bytes(cons.as_bytes());
+            message.push_bytes(b" ");
+            message.push_bytes(a.as_bytes());
+            message.push_bytes(b"\n");
+
+            secrets::wipe_string(cons);
+            secrets::wipe_string(a);
+        }
+
+        common::write_message(&mut self.providers[provider].channel, message.as_slice(), "dispute response");
+
+        // the provider sends its transcript straight away and waits for our countersignature
+        self.receive_dispute_transcript(provider, dispute_id, bill_number, &others)
+    }
+
+    // prove the weighted sum of a billed table the provider asked for, if the statement policy allows it
+    fn answer_linear_statement(&mut self, provider: ProviderId) -> Result<(), String> {
+        let request = try!(self.read_signed_request(provider, "statement request"));
+
+        // request looks like "request_id bill_number weight[0] ... weight[24*7-1]"
+        let mut request_iter = request.split_whitespace();
+        let request_id: u64 = try!(next_field(&mut request_iter, "request id"));
+        let bill_number: usize = try!(next_field(&mut request_iter, "bill number"));
+        let mut weights: Weights = [0; 24*7];
+        for i in 0..weights.len() {
+            weights[i] = try!(next_field(&mut request_iter, "weight"));
+        }
+        if request_iter.next().is_some() {
+            return Err(String::from("the statement request has too many weights"));
+        }
+
+        if !self.statement_policy.allows(&weights) {
+            let message = format!("statement_response\n{}\nrefused\n", request_id);
+            common::write_message(&mut self.providers[provider].channel, message.as_bytes(), "statement response");
+            return Ok(());
+        }
+
+        // the openings may have been purged after the dispute window
+        let answer = match self.billed_rows(provider, bill_number) {
+            Some(rows) if rows.len() > 0 => {
+                let (value, a) = weighted_opening(rows, &weights, &self.params.0);
+                format!("{}\n{}\n", value, a.to_str_radix(16))
+            },
+            _ => "unavailable\n".to_string(),
+        };
+
+        let message = format!("statement_response\n{}\n{}", request_id, answer);
+        common::write_message(&mut self.providers[provider].channel, message.as_bytes(), "statement response");
+        Ok(())
+    }
+
+    // prove that the total consumption over some bills is below the threshold the provider asked about
+    fn answer_threshold_statement(&mut self, provider: ProviderId) -> Result<(), String> {
+        let request = try!(self.read_signed_request(provider, "threshold request"));
+
+        // request looks like "request_id first_bill last_bill threshold"
+        let mut request_iter = request.split_whitespace();
+        let request_id: u64 = try!(next_field(&mut request_iter, "request id"));
+        let first_bill: usize = try!(next_field(&mut request_iter, "first bill"));
+        let last_bill: usize = try!(next_field(&mut request_iter, "last bill"));
+        let threshold: i64 = try!(next_field(&mut request_iter, "threshold"));
+        if request_iter.next().is_some() {
+            return Err(String::from("the threshold request has too many fields"));
+        }
+
+        // a "no" answers the question too, so every threshold answered counts
+        let allowed = {
+            let answered = self.thresholds_answered.entry((provider, first_bill, last_bill)).or_insert(Vec::new());
+            let allowed = self.threshold_policy.allows(threshold, &answered[..]);
+            if allowed && !answered.contains(&threshold) {
+                answered.push(threshold);
+            }
+            allowed
+        };
+        if !allowed {
+            let message = format!("threshold_response\n{}\nrefused\n", request_id);
+            common::write_message(&mut self.providers[provider].channel, message.as_bytes(), "threshold response");
+            return Ok(());
+        }
+
+        let answer = {
+            let group = Group::from_params(&self.params);
+
+            // the total and the sum of the openings, unless some of the openings have been purged
+            let mut total = Some((0 as i64, Mpz::zero()));
+            for bill_number in first_bill..(last_bill + 1) {
+                let bill_total = match self.billed_rows(provider, bill_number) {
+                    Some(rows) if rows.len() > 0 => Some(weighted_opening(rows, &[1; 24*7], &group.q)),
+                    _ => None,
+                };
+
+                total = match (total, bill_total) {
+                    (Some((cons, a)), Some((bill_cons, bill_a))) => Some((cons + bill_cons, (a + bill_a).modulus(&group.q))),
+                    _ => None,
+                };
+            }
+
+            match total {
+                Some((cons, mut a)) => {
+                    let remainder = threshold - 1 - cons;
+                    let answer = if remainder >= 0 && (remainder as u64) >> THRESHOLD_RANGE_BITS == 0 {
+                        // the commitment to threshold - 1 - cons has opening -a
+                        let minus_a = (Mpz::zero() - a.clone()).modulus(&group.q);
+                        let proof = RangeProof::prove(&group, remainder as u64, &minus_a, THRESHOLD_RANGE_BITS,
+                                                      threshold_context(request_id, first_bill, last_bill, threshold).as_bytes());
+                        format!("{}\n", proof.to_text())
+                    } else {
+                        "unavailable\n".to_string()
+                    };
+                    secrets::wipe_mpz(&mut a);
+                    answer
+                },
+                None => "unavailable\n".to_string(),
+            }
+        };
+
+        // Message format: "threshold_response\nrequest_id\nrange proof\n", "threshold_response\nrequest_id\nunavailable\n" or "threshold_response\nrequest_id\nrefused\n"
+        let message = format!("threshold_response\n{}\n{}", request_id, answer);
+        common::write_message(&mut self.providers[provider].channel, message.as_bytes(), "threshold response");
+        Ok(())
+    }
+
+    // read the transcript of the dispute we just answered and countersign it if it records exactly what we opened.
+    // A transcript which was not signed by the provider or is malformed is not countersigned or kept.
+    fn receive_dispute_transcript(&mut self, provider: ProviderId, dispute_id: u64, bill_number: usize, others: &[u64]) -> Result<(), String> {
+        common::expect_header(&mut self.providers[provider].channel, "dispute_transcript");
+        let signed_str = String::from_utf8_lossy(&read_line_blocking(&mut self.providers[provider].channel)).into_owned();
+        let parsed = match common::try_unstringify_bytes(&signed_str) {
+            Some(signed) => {
+                let verified = sign::verify(&signed, &self.providers[provider].key);
+                match verified {
+                    Ok(b) => match String::from_utf8(b) {
+                        Ok(text) => DisputeTranscript::from_text(&text, signed),
+                        Err(_) => Err(String::from("the dispute transcript is not text")),
+                    },
+                    Err(_) => Err(String::from("verification of the dispute transcript failed")),
+                }
+            },
+            None => Err(String::from("the dispute transcript is malformed")),
+        };
+        let mut transcript = match parsed {
+            Ok(t) => t,
+            Err(why) => {
+                // the provider is waiting for an answer
+                let message = format!("dispute_countersignature\n{}\nnone\n", dispute_id);
+                common::write_message(&mut self.providers[provider].channel, message.as_bytes(), "dispute countersignature");
+                return Err(why);
+            },
+        };
+
+        // every reading we opened should have been accepted and every slot we did not open should be missing
+        let agrees = {
+            let rows = self.billed_rows(provider, bill_number).unwrap_or(&[]);
+            let opened: Vec<(usize, &ConsumptionTableRow)> = rows.iter().enumerate().filter(|&(_, r)| others.contains(&r.other)).collect();
+            let missing: Vec<u64> = others.iter().filter(|o| !opened.iter().any(|&(_, r)| r.other == **o)).map(|o| *o).collect();
+
+            transcript.dispute_id == dispute_id && transcript.bill_number == bill_number && transcript.missing == missing &&
+                transcript.openings.len() == opened.len() &&
+                transcript.openings.iter().zip(opened.iter()).all(|(o, &(index, r))| o.valid && o.index == index && o.other == r.other && o.cons == r.cons && o.a == r.a)
+        };
+
+        let countersignature = match self.sign_sk {
+            Some(ref sk) if agrees => sign::sign(&dispute_countersignature_text(&transcript.signed), sk),
+            _ => Vec::new(),
+        };
+
+        // Message format: "dispute_countersignature\ndispute_id\nsigned\n" or "dispute_countersignature\ndispute_id\nnone\n"
+        let countersignature_str = if countersignature.is_empty() { String::from("none") } else { stringify_bytes(&countersignature) };
+        let message = format!("dispute_countersignature\n{}\n{}\n", transcript.dispute_id, countersignature_str);
+        common::write_message(&mut self.providers[provider].channel, message.as_bytes(), "dispute countersignature");
+
+        transcript.countersignature = countersignature;
+        self.providers[provider].dispute_transcripts.push(transcript);
+        Ok(())
+    }
+
+    /// Split bills between the tenants sharing the meter according to agreement (see the split module). Panics if any tenant's signature is missing.
+    pub fn set_split_agreement(&mut self, agreement: SignedSplitAgreement) {
+        assert!(agreement.verify());
+        self.split_agreement = Some(agreement);
+    }
+
+    /// Prove each tenant's amount for provider's last receipted bill to provider. The provider's signed SplitReceipt arrives later (see read_provider_messages and split_receipts), or read_provider_messages reports that the provider rejected the split.
+    /// Returns the amounts in units of 1 / SHARE_DENOMINATOR, indexed like the tenants in the agreement. Nothing is sent if there is an error.
+    pub fn send_bill_split(&mut self, provider: ProviderId) -> Result<Vec<i64>, SplitError> {
+        let bill_number = match self.providers[provider].receipts.len() {
+            0 => return Err(SplitError::NoBill(provider)),
+            n => n - 1,
+        };
+
+        let (message, amounts) = {
+            let signed_agreement = match self.split_agreement {
+                Some(ref a) => a,
+                None => return Err(SplitError::NoAgreement),
+            };
+            let rows = match self.billed_rows(provider, bill_number) {
+                Some(rows) if rows.len() > 0 => rows,
+                _ => return Err(SplitError::ReadingsUnavailable(provider, bill_number)),
+            };
+            let ref prices = self.providers[provider].billed_prices[bill_number];
+
+            // Message format: "split\nBILL_NUMBER\nNUM_TENANTS\n" signed agreement (see SignedSplitAgreement::to_text) then "VALUE\nA\n" for each tenant
+            let mut message = format!("split\n{}\n{}\n{}", bill_number, signed_agreement.agreement.tenants.len(), signed_agreement.to_text());
+            let mut amounts = Vec::new();
+            for tenant in 0..signed_agreement.agreement.tenants.len() {
+                let (value, a) = weighted_opening(rows, &signed_agreement.agreement.weights(tenant, prices), &self.params.0);
+                message += &format!("{}\n{}\n", value, a.to_str_radix(16));
+                amounts.push(value);
+            }
+
+            (message, amounts)
+        };
+
+        let link = &mut self.providers[provider];
+        common::write_message(&mut link.channel, message.as_bytes(), "bill split");
+        link.pending_split = Some((bill_number, amounts.clone()));
+
+        Ok(amounts)
+    }
+
+    /// The provider's signed receipts for bill splits. These can be given to the tenants (see SplitReceipt::share_for).
+    pub fn split_receipts(&self, provider: ProviderId) -> &[SplitReceipt] {
+        &self.providers[provider].split_receipts
+    }
+
+    fn receive_split_receipt(&mut self, provider: ProviderId) -> Option<ProviderProblem> {
+        let link = &mut self.providers[provider];
+        let problem = |why: &str| Some(ProviderProblem::BadSplitReceipt(provider, why.to_string()));
+
+        let signed = match String::from_utf8(read_line_blocking(&mut link.channel)).ok().and_then(|l| common::try_unstringify_bytes(&l)) {
+            Some(s) => s,
+            None => return problem("it is malformed"),
+        };
+        let receipt = match SplitReceipt::verify(signed, &link.key) {
+            Some(r) => r,
+            None => return problem("it is not signed by the provider or is malformed"),
+        };
+
+        let matches = match link.pending_split {
+            // check that the provider accepted exactly what we sent
+            Some((bill_number, ref amounts)) => receipt.bill_number == bill_number && receipt.amounts == *amounts &&
+                receipt.total == link.receipts[bill_number].amount,
+            None => return problem("no split is waiting for a receipt"),
+        };
+        if !matches {
+            return problem("it does not match the split which was sent");
+        }
+
+        link.pending_split = None;
+        link.split_receipts.push(receipt);
+        None
+    }
+
+    fn receive_split_rejection(&mut self, provider: ProviderId) -> Option<ProviderProblem> {
+        let link = &mut self.providers[provider];
+        let why = String::from_utf8_lossy(&read_line_blocking(&mut link.channel)).into_owned();
+
+        match link.pending_split.take() {
+            Some(_) => Some(ProviderProblem::SplitRejected(provider, why)),
+            None => Some(ProviderProblem::BadSplitReceipt(provider, "it rejects a split when no split was sent".to_string())),
+        }
+    }
+
+    /// Send our masked contribution for the slot other in the aggregation round round to an aggregator (see the aggregation module).
+    /// The latest reading signed by the meter for the slot is used. Returns false if there is no such reading in the consumption table.
+    pub fn send_aggregation_contribution<W: Write>(&self, channel: &mut W, round: u64, other: u64, masks: &AggregationMasks) -> bool {
+        let row_index = match self.consumption_table.iter().rposition(|r| r.other == other) {
+            Some(i) => i,
+            None => return false,
+        };
+        let ref row = self.consumption_table[row_index];
+
+        // find the batch containing the row
+        let mut batch_start = 0;
+        let mut batch_index = 0;
+        while row_index >= batch_start + self.batches[batch_index].len {
+            batch_start += self.batches[batch_index].len;
+            batch_index += 1;
+        }
+        let ref batch = self.batches[batch_index];
+
+        let leaves: Vec<&str> = self.consumption_table[batch_start..(batch_start + batch.len)].iter().map(|r| r.leaf.as_str()).collect();
+        let mut proof_str = String::new();
+        for hash in merkle::proof(&leaves, row_index - batch_start) {
+            proof_str = proof_str + &common::to_hex(&hash) + " ";
+        }
+
+        let q = &self.params.1;
+        let (cons_mask, a_mask) = masks.masks_for(round, other, q);
+        let masked_cons = (Mpz::from(row.cons) + cons_mask).modulus(q);
+        let masked_a = (row.a.clone() + a_mask).modulus(q);
+
+        // Message format: "round\nother\nmasked_cons\nmasked_a\nleaf\nindex_in_batch\nproof\nsigned_root\n"
+        let message_str = format!("{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n", round, other, masked_cons.to_str_radix(16), masked_a.to_str_radix(16),
+                                  row.leaf, row_index - batch_start, proof_str, batch.signed_root);
+        common::write_message(channel, message_str.as_bytes(), "aggregation contribution");
+
+        true
+    }
+}
+
+/// State associated with the provider
+pub struct ProviderState<T: Read + Write> {
+    /// Channel through which to communicate to the customer
+    channel: T,
+    /// The prices currently used to calculate the bill
+    pub prices: Prices,
+    /// Signing keys
+    keys: super::Keys,
+    /// Commitment parameters
+    params: commitments::DHParams,
+    /// Bill total
+    bill_total: i64,
+    /// (other, commitment) for every reading in each verified bill, indexed by bill number
+    bill_log: Vec<Vec<(u64, commitments::Commitment)>>,
+    /// The amount of each verified bill, indexed by bill number
+    bill_amounts: Vec<i64>,
+    /// The prices used to check each verified bill, indexed by bill number
+    bill_prices: Vec<Prices>,
+    /// Identifier for the next dispute
+    next_dispute_id: u64,
+    /// Identifier for the next linear statement request
+    next_statement_id: u64,
+    /// The latest tariff signed
+    tariff_chain: TariffChain,
+    /// The hex hash and prices of the tariff before the latest, which bills sent before the customer received the latest tariff use, and when it was replaced
+    previous_tariff: Option<(String, Prices, Instant)>,
+    /// How long after it is replaced bills calculated with the previous tariff are accepted (see set_tariff_grace)
+    tariff_grace: Duration,
+    /// A tariff signed by sign_tariff which is waiting for the regulator's countersignature
+    unsent_tariff: Option<UnsentTariff>,
+    /// The end of the hash chain of the meter's batches which have been billed
+    chain_head: ChainHead,
+    /// Appliance sub-meters, in the same order as the customer's
+    sub_meters: Vec<ProviderSubMeter>,
+    /// The customer's key for countersigning dispute transcripts (see set_customer_key)
+    customer_key: Option<sign::PublicKey>,
+}
+
+// a tariff signed by sign_tariff: the signed prices message, the prices and the tariff chain once it is sent
+struct UnsentTariff {
+    signed_prices: Vec<u8>,
+    prices: Prices,
+    chain: TariffChain,
+}
+
+// what the provider knows about an appliance sub-meter
+struct ProviderSubMeter {
+    key: sign::PublicKey,
+    prices: Prices,
+    chain_head: ChainHead,
+}
+
+impl<T: Read + Write> ProviderState<T> {
+    /// create a new ProviderState
+    pub fn new(channel: T, prices: Prices, keys: super::Keys, params: commitments::DHParams) -> ProviderState<T> {
+        //assert!(commitments::verify_dh_params(&params));
+        ProviderState {
+            channel: channel,
+            prices: prices,
+            keys: keys,
+            params: params,
+            bill_total: 0,
+            bill_log: Vec::new(),
+            bill_amounts: Vec::new(),
+            bill_prices: Vec::new(),
+            next_dispute_id: 0,
+            next_statement_id: 0,
+            tariff_chain: TariffChain::genesis(),
+            previous_tariff: None,
+            tariff_grace: Duration::from_secs(common::PREVIOUS_TARIFF_GRACE_SECS),
+            unsent_tariff: None,
+            chain_head: ChainHead::genesis(),
+            sub_meters: Vec::new(),
+            customer_key: None,
+        }
+    }
+
+    /// Accept bills calculated with the previous tariff for grace after the prices change, instead of PREVIOUS_TARIFF_GRACE_SECS
+    pub fn set_tariff_grace(&mut self, grace: Duration) {
+        self.tariff_grace = grace;
+    }
+
+    /// Check the customer's countersignatures of dispute transcripts with key. Without it no countersignature is kept.
+    pub fn set_customer_key(&mut self, key: sign::PublicKey) {
+        self.customer_key = Some(key);
+    }
+
+    /// Add an appliance sub-meter with public key key, whose consumption is billed at the appliance tariff prices (see CustomerState::add_sub_meter).
+    /// The appliance tariff must be agreed with the customer beforehand. Returns the index of the sub-meter.
+    pub fn add_sub_meter(&mut self, key: sign::PublicKey, prices: Prices) -> usize {
+        self.sub_meters.push(ProviderSubMeter {
+            key: key,
+            prices: prices,
+            chain_head: ChainHead::genesis(),
+        });
+        self.sub_meters.len() - 1
+    }
+
+    /// Block until sub-meter readings arrive from the customer (see CustomerState::send_sub_metering), check them and add the adjustment to the bill total.
+    /// Panics if the readings were not signed by the sub-meters, if the sub-meter readings for any slot might exceed the main meter's reading or if the adjustment is wrong.
+    pub fn receive_sub_metering(&mut self) -> i64 {
+        common::expect_header(&mut self.channel, "submetering");
+        let bill_number = usize::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+        let num_sub_meters = usize::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+        assert!(bill_number < self.bill_log.len());
+        assert_eq!(num_sub_meters, self.sub_meters.len());
+
+        let group = Group::from_params(&self.params);
+        let mut adjustment = 0;
+        let mut chain_heads = Vec::new();
+
+        // the product of the sub-meter commitments in each slot, indexed by the position of the main reading for the slot
+        let mut slots: BTreeMap<usize, Mpz> = BTreeMap::new();
+
+        for i in 0..num_sub_meters {
+            let num_batches = usize::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+            let mut chain_head = self.sub_meters[i].chain_head.clone();
+            let mut readings = Vec::new();
+            if let Err(why) = provider_read_batches(&mut self.channel, num_batches, &self.sub_meters[i].key, &self.params, &mut chain_head, &mut readings, &mut Vec::new()) {
+                panic!("Refused the readings of sub-meter {}: {}", i, why);
+            }
+
+            for &(other, ref commitment) in &readings {
+                let index = match main_index(self.bill_log[bill_number].iter().map(|r| r.0), other) {
+                    Ok(index) => index,
+                    Err(n) => panic!("Slot {} appears {} times in bill {}. Sub-meter readings need exactly one main reading for their slot.", other, n, bill_number),
+                };
+                let entry = slots.entry(index).or_insert(Mpz::one());
+                *entry = (entry.clone() * commitment.x.clone()).modulus(&group.p);
+            }
+
+            let value = i64::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+            let a = Mpz::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 16).unwrap();
+
+            let mut weights: Weights = [0; 24*7];
+            for j in 0..weights.len() {
+                weights[j] = self.sub_meters[i].prices[j] - self.bill_prices[bill_number][j];
+            }
+            assert!(verify_linear_combination(&self.params, &readings, &weights, value, a));
+
+            adjustment += value;
+            chain_heads.push(chain_head);
+        }
+
+        // every slot with sub-meter readings needs a proof that they are at most the main reading
+        let num_proofs = usize::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+        assert_eq!(num_proofs, slots.len());
+        for _ in 0..num_proofs {
+            let index = usize::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+            let proof = RangeProof::from_text(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap()).unwrap();
+
+            let sub_product = match slots.remove(&index) {
+                Some(x) => x,
+                None => panic!("Unexpected sub-metering proof for reading {}", index),
+            };
+            let remainder = group.divide(&self.bill_log[bill_number][index].1.x, &sub_product);
+            assert!(proof.verify(&group, &remainder, SUBMETER_RANGE_BITS, submetering_context(bill_number, index).as_bytes()));
+        }
+
+        // it worked so trust it
+        for (sub, chain_head) in self.sub_meters.iter_mut().zip(chain_heads.into_iter()) {
+            sub.chain_head = chain_head;
+        }
+        self.bill_total += adjustment;
+        adjustment
+    }
+
+    /// The end of the hash chain of the meter's batches which have been billed. The next bill must continue from here.
+    /// This should be stored between bills (see save_chain_head).
+    pub fn chain_head(&self) -> &ChainHead {
+        &self.chain_head
+    }
+
+    /// Continue from a chain head stored earlier (see load_chain_head)
+    pub fn set_chain_head(&mut self, head: ChainHead) {
+        self.chain_head = head;
+    }
+
+    /// The meter's public key
+    pub fn meter_key(&self) -> &sign::PublicKey {
+        &self.keys.their_pk
+    }
+
+    /// The number of the current tariff. The prices the provider was created with are number 0.
+    pub fn tariff_number(&self) -> u64 {
+        self.tariff_chain.seq
+    }
+
+    /// Hex hash of the current tariff, which bills and receipts refer to
+    pub fn tariff_hash(&self) -> String {
+        self.tariff_chain.hex_hash()
+    }
+
+    /// for implementing BillingProtocol
+    pub fn pay_bill(&mut self) -> i64 {
+        let ret = self.bill_total;
+        self.bill_total = 0;
+        ret
+    }
+
+    /// Block until billing information arrives from the customer, check it and send a receipt.
+    /// The bill may use the latest tariff or, if it arrives within the grace period after the prices changed (see set_tariff_grace) because the customer sent it before receiving the latest tariff, the one before.
+    /// A bill using any other tariff is rejected: the customer is told to bill again with the latest tariff and the reason is returned.
+    /// A bill which is malformed, whose batches were not signed by the meter or do not follow on from the last bill (so readings are missing or out of order), or which does not match the readings is refused
+    /// and the reason is returned. Nothing is sent to the customer and the hash chain of batches is left where it was.
+    pub fn receive_billing_information(&mut self) -> Result<(), String> {
+        common::expect_header(&mut self.channel, "bill");
+
+        // get the fixed-length part
+        let bill_str = String::from_utf8_lossy(&read_line_blocking(&mut self.channel)).into_owned();
+        let a_str = String::from_utf8_lossy(&read_line_blocking(&mut self.channel)).into_owned();
+        let tariff_hash = String::from_utf8_lossy(&read_line_blocking(&mut self.channel)).into_owned();
+        let num_batches_str = String::from_utf8_lossy(&read_line_blocking(&mut self.channel)).into_owned();
+
+        let num_batches = match usize::from_str_radix(&num_batches_str, 10) {
+            Ok(n) => n,
+            Err(_) => return Err(format!("could not parse the number of batches {:?}", num_batches_str)),
+        };
+
+        // get all of the signed (other, commitment) pairs. Only move the chain head on once the whole bill has been checked.
+        let mut readings = Vec::new();
+        let mut commitment_lines = Vec::new();
+        let mut chain_head = self.chain_head.clone();
+        try!(provider_read_batches(&mut self.channel, num_batches, &self.keys.their_pk, &self.params, &mut chain_head, &mut readings, &mut commitment_lines));
+
+        let bill = match i64::from_str_radix(&bill_str, 10) {
+            Ok(b) => b,
+            Err(_) => return Err(format!("could not parse the bill {:?}", bill_str)),
+        };
+        let a = match Mpz::from_str_radix(&a_str, 16) {
+            Ok(a) => a,
+            Err(_) => return Err(String::from("could not parse the opening of the bill")),
+        };
+
+        if num_batches == 0 {
+            if bill != 0 {
+                return Err(format!("a bill with no readings should be 0, not {}", bill));
+            }
+            return Ok(());
+        }
+
+        // the bill must use the latest tariff or, during the grace period, the one before it
+        let prices = if tariff_hash == self.tariff_chain.hex_hash() {
+            self.prices
+        } else {
+            match self.previous_tariff {
+                Some((ref hash, prices, replaced)) if *hash == tariff_hash && replaced.elapsed() < self.tariff_grace => prices,
+                _ => {
+                    let why = format!("the bill was calculated with an out of date tariff. Bill again with tariff number {}", self.tariff_chain.seq);
+
+                    // Message format: "bill_rejected\nREASON\n"
+                    let message = format!("bill_rejected\n{}\n", why);
+                    common::write_message(&mut self.channel, message.as_bytes(), "bill rejection");
+                    return Err(why);
+                },
+            }
+        };
+
+        // check the bill
+        if !verify_linear_combination(&self.params, &readings, &prices, bill, a) {
+            return Err(format!("the bill of {} does not match the readings", bill));
+        }
+
+        // it worked so trust it
+        self.bill_total += bill;
+        self.chain_head = chain_head;
+
+        // tell the customer what we accepted
+        let lines: Vec<&str> = commitment_lines.iter().map(|l| l.as_str()).collect();
+        let mut receipt = Receipt {
+            bill_number: self.bill_log.len(),
+            period_start: readings.iter().map(|r| r.0).min().unwrap(),
+            period_end: readings.iter().map(|r| r.0).max().unwrap(),
+            amount: bill,
+            commitments_hash: commitments_hash(&lines),
+            tariff_hash: tariff_hash,
+            signed: Vec::new(),
+        };
+        receipt.signed = sign::sign(receipt.to_text().as_bytes(), &self.keys.my_sk);
+
+        // Message format: "receipt\nsigned receipt\n"
+        let message = format!("receipt\n{}\n", stringify_bytes(&receipt.signed));
+        common::write_message(&mut self.channel, message.as_bytes(), "receipt");
+
+        self.bill_log.push(readings);
+        self.bill_amounts.push(bill);
+        self.bill_prices.push(prices);
+        Ok(())
+    }
+
+    /// Block until a bill split arrives from the customer (see CustomerState::send_bill_split), check it and send the signed SplitReceipt to the customer.
+    /// If the agreement is malformed, a tenant has not signed it, any amount does not match the readings or the amounts do not add up to the bill, the split is rejected: the customer is told why and so is the caller.
+    pub fn receive_bill_split(&mut self) -> Result<SplitReceipt, String> {
+        common::expect_header(&mut self.channel, "split");
+        let bill_number = String::from_utf8(read_line_blocking(&mut self.channel)).unwrap();
+        let num_tenants = usize::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+
+        // read the whole message before checking any of it, so that a rejected split leaves nothing behind on the channel
+        let agreement_hex = String::from_utf8(read_line_blocking(&mut self.channel)).unwrap();
+        let mut signature_hexes = Vec::new();
+        for _ in 0..num_tenants {
+            signature_hexes.push(String::from_utf8(read_line_blocking(&mut self.channel)).unwrap());
+        }
+        let mut openings = Vec::new();
+        for _ in 0..num_tenants {
+            let value = String::from_utf8(read_line_blocking(&mut self.channel)).unwrap();
+            let a = String::from_utf8(read_line_blocking(&mut self.channel)).unwrap();
+            openings.push((value, a));
+        }
+
+        let result = self.check_bill_split(&bill_number, &agreement_hex, &signature_hexes, &openings);
+
+        // Message format: "split_receipt\nsigned receipt\n" or "split_rejected\nREASON\n"
+        let message = match result {
+            Ok(ref receipt) => format!("split_receipt\n{}\n", stringify_bytes(&receipt.signed)),
+            Err(ref why) => format!("split_rejected\n{}\n", why),
+        };
+        common::write_message(&mut self.channel, message.as_bytes(), "split receipt");
+
+        result
+    }
+
+    // the signed receipt for a bill split if it checks out
+    fn check_bill_split(&self, bill_number: &str, agreement_hex: &str, signature_hexes: &[String], openings: &[(String, String)]) -> Result<SplitReceipt, String> {
+        let bill_number = match usize::from_str_radix(bill_number, 10) {
+            Ok(n) if n < self.bill_log.len() => n,
+            _ => return Err(format!("there is no bill {}", bill_number)),
+        };
+
+        let agreement = match common::from_hex(agreement_hex).and_then(|t| String::from_utf8(t).ok()).and_then(|t| SplitAgreement::from_text(&t)) {
+            Some(a) => a,
+            None => return Err("the agreement is malformed".to_string()),
+        };
+        if agreement.tenants.len() != signature_hexes.len() {
+            return Err("the number of tenants does not match the agreement".to_string());
+        }
+        let mut signatures = Vec::new();
+        for hex in signature_hexes {
+            match common::from_hex(hex) {
+                Some(s) => signatures.push(s),
+                None => return Err("a signature is malformed".to_string()),
+            }
+        }
+        let signed_agreement = SignedSplitAgreement {
+            agreement: agreement,
+            signatures: signatures,
+        };
+        if !signed_agreement.verify() {
+            return Err("not every tenant has signed the agreement".to_string());
+        }
+
+        let mut amounts = Vec::new();
+        for (tenant, &(ref value, ref a)) in openings.iter().enumerate() {
+            let (value, a) = match (i64::from_str_radix(value, 10), Mpz::from_str_radix(a, 16)) {
+                (Ok(v), Ok(a)) => (v, a),
+                _ => return Err(format!("the amount for tenant {} is malformed", tenant)),
+            };
+
+            let weights = signed_agreement.agreement.weights(tenant, &self.bill_prices[bill_number]);
+            if !verify_linear_combination(&self.params, &self.bill_log[bill_number], &weights, value, a) {
+                return Err(format!("the amount for tenant {} does not match the readings", tenant));
+            }
+            amounts.push(value);
+        }
+
+        let total = self.bill_amounts[bill_number];
+        if amounts.iter().sum::<i64>() != total * SHARE_DENOMINATOR as i64 {
+            return Err("the amounts do not add up to the bill".to_string());
+        }
+
+        let mut receipt = SplitReceipt {
+            bill_number: bill_number,
+            agreement_hash: signed_agreement.agreement.hash(),
+            total: total,
+            amounts: amounts,
+            signed: Vec::new(),
+        };
+        receipt.signed = sign::sign(receipt.to_text().as_bytes(), &self.keys.my_sk);
+
+        Ok(receipt)
+    }
+
+    /// The number of bills which have been verified so far
+    pub fn bills_received(&self) -> usize {
+        self.bill_log.len()
+    }
+
+    /// The amount of verified bill number bill_number, or None if it has not been received
+    pub fn bill_amount(&self, bill_number: usize) -> Option<i64> {
+        self.bill_amounts.get(bill_number).map(|a| *a)
+    }
+
+    /// Ask the customer to open the readings in bill number bill_number which are for one of the slots in others.
+    /// This blocks until the customer answers. The openings are checked against the commitments signed by the meter (an opening for an index which is not in the bill is invalid) and then a signed transcript is sent to the customer.
+    /// The transcript is returned once the customer has countersigned it or declined to.
+    pub fn request_dispute(&mut self, bill_number: usize, others: &[u64]) -> DisputeTranscript {
+        assert!(bill_number < self.bill_log.len());
+
+        let dispute_id = self.next_dispute_id;
+        self.next_dispute_id += 1;
+
+        // Message format: "dispute\nsigned(dispute_id bill_number other other ...)\n"
+        let mut request = format!("{} {}", dispute_id, bill_number);
+        for other in others {
+            request += &format!(" {}", other);
+        }
+        let signed_request = sign::sign(request.as_bytes(), &self.keys.my_sk);
+        let message = format!("dispute\n{}\n", stringify_bytes(&signed_request));
+        common::write_message(&mut self.channel, message.as_bytes(), "dispute request");
+
+        // read the openings
+        common::expect_header(&mut self.channel, "dispute_response");
+        let response_id = u64::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+        assert_eq!(response_id, dispute_id);
+        let num_openings = usize::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+
+        let mut openings = Vec::new();
+        for _ in 0..num_openings {
+            let line = String::from_utf8(read_line_blocking(&mut self.channel)).unwrap();
+            let mut iter = line.split_whitespace();
+            let index = usize::from_str_radix(iter.next().unwrap(), 10).unwrap();
+            let cons = i32::from_str_radix(iter.next().unwrap(), 10).unwrap();
+            let a = Mpz::from_str_radix(iter.next().unwrap(), 16).unwrap();
+            assert_eq!(None, iter.next());
+
+            // an index which is not in the bill cannot open anything
+            let (other, valid) = match self.bill_log[bill_number].get(index) {
+                Some(&(other, ref commitment)) => {
+                    let opened = commitments::CommitmentContext::from_opening((Mpz::from(cons), a.clone()), self.params.clone()).unwrap().to_commitment();
+                    (other, others.contains(&other) && opened == *commitment)
+                },
+                None => (0, false),
+            };
+
+            openings.push(DisputeOpening {
+                index: index,
+                other: other,
+                cons: cons,
+                a: a,
+                valid: valid,
+            });
+        }
+
+        let missing = others.iter().filter(|o| !openings.iter().any(|op| op.valid && op.other == **o)).map(|o| *o).collect();
+
+        let mut transcript = DisputeTranscript {
+            dispute_id: dispute_id,
+            bill_number: bill_number,
+            openings: openings,
+            missing: missing,
+            signed: Vec::new(),
+            countersignature: Vec::new(),
+        };
+
+        transcript.signed = sign::sign(transcript.to_text().as_bytes(), &self.keys.my_sk);
+        let message = format!("dispute_transcript\n{}\n", stringify_bytes(&transcript.signed));
+        common::write_message(&mut self.channel, message.as_bytes(), "dispute transcript");
+
+        // the customer countersigns the transcript if it agrees with it
+        common::expect_header(&mut self.channel, "dispute_countersignature");
+        let response_id = u64::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+        assert_eq!(response_id, dispute_id);
+        let countersignature_str = String::from_utf8(read_line_blocking(&mut self.channel)).unwrap();
+        if countersignature_str != "none" {
+            transcript.countersignature = unstringify_bytes(&countersignature_str);
+            let valid = match self.customer_key {
+                Some(ref key) => transcript.countersigned_by(key),
+                None => false,
+            };
+            if !valid {
+                transcript.countersignature = Vec::new();
+            }
+        }
+
+        transcript
+    }
+    
+    /// Ask the customer to prove the value of the sum of weights[hour] * cons over the readings in bill number bill_number.
+    /// This blocks until the customer answers. The answer is checked against the commitments signed by the meter.
+    pub fn request_linear_statement(&mut self, bill_number: usize, weights: &Weights) -> LinearStatement {
+        assert!(bill_number < self.bill_log.len());
+
+        let request_id = self.next_statement_id;
+        self.next_statement_id += 1;
+
+        // Message format: "statement\nsigned(request_id bill_number weight[0] ... weight[24*7-1])\n"
+        let mut request = format!("{} {}", request_id, bill_number);
+        for weight in weights.iter() {
+            request += &format!(" {}", weight);
+        }
+        let signed_request = sign::sign(request.as_bytes(), &self.keys.my_sk);
+        let message = format!("statement\n{}\n", stringify_bytes(&signed_request));
+        common::write_message(&mut self.channel, message.as_bytes(), "statement request");
+
+        // Response format: "statement_response\nrequest_id\nvalue\na\n", "statement_response\nrequest_id\nunavailable\n" or "statement_response\nrequest_id\nrefused\n"
+        common::expect_header(&mut self.channel, "statement_response");
+        let response_id = u64::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+        assert_eq!(response_id, request_id);
+
+        let value_str = String::from_utf8(read_line_blocking(&mut self.channel)).unwrap();
+        let refused = value_str == "refused";
+        let value = if value_str == "unavailable" || refused {
+            None
+        } else {
+            let value = i64::from_str_radix(&value_str, 10).unwrap();
+            let a = Mpz::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 16).unwrap();
+
+            if verify_linear_combination(&self.params, &self.bill_log[bill_number], weights, value, a) {
+                Some(value)
+            } else {
+                None
+            }
+        };
+
+        LinearStatement {
+            request_id: request_id,
+            bill_number: bill_number,
+            weights: *weights,
+            value: value,
+            refused: refused,
+        }
+    }
+
+    /// Ask the customer to prove that its total consumption over bills first_bill to last_bill (inclusive) is less than threshold, without revealing the total.
+    /// The customer may refuse if it has already answered for other thresholds for the same bills (see ThresholdPolicy).
+    /// This blocks until the customer answers. The proof is checked against the commitments signed by the meter.
+    pub fn request_threshold_statement(&mut self, first_bill: usize, last_bill: usize, threshold: i64) -> ThresholdStatement {
+        assert!(first_bill <= last_bill && last_bill < self.bill_log.len());
+        assert!(threshold > 0 && (threshold as u64) >> THRESHOLD_RANGE_BITS == 0);
+
+        let request_id = self.next_statement_id;
+        self.next_statement_id += 1;
+
+        // Message format: "threshold\nsigned(request_id first_bill last_bill threshold)\n"
+        let request = format!("{} {} {} {}", request_id, first_bill, last_bill, threshold);
+        let signed_request = sign::sign(request.as_bytes(), &self.keys.my_sk);
+        let message = format!("threshold\n{}\n", stringify_bytes(&signed_request));
+        common::write_message(&mut self.channel, message.as_bytes(), "threshold request");
+
+        common::expect_header(&mut self.channel, "threshold_response");
+        let response_id = u64::from_str_radix(&String::from_utf8(read_line_blocking(&mut self.channel)).unwrap(), 10).unwrap();
+        assert_eq!(response_id, request_id);
+
+        let proof_str = String::from_utf8(read_line_blocking(&mut self.channel)).unwrap();
+        let refused = proof_str == "refused";
+        let proven = if proof_str == "unavailable" || refused {
+            false
+        } else {
+            let group = Group::from_params(&self.params);
+
+            // threshold - 1 - the total consumption, committed to with the customer's openings negated
+            let mut total = Mpz::one();
+            for bill_number in first_bill..(last_bill + 1) {
+                for &(_, ref commitment) in &self.bill_log[bill_number] {
+                    total = (total * commitment.x.clone()).modulus(&group.p);
+                }
+            }
+            let remainder = group.divide(&group.commit(&Mpz::from(threshold - 1), &Mpz::zero()), &total);
+
+            match RangeProof::from_text(&proof_str) {
+                Some(proof) => proof.verify(&group, &remainder, THRESHOLD_RANGE_BITS, threshold_context(request_id, first_bill, last_bill, threshold).as_bytes()),
+                None => false,
+            }
+        };
+
+        ThresholdStatement {
+            request_id: request_id,
+            first_bill: first_bill,
+            last_bill: last_bill,
+            threshold: threshold,
+            proven: proven,
+            refused: refused,
+        }
+    }
+
+    /// Store and send the new prices to the customer. Does not check if the prices have actually changed before sending.
+    pub fn change_prices(&mut self, prices: &Prices) {
+        self.previous_tariff = Some((self.tariff_chain.hex_hash(), self.prices, Instant::now()));
+        self.unsent_tariff = None;
+
+        // send them
+        common::write_message(&mut self.channel, b"prices\n", "prices header");
+        common::change_prices::<T, i32, u64, IntegerConsumption>(&mut self.channel, &self.keys.my_sk, prices, &mut self.tariff_chain);
+
+        // store the prices 
+        self.prices = *prices;
+    }
+
+    /// Like change_prices but the signed prices are countersigned (by calling countersign, which should ask the regulator) and the countersignature is sent too
+    pub fn change_prices_countersigned<F: Fn(&[u8]) -> Vec<u8>>(&mut self, prices: &Prices, countersign: F) {
+        let signed_prices = self.sign_tariff(prices);
+        let countersignature = countersign(&signed_prices);
+        assert_eq!(countersignature.len(), COUNTERSIGNATURE_LEN);
+        self.send_countersigned_tariff(&countersignature).unwrap();
+    }
+
+    /// Sign prices as the next tariff without sending them, so that the regulator can countersign the signed prices message which is returned.
+    /// Send it with send_countersigned_tariff. Signing another tariff, or changing the prices in the meantime, throws this one away.
+    pub fn sign_tariff(&mut self, prices: &Prices) -> Vec<u8> {
+        let mut chain = self.tariff_chain.clone();
+        let signed_prices = common::sign_prices::<i32, u64, IntegerConsumption>(&self.keys.my_sk, prices, &mut chain);
+
+        self.unsent_tariff = Some(UnsentTariff {
+            signed_prices: signed_prices.clone(),
+            prices: *prices,
+            chain: chain,
+        });
+
+        signed_prices
+    }
+
+    /// Send the tariff signed by sign_tariff with the regulator's countersignature on it.
+    /// Fails if there is no such tariff or the countersignature is the wrong length. Whether it is the regulator's is up to the customer.
+    pub fn send_countersigned_tariff(&mut self, countersignature: &[u8]) -> Result<(), String> {
+        if countersignature.len() != COUNTERSIGNATURE_LEN {
+            return Err(format!("a countersignature is {} bytes long, not {}", COUNTERSIGNATURE_LEN, countersignature.len()));
+        }
+
+        let tariff = match self.unsent_tariff.take() {
+            Some(t) => t,
+            None => return Err(String::from("there is no signed tariff waiting to be sent")),
+        };
+
+        // Message format: "countersigned_prices\n" signed_prices countersignature
+        common::write_message(&mut self.channel, b"countersigned_prices\n", "prices header");
+        common::write_message(&mut self.channel, &tariff.signed_prices, "prices");
+        common::write_message(&mut self.channel, countersignature, "countersignature");
+
+        self.previous_tariff = Some((self.tariff_chain.hex_hash(), self.prices, Instant::now()));
+        self.tariff_chain = tariff.chain;
+        self.prices = tariff.prices;
+        Ok(())
+    }
+}
+
+/************************************** Small tests unique to this module ***********************************************/
+#[cfg(test)]
+pub mod tests {
+    use super::super::tests::{random_hour_of_week};
     use sodiumoxide;
     use super::super::consumption::integer_consumption::*;
     use super::super::consumption::Consumption;
@@ -424,6 +2653,7 @@ pub mod tests {
     use std::thread;
     use std::time::Duration;
     use std::os::unix::net::*;
+    use std::sync::mpsc;
     use super::super::BillingProtocol;
     use super::*;
 
@@ -449,20 +2679,882 @@ pub mod tests {
 
         let params = read_or_gen_params(DEFAULT_PARAMS_PATH);
         let (pk, sk) = sign::gen_keypair();
+        let mut unbatched = Vec::new();
         let mut table = Vec::new();
+        let mut batches = Vec::new();
+        let mut meter_chain = ChainHead::genesis();
+        let mut customer_chain = None;
 
         // send message
-        meter_consume(&params, &sk, &mut channel, &consumption);
+        let leaf = meter_consume(&params, &mut channel, &consumption, 0);
 
-        // receive
-        customer_read_consumption(&mut channel.as_slice(), &pk, &mut table);
+        // the reading can't be used until the batch is signed
+        customer_read_consumption(&mut channel.as_slice(), &pk, &mut unbatched, &mut table, &mut batches, &mut customer_chain);
+        assert_eq!(unbatched.len(), 1);
+        assert_eq!(table.len(), 0);
+
+        let mut channel: Vec<u8> = Vec::new();
+        meter_end_batch(&sk, &mut channel, &[leaf], &mut meter_chain);
+        customer_read_consumption(&mut channel.as_slice(), &pk, &mut unbatched, &mut table, &mut batches, &mut customer_chain);
 
         // check result
+        assert_eq!(unbatched.len(), 0);
+        assert_eq!(batches.len(), 1);
+        assert_eq!(meter_chain.next_counter, 1);
+        assert_eq!(customer_chain, Some(meter_chain));
         let ref row = table[0];
         assert_eq!(row.cons, units);
         assert_eq!(row.other, hour);
     }
 
+    // sign n batches of one reading each, returning the signed roots
+    fn signed_batches(n: usize) -> (sign::PublicKey, Vec<merkle::SignedRoot>) {
+        let params = read_or_gen_params(DEFAULT_PARAMS_PATH);
+        let (pk, sk) = sign::gen_keypair();
+        let mut chain = ChainHead::genesis();
+        let mut ret = Vec::new();
+
+        for i in 0..n {
+            let leaf = meter_consume(&params, &mut Vec::<u8>::new(), &IntegerConsumption::new(1, i as u64), chain.next_counter);
+            let mut channel: Vec<u8> = Vec::new();
+            meter_end_batch(&sk, &mut channel, &[leaf], &mut chain);
+
+            let mut slice = channel.as_slice();
+            common::expect_header(&mut slice, "batch");
+            ret.push(merkle::verify_signed_root(&String::from_utf8(read_line_blocking(&mut slice)).unwrap(), &pk).unwrap());
+        }
+
+        (pk, ret)
+    }
+
+    #[test]
+    fn hash_chain() {
+        sodiumoxide::init();
+        let (pk, batches) = signed_batches(3);
+
+        let mut head = ChainHead::genesis();
+        for batch in &batches {
+            head.extend(batch).unwrap();
+        }
+        assert_eq!(head.next_counter, 3);
+
+        // the head survives being stored
+        let path = "./chain_head_test_file";
+        save_chain_head(path, &pk, &head).unwrap();
+        let (other_pk, _) = sign::gen_keypair();
+        save_chain_head(path, &other_pk, &ChainHead::genesis()).unwrap();
+        assert_eq!(load_chain_head(path, &pk).unwrap(), head);
+        assert_eq!(load_chain_head(path, &other_pk).unwrap(), ChainHead::genesis());
+        ::std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hash_chain_gap() {
+        sodiumoxide::init();
+        let (_, batches) = signed_batches(3);
+
+        let mut head = ChainHead::genesis();
+        head.extend(&batches[0]).unwrap();
+        let after_first = head.clone();
+
+        // a missing batch, or the same one again, is refused without moving the head
+        assert!(head.extend(&batches[2]).is_err());
+        assert!(head.extend(&batches[0]).is_err());
+        assert_eq!(head, after_first);
+
+        head.extend(&batches[1]).unwrap();
+        head.extend(&batches[2]).unwrap();
+        assert_eq!(head.next_counter, 3);
+    }
+
+    // a meter and a customer connected to each other, and the far end of the customer's link to its provider (provider 0)
+    struct Fixture {
+        params: commitments::DHParams,
+        meter: MeterState<UnixStream>,
+        customer: CustomerState<UnixStream, UnixStream>,
+        provider_end: UnixStream,
+        // the keys for a ProviderState at provider_end
+        provider_keys: super::super::Keys,
+        m_pk: sign::PublicKey,
+        p_pk: sign::PublicKey,
+    }
+
+    fn fixture(prices: Prices) -> Fixture {
+        sodiumoxide::init();
+        let params = read_or_gen_params(DEFAULT_PARAMS_PATH);
+        let (m_pk, m_sk) = sign::gen_keypair();
+        let (p_pk, p_sk) = sign::gen_keypair();
+
+        let (meter_end, customer_meter_end) = UnixStream::pair().unwrap();
+        customer_meter_end.set_nonblocking(true).unwrap();
+        let (provider_end, customer_provider_end) = UnixStream::pair().unwrap();
+        customer_provider_end.set_nonblocking(true).unwrap();
+
+        Fixture {
+            meter: MeterState::new(meter_end, m_sk, params.clone()),
+            customer: CustomerState::new(customer_meter_end, customer_provider_end, prices, p_pk.clone(), m_pk.clone(), params.clone()),
+            provider_end: provider_end,
+            provider_keys: super::super::Keys { my_sk: p_sk, their_pk: m_pk.clone() },
+            m_pk: m_pk,
+            p_pk: p_pk,
+            params: params,
+        }
+    }
+
+    // Block until provider sends a message with header, handling it and any messages before it as read_provider_messages would.
+    // Messages sent from the test's own thread are already there to read, so only messages from other threads need this.
+    fn wait_for_message(customer: &mut CustomerState<UnixStream, UnixStream>, provider: ProviderId, header: &str) -> Vec<ProviderProblem> {
+        let mut problems = Vec::new();
+        loop {
+            let next = String::from_utf8(read_line_blocking(&mut customer.providers[provider].channel)).unwrap();
+            problems.extend(customer.receive_provider_message(provider, &next));
+            if next == header {
+                return problems;
+            }
+        }
+    }
+
+    #[test]
+    fn dispute() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([1; 24*7]);
+
+        let disputed_other = random_hour_of_week() as u64;
+        let disputed_units = super::super::tests::random_positive_i32() >> 20;
+
+        let (c_pk, c_sk) = sign::gen_keypair();
+        customer.set_signing_key(c_sk);
+        let c_pk_provider = c_pk.clone();
+
+        meter.consume(&IntegerConsumption::new(disputed_units, disputed_other));
+        meter.consume(&IntegerConsumption::new(1, disputed_other + 1));
+        meter.end_batch();
+        customer.read_meter_messages();
+        customer.send_billing_information();
+
+        let provider_thread = thread::spawn(move || -> (Vec<(u64, i32, bool)>, Vec<u64>, bool) {
+            let mut provider = ProviderState::new(provider_end, [1; 24*7], provider_keys, params);
+            provider.set_customer_key(c_pk_provider.clone());
+            provider.receive_billing_information().unwrap();
+            let transcript = provider.request_dispute(0, &[disputed_other, disputed_other + 2]);
+            let countersigned = transcript.countersigned_by(&c_pk_provider);
+            (transcript.openings.iter().map(|o| (o.other, o.cons, o.valid)).collect(), transcript.missing, countersigned)
+        });
+
+        // answering the dispute waits for the transcript
+        wait_for_message(&mut customer, 0, "dispute");
+
+        let (openings, missing, countersigned) = provider_thread.join().unwrap();
+        assert_eq!(openings, vec!((disputed_other, disputed_units, true)));
+        assert_eq!(missing, vec!(disputed_other + 2));
+        assert!(countersigned);
+
+        // both sides hold the same transcript signed by the other
+        {
+            let ref transcript = customer.dispute_transcripts(0)[0];
+            assert_eq!(transcript.openings.len(), 1);
+            assert_eq!(transcript.missing, vec!(disputed_other + 2));
+            assert!(transcript.countersigned_by(&c_pk));
+        }
+
+        // the openings are kept until the dispute window has passed
+        assert_eq!(customer.purge_expired_openings(SystemTime::now()), 0);
+        let after_window = SystemTime::now() + Duration::from_secs(DISPUTE_WINDOW_SECS + 1);
+        assert_eq!(customer.purge_expired_openings(after_window), 2);
+        assert_eq!(customer.purge_expired_openings(after_window), 0);
+    }
+
+    #[test]
+    fn dispute_index_out_of_range() {
+        sodiumoxide::init();
+        let params = read_or_gen_params(DEFAULT_PARAMS_PATH);
+        let (m_pk, _) = sign::gen_keypair();
+        let (p_pk, p_sk) = sign::gen_keypair();
+        let (provider_end, mut customer_end) = UnixStream::pair().unwrap();
+
+        let keys = super::super::Keys { my_sk: p_sk, their_pk: m_pk };
+        let mut provider = ProviderState::new(provider_end, [1; 24*7], keys, params.clone());
+        let commitment = commitments::CommitmentContext::from_opening((Mpz::from(3), Mpz::from(5)), params).unwrap().to_commitment();
+        provider.bill_log.push(vec!((7, commitment)));
+
+        let provider_thread = thread::spawn(move || -> Vec<(usize, u64, bool)> {
+            let transcript = provider.request_dispute(0, &[7]);
+            assert!(transcript.countersignature.is_empty());
+            transcript.openings.iter().map(|o| (o.index, o.other, o.valid)).collect()
+        });
+
+        // a customer which opens a reading the bill does not have
+        common::expect_header(&mut customer_end, "dispute");
+        let signed_request = unstringify_bytes(&String::from_utf8(read_line_blocking(&mut customer_end)).unwrap());
+        assert_eq!(String::from_utf8(sign::verify(&signed_request, &p_pk).unwrap()).unwrap(), "0 0 7");
+        common::write_message(&mut customer_end, b"dispute_response\n0\n1\n1 3 5\n", "dispute response");
+        common::expect_header(&mut customer_end, "dispute_transcript");
+        read_line_blocking(&mut customer_end);
+        common::write_message(&mut customer_end, b"dispute_countersignature\n0\nnone\n", "dispute countersignature");
+
+        assert_eq!(provider_thread.join().unwrap(), vec!((1, 0, false)));
+    }
+
+    #[test]
+    fn bad_requests() {
+        let Fixture { mut customer, mut provider_end, provider_keys, .. } = fixture([1; 24*7]);
+        let (_, other_sk) = sign::gen_keypair();
+
+        let send = |channel: &mut UnixStream, header: &str, request: &[u8], sk: &sign::SecretKey| {
+            let message = format!("{}\n{}\n", header, stringify_bytes(&sign::sign(request, sk)));
+            common::write_message(channel, message.as_bytes(), "request");
+        };
+
+        // not signed by the provider, not even bytes, and a field short
+        send(&mut provider_end, "statement", b"0 0", &other_sk);
+        common::write_message(&mut provider_end, b"dispute\nnot bytes\n", "request");
+        send(&mut provider_end, "threshold", b"0 0 0", &provider_keys.my_sk);
+
+        // a genuine dispute request followed by a transcript the customer cannot read
+        send(&mut provider_end, "dispute", b"0 0 7", &provider_keys.my_sk);
+        send(&mut provider_end, "dispute_transcript", b"not a transcript", &provider_keys.my_sk);
+
+        for header in &["statement", "dispute", "threshold", "dispute"] {
+            let problems = wait_for_message(&mut customer, 0, header);
+            assert_eq!(problems.len(), 1);
+            match problems[0] {
+                ProviderProblem::BadRequest(0, _) => (),
+                ref p => panic!("unexpected problem {}", p),
+            }
+        }
+        assert!(customer.dispute_transcripts(0).is_empty());
+
+        // the dispute was answered (with nothing to open) but the transcript was not countersigned
+        common::expect_header(&mut provider_end, "dispute_response");
+        assert_eq!(read_line_blocking(&mut provider_end), b"0");
+        assert_eq!(read_line_blocking(&mut provider_end), b"0");
+        common::expect_header(&mut provider_end, "dispute_countersignature");
+        assert_eq!(read_line_blocking(&mut provider_end), b"0");
+        assert_eq!(read_line_blocking(&mut provider_end), b"none");
+    }
+
+    #[test]
+    fn bad_receipt() {
+        let Fixture { mut meter, mut customer, mut provider_end, provider_keys, .. } = fixture([1; 24*7]);
+
+        meter.consume(&IntegerConsumption::new(5, 0));
+        meter.end_batch();
+        customer.read_meter_messages();
+        assert_eq!(customer.send_billing_information(), vec!(5));
+
+        // a receipt for a different amount
+        let receipt = sign::sign(b"0 0 0 4 hash hash", &provider_keys.my_sk);
+        common::write_message(&mut provider_end, format!("receipt\n{}\n", stringify_bytes(&receipt)).as_bytes(), "receipt");
+
+        let problems = customer.wait_for_receipt(Duration::from_secs(10));
+        assert_eq!(problems, vec!(ProviderProblem::BadReceipt(0, String::from("it does not match the bill which was sent"))));
+        assert!(customer.awaiting_receipt());
+        assert_eq!(customer.receipts(0).len(), 0);
+
+        // and then nothing
+        assert_eq!(customer.wait_for_receipt(Duration::from_millis(10)), vec!(ProviderProblem::NoReceipt(0)));
+        assert!(customer.awaiting_receipt());
+    }
+
+    #[test]
+    fn linear_statement() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([1; 24*7]);
+
+        let mut expected_total = 0;
+        let mut expected_peak = 0;
+        for hour in 0..24 {
+            let units = super::super::tests::random_positive_i32() >> 20;
+            meter.consume(&IntegerConsumption::new(units, hour));
+            expected_total += units as i64;
+            if hour >= 17 && hour < 20 {
+                expected_peak += units as i64;
+            }
+        }
+        meter.end_batch();
+        customer.read_meter_messages();
+        customer.send_billing_information();
+        customer.set_statement_policy(StatementPolicy::new(2));
+
+        let provider_thread = thread::spawn(move || {
+            let mut provider = ProviderState::new(provider_end, [1; 24*7], provider_keys, params);
+            provider.receive_billing_information().unwrap();
+
+            let total = provider.request_linear_statement(0, &[1; 24*7]);
+
+            let mut peak_weights = [0; 24*7];
+            for hour in 17..20 {
+                peak_weights[hour] = 1;
+            }
+            let peak = provider.request_linear_statement(0, &peak_weights);
+
+            // one reading on its own is refused
+            let mut single_weights = [0; 24*7];
+            single_weights[18] = 1;
+            let single = provider.request_linear_statement(0, &single_weights);
+
+            (total.value, peak.value, single.value, single.refused)
+        });
+
+        // answer (or refuse) the three requests
+        for _ in 0..3 {
+            wait_for_message(&mut customer, 0, "statement");
+        }
+
+        let (total, peak, single, refused) = provider_thread.join().unwrap();
+        assert_eq!(total, Some(expected_total));
+        assert_eq!(peak, Some(expected_peak));
+        assert_eq!(single, None);
+        assert!(refused);
+    }
+
+    #[test]
+    fn differencing_statements() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([1; 24*7]);
+
+        let mut expected_total = 0;
+        for hour in 0..2 {
+            let units = super::super::tests::random_positive_i32() >> 20;
+            meter.consume(&IntegerConsumption::new(units, hour));
+            expected_total += units as i64;
+        }
+        meter.end_batch();
+        customer.read_meter_messages();
+        customer.send_billing_information();
+
+        let provider_thread = thread::spawn(move || {
+            let mut provider = ProviderState::new(provider_end, [1; 24*7], provider_keys, params);
+            provider.receive_billing_information().unwrap();
+
+            let total = provider.request_linear_statement(0, &[1; 24*7]);
+
+            // the second minus the first would be the reading in hour 1
+            let mut first_weights = [0; 24*7];
+            first_weights[0] = 1;
+            first_weights[1] = 1;
+            let mut second_weights = first_weights;
+            second_weights[1] = 2;
+            let first = provider.request_linear_statement(0, &first_weights);
+            let second = provider.request_linear_statement(0, &second_weights);
+
+            (total.value, (first.value, first.refused), (second.value, second.refused))
+        });
+
+        for _ in 0..3 {
+            wait_for_message(&mut customer, 0, "statement");
+        }
+
+        let (total, first, second) = provider_thread.join().unwrap();
+        assert_eq!(total, Some(expected_total));
+        assert_eq!(first, (None, true));
+        assert_eq!(second, (None, true));
+    }
+
+    #[test]
+    fn statement_policy() {
+        let mut one_slot = [0; 24*7];
+        one_slot[5] = 1;
+        let mut two_slots = one_slot;
+        two_slots[6] = 1;
+        let policy = StatementPolicy::totals_only();
+        assert!(!policy.allows(&one_slot));
+        assert!(!policy.allows(&two_slots));
+        assert!(policy.allows(&[1; 24*7]));
+
+        let policy = StatementPolicy::new(2);
+        assert!(!policy.allows(&one_slot));
+        assert!(policy.allows(&two_slots));
+        assert!(policy.allows(&[1; 24*7]));
+
+        let policy = StatementPolicy {
+            min_nonzero_weights: 0,
+            allowed: Some(vec!([1; 24*7])),
+        };
+        assert!(policy.allows(&[1; 24*7]));
+        assert!(!policy.allows(&[2; 24*7]));
+    }
+
+    #[test]
+    fn bill_preview() {
+        let mut prices = [1; 24*7];
+        prices[25] = 3;
+        let Fixture { mut meter, mut customer, provider_end: _provider_end, .. } = fixture(prices);
+
+        // 23:00 on Monday to 01:00 on Tuesday, then one reading the meter has not signed
+        meter.consume(&IntegerConsumption::new(2, 23));
+        meter.consume(&IntegerConsumption::new(4, 24));
+        meter.consume(&IntegerConsumption::new(6, 25));
+        meter.end_batch();
+        meter.consume(&IntegerConsumption::new(8, 26));
+        customer.read_meter_messages();
+
+        let preview = customer.preview_bill(0, 4);
+        assert_eq!(preview.tariff_number, 0);
+        assert_eq!(preview.slots[2], SlotCharge { other: 25, units: 6, price: 3, amount: 18 });
+        assert_eq!(preview.days, vec!(DayCharge { day: 0, units: 2, amount: 2 }, DayCharge { day: 1, units: 10, amount: 22 }));
+        assert_eq!(preview.total, 24);
+        assert_eq!(preview.unsigned_readings, 1);
+        // one more hour (26) with no readings yet, so the average of 4 units
+        assert_eq!(preview.projected_total, Some(28));
+
+        // previewing does not send anything
+        assert!(!customer.awaiting_receipt());
+        assert_eq!(customer.preview_bill(0, 0).projected_total, Some(24));
+    }
+
+    #[test]
+    fn countersigned_prices() {
+        use super::super::regulator::Regulator;
+
+        let Fixture { params, mut customer, provider_end, provider_keys, .. } = fixture([1; 24*7]);
+        let (r_pk, r_sk) = sign::gen_keypair();
+        let regulator = Regulator::new(r_sk);
+
+        customer.set_tariff_policy(0, TariffPolicy::new(r_pk, true));
+        let mut provider = ProviderState::new(provider_end, [1; 24*7], provider_keys, params);
+
+        // without the countersignature the tariff is ignored
+        provider.change_prices(&[2; 24*7]);
+        customer.read_provider_messages();
+        assert_eq!(customer.prices(0)[0], 1);
+        assert!(customer.tariff_evidence(0).is_none());
+
+        provider.change_prices_countersigned(&[3; 24*7], |signed| regulator.countersign(signed));
+        customer.read_provider_messages();
+        assert_eq!(customer.prices(0)[0], 3);
+        assert!(customer.tariff_evidence(0).unwrap().countersignature.is_some());
+
+        // signed first and sent once the regulator has countersigned it
+        assert!(provider.send_countersigned_tariff(&[0; COUNTERSIGNATURE_LEN]).is_err());
+        let signed_prices = provider.sign_tariff(&[4; 24*7]);
+        assert_eq!(provider.prices[0], 3);
+        assert!(provider.send_countersigned_tariff(&[0; 7]).is_err());
+        provider.send_countersigned_tariff(&regulator.countersign(&signed_prices)).unwrap();
+        customer.read_provider_messages();
+        assert_eq!(customer.prices(0)[0], 4);
+        assert_eq!(provider.tariff_number(), 2);
+    }
+
+    #[test]
+    fn tariff_fork() {
+        let Fixture { mut customer, mut provider_end, provider_keys, .. } = fixture([1; 24*7]);
+        let p_sk = provider_keys.my_sk;
+
+        // two different tariffs signed as the successor to the initial prices
+        let mut chain = TariffChain::genesis();
+        let mut forked_chain = chain.clone();
+        common::write_message(&mut provider_end, b"prices\n", "prices header");
+        common::change_prices::<UnixStream, i32, u64, IntegerConsumption>(&mut provider_end, &p_sk, &[2; 24*7], &mut chain);
+        common::write_message(&mut provider_end, b"prices\n", "prices header");
+        common::change_prices::<UnixStream, i32, u64, IntegerConsumption>(&mut provider_end, &p_sk, &[3; 24*7], &mut forked_chain);
+        assert_eq!(chain.seq, forked_chain.seq);
+
+        customer.read_provider_messages();
+        assert_eq!(customer.prices(0)[0], 2);
+
+        // the chain continues from the first one
+        common::write_message(&mut provider_end, b"prices\n", "prices header");
+        common::change_prices::<UnixStream, i32, u64, IntegerConsumption>(&mut provider_end, &p_sk, &[4; 24*7], &mut chain);
+        customer.read_provider_messages();
+        assert_eq!(customer.prices(0)[0], 4);
+    }
+
+    #[test]
+    fn two_providers() {
+        // provider 0 is the supplier
+        let Fixture { params, mut meter, mut customer, provider_end: supplier_end, provider_keys: supplier_keys, m_pk, .. } = fixture([3; 24*7]);
+
+        let (network_pk, network_sk) = sign::gen_keypair();
+        let (network_end, customer_network_end) = UnixStream::pair().unwrap();
+        customer_network_end.set_nonblocking(true).unwrap();
+        let network = customer.add_provider(customer_network_end, [2; 24*7], network_pk);
+        assert_eq!(network, 1);
+
+        let mut total_units = 0;
+        for hour in 0..5 {
+            let units = super::super::tests::random_positive_i32() >> 20;
+            meter.consume(&IntegerConsumption::new(units, hour));
+            total_units += units as i64;
+        }
+        meter.end_batch();
+        customer.read_meter_messages();
+        assert_eq!(customer.send_billing_information(), vec!(3 * total_units, 2 * total_units));
+
+        // the network operator checks its bill and asks about it before the supplier has seen the bill
+        let network_params = params.clone();
+        let network_thread = thread::spawn(move || {
+            let keys = super::super::Keys { my_sk: network_sk, their_pk: m_pk };
+            let mut provider = ProviderState::new(network_end, [2; 24*7], keys, network_params);
+            provider.receive_billing_information().unwrap();
+            let bill = provider.pay_bill();
+            assert_eq!(provider.bill_amount(0), Some(bill));
+            assert_eq!(provider.bill_amount(1), None);
+            let total = provider.request_linear_statement(0, &[1; 24*7]);
+            (bill, total.value)
+        });
+
+        // the receipt comes before the request
+        assert!(wait_for_message(&mut customer, network, "statement").is_empty());
+        assert_eq!(network_thread.join().unwrap(), (2 * total_units, Some(total_units)));
+        assert!(customer.awaiting_receipt());
+        assert_eq!(customer.receipts(network).len(), 1);
+        assert_eq!(customer.receipts(0).len(), 0);
+
+        let supplier_thread = thread::spawn(move || -> i64 {
+            let mut provider = ProviderState::new(supplier_end, [3; 24*7], supplier_keys, params);
+            provider.receive_billing_information().unwrap();
+            provider.pay_bill()
+        });
+
+        assert!(wait_for_message(&mut customer, 0, "receipt").is_empty());
+        assert!(!customer.awaiting_receipt());
+        assert_eq!(supplier_thread.join().unwrap(), 3 * total_units);
+        assert_eq!(customer.receipts(0).len(), 1);
+        assert_eq!(customer.receipts(0)[0].commitments_hash, customer.receipts(network)[0].commitments_hash);
+        assert_eq!(customer.readable_consumption_table(), "");
+    }
+
+    #[test]
+    fn stale_tariff() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([1; 24*7]);
+
+        meter.consume(&IntegerConsumption::new(5, 0));
+        meter.end_batch();
+        customer.read_meter_messages();
+        assert_eq!(customer.send_billing_information(), vec!(5));
+
+        let (sent_tx, sent_rx) = mpsc::channel();
+        let provider_thread = thread::spawn(move || {
+            let mut provider = ProviderState::new(provider_end, [1; 24*7], provider_keys, params);
+
+            // the bill was sent before the customer received the new tariff so it is checked with the one before
+            provider.change_prices(&[2; 24*7]);
+            provider.receive_billing_information().unwrap();
+            let first = provider.pay_bill();
+
+            // two tariffs behind is too old
+            sent_rx.recv().unwrap();
+            provider.change_prices(&[3; 24*7]);
+            provider.change_prices(&[4; 24*7]);
+            let rejected = provider.receive_billing_information().is_err();
+            provider.receive_billing_information().unwrap();
+            (first, rejected, provider.pay_bill())
+        });
+
+        assert!(wait_for_message(&mut customer, 0, "receipt").is_empty());
+        assert_eq!(customer.receipts(0)[0].amount, 5);
+
+        meter.consume(&IntegerConsumption::new(7, 1));
+        meter.end_batch();
+        customer.read_meter_messages();
+        assert_eq!(customer.send_billing_information(), vec!(14));
+        sent_tx.send(()).unwrap();
+
+        // the customer sends the bill again with the latest tariff
+        let problems = wait_for_message(&mut customer, 0, "receipt");
+        assert_eq!(problems.len(), 1);
+        match problems[0] {
+            ProviderProblem::BillRejected(0, _) => (),
+            ref p => panic!("unexpected problem {}", p),
+        }
+        assert_eq!(customer.receipts(0)[1].amount, 28);
+        assert_eq!(provider_thread.join().unwrap(), (5, true, 28));
+    }
+
+    #[test]
+    fn previous_tariff_grace() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([1; 24*7]);
+
+        meter.consume(&IntegerConsumption::new(5, 0));
+        meter.end_batch();
+        customer.read_meter_messages();
+        assert_eq!(customer.send_billing_information(), vec!(5));
+
+        let provider_thread = thread::spawn(move || {
+            let mut provider = ProviderState::new(provider_end, [1; 24*7], provider_keys, params);
+
+            // the grace period is over by the time the bill is read, so the previous tariff is no longer good enough
+            provider.set_tariff_grace(Duration::from_secs(0));
+            provider.change_prices(&[2; 24*7]);
+            let rejected = provider.receive_billing_information().is_err();
+            provider.receive_billing_information().unwrap();
+            (rejected, provider.pay_bill())
+        });
+
+        let problems = wait_for_message(&mut customer, 0, "receipt");
+        assert_eq!(problems.len(), 1);
+        match problems[0] {
+            ProviderProblem::BillRejected(0, _) => (),
+            ref p => panic!("unexpected problem {}", p),
+        }
+        assert_eq!(customer.receipts(0)[0].amount, 10);
+        assert_eq!(provider_thread.join().unwrap(), (true, 10));
+    }
+
+    #[test]
+    fn sub_metering() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([3; 24*7]);
+
+        let (ev_pk, ev_sk) = sign::gen_keypair();
+        let (ev_end, customer_ev_end) = UnixStream::pair().unwrap();
+        customer_ev_end.set_nonblocking(true).unwrap();
+        let mut ev_meter = MeterState::new(ev_end, ev_sk, params.clone());
+        assert_eq!(customer.add_sub_meter(customer_ev_end, ev_pk.clone(), [1; 24*7]), 0);
+
+        // the EV charger uses some of the consumption in the first two slots
+        let mut total_units = 0;
+        let mut ev_units = 0;
+        for hour in 0..3 {
+            let units = super::super::tests::random_positive_i32() >> 20;
+            meter.consume(&IntegerConsumption::new(units, hour));
+            total_units += units as i64;
+
+            if hour < 2 {
+                ev_meter.consume(&IntegerConsumption::new(units / 2, hour));
+                ev_units += (units / 2) as i64;
+            }
+        }
+        meter.end_batch();
+        ev_meter.end_batch();
+        // a reading from after the bill is kept for the next one
+        ev_meter.consume(&IntegerConsumption::new(1, 10));
+        ev_meter.end_batch();
+        customer.read_meter_messages();
+        customer.send_billing_information();
+
+        let provider_thread = thread::spawn(move || -> i64 {
+            let mut provider = ProviderState::new(provider_end, [3; 24*7], provider_keys, params);
+            assert_eq!(provider.add_sub_meter(ev_pk, [1; 24*7]), 0);
+            provider.receive_billing_information().unwrap();
+            provider.receive_sub_metering();
+            provider.pay_bill()
+        });
+
+        assert!(wait_for_message(&mut customer, 0, "receipt").is_empty());
+        assert_eq!(customer.send_sub_metering(0), Ok(-2 * ev_units));
+        assert_eq!(customer.sub_meters[0].table.len(), 1);
+
+        assert_eq!(provider_thread.join().unwrap(), 3 * total_units - 2 * ev_units);
+    }
+
+    #[test]
+    fn sub_metering_errors() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([3; 24*7]);
+
+        let (ev_pk, ev_sk) = sign::gen_keypair();
+        let (ev_end, customer_ev_end) = UnixStream::pair().unwrap();
+        customer_ev_end.set_nonblocking(true).unwrap();
+        let mut ev_meter = MeterState::new(ev_end, ev_sk, params.clone());
+        customer.add_sub_meter(customer_ev_end, ev_pk, [1; 24*7]);
+        assert_eq!(customer.send_sub_metering(0), Err(SubMeteringError::NoBill(0)));
+
+        meter.consume(&IntegerConsumption::new(10, 0));
+        meter.end_batch();
+        // the sub-meter reads one unit more than the main meter in slot 0 and also has a reading from after the bill
+        ev_meter.consume(&IntegerConsumption::new(11, 0));
+        ev_meter.end_batch();
+        ev_meter.consume(&IntegerConsumption::new(1, 5));
+        ev_meter.end_batch();
+        customer.read_meter_messages();
+        customer.send_billing_information();
+
+        let provider_thread = thread::spawn(move || {
+            let mut provider = ProviderState::new(provider_end, [3; 24*7], provider_keys, params);
+            provider.receive_billing_information().unwrap();
+        });
+
+        assert!(wait_for_message(&mut customer, 0, "receipt").is_empty());
+        provider_thread.join().unwrap();
+
+        assert_eq!(customer.send_sub_metering(0), Err(SubMeteringError::OverRead(0)));
+        // nothing was claimed
+        assert_eq!(customer.sub_meters[0].table.len(), 2);
+        assert_eq!(customer.sub_meters[0].batches.len(), 2);
+    }
+
+    #[test]
+    fn bill_split() {
+        use super::super::split::*;
+
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, p_pk, .. } = fixture([2; 24*7]);
+        let (tenant1_pk, tenant1_sk) = sign::gen_keypair();
+        let (tenant2_pk, tenant2_sk) = sign::gen_keypair();
+
+        // tenant 2 is only home in the evening so pays 80% of the evening and 10% of the rest
+        let mut tenant1_shares = [90; 24*7];
+        let mut tenant2_shares = [10; 24*7];
+        for hour in 18..24 {
+            tenant1_shares[hour] = 20;
+            tenant2_shares[hour] = 80;
+        }
+        let agreement = SplitAgreement::per_slot(vec!(tenant1_pk, tenant2_pk), vec!(tenant1_shares, tenant2_shares));
+        let signed_agreement = SignedSplitAgreement {
+            agreement: agreement.clone(),
+            signatures: vec!(agreement.sign(&tenant1_sk), agreement.sign(&tenant2_sk)),
+        };
+
+        assert_eq!(customer.send_bill_split(0), Err(SplitError::NoBill(0)));
+
+        let mut expected = [0 as i64; 2];
+        for hour in 16..20 {
+            let units = super::super::tests::random_positive_i32() >> 20;
+            meter.consume(&IntegerConsumption::new(units, hour));
+            expected[0] += 2 * tenant1_shares[hour as usize] as i64 * units as i64;
+            expected[1] += 2 * tenant2_shares[hour as usize] as i64 * units as i64;
+        }
+        meter.end_batch();
+        customer.read_meter_messages();
+        customer.send_billing_information();
+
+        let provider_thread = thread::spawn(move || -> Vec<i64> {
+            let mut provider = ProviderState::new(provider_end, [2; 24*7], provider_keys, params);
+            provider.receive_billing_information().unwrap();
+            let amounts = provider.receive_bill_split().unwrap().amounts;
+            // a malformed agreement is rejected without stopping the provider
+            assert_eq!(provider.receive_bill_split().err(), Some("the agreement is malformed".to_string()));
+            amounts
+        });
+
+        assert!(wait_for_message(&mut customer, 0, "receipt").is_empty());
+        assert_eq!(customer.send_bill_split(0), Err(SplitError::NoAgreement));
+        customer.set_split_agreement(signed_agreement);
+        assert_eq!(customer.send_bill_split(0), Ok(expected.to_vec()));
+        assert!(wait_for_message(&mut customer, 0, "split_receipt").is_empty());
+
+        common::write_message(&mut customer.providers[0].channel, b"split\n0\n1\nzz\n00\n1\n1\n", "bill split");
+        customer.providers[0].pending_split = Some((0, vec!(1)));
+        assert_eq!(wait_for_message(&mut customer, 0, "split_rejected"), vec!(ProviderProblem::SplitRejected(0, "the agreement is malformed".to_string())));
+        assert_eq!(provider_thread.join().unwrap(), expected.to_vec());
+
+        // each tenant checks the provider's receipt against the agreement they signed
+        let receipt = SplitReceipt::verify(customer.split_receipts(0)[0].signed.clone(), &p_pk).unwrap();
+        assert_eq!(receipt.share_for(&agreement, 0), Some(expected[0]));
+        assert_eq!(receipt.share_for(&agreement, 1), Some(expected[1]));
+        assert_eq!(receipt.total * SHARE_DENOMINATOR as i64, expected[0] + expected[1]);
+
+        // the bill can no longer be split once its openings have been purged
+        customer.purge_expired_openings(SystemTime::now() + Duration::from_secs(DISPUTE_WINDOW_SECS + 1));
+        assert_eq!(customer.send_bill_split(0), Err(SplitError::ReadingsUnavailable(0, 0)));
+    }
+
+    #[test]
+    fn threshold_statement() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([1; 24*7]);
+
+        let mut total = 0;
+        for hour in 0..4 {
+            let units = super::super::tests::random_positive_i32() >> 20;
+            meter.consume(&IntegerConsumption::new(units, hour));
+            total += units as i64;
+        }
+        meter.end_batch();
+        customer.read_meter_messages();
+        customer.send_billing_information();
+        customer.set_threshold_policy(ThresholdPolicy::new(2));
+
+        let provider_thread = thread::spawn(move || {
+            let mut provider = ProviderState::new(provider_end, [1; 24*7], provider_keys, params);
+            provider.receive_billing_information().unwrap();
+
+            let below = provider.request_threshold_statement(0, 0, total + 1);
+            let at = provider.request_threshold_statement(0, 0, total);
+            (below.proven, at.proven)
+        });
+
+        for _ in 0..2 {
+            wait_for_message(&mut customer, 0, "threshold");
+        }
+
+        assert_eq!(provider_thread.join().unwrap(), (true, false));
+    }
+
+    #[test]
+    fn threshold_policy() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([1; 24*7]);
+
+        let units = super::super::tests::random_positive_i32() >> 20;
+        meter.consume(&IntegerConsumption::new(units, 0));
+        meter.end_batch();
+        customer.read_meter_messages();
+        customer.send_billing_information();
+
+        let provider_thread = thread::spawn(move || {
+            let mut provider = ProviderState::new(provider_end, [1; 24*7], provider_keys, params);
+            provider.receive_billing_information().unwrap();
+
+            // a second threshold for the same bill would start a binary search for the total, but asking again about the first is harmless
+            let first = provider.request_threshold_statement(0, 0, units as i64 + 1);
+            let second = provider.request_threshold_statement(0, 0, units as i64 / 2 + 1);
+            let again = provider.request_threshold_statement(0, 0, units as i64 + 1);
+            ((first.proven, first.refused), (second.proven, second.refused), (again.proven, again.refused))
+        });
+
+        for _ in 0..3 {
+            wait_for_message(&mut customer, 0, "threshold");
+        }
+
+        assert_eq!(provider_thread.join().unwrap(), ((true, false), (false, true), (true, false)));
+
+        let policy = ThresholdPolicy {
+            max_thresholds: 2,
+            allowed: Some(vec!(100, 200)),
+        };
+        assert!(policy.allows(100, &[]));
+        assert!(!policy.allows(150, &[]));
+        assert!(policy.allows(200, &[100]));
+        assert!(policy.allows(100, &[100, 200]));
+        assert!(!policy.allows(200, &[100, 300]));
+    }
+
+    #[test]
+    fn threshold_statement_rejected() {
+        let Fixture { params, mut meter, mut customer, provider_end, provider_keys, .. } = fixture([1; 24*7]);
+
+        let mut total = 0;
+        for hour in 0..4 {
+            let units = super::super::tests::random_positive_i32() >> 20;
+            meter.consume(&IntegerConsumption::new(units, hour));
+            total += units as i64;
+        }
+        meter.end_batch();
+        customer.read_meter_messages();
+        customer.send_billing_information();
+
+        let provider_params = params.clone();
+        let provider_thread = thread::spawn(move || {
+            let mut provider = ProviderState::new(provider_end, [1; 24*7], provider_keys, provider_params);
+            provider.receive_billing_information().unwrap();
+
+            let honest = provider.request_threshold_statement(0, 0, total + 1);
+            let replayed = provider.request_threshold_statement(0, 0, total + 1);
+            let above = provider.request_threshold_statement(0, 0, total);
+            (honest.proven, replayed.proven, above.proven)
+        });
+        assert!(wait_for_message(&mut customer, 0, "receipt").is_empty());
+
+        // request 0 is answered honestly
+        common::expect_header(&mut customer.providers[0].channel, "threshold");
+        customer.answer_threshold_statement(0).unwrap();
+
+        // the commitment to threshold - 1 - total has opening -a
+        let group = Group::from_params(&params);
+        let (cons, a) = weighted_opening(&customer.billed_tables[0].rows, &[1; 24*7], &group.q);
+        assert_eq!(cons, total);
+        let minus_a = (Mpz::zero() - a).modulus(&group.q);
+
+        // a proof bound to request 0 does not answer request 1
+        let replayed = RangeProof::prove(&group, 0, &minus_a, THRESHOLD_RANGE_BITS, threshold_context(0, 0, 0, total + 1).as_bytes());
+        common::expect_header(&mut customer.providers[0].channel, "threshold");
+        read_line_blocking(&mut customer.providers[0].channel);
+        common::write_message(&mut customer.providers[0].channel, format!("threshold_response\n1\n{}\n", replayed.to_text()).as_bytes(), "threshold response");
+
+        // the total is not below a threshold of total, so claiming a remainder of 0 instead of -1 fails
+        let above = RangeProof::prove(&group, 0, &minus_a, THRESHOLD_RANGE_BITS, threshold_context(2, 0, 0, total).as_bytes());
+        common::expect_header(&mut customer.providers[0].channel, "threshold");
+        read_line_blocking(&mut customer.providers[0].channel);
+        common::write_message(&mut customer.providers[0].channel, format!("threshold_response\n2\n{}\n", above.to_text()).as_bytes(), "threshold response");
+
+        assert_eq!(provider_thread.join().unwrap(), (true, false, false));
+    }
+
     /************************ Stuff that is just for the impl of BillingProtocol so that the test works *********************/
     enum Role<P: Read + Write, M: Read + Write> {
         Server(ProviderState<P>),
@@ -497,13 +3589,16 @@ pub mod tests {
     
         fn send_billing_information(&mut self) {
             // assert we are a Client
-            let ref mut customer = match self.role {
-                Role::Client(_, ref mut c) => c,
+            let (ref mut meter, ref mut customer) = match self.role {
+                Role::Client(ref mut m, ref mut c) => (m, c),
                 _ => panic!("This function should be called on the Client"),
             };
     
+            meter.end_batch();
+            customer.read_meter_messages();
             customer.read_provider_messages();
             customer.send_billing_information();
+            assert!(customer.wait_for_receipt(Duration::from_secs(10)).is_empty());
         }
     
         fn pay_bill(&mut self) -> i64 {
@@ -513,7 +3608,9 @@ pub mod tests {
                 _ => panic!("This function should be called on the Server"),
             };
     
-            provider.receive_billing_information();
+            while let Err(why) = provider.receive_billing_information() {
+                println!("Rejected a bill: {}", why);
+            }
             provider.pay_bill()
         }
     
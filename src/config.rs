@@ -0,0 +1,393 @@
+//! Configuration files for the interactive demo
+//!
+//! Each role can read its settings from a TOML file given with `--config` instead of from the command line. Options given on the command line override the file.
+//!
+//! ```toml
+//! role = "customer"                   # "provider", "customer" or "meter"
+//!
+//! [keys]
+//! coms_keypair = "customer.comsk"     # provider and customer
+//! public_coms_key = "provider.comsk.pub" # provider and customer
+//! dh_params = "dhparams.txt"          # every role
+//! sign_key = "meter.signk"            # every role. Optional for the customer, which countersigns dispute transcripts with it
+//! meter_sign_pk = "meter.signk.pub"   # provider and customer
+//! provider_sign_pk = "provider.signk.pub" # customer
+//! customer_sign_pk = "customer.signk.pub" # provider (optional)
+//! regulator_sign_pk = "regulator.signk.pub" # customer (optional)
+//!
+//! [network]
+//! wan_socket = "127.0.0.1:1025"       # provider and customer
+//! lan_socket = "127.0.0.1:1026"       # customer and meter. unix:PATH for a Unix domain socket
+//!
+//! [storage]
+//! chain_head = "meter_chain_head.txt" # provider and meter
+//!
+//! [tariff]
+//! prices = 1                          # provider and customer: one price for every hour or a list of 168
+//!
+//! [policy]
+//! require_countersignature = true     # customer, needs regulator_sign_pk. The default is true, so the provider sends tariffs countersigned by the regulator.
+//! price_cap = "price_cap.bin"         # customer, needs regulator_sign_pk: a price cap signed by the regulator
+//! batch_size = 24                     # meter
+//! min_statement_weights = 2           # customer: also prove consumption statements covering at least this many slots, not just bill totals. Two which differ in one slot reveal its reading.
+//! ```
+//!
+//! Relative paths (including those of unix: sockets) are relative to the directory containing the configuration file.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use lan;
+use proj_billing::billing::consumption::integer_consumption::Prices;
+use toml;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+/// Things which can go wrong reading a configuration file
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Reading the file failed
+    Io(io::Error),
+    /// The file is not valid TOML
+    Syntax(String),
+    /// The file is valid TOML but a setting is missing, unknown or has the wrong type
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "{}", e),
+            ConfigError::Syntax(ref e) => write!(f, "the file is not valid TOML: {}", e),
+            ConfigError::Invalid(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+// (section, key, command line option, roles which use it). coms_keypair is given by the option named after the role.
+const STRING_SETTINGS: &'static [(&'static str, &'static str, &'static str, &'static [&'static str])] = &[
+    ("keys", "coms_keypair", "", &["provider", "customer"]),
+    ("keys", "public_coms_key", "public-coms-key", &["provider", "customer"]),
+    ("keys", "dh_params", "dh-params", &["provider", "customer", "meter"]),
+    ("keys", "sign_key", "sign-key", &["provider", "customer", "meter"]),
+    ("keys", "meter_sign_pk", "meter-sign-pk", &["provider", "customer"]),
+    ("keys", "provider_sign_pk", "provider-sign-pk", &["customer"]),
+    ("keys", "customer_sign_pk", "customer-sign-pk", &["provider"]),
+    ("keys", "regulator_sign_pk", "regulator-sign-pk", &["customer"]),
+    ("network", "wan_socket", "wan-socket", &["provider", "customer"]),
+    ("network", "lan_socket", "lan-socket", &["customer", "meter"]),
+    ("storage", "chain_head", "chain-head", &["provider", "meter"]),
+    ("policy", "price_cap", "price-cap", &["customer"]),
+];
+
+// settings which are not paths
+const NOT_PATHS: &'static [&'static str] = &["wan-socket", "lan-socket"];
+
+/// The settings for one role
+pub struct Config {
+    /// "provider", "customer" or "meter"
+    pub role: String,
+    /// The starting tariff
+    pub prices: Option<Prices>,
+    /// Reject tariffs which the regulator has not countersigned (the default when there is a regulator)
+    pub require_countersignature: Option<bool>,
+    /// The number of readings the meter signs at once
+    pub batch_size: Option<usize>,
+    /// If set, the customer proves linear statements with at least this many non-zero weights instead of only bill totals
+    pub min_statement_weights: Option<usize>,
+    // the string settings, by the command line option which overrides them
+    values: HashMap<String, String>,
+}
+
+/// The name of the setting in the configuration file which corresponds to a command line option (e.g. keys.dh_params for dh-params)
+pub fn setting_name(option: &str) -> String {
+    for &(section, key, opt, _) in STRING_SETTINGS {
+        if opt == option || (opt == "" && (option == "provider" || option == "customer")) {
+            return format!("{}.{}", section, key);
+        }
+    }
+
+    panic!("{} is not a configuration setting", option);
+}
+
+/// The value of a setting: command_line, the value of the option on the command line, if it was given and otherwise the setting in config (if any)
+pub fn setting(command_line: Option<String>, config: Option<&Config>, option: &str) -> Option<String> {
+    match command_line {
+        Some(v) => Some(v),
+        None => config.and_then(|c| c.get(option)),
+    }
+}
+
+fn invalid<T>(message: String) -> Result<T, ConfigError> {
+    Err(ConfigError::Invalid(message))
+}
+
+fn parse_prices(value: &toml::Value) -> Result<Prices, ConfigError> {
+    let mut prices = [0; 24*7];
+
+    let in_range = |v: i64| -> Result<i32, ConfigError> {
+        if v < i32::min_value() as i64 || v > i32::max_value() as i64 {
+            invalid(format!("tariff.prices: {} does not fit in a 32 bit signed integer", v))
+        } else {
+            Ok(v as i32)
+        }
+    };
+
+    match *value {
+        toml::Value::Integer(p) => {
+            let p = try!(in_range(p));
+            for price in prices.iter_mut() {
+                *price = p;
+            }
+        },
+        toml::Value::Array(ref list) => {
+            if list.len() != prices.len() {
+                return invalid(format!("tariff.prices should have one price for each of the {} hours of the week, not {}", prices.len(), list.len()));
+            }
+            for (price, v) in prices.iter_mut().zip(list.iter()) {
+                *price = match v.as_integer() {
+                    Some(p) => try!(in_range(p)),
+                    None => return invalid(String::from("tariff.prices should only contain integers")),
+                };
+            }
+        },
+        _ => return invalid(String::from("tariff.prices should be an integer or a list of integers")),
+    }
+
+    Ok(prices)
+}
+
+impl Config {
+    /// Read and check the configuration file at path
+    pub fn read(path: &str) -> Result<Config, ConfigError> {
+        let mut text = String::new();
+        let mut file = try!(File::open(path));
+        try!(file.read_to_string(&mut text));
+
+        let base = Path::new(path).parent().unwrap_or(Path::new(""));
+        Config::from_text(&text, base)
+    }
+
+    /// Parse and check a configuration. Relative paths are taken to be relative to base.
+    pub fn from_text(text: &str, base: &Path) -> Result<Config, ConfigError> {
+        let top = match text.parse::<toml::Value>() {
+            Ok(toml::Value::Table(t)) => t,
+            Ok(_) => return Err(ConfigError::Syntax(String::from("expected a table"))),
+            Err(e) => return Err(ConfigError::Syntax(format!("{}", e))),
+        };
+
+        let role = match top.get("role") {
+            Some(&toml::Value::String(ref r)) if r == "provider" || r == "customer" || r == "meter" => r.clone(),
+            Some(_) => return invalid(String::from("role should be \"provider\", \"customer\" or \"meter\"")),
+            None => return invalid(String::from("role is missing")),
+        };
+
+        let mut config = Config {
+            role: role,
+            prices: None,
+            require_countersignature: None,
+            batch_size: None,
+            min_statement_weights: None,
+            values: HashMap::new(),
+        };
+
+        for (section_name, section) in top.iter() {
+            if section_name == "role" {
+                continue;
+            }
+
+            let section = match section.as_table() {
+                Some(s) => s,
+                None => return invalid(format!("{} should be a section", section_name)),
+            };
+
+            for (key, value) in section.iter() {
+                let name = format!("{}.{}", section_name, key);
+                try!(config.set(section_name, key, value, &name, base));
+            }
+        }
+
+        if config.require_countersignature.is_some() && !config.values.contains_key("regulator-sign-pk") {
+            return invalid(String::from("policy.require_countersignature needs keys.regulator_sign_pk"));
+        }
+
+        if config.values.contains_key("price-cap") && !config.values.contains_key("regulator-sign-pk") {
+            return invalid(String::from("policy.price_cap needs keys.regulator_sign_pk"));
+        }
+
+        Ok(config)
+    }
+
+    // check and store one setting
+    fn set(&mut self, section: &str, key: &str, value: &toml::Value, name: &str, base: &Path) -> Result<(), ConfigError> {
+        let role = self.role.clone();
+        let used_by = |roles: &[&str]| -> Result<(), ConfigError> {
+            if roles.contains(&role.as_str()) {
+                Ok(())
+            } else {
+                invalid(format!("{} is not a setting for a {}", name, role))
+            }
+        };
+
+        for &(s, k, option, roles) in STRING_SETTINGS {
+            if s != section || k != key {
+                continue;
+            }
+            try!(used_by(roles));
+
+            let string = match value.as_str() {
+                Some(v) => v,
+                None => return invalid(format!("{} should be a string", name)),
+            };
+
+            let option = if option == "" { role.as_str() } else { option };
+            let string = if let (true, Some(path)) = (option == "lan-socket", lan::unix_path(string)) {
+                format!("{}{}", lan::UNIX_PREFIX, base.join(path).to_string_lossy())
+            } else if NOT_PATHS.contains(&option) {
+                string.to_string()
+            } else {
+                base.join(string).to_string_lossy().into_owned()
+            };
+
+            self.values.insert(option.to_string(), string);
+            return Ok(());
+        }
+
+        match (section, key) {
+            ("tariff", "prices") => {
+                try!(used_by(&["provider", "customer"][..]));
+                self.prices = Some(try!(parse_prices(value)));
+            },
+            ("policy", "require_countersignature") => {
+                try!(used_by(&["customer"][..]));
+                self.require_countersignature = match value.as_bool() {
+                    Some(b) => Some(b),
+                    None => return invalid(format!("{} should be true or false", name)),
+                };
+            },
+            ("policy", "batch_size") => {
+                try!(used_by(&["meter"][..]));
+                self.batch_size = match value.as_integer() {
+                    Some(b) if b > 0 => Some(b as usize),
+                    _ => return invalid(format!("{} should be a positive integer", name)),
+                };
+            },
+            ("policy", "min_statement_weights") => {
+                try!(used_by(&["customer"][..]));
+                self.min_statement_weights = match value.as_integer() {
+                    Some(m) if m >= 0 => Some(m as usize),
+                    _ => return invalid(format!("{} should be a non-negative integer", name)),
+                };
+            },
+            _ => return invalid(format!("{} is not a known setting", name)),
+        }
+
+        Ok(())
+    }
+
+    /// The value of the setting overridden by the command line option option
+    pub fn get(&self, option: &str) -> Option<String> {
+        self.values.get(option).map(|v| v.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lan;
+
+    fn expect_invalid(text: &str) {
+        match Config::from_text(text, Path::new("")) {
+            Err(ConfigError::Invalid(_)) => (),
+            Err(e) => panic!("wrong error for {}: {}", text, e),
+            Ok(_) => panic!("accepted {}", text),
+        }
+    }
+
+    #[test]
+    fn prices() {
+        let config = Config::from_text("role = \"provider\"\n[tariff]\nprices = 3\n", Path::new("")).unwrap();
+        assert!(config.prices.unwrap().iter().all(|p| *p == 3));
+
+        let mut list: Vec<String> = (0..24*7).map(|i| i.to_string()).collect();
+        let config = Config::from_text(&format!("role = \"customer\"\n[tariff]\nprices = [{}]\n", list.join(", ")), Path::new("")).unwrap();
+        let prices = config.prices.unwrap();
+        assert_eq!(prices[0], 0);
+        assert_eq!(prices[24*7 - 1], 24*7 - 1);
+
+        expect_invalid("role = \"provider\"\n[tariff]\nprices = 2147483648\n");
+        expect_invalid("role = \"provider\"\n[tariff]\nprices = -2147483649\n");
+        expect_invalid("role = \"provider\"\n[tariff]\nprices = [1, 2, 3]\n");
+        expect_invalid("role = \"provider\"\n[tariff]\nprices = \"1\"\n");
+        list[5] = String::from("2147483648");
+        expect_invalid(&format!("role = \"provider\"\n[tariff]\nprices = [{}]\n", list.join(", ")));
+
+        // the meter does not use a tariff
+        expect_invalid("role = \"meter\"\n[tariff]\nprices = 1\n");
+
+        // i32::min_value() and i32::max_value() fit
+        Config::from_text("role = \"provider\"\n[tariff]\nprices = -2147483648\n", Path::new("")).unwrap();
+        Config::from_text("role = \"provider\"\n[tariff]\nprices = 2147483647\n", Path::new("")).unwrap();
+    }
+
+    #[test]
+    fn relative_paths() {
+        let text = "role = \"customer\"\n\
+                    [keys]\ncoms_keypair = \"customer.comsk\"\ndh_params = \"/etc/billing/dhparams.txt\"\nmeter_sign_pk = \"keys/meter.signk.pub\"\n\
+                    [network]\nwan_socket = \"127.0.0.1:1025\"\nlan_socket = \"unix:meter.sock\"\n";
+        let base = Path::new("/srv/billing");
+        let config = Config::from_text(text, base).unwrap();
+
+        assert_eq!(config.get("customer").unwrap(), base.join("customer.comsk").to_string_lossy());
+        assert_eq!(config.get("dh-params").unwrap(), "/etc/billing/dhparams.txt");
+        assert_eq!(config.get("meter-sign-pk").unwrap(), base.join("keys/meter.signk.pub").to_string_lossy());
+        assert_eq!(config.get("wan-socket").unwrap(), "127.0.0.1:1025");
+        assert_eq!(config.get("lan-socket").unwrap(), format!("{}{}", lan::UNIX_PREFIX, base.join("meter.sock").to_string_lossy()));
+        assert_eq!(config.get("sign-key"), None);
+
+        // a TCP LAN socket is not a path
+        let config = Config::from_text("role = \"meter\"\n[network]\nlan_socket = \"127.0.0.1:1026\"\n", base).unwrap();
+        assert_eq!(config.get("lan-socket").unwrap(), "127.0.0.1:1026");
+    }
+
+    #[test]
+    fn command_line_overrides() {
+        let config = Config::from_text("role = \"meter\"\n[keys]\ndh_params = \"dhparams.txt\"\nsign_key = \"meter.signk\"\n", Path::new("")).unwrap();
+
+        assert_eq!(setting(Some(String::from("other.signk")), Some(&config), "sign-key").unwrap(), "other.signk");
+        assert_eq!(setting(None, Some(&config), "sign-key").unwrap(), "meter.signk");
+        assert_eq!(setting(None, Some(&config), "lan-socket"), None);
+        assert_eq!(setting(Some(String::from("127.0.0.1:2000")), None, "lan-socket").unwrap(), "127.0.0.1:2000");
+        assert_eq!(setting(None, None, "lan-socket"), None);
+    }
+
+    #[test]
+    fn wrong_settings() {
+        expect_invalid("role = \"regulator\"\n");
+        expect_invalid("[keys]\ndh_params = \"dhparams.txt\"\n");
+        expect_invalid("role = \"meter\"\n[keys]\nprovider_sign_pk = \"provider.signk.pub\"\n");
+        expect_invalid("role = \"meter\"\n[keys]\nunknown = \"x\"\n");
+        expect_invalid("role = \"customer\"\n[policy]\nrequire_countersignature = true\n");
+        expect_invalid("role = \"customer\"\n[policy]\nprice_cap = \"price_cap.bin\"\n");
+        expect_invalid("role = \"meter\"\n[policy]\nbatch_size = 0\n");
+    }
+}
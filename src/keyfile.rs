@@ -0,0 +1,465 @@
+//! Passphrase-encrypted key files for the interactive demo
+//!
+//! Secret keys are stored as `MAGIC || salt || nonce || secretbox(key bytes)`. The secretbox key is derived from the passphrase using scrypt (sodiumoxide's pwhash).
+//! Public keys are stored in plain text as a header line followed by one hexadecimal key per line so that several trusted keys can share a file.
+//!
+//! Files which do not start with the expected header are handed to the plaintext loaders in proj_net and proj_crypto so that old key files still work.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use rpassword;
+use sodiumoxide::crypto::pwhash;
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::utils::memzero;
+use proj_crypto::asymmetric::{sign, key_id, PublicKey, SecretKey};
+use proj_crypto::asymmetric::key_exchange::gen_keypair;
+use proj_net::{Keypair, get_keys};
+use proj_billing::billing::{to_hex, from_hex};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions, Permissions};
+use std::io;
+use std::io::{Read, Write, BufRead, BufReader};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+
+/// Environment variable checked for the passphrase before prompting
+pub const PASSPHRASE_ENV_VAR: &'static str = "PROJ_BILLING_PASSPHRASE";
+
+/// First bytes of an encrypted secret key file
+const SECRET_MAGIC: &'static [u8] = b"proj_billing encrypted key v1\n";
+
+/// First line of a public key file
+const PUBLIC_HEADER: &'static str = "proj_billing public keys v1";
+
+/// Things which can go wrong reading a key file
+#[derive(Debug)]
+pub enum KeyFileError {
+    /// Reading or writing the file failed
+    Io(io::Error),
+    /// The file is not in the expected format
+    Malformed,
+    /// Authenticated decryption failed. Usually this means the passphrase was wrong.
+    BadPassphrase,
+}
+
+impl fmt::Display for KeyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyFileError::Io(ref e) => write!(f, "{}", e),
+            KeyFileError::Malformed => write!(f, "the key file is malformed"),
+            KeyFileError::BadPassphrase => write!(f, "decryption failed (wrong passphrase?)"),
+        }
+    }
+}
+
+impl From<io::Error> for KeyFileError {
+    fn from(e: io::Error) -> KeyFileError {
+        KeyFileError::Io(e)
+    }
+}
+
+/// Where to get the passphrase from. The passphrase is only requested once and then cached.
+pub struct PassphraseSource {
+    fd: Option<i32>,
+    cached: Option<Vec<u8>>,
+}
+
+impl PassphraseSource {
+    /// If fd is Some then the passphrase is read from that file descriptor, otherwise the environment is checked before prompting on the terminal
+    pub fn new(fd: Option<i32>) -> PassphraseSource {
+        PassphraseSource {
+            fd: fd,
+            cached: None,
+        }
+    }
+
+    /// Get the passphrase, prompting with prompt if we have to ask the user. If confirm is set then interactive users are asked twice.
+    /// The passphrase is borrowed from the cache so that the only copy is the one wiped when the source is dropped.
+    pub fn get(&mut self, prompt: &str, confirm: bool) -> &[u8] {
+        if self.cached.is_none() {
+            let passphrase = if let Some(fd) = self.fd {
+                let file = unsafe { File::from_raw_fd(fd) };
+                first_line(BufReader::new(file))
+            } else if let Ok(p) = env::var(PASSPHRASE_ENV_VAR) {
+                p.into_bytes()
+            } else {
+                let mut first = prompt_hidden_or_exit(prompt);
+                if confirm {
+                    let mut second = prompt_hidden_or_exit("Repeat the passphrase: ");
+                    let matched = first == second;
+                    memzero(&mut second);
+                    if !matched {
+                        memzero(&mut first);
+                        println!("The passphrases did not match. Exiting.");
+                        ::std::process::exit(1);
+                    }
+                }
+                first
+            };
+
+            self.cached = Some(passphrase);
+        }
+
+        self.cached.as_ref().unwrap()
+    }
+}
+
+impl Drop for PassphraseSource {
+    fn drop(&mut self) {
+        if let Some(ref mut p) = self.cached {
+            memzero(p);
+        }
+    }
+}
+
+fn first_line<R: BufRead>(mut reader: R) -> Vec<u8> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        println!("Error reading the passphrase. Exiting.");
+        ::std::process::exit(1);
+    }
+
+    let ret = line.trim_right_matches(|c| c == '\n' || c == '\r').as_bytes().to_vec();
+    unsafe { memzero(line.as_mut_vec()); }
+    ret
+}
+
+// read a line from the terminal without echoing it. There is deliberately no fallback to an echoed read: without a terminal the passphrase has to come from --passphrase-fd or the environment.
+fn prompt_hidden(prompt: &str) -> io::Result<Vec<u8>> {
+    print!("{}", prompt);
+    try!(io::stdout().flush());
+
+    rpassword::read_password().map(|p| p.into_bytes())
+}
+
+fn prompt_hidden_or_exit(prompt: &str) -> Vec<u8> {
+    match prompt_hidden(prompt) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("\nCould not read the passphrase from the terminal ({}). Use --passphrase-fd or ${} instead. Exiting.", e, PASSPHRASE_ENV_VAR);
+            ::std::process::exit(1);
+        },
+    }
+}
+
+fn derive_key(passphrase: &[u8], salt: &pwhash::Salt) -> secretbox::Key {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    {
+        let secretbox::Key(ref mut kb) = key;
+        pwhash::derive_key(kb, passphrase, salt, pwhash::OPSLIMIT_INTERACTIVE, pwhash::MEMLIMIT_INTERACTIVE).unwrap();
+    }
+    key
+}
+
+/// Encrypt plaintext under a key derived from passphrase
+pub fn encrypt(plaintext: &[u8], passphrase: &[u8]) -> Vec<u8> {
+    let salt = pwhash::gen_salt();
+    let nonce = secretbox::gen_nonce();
+    let key = derive_key(passphrase, &salt);
+
+    let mut ret = Vec::from(SECRET_MAGIC);
+    ret.extend_from_slice(&salt.0);
+    ret.extend_from_slice(&nonce.0);
+    ret.append(&mut secretbox::seal(plaintext, &nonce, &key));
+    ret
+}
+
+/// Reverses encrypt
+pub fn decrypt(contents: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, KeyFileError> {
+    let header_len = SECRET_MAGIC.len() + pwhash::SALTBYTES + secretbox::NONCEBYTES;
+    if contents.len() < header_len || !contents.starts_with(SECRET_MAGIC) {
+        return Err(KeyFileError::Malformed);
+    }
+
+    let salt_start = SECRET_MAGIC.len();
+    let nonce_start = salt_start + pwhash::SALTBYTES;
+    let salt = pwhash::Salt::from_slice(&contents[salt_start..nonce_start]).unwrap();
+    let nonce = secretbox::Nonce::from_slice(&contents[nonce_start..header_len]).unwrap();
+    let key = derive_key(passphrase, &salt);
+
+    secretbox::open(&contents[header_len..], &nonce, &key).map_err(|_| KeyFileError::BadPassphrase)
+}
+
+fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, KeyFileError> {
+    let mut contents = Vec::new();
+    let mut file = try!(File::open(path));
+    try!(file.read_to_end(&mut contents));
+    Ok(contents)
+}
+
+/// Is the file at path an encrypted secret key file?
+pub fn is_encrypted<P: AsRef<Path>>(path: P) -> bool {
+    match read_file(path) {
+        Ok(contents) => contents.starts_with(SECRET_MAGIC),
+        Err(_) => false,
+    }
+}
+
+fn write_secret_file<P: AsRef<Path>>(path: P, plaintext: &[u8], passphrase: &[u8]) -> Result<(), KeyFileError> {
+    let contents = encrypt(plaintext, passphrase);
+    let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path));
+    // mode only applies to new files, and we might be overwriting one anyone can read
+    try!(file.set_permissions(Permissions::from_mode(0o600)));
+    try!(file.write_all(&contents));
+    Ok(())
+}
+
+/// Write public keys to a file (one per line)
+pub fn write_public_file<P: AsRef<Path>>(path: P, keys: &[&[u8]]) -> Result<(), KeyFileError> {
+    let mut file = try!(File::create(path));
+    try!(writeln!(file, "{}", PUBLIC_HEADER));
+    for key in keys {
+        try!(writeln!(file, "{}", to_hex(key)));
+    }
+    Ok(())
+}
+
+/// Read the public keys from a file written with write_public_file. Returns Ok(None) if the file is not in that format.
+pub fn read_public_file<P: AsRef<Path>>(path: P) -> Result<Option<Vec<Vec<u8>>>, KeyFileError> {
+    let file = try!(File::open(path));
+    let mut lines = BufReader::new(file).lines();
+
+    match lines.next() {
+        Some(Ok(ref header)) if header.trim() == PUBLIC_HEADER => {},
+        _ => return Ok(None),
+    }
+
+    let mut ret = Vec::new();
+    for line in lines {
+        let line = try!(line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        match from_hex(line.trim()) {
+            Some(k) => ret.push(k),
+            None => return Err(KeyFileError::Malformed),
+        }
+    }
+
+    Ok(Some(ret))
+}
+
+fn pub_path(path: &str) -> String {
+    format!("{}.pub", path)
+}
+
+/// Generate an encrypted signing keypair. The secret key goes in path and the public key in path.pub
+pub fn sign_key_gen_to_file(path: &str, passphrase: &[u8]) -> Result<(), KeyFileError> {
+    let (pk, sk) = sign::gen_keypair();
+    try!(write_secret_file(path, &sk.0, passphrase));
+    write_public_file(pub_path(path), &[&pk.0[..]])
+}
+
+/// Generate an encrypted communication keypair. The keypair goes in path and the public key in path.pub
+pub fn coms_key_gen_to_file(path: &str, passphrase: &[u8]) -> Result<(), KeyFileError> {
+    let (pk, sk) = gen_keypair();
+
+    let mut plaintext = Vec::from(pk.0.as_ref());
+    plaintext.extend_from_slice(&sk.0);
+    let res = write_secret_file(path, &plaintext, passphrase);
+    memzero(&mut plaintext);
+    try!(res);
+
+    write_public_file(pub_path(path), &[&pk.0[..]])
+}
+
+/// Read a signing secret key, decrypting it if necessary
+pub fn read_sign_sk(path: &str, passphrase: &mut PassphraseSource) -> Result<sign::SecretKey, KeyFileError> {
+    if !is_encrypted(path) {
+        let (_, sk) = sign::get_keypair(path);
+        return Ok(sk);
+    }
+
+    let contents = try!(read_file(path));
+    let mut plaintext = try!(decrypt(&contents, passphrase.get(&format!("Passphrase for {}: ", path), false)));
+    let sk = sign::SecretKey::from_slice(&plaintext);
+    memzero(&mut plaintext);
+
+    sk.ok_or(KeyFileError::Malformed)
+}
+
+/// Read a signing public key from either format
+pub fn read_sign_pk(path: &str) -> Result<sign::PublicKey, KeyFileError> {
+    match try!(read_public_file(path)) {
+        None => Ok(sign::get_pubkey(path)),
+        Some(keys) => {
+            if keys.len() != 1 {
+                return Err(KeyFileError::Malformed);
+            }
+            sign::PublicKey::from_slice(&keys[0]).ok_or(KeyFileError::Malformed)
+        },
+    }
+}
+
+/// Read our communication keypair and the trusted public keys. This replaces proj_net::get_keys.
+pub fn read_coms_keys(private_path: &str, public_path: &str, passphrase: &mut PassphraseSource)
+                      -> Result<(HashMap<key_id::PublicKeyId, PublicKey>, Keypair), KeyFileError> {
+    if !is_encrypted(private_path) {
+        return Ok(get_keys(private_path, public_path));
+    }
+
+    let contents = try!(read_file(private_path));
+    let mut plaintext = try!(decrypt(&contents, passphrase.get(&format!("Passphrase for {}: ", private_path), false)));
+    let pk_len = plaintext.len() / 2;
+    let pk = PublicKey::from_slice(&plaintext[0..pk_len]);
+    let sk = SecretKey::from_slice(&plaintext[pk_len..]);
+    memzero(&mut plaintext);
+
+    let keypair = match (pk, sk) {
+        (Some(p), Some(s)) => (p, s),
+        _ => return Err(KeyFileError::Malformed),
+    };
+
+    let trusted = match try!(read_public_file(public_path)) {
+        Some(keys) => keys,
+        None => return Err(KeyFileError::Malformed),
+    };
+
+    let mut pks = HashMap::new();
+    for bytes in trusted {
+        let pk = try!(PublicKey::from_slice(&bytes).ok_or(KeyFileError::Malformed));
+        pks.insert(key_id::id_of_pk(&pk), pk);
+    }
+
+    Ok((pks, keypair))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proj_crypto::asymmetric::sign;
+    use proj_net;
+    use sodiumoxide;
+    use sodiumoxide::randombytes::randombytes;
+    use std::env;
+    use std::fs;
+
+    // a file name in the temporary directory which no other test run uses
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("proj_billing_keyfile_{}_{}", to_hex(&randombytes(8)), name)).to_string_lossy().into_owned()
+    }
+
+    fn remove(path: &str) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(pub_path(path));
+    }
+
+    #[test]
+    fn round_trip() {
+        sodiumoxide::init();
+
+        let contents = encrypt(b"secret", b"passphrase");
+        assert!(contents.starts_with(SECRET_MAGIC));
+        assert_eq!(decrypt(&contents, b"passphrase").unwrap(), b"secret");
+
+        match decrypt(&contents, b"wrong") {
+            Err(KeyFileError::BadPassphrase) => (),
+            _ => panic!("decrypted with the wrong passphrase"),
+        }
+        match decrypt(&contents[0..10], b"passphrase") {
+            Err(KeyFileError::Malformed) => (),
+            _ => panic!("decrypted a truncated file"),
+        }
+    }
+
+    #[test]
+    fn sign_key_files() {
+        sodiumoxide::init();
+        let path = temp_path("signk");
+
+        sign_key_gen_to_file(&path, b"passphrase").unwrap();
+        assert!(is_encrypted(&path));
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let sk = read_sign_sk(&path, &mut PassphraseSource { fd: None, cached: Some(b"passphrase".to_vec()) }).unwrap();
+        let pk = read_sign_pk(&pub_path(&path)).unwrap();
+        let signed = sign::sign(b"message", &sk);
+        assert_eq!(sign::verify(&signed, &pk).unwrap(), b"message");
+
+        match read_sign_sk(&path, &mut PassphraseSource { fd: None, cached: Some(b"wrong".to_vec()) }) {
+            Err(KeyFileError::BadPassphrase) => (),
+            _ => panic!("read the key with the wrong passphrase"),
+        }
+
+        remove(&path);
+    }
+
+    #[test]
+    fn overwrite_permissions() {
+        sodiumoxide::init();
+        let path = temp_path("readable");
+
+        File::create(&path).unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        sign_key_gen_to_file(&path, b"passphrase").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        remove(&path);
+    }
+
+    #[test]
+    fn plaintext_fallback() {
+        sodiumoxide::init();
+        let sign_path = temp_path("plain_signk");
+        let coms_path = temp_path("plain_comsk");
+
+        sign::key_gen_to_file(&sign_path);
+        proj_net::key_gen_to_file(&coms_path);
+        assert!(!is_encrypted(&sign_path));
+
+        // no passphrase is needed (or available)
+        let mut passphrase = PassphraseSource::new(None);
+        read_sign_sk(&sign_path, &mut passphrase).unwrap();
+        read_sign_pk(&pub_path(&sign_path)).unwrap();
+        let (trusted, _) = read_coms_keys(&coms_path, &pub_path(&coms_path), &mut passphrase).unwrap();
+        assert_eq!(trusted.len(), 1);
+        assert!(passphrase.cached.is_none());
+
+        remove(&sign_path);
+        remove(&coms_path);
+    }
+
+    #[test]
+    fn coms_keys() {
+        sodiumoxide::init();
+        let path = temp_path("comsk");
+        let other = temp_path("other_comsk");
+
+        coms_key_gen_to_file(&path, b"passphrase").unwrap();
+        coms_key_gen_to_file(&other, b"passphrase").unwrap();
+
+        // trust both public keys
+        let mut keys = read_public_file(pub_path(&path)).unwrap().unwrap();
+        keys.append(&mut read_public_file(pub_path(&other)).unwrap().unwrap());
+        let trusted_path = temp_path("trusted.pub");
+        {
+            let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+            write_public_file(&trusted_path, &keys).unwrap();
+        }
+
+        let mut passphrase = PassphraseSource { fd: None, cached: Some(b"passphrase".to_vec()) };
+        let (trusted, (pk, _)) = read_coms_keys(&path, &trusted_path, &mut passphrase).unwrap();
+        assert_eq!(trusted.len(), 2);
+        assert_eq!(&pk.0[..], &keys[0][..]);
+
+        let mut wrong = PassphraseSource { fd: None, cached: Some(b"wrong".to_vec()) };
+        assert!(read_coms_keys(&path, &trusted_path, &mut wrong).is_err());
+
+        remove(&path);
+        remove(&other);
+        let _ = fs::remove_file(&trusted_path);
+    }
+}
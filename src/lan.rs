@@ -0,0 +1,274 @@
+//! The link between the meter and the customer
+//!
+//! `--lan-socket` is either IPADDR:PORT for TCP or unix:PATH for a Unix domain socket, for when the meter's bridge runs on the same machine as the customer.
+//! The customer listens and the meter connects. The customer only ever accepts one meter, so it removes the socket file once the meter has connected.
+//!
+//! The customer holds a lock on PATH.lock while it listens on a Unix domain socket at PATH, and the lock goes away with the process however it exits.
+//! So a socket file left behind by a customer which did not exit cleanly is removed when the next customer binds, but only if it is a socket and no customer holds the lock.
+//! The lock file itself is left in place.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use libc;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// The prefix of a Unix domain socket address, e.g. unix:/run/billing/meter.sock
+pub const UNIX_PREFIX: &'static str = "unix:";
+
+/// The path of the socket if addr is a Unix domain socket address
+pub fn unix_path(addr: &str) -> Option<&Path> {
+    if addr.starts_with(UNIX_PREFIX) {
+        Some(Path::new(&addr[UNIX_PREFIX.len()..]))
+    } else {
+        None
+    }
+}
+
+/// A connection between the meter and the customer
+pub enum LanStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl LanStream {
+    /// Connect to the customer listening on addr
+    pub fn connect(addr: &str) -> io::Result<LanStream> {
+        match unix_path(addr) {
+            Some(path) => UnixStream::connect(path).map(LanStream::Unix),
+            None => TcpStream::connect(addr).map(LanStream::Tcp),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            LanStream::Tcp(ref s) => s.set_nonblocking(nonblocking),
+            LanStream::Unix(ref s) => s.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for LanStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            LanStream::Tcp(ref mut s) => s.read(buf),
+            LanStream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for LanStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            LanStream::Tcp(ref mut s) => s.write(buf),
+            LanStream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            LanStream::Tcp(ref mut s) => s.flush(),
+            LanStream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// Waits for the meter to connect
+pub enum LanListener {
+    Tcp(TcpListener),
+    // the socket file and its (device, inode), so that we only ever remove the file we made, and the locked lock file
+    Unix(UnixListener, PathBuf, (u64, u64), File),
+}
+
+// (device, inode) of path, without following symbolic links
+fn identity(path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = try!(fs::symlink_metadata(path));
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+// the lock file for the socket at path
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock = path.as_os_str().to_os_string();
+    lock.push(".lock");
+    PathBuf::from(lock)
+}
+
+// lock the lock file for the socket at path. The lock is released when the file is closed.
+// Another customer listening on path holds the lock. Nothing connects to the socket to find out, so a live listener is not disturbed.
+fn lock_socket(path: &Path) -> io::Result<File> {
+    let lock = try!(OpenOptions::new().write(true).create(true).open(lock_path(path)));
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(lock);
+    }
+
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::WouldBlock {
+        Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use by another customer", path.display())))
+    } else {
+        Err(e)
+    }
+}
+
+// remove the socket file at path if a previous run left it behind. Only call this while holding the lock (see lock_socket).
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+
+    // nobody else holds the lock, so nobody is listening on it
+    fs::remove_file(path)
+}
+
+impl LanListener {
+    /// Listen on addr. A stale socket file at a Unix domain socket address is removed first. Fails with AddrInUse if another customer is listening on it.
+    pub fn bind(addr: &str) -> io::Result<LanListener> {
+        match unix_path(addr) {
+            Some(path) => {
+                let lock = try!(lock_socket(path));
+                try!(remove_stale_socket(path));
+                let listener = try!(UnixListener::bind(path));
+                let id = try!(identity(path));
+                Ok(LanListener::Unix(listener, path.to_path_buf(), id, lock))
+            },
+            None => TcpListener::bind(addr).map(LanListener::Tcp),
+        }
+    }
+
+    /// Wait for one connection and stop listening
+    pub fn accept_one(self) -> io::Result<LanStream> {
+        match self {
+            LanListener::Tcp(ref l) => l.accept().map(|(s, _)| LanStream::Tcp(s)),
+            LanListener::Unix(ref l, _, _, _) => l.accept().map(|(s, _)| LanStream::Unix(s)),
+        }
+    }
+}
+
+impl Drop for LanListener {
+    fn drop(&mut self) {
+        // the lock is released when the lock file is closed, after the socket file is removed
+        if let LanListener::Unix(_, ref path, id, _) = *self {
+            // the file might have been replaced since we bound it
+            if identity(path).ok() == Some(id) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proj_billing::billing::to_hex;
+    use sodiumoxide;
+    use sodiumoxide::randombytes::randombytes;
+    use std::env;
+    use std::fs::File;
+
+    // a socket path which no other test run uses
+    fn temp_path(name: &str) -> PathBuf {
+        sodiumoxide::init();
+        env::temp_dir().join(format!("proj_billing_lan_{}_{}", to_hex(&randombytes(8)), name))
+    }
+
+    #[test]
+    fn stale_socket() {
+        let path = temp_path("stale.sock");
+        let addr = format!("{}{}", UNIX_PREFIX, path.to_string_lossy());
+
+        // nobody is listening once the listener is dropped, but the file stays
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        // nothing holds the lock, so the socket is stale
+        let listener = LanListener::bind(&addr).unwrap();
+        drop(listener);
+        assert!(!path.exists());
+
+        // there is nothing to remove
+        drop(LanListener::bind(&addr).unwrap());
+        fs::remove_file(lock_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn live_socket() {
+        let path = temp_path("live.sock");
+        let addr = format!("{}{}", UNIX_PREFIX, path.to_string_lossy());
+        let listener = LanListener::bind(&addr).unwrap();
+
+        match LanListener::bind(&addr) {
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => (),
+            Err(e) => panic!("expected AddrInUse, got {:?}", e),
+            Ok(_) => panic!("expected AddrInUse, got a listener"),
+        }
+        assert!(path.exists());
+
+        // the failed bind did not disturb the live listener: the first connection it accepts is the meter's
+        let mut meter = LanStream::connect(&addr).unwrap();
+        let mut customer = listener.accept_one().unwrap();
+        meter.write_all(b"reading\n").unwrap();
+        let mut buf = [0; 8];
+        customer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"reading\n");
+
+        // the lock went with the listener
+        drop(LanListener::bind(&addr).unwrap());
+        fs::remove_file(lock_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn not_a_socket() {
+        let path = temp_path("regular");
+        File::create(&path).unwrap();
+
+        match remove_stale_socket(&path) {
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            r => panic!("expected AlreadyExists, got {:?}", r),
+        }
+        assert!(path.exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_over_stale_socket() {
+        let path = temp_path("bind.sock");
+        let addr = format!("{}{}", UNIX_PREFIX, path.to_string_lossy());
+        drop(UnixListener::bind(&path).unwrap());
+
+        let listener = LanListener::bind(&addr).unwrap();
+        let mut meter = LanStream::connect(&addr).unwrap();
+        let mut customer = listener.accept_one().unwrap();
+
+        meter.write_all(b"reading\n").unwrap();
+        let mut buf = [0; 8];
+        customer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"reading\n");
+
+        // accept_one dropped the listener, which removed the socket file
+        assert!(!path.exists());
+        fs::remove_file(lock_path(&path)).unwrap();
+    }
+}
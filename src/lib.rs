@@ -32,5 +32,6 @@ extern crate proj_crypto;
 extern crate sodiumoxide;
 extern crate gmp;
 extern crate num;
+extern crate serde_json;
 
 pub mod billing;
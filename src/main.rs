@@ -1,4 +1,6 @@
 //! Executable for demonstrating the three party billing protocol
+//!
+//! The library lets a customer bill several providers over the same meter commitments, but the demo's customer connects to a single provider (provider 0 in the library), so its commands never take a provider index.
 
 /*  This file is part of project-net.
     project-net is free software: you can redistribute it and/or modify
@@ -13,12 +15,22 @@
     along with project-net.  If not, see http://www.gnu.org/licenses/.*/
 
 mod shell;
+mod keyfile;
+mod config;
+mod setup;
+mod output;
+mod lan;
 
 extern crate getopts;
 extern crate proj_crypto;
 extern crate proj_net;
 extern crate proj_billing;
 extern crate sodiumoxide;
+extern crate toml;
+extern crate rustyline;
+extern crate serde_json;
+extern crate rpassword;
+extern crate libc;
 
 use getopts::Options;
 use std::env;
@@ -26,26 +38,58 @@ use std::process;
 use proj_net::*;
 use proj_crypto::asymmetric::sign;
 use proj_billing::billing::three_party::*;
-use std::net::{TcpListener, TcpStream};
+use std::net::TcpListener;
 use std::path::Path;
 use std::process::exit;
 use proj_billing::billing::Keys;
+use proj_billing::billing::regulator;
+use proj_billing::billing::regulator::{Regulator, TariffPolicy};
+use proj_billing::billing::consumption::integer_consumption::Prices;
 use proj_billing::billing::consumption::integer_consumption::IntegerConsumption;
+use proj_billing::billing::consumption::load_profile;
+use proj_billing::billing::consumption::tariff;
+use proj_billing::billing::inspect;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use keyfile::PassphraseSource;
+use shell::{ArgSpec, Args};
+use output::Event;
+use lan::{LanListener, LanStream};
+use serde_json::Value;
 
 const DEFAULT_WAN_SOCKET_ADDR: &'static str = "127.0.0.1:1025";
 const DEFAULT_LAN_SOCKET_ADDR: &'static str = "127.0.0.1:1026";
 
+// where the meter and the provider keep the heads of the hash chain of signed batches between runs, unless --chain-head says otherwise
+const METER_CHAIN_HEAD_PATH: &'static str = "meter_chain_head.txt";
+const PROVIDER_CHAIN_HEAD_PATH: &'static str = "provider_chain_heads.txt";
+
+// how long expect_bill waits for the provider's receipt
+const RECEIPT_TIMEOUT_SECS: u64 = 30;
+
 fn print_usage(executable_name: &str, opts: &Options) -> ! {
     println!("\n{} is free software licenced under GPLv3+: you are free to change and redistribute it.", executable_name);
     println!("There is NO WAARRANTY, to the extent permitted by law.");
     println!("The cryptography used has not been reviewed by any experts. You should not use it for anything serious.\n");
     
-    let brief1 = format!("To generate communication (and optionally: signing) keys: {} --keygen OUTPUT_FILE [--sign-key OUTPUT_FILE2]\n", executable_name);
-    let brief2 = format!("To run a provider: {} --provider MY_KEYPAIR --public-coms-key PUBLIC_KEY_FILE --dh-params DH_PARAMS --sign-key SIGN_KEY --sign-trusted-pk SIGN_PUBKEY --meter-sign-pk SIGN_PUBKEY [--wan-socket IPADDR:PORT]\n", executable_name);
-    let brief3 = format!("To run a customer: {} --customer MY_KEYPAIR --public-coms-key PUBLIC_KEY_FILE --dh-params DH_PARAMS --meter-sign-pk SIGN_PUBKEY --provider-sign-pk SIGN_PUBKEY [--wan-socket IPADDR:PORT] [--lan-socket IPADDR:PORT]\n", executable_name);
-    let brief4 = format!("To run a meter: {} --meter --dh-params DH_PARAMS --sign-key SIGN_KEY [--lan-socket IPADDR:PORT]\n", executable_name);
+    let brief1 = format!("To generate encrypted communication (and optionally: signing) keys: {} --keygen OUTPUT_FILE [--sign-key OUTPUT_FILE2]\n", executable_name);
+    let brief2 = format!("To run a provider: {} --provider MY_KEYPAIR --public-coms-key PUBLIC_KEY_FILE --dh-params DH_PARAMS --sign-key SIGN_KEY --sign-trusted-pk SIGN_PUBKEY --meter-sign-pk SIGN_PUBKEY [--customer-sign-pk SIGN_PUBKEY] [--wan-socket IPADDR:PORT]\n", executable_name);
+    let brief3 = format!("To run a customer: {} --customer MY_KEYPAIR --public-coms-key PUBLIC_KEY_FILE --dh-params DH_PARAMS --meter-sign-pk SIGN_PUBKEY --provider-sign-pk SIGN_PUBKEY [--sign-key SIGN_KEY] [--regulator-sign-pk SIGN_PUBKEY [--price-cap CAP_FILE]] [--wan-socket IPADDR:PORT] [--lan-socket IPADDR:PORT|unix:PATH]. The customer is billed by a single provider.\n", executable_name);
+    let brief4 = format!("To run a meter: {} --meter --dh-params DH_PARAMS --sign-key SIGN_KEY [--lan-socket IPADDR:PORT|unix:PATH]\n", executable_name);
+    let brief_regulator = format!("To countersign tariffs and sign price caps as the regulator: {} --regulator SIGN_KEY --provider-sign-pk SIGN_PUBKEY\n", executable_name);
+    let brief_setup = format!("To generate the keys and configuration files for a whole deployment: {} --setup DIRECTORY [--customers NUMBER], and to add a customer and meter to it later: {} --add-customer DIRECTORY\n", executable_name, executable_name);
+    let brief_inspect = format!("To inspect a captured message stream: {} --inspect CAPTURE_FILE --from meter|customer|provider [--dh-params DH_PARAMS] [--meter-sign-pk SIGN_PUBKEY] [--provider-sign-pk SIGN_PUBKEY] [--regulator-sign-pk SIGN_PUBKEY] [--json]\n", executable_name);
+    let brief5 = format!("Any of the roles can take --script FILE to run shell commands from FILE (- for standard input) instead of interactively. The exit status is 1 if an expect_ command fails.\n");
+    let brief6 = format!("Instead of these options the settings can be read from a TOML file with --config FILE. Options given as well override the file.\n");
+    let brief7 = format!("Secret key files are encrypted with a passphrase. It is read from --passphrase-fd, then ${}, and otherwise prompted for.\n", keyfile::PASSPHRASE_ENV_VAR);
     
-    print!("{}", opts.usage(&(brief1+&brief2+&brief3+&brief4)));
+    print!("{}", opts.usage(&(brief1+&brief2+&brief3+&brief4+&brief_regulator+&brief_setup+&brief_inspect+&brief5+&brief6+&brief7)));
     process::exit(1)
 }
 
@@ -77,21 +121,63 @@ fn main() {
     // required for all main modes
     opts.optopt("d", "dh-params", "The diffie-hellman parameters for the commitments", "DH_PARAMS");
 
-    // required for meter and provider
-    opts.optopt("k", "sign-key", "The secret key for signing billing messages", "SIGN_KEY");
+    // required for meter and provider, optional for the customer
+    opts.optopt("k", "sign-key", "The secret key for signing billing messages (for the customer: for countersigning dispute transcripts)", "SIGN_KEY");
 
     // required for the customer
-    opts.optopt("p", "provider-sign-pk", "The public key for verifying signatures", "SIGN_PUBKEY");
+    opts.optopt("p", "provider-sign-pk", "The public key for verifying signatures (for the regulator: for checking the tariffs it countersigns)", "SIGN_PUBKEY");
+
+    // optional for the provider
+    opts.optopt("", "customer-sign-pk", "The public key used to verify the customer's countersignatures of dispute transcripts", "SIGN_PUBKEY");
 
     // required for customer and provider
     opts.optopt("m", "meter-sign-pk", "The public key used to verify signatures from the meter", "SIGN_PUBKEY");
 
     // required for meter and customer
-    opts.optopt("l", "lan-socket", &format!("The socket for communication between the customer and meter. The default is {}.", DEFAULT_LAN_SOCKET_ADDR), "IPADDR:PORT");
+    opts.optopt("l", "lan-socket", &format!("The socket for communication between the customer and meter: TCP or {}PATH for a Unix domain socket. The default is {}.", lan::UNIX_PREFIX, DEFAULT_LAN_SOCKET_ADDR), "IPADDR:PORT|unix:PATH");
+
+    // optional for everything
+    opts.optopt("", "passphrase-fd", "Read the passphrase for the secret key files from this file descriptor", "FD");
 
     // required for customer and provider
     opts.optopt("w", "wan-socket", &format!("The socket for communication between the customer and provider. The default is {}.", DEFAULT_WAN_SOCKET_ADDR), "IPADDR:PORT");
 
+    // optional for provider, customer and meter
+    opts.optopt("", "config", "Read the settings for the provider, customer or meter from a TOML file. Options on the command line override it.", "FILE");
+
+    // optional for provider and meter
+    opts.optopt("", "chain-head", "Where to keep the head of the meter's hash chain between runs", "FILE");
+
+    // regulator mode - optional, takes an argument
+    opts.optopt("", "regulator", "Starts a regulator, which countersigns tariffs and signs price caps with SIGN_KEY", "SIGN_KEY");
+
+    // optional for the customer
+    opts.optopt("r", "regulator-sign-pk", "Only accept tariffs countersigned by the regulator with this public key", "SIGN_PUBKEY");
+
+    // optional for the customer
+    opts.optopt("", "price-cap", "Only accept tariffs below the price cap in CAP_FILE, signed by the regulator (needs --regulator-sign-pk)", "CAP_FILE");
+
+    // optional for provider, customer and meter
+    opts.optopt("", "script", "Run the shell commands in FILE (- for standard input) and exit instead of reading commands interactively", "FILE");
+
+    // setup mode - optional, takes an argument
+    opts.optopt("", "setup", "Generate the keys, commitment parameters and configuration files for a provider, customers and their meters in DIRECTORY", "DIRECTORY");
+
+    // optional for setup
+    opts.optopt("", "customers", "The number of customers (each with a meter) to set up. The default is 1.", "NUMBER");
+
+    // add customer mode - optional, takes an argument
+    opts.optopt("", "add-customer", "Add another customer and meter to the deployment made with --setup in DIRECTORY", "DIRECTORY");
+
+    // inspect mode - optional, takes an argument
+    opts.optopt("", "inspect", "Decode and check a captured stream of protocol messages (- for standard input). Checks needing a key or the commitment parameters are only made if it is given.", "CAPTURE_FILE");
+
+    // required for inspect
+    opts.optopt("", "from", "Who sent the captured messages: meter (to the customer), customer (to the provider) or provider (to the customer)", "SENDER");
+
+    // optional for inspect
+    opts.optflag("", "json", "Print JSON instead of text: one object per message for --inspect, and one object per event (results, errors, table rows) for the shells");
+
     // parse options
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -103,200 +189,522 @@ fn main() {
     }
 
     // enforce exclusivity between operation modes
-    if (matches.opt_present("keygen") && (matches.opt_present("provider") | matches.opt_present("customer") | matches.opt_present("meter"))) |
-        (matches.opt_present("provider") && (matches.opt_present("customer") | matches.opt_present("meter"))) |
-        (matches.opt_present("customer") && matches.opt_present("meter")) {
+    let modes = ["keygen", "provider", "customer", "meter", "regulator", "setup", "add-customer", "inspect"];
+    let modes_present = modes.iter().filter(|m| matches.opt_present(m)).count();
+    let config_mode = matches.opt_present("keygen") | matches.opt_present("provider") | matches.opt_present("customer") | matches.opt_present("meter");
+    if modes_present > 1 || (matches.opt_present("config") && modes_present > 0 && !config_mode) {
 
         println!("Use only one mode at a time.\n");
         print_usage(&executable_name, &opts);
     }
 
+    let config = match matches.opt_str("config") {
+        None => None,
+        Some(path) => match config::Config::read(&path) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                println!("Error in the configuration file {}: {}. Exiting.", path, e);
+                exit(1);
+            },
+        },
+    };
+
+    // the mode comes from the command line or the configuration file, which must agree
+    let mode = if matches.opt_present("keygen") {
+        "keygen"
+    } else if matches.opt_present("provider") {
+        "provider"
+    } else if matches.opt_present("customer") {
+        "customer"
+    } else if matches.opt_present("meter") {
+        "meter"
+    } else if matches.opt_present("regulator") {
+        "regulator"
+    } else if matches.opt_present("setup") {
+        "setup"
+    } else if matches.opt_present("add-customer") {
+        "add-customer"
+    } else if matches.opt_present("inspect") {
+        "inspect"
+    } else {
+        ""
+    };
+
+    let mode = match config {
+        Some(ref c) if mode == "" => c.role.clone(),
+        Some(ref c) if c.role != mode => {
+            println!("The configuration file is for a {}, not a {}.\n", c.role, mode);
+            print_usage(&executable_name, &opts);
+        },
+        _ => mode.to_string(),
+    };
+
+    // options on the command line override the configuration file
+    let setting = |option: &str| config::setting(matches.opt_str(option), config.as_ref(), option);
+
+    let prices = match config {
+        Some(ref c) => c.prices.unwrap_or([1; 24*7]),
+        None => [1; 24*7],
+    };
+
+    let passphrase_fd = match matches.opt_str("passphrase-fd") {
+        None => None,
+        Some(s) => match s.parse::<i32>() {
+            Ok(fd) => Some(fd),
+            Err(_) => {
+                println!("The passphrase file descriptor should be an integer");
+                print_usage(&executable_name, &opts);
+            },
+        },
+    };
+    let mut passphrase = PassphraseSource::new(passphrase_fd);
+
+    // from here on the shells report events as JSON if asked to
+    output::set_json(matches.opt_present("json"));
+
     // actually do stuff
     sodiumoxide::init();
     
-    if matches.opt_present("keygen") {
+    if mode == "keygen" {
         // incompatible options
-        if matches.opt_present("public-coms-key") | matches.opt_present("dh-params") | matches.opt_present("meter-sign-pk") | matches.opt_present("provider-sign-pk") | matches.opt_present("lan-socket") | matches.opt_present("wan-socket") {
+        if matches.opt_present("public-coms-key") | matches.opt_present("dh-params") | matches.opt_present("meter-sign-pk") | matches.opt_present("provider-sign-pk") | matches.opt_present("lan-socket") | matches.opt_present("wan-socket") | matches.opt_present("script") |
+            matches.opt_present("config") | matches.opt_present("chain-head") | matches.opt_present("regulator-sign-pk") | matches.opt_present("json") | matches.opt_present("customer-sign-pk") | matches.opt_present("price-cap") {
             println!("Those options do not work with keygen");
             print_usage(&executable_name, &opts);
         }
 
+        let pass = passphrase.get("Passphrase to encrypt the new keys: ", true);
+
         if matches.opt_present("sign-key") {
-            sign::key_gen_to_file(matches.opt_str("sign-key").unwrap().as_str());
+            let path = matches.opt_str("sign-key").unwrap();
+            or_exit(keyfile::sign_key_gen_to_file(path.as_str(), pass), &path);
         }
 
-        return key_gen_to_file(matches.opt_str("keygen").unwrap().as_str());
+        let path = matches.opt_str("keygen").unwrap();
+        or_exit(keyfile::coms_key_gen_to_file(path.as_str(), pass), &path);
+        return;
     }
     
-    if matches.opt_present("provider") {
-        // incompatible options
-        if matches.opt_present("lan-socket") | matches.opt_present("provider-sign-pk") {
-            println!("That is not a compatible option for provider");
+    if mode == "setup" || mode == "add-customer" {
+        // only the number of customers and the passphrase make sense here
+        let others = ["public-coms-key", "dh-params", "sign-key", "meter-sign-pk", "provider-sign-pk", "customer-sign-pk", "regulator-sign-pk", "price-cap", "lan-socket", "wan-socket",
+                      "script", "chain-head", "from", "json"];
+        if others.iter().any(|o| matches.opt_present(o)) || (mode == "add-customer" && matches.opt_present("customers")) {
+            println!("Those options do not work with {}", mode);
             print_usage(&executable_name, &opts);
         }
 
-        // required options
-        if !(matches.opt_present("public-coms-key") && matches.opt_present("dh-params") && matches.opt_present("sign-key") && matches.opt_present("meter-sign-pk")) {
-            println!("Missing some required option");
+        let customers = match matches.opt_str("customers").map(|n| n.parse::<usize>()) {
+            None => 1,
+            Some(Ok(n)) if n > 0 => n,
+            Some(_) => {
+                println!("The number of customers should be a positive integer");
+                print_usage(&executable_name, &opts);
+            },
+        };
+
+        let pass = passphrase.get("Passphrase to encrypt the new keys: ", true);
+
+        let result = if mode == "setup" {
+            let dir = matches.opt_str("setup").unwrap();
+            setup::create(&dir, customers, pass).map(|_| dir)
+        } else {
+            let dir = matches.opt_str("add-customer").unwrap();
+            setup::add_customer(&dir, pass).map(|_| dir)
+        };
+
+        match result {
+            Ok(dir) => println!("Done. Start each role with --config and the matching file in {}", dir),
+            Err(e) => {
+                println!("Setup failed: {}. Exiting.", e);
+                exit(1);
+            },
+        }
+        return;
+    }
+
+    if mode == "inspect" {
+        // incompatible options
+        if matches.opt_present("public-coms-key") | matches.opt_present("sign-key") | matches.opt_present("lan-socket") | matches.opt_present("wan-socket") | matches.opt_present("script") |
+            matches.opt_present("chain-head") | matches.opt_present("passphrase-fd") | matches.opt_present("customers") | matches.opt_present("customer-sign-pk") | matches.opt_present("price-cap") {
+            println!("Those options do not work with inspect");
             print_usage(&executable_name, &opts);
         }
 
-        // optional 
-        let wan_socket = if matches.opt_present("wan-socket") {
-            matches.opt_str("wan-socket").unwrap()
-        } else {
-            String::from(DEFAULT_WAN_SOCKET_ADDR)
+        let sender = match matches.opt_str("from").as_ref().map(|s| s.as_str()) {
+            Some("meter") => inspect::Sender::Meter,
+            Some("customer") => inspect::Sender::Customer,
+            Some("provider") => inspect::Sender::Provider,
+            _ => {
+                println!("--from should be meter, customer or provider\n");
+                print_usage(&executable_name, &opts);
+            },
         };
 
-        start_provider(matches.opt_str("dh-params").unwrap(), matches.opt_str("provider").unwrap(),
-                       matches.opt_str("public-coms-key").unwrap(), matches.opt_str("sign-key").unwrap(),
-                       matches.opt_str("meter-sign-pk").unwrap(), wan_socket);
+        inspect_capture(matches.opt_str("inspect").unwrap(), sender, matches.opt_str("dh-params"), matches.opt_str("meter-sign-pk"), matches.opt_str("provider-sign-pk"),
+                        matches.opt_str("regulator-sign-pk"));
     }
 
-    if matches.opt_present("customer") {
+    if mode == "provider" {
         // incompatible options
-        if matches.opt_present("sign-key") {
-            println!("sign-key is not a compatible option for customer");
+        if matches.opt_present("lan-socket") | matches.opt_present("provider-sign-pk") | matches.opt_present("regulator-sign-pk") | matches.opt_present("price-cap") {
+            println!("That is not a compatible option for provider");
             print_usage(&executable_name, &opts);
         }
 
         // required options
-        if !(matches.opt_present("public-coms-key") && matches.opt_present("dh-params") && matches.opt_present("meter-sign-pk") && matches.opt_present("provider-sign-pk")) {
-            println!("Missing some required option");
+        require(&setting, &["provider", "public-coms-key", "dh-params", "sign-key", "meter-sign-pk"], &executable_name, &opts);
+
+        // optional
+        let wan_socket = setting("wan-socket").unwrap_or(String::from(DEFAULT_WAN_SOCKET_ADDR));
+        let chain_head = setting("chain-head").unwrap_or(String::from(PROVIDER_CHAIN_HEAD_PATH));
+
+        start_provider(setting("dh-params").unwrap(), setting("provider").unwrap(), setting("public-coms-key").unwrap(), setting("sign-key").unwrap(),
+                       setting("meter-sign-pk").unwrap(), setting("customer-sign-pk"), wan_socket, chain_head, prices, matches.opt_str("script"), &mut passphrase);
+    }
+
+    if mode == "customer" {
+        // incompatible options
+        if matches.opt_present("chain-head") | matches.opt_present("customer-sign-pk") {
+            println!("That is not a compatible option for customer");
             print_usage(&executable_name, &opts);
         }
 
+        // required options
+        require(&setting, &["customer", "public-coms-key", "dh-params", "meter-sign-pk", "provider-sign-pk"], &executable_name, &opts);
+
         // optional
-        let wan_socket = if matches.opt_present("wan-socket") {
-            matches.opt_str("wan-socket").unwrap()
-        } else {
-            String::from(DEFAULT_WAN_SOCKET_ADDR)
+        let wan_socket = setting("wan-socket").unwrap_or(String::from(DEFAULT_WAN_SOCKET_ADDR));
+        let lan_socket = setting("lan-socket").unwrap_or(String::from(DEFAULT_LAN_SOCKET_ADDR));
+
+        // countersignatures are required unless the configuration file says otherwise
+        let regulator = setting("regulator-sign-pk").map(|path| {
+            let require = match config {
+                Some(ref c) => c.require_countersignature.unwrap_or(true),
+                None => true,
+            };
+            RegulatorSettings {
+                sign_pk_path: path,
+                require_countersignature: require,
+                price_cap_path: setting("price-cap"),
+            }
+        });
+        if regulator.is_none() && setting("price-cap").is_some() {
+            println!("--price-cap needs --regulator-sign-pk to check the cap\n");
+            print_usage(&executable_name, &opts);
+        }
+        let min_statement_weights = match config {
+            Some(ref c) => c.min_statement_weights,
+            None => None,
         };
 
-        let lan_socket = if matches.opt_present("lan-socket") {
-            matches.opt_str("lan-socket").unwrap()
-        } else {
-            String::from(DEFAULT_LAN_SOCKET_ADDR)
-        };
-        
-        start_customer(matches.opt_str("dh-params").unwrap(), matches.opt_str("customer").unwrap(),
-                       matches.opt_str("public-coms-key").unwrap(), matches.opt_str("meter-sign-pk").unwrap(),
-                       matches.opt_str("provider-sign-pk").unwrap(), wan_socket, lan_socket);
+        start_customer(setting("dh-params").unwrap(), setting("customer").unwrap(), setting("public-coms-key").unwrap(), setting("meter-sign-pk").unwrap(),
+                       setting("provider-sign-pk").unwrap(), setting("sign-key"), wan_socket, lan_socket, prices, regulator, min_statement_weights, matches.opt_str("script"), &mut passphrase);
     }
 
-    if matches.opt_present("meter") {
+    if mode == "meter" {
         // incompatible options
-        if matches.opt_present("public-coms-key") | matches.opt_present("meter-sign-pk") | matches.opt_present("provider-sign-pk") | matches.opt_present("wan-socket") {
+        if matches.opt_present("public-coms-key") | matches.opt_present("meter-sign-pk") | matches.opt_present("provider-sign-pk") | matches.opt_present("wan-socket") |
+            matches.opt_present("regulator-sign-pk") | matches.opt_present("customer-sign-pk") | matches.opt_present("price-cap") {
             println!("Those options do not work with meter");
             print_usage(&executable_name, &opts);
         }
 
         // required options
-        if !(matches.opt_present("dh-params") && matches.opt_present("sign-key")) {
-            println!("Missing some required option");
+        require(&setting, &["dh-params", "sign-key"], &executable_name, &opts);
+
+        // optional
+        let lan_socket = setting("lan-socket").unwrap_or(String::from(DEFAULT_LAN_SOCKET_ADDR));
+        let chain_head = setting("chain-head").unwrap_or(String::from(METER_CHAIN_HEAD_PATH));
+        let batch_size = match config {
+            Some(ref c) => c.batch_size,
+            None => None,
+        };
+
+        start_meter(setting("dh-params").unwrap(), setting("sign-key").unwrap(), lan_socket, chain_head, batch_size, matches.opt_str("script"), &mut passphrase);
+    }
+
+    if mode == "regulator" {
+        // only the provider's key, the script and the output format make sense here
+        let others = ["public-coms-key", "dh-params", "sign-key", "meter-sign-pk", "customer-sign-pk", "regulator-sign-pk", "price-cap", "lan-socket", "wan-socket",
+                      "config", "chain-head", "customers", "from"];
+        if others.iter().any(|o| matches.opt_present(o)) {
+            println!("Those options do not work with regulator");
             print_usage(&executable_name, &opts);
         }
 
-        let lan_socket = if matches.opt_present("lan-socket") {
-            matches.opt_str("lan-socket").unwrap()
-        } else {
-            String::from(DEFAULT_LAN_SOCKET_ADDR)
-        };
+        // there is no configuration file for the regulator
+        if !matches.opt_present("provider-sign-pk") {
+            println!("Missing some required options: --provider-sign-pk\n");
+            print_usage(&executable_name, &opts);
+        }
 
-        start_meter(matches.opt_str("dh-params").unwrap(), matches.opt_str("sign-key").unwrap(), lan_socket);
+        start_regulator(matches.opt_str("regulator").unwrap(), matches.opt_str("provider-sign-pk").unwrap(), matches.opt_str("script"), &mut passphrase);
     }
 
     println!("No mode specified!");
     print_usage(&executable_name, &opts);
 }
 
+/// Exits if any of options is in neither the command line nor the configuration file
+fn require<F: Fn(&str) -> Option<String>>(setting: &F, options: &[&str], executable_name: &str, opts: &Options) {
+    let mut missing = Vec::new();
+    for option in options {
+        if setting(*option).is_none() {
+            missing.push(format!("--{} (or {} in the configuration file)", option, config::setting_name(*option)));
+        }
+    }
+
+    if !missing.is_empty() {
+        println!("Missing some required options: {}\n", missing.join(", "));
+        print_usage(executable_name, opts);
+    }
+}
+
 fn assert_file_exists(path_str: &String) {
     let path = Path::new(path_str);
 
     if !(path.is_file()) {
-        println!("{} is not a file. Exiting.", path_str);
+        Event::new("error", format!("{} is not a file. Exiting.", path_str)).field("file", path_str.as_str()).print();
         exit(1); // failure
     }
 }
 
-fn start_meter(dhparams_path: String, sign_key_path: String, lan_socket_path: String) -> ! {
+/// Prints the breakdown of each message in the capture at capture_path. Exits with status 1 if any check fails.
+fn inspect_capture(capture_path: String, sender: inspect::Sender, dhparams_path: Option<String>, meter_sign_pk_path: Option<String>,
+                   provider_sign_pk_path: Option<String>, regulator_sign_pk_path: Option<String>) -> ! {
+    let read_pk = |path: Option<String>| path.map(|p| or_exit(keyfile::read_sign_pk(&p), &p));
+
+    // read_or_gen_params would make up new parameters, which no commitment would open with
+    let params = dhparams_path.map(|path| {
+        assert_file_exists(&path);
+        read_or_gen_params(path)
+    });
+
+    let keys = inspect::InspectKeys {
+        meter: read_pk(meter_sign_pk_path),
+        provider: read_pk(provider_sign_pk_path),
+        regulator: read_pk(regulator_sign_pk_path),
+        params: params,
+    };
+
+    let messages = if capture_path == "-" {
+        let stdin = io::stdin();
+        let input = stdin.lock();
+        inspect::inspect(input, sender, &keys)
+    } else {
+        match File::open(&capture_path) {
+            Ok(f) => inspect::inspect(BufReader::new(f), sender, &keys),
+            Err(e) => {
+                output::error(format!("Error opening {}: {}. Exiting.", capture_path, e));
+                exit(1);
+            },
+        }
+    };
+
+    for message in &messages {
+        Event::new("inspected_message", message.to_text().trim_right())
+            .field("header", message.kind.as_str())
+            .field("fields", message.fields_json())
+            .field("checks", message.checks_json())
+            .field("passed", message.passed())
+            .print();
+    }
+
+    let failed = messages.iter().filter(|m| !m.passed()).count();
+    Event::new("inspected", format!("{} messages, {} with failed checks", messages.len(), failed))
+        .field("messages", messages.len()).field("failed", failed).print();
+
+    exit(if failed == 0 { 0 } else { 1 });
+}
+
+/// Unwraps the result of a key file operation or exits with an error message
+fn or_exit<T>(result: Result<T, keyfile::KeyFileError>, path: &str) -> T {
+    match result {
+        Ok(t) => t,
+        Err(e) => {
+            Event::new("error", format!("Error with the key file {}: {}. Exiting.", path, e)).field("file", path).print();
+            exit(1); // failure
+        },
+    }
+}
+
+// the whole of a file, e.g. a signed tariff, countersignature or price cap
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut buf)));
+    Ok(buf)
+}
+
+fn write_file(path: &str, contents: &[u8]) -> io::Result<()> {
+    File::create(path).and_then(|mut f| f.write_all(contents))
+}
+
+fn start_meter(dhparams_path: String, sign_key_path: String, lan_socket_path: String, chain_head_path: String, batch_size: Option<usize>,
+               script: Option<String>, passphrase: &mut PassphraseSource) -> ! {
     assert_file_exists(&sign_key_path);
 
-    println!("Starting a meter on {} using the diffie-hellman parameters at {} and the signing key at {}", lan_socket_path, dhparams_path, sign_key_path);
+    Event::new("starting", format!("Starting a meter on {} using the diffie-hellman parameters at {} and the signing key at {}", lan_socket_path, dhparams_path, sign_key_path))
+        .field("role", "meter").field("lan_socket", lan_socket_path.as_str()).print();
     
     // get dh-params
     let dh_params = read_or_gen_params(dhparams_path);
 
     // get signing key
-    let (_, sk) = sign::get_keypair(sign_key_path);
+    let sk = or_exit(keyfile::read_sign_sk(&sign_key_path, passphrase), &sign_key_path);
 
     // set up channel
-    let channel = match TcpStream::connect(lan_socket_path.as_str()) {
+    let channel = match LanStream::connect(&lan_socket_path) {
         Ok(c) => c,
         Err(e) => {
-            println!("Error connecting to {}: {}.", lan_socket_path, e);
+            output::error(format!("Error connecting to {}: {}.", lan_socket_path, e));
             exit(1);
         },
     };
 
-    let meter = MeterState::new(channel, sk, dh_params);
+    let mut meter = MeterState::new(channel, sk, dh_params);
+    if let Some(size) = batch_size {
+        meter.set_batch_size(size);
+    }
+
+    // continue the hash chain from the last run
+    match load_chain_head(&chain_head_path, &meter.public_key()) {
+        Ok(head) => meter.set_chain_head(head),
+        Err(e) => {
+            output::error(format!("Error reading {}: {}.", chain_head_path, e));
+            exit(1);
+        },
+    };
 
     let mut shell = shell::InteractiveShell::new("meter", meter);
 
-    // consume command
-    fn consume(meter: &mut MeterState<TcpStream>, args: Vec<String>) {
-        if args.len() != 2 {
-            println!("There should be two integer arguments to this command: cons and other");
-            return;
+    fn save_meter_chain_head(meter: &MeterState<LanStream>, path: &str) {
+        if let Err(e) = save_chain_head(path, &meter.public_key(), meter.chain_head()) {
+            output::warning(format!("Warning: failed to save the chain head to {}: {}", path, e));
         }
+    }
 
-        let cons: i32 = match args[0].parse() {
-            Ok(c) => c,
-            Err(_) => {
-                println!("Error parsing cons. It should be a 32 bit signed integer.");
-                return;
-            },
-        };
+    // consume command
+    fn consume(meter: &mut MeterState<LanStream>, args: &Args, chain_head_path: &str) -> Result<(), String> {
+        let cons = args.integer(0) as i32;
+        let other = args.integer(1) as u64;
 
-        let other: u64 = match args[1].parse() {
-            Ok(o) => o,
-            Err(_) => {
-                println!("Error parsing other. It should be a unsigned integer");
-                return;
+        meter.consume(&IntegerConsumption{ hour_of_week: other, units_consumed: cons });
+        save_meter_chain_head(meter, chain_head_path);
+        Ok(())
+    }
+
+    let path = chain_head_path.clone();
+    shell.register_command("consume", vec!(ArgSpec::integer("CONS", 0, i32::max_value() as i64), ArgSpec::unsigned("OTHER")), "Consume CONS units at time OTHER",
+                           Box::new(move |meter: &mut MeterState<LanStream>, args: &Args| consume(meter, args, &path)));
+
+    fn end_batch(meter: &mut MeterState<LanStream>, chain_head_path: &str) -> Result<(), String> {
+        meter.end_batch();
+        save_meter_chain_head(meter, chain_head_path);
+        Ok(())
+    }
+
+    let path = chain_head_path.clone();
+    shell.register_command("end_batch", vec!(), "Sign the readings sent since the last batch so the customer can bill them",
+                           Box::new(move |meter: &mut MeterState<LanStream>, _: &Args| end_batch(meter, &path)));
+
+    fn replay(meter: &mut MeterState<LanStream>, args: &Args, chain_head_path: &str) -> Result<(), String> {
+        let path = args.word(0);
+        let speedup = args.integer(1) as u64;
+
+        let readings = match File::open(path) {
+            Ok(f) => match load_profile::read_csv(BufReader::new(f)) {
+                Ok(r) => r,
+                Err(e) => return Err(format!("Error in the load profile {}: {}", path, e)),
             },
+            Err(e) => return Err(format!("Error opening {}: {}", path, e)),
         };
 
-        meter.consume(&IntegerConsumption{ hour_of_week: other, units_consumed: cons });
+        if readings.is_empty() {
+            return Err(format!("The load profile {} has no readings", path));
+        }
+
+        if let Err(e) = load_profile::check_one_week(&readings) {
+            return Err(format!("Not replaying the load profile {}: {}", path, e));
+        }
+
+        // keep the gaps between readings, scaled down by speedup
+        let first_hour = readings[0].hour;
+        let start = Instant::now();
+        for reading in &readings {
+            if speedup != 0 {
+                let due = Duration::from_millis((reading.hour - first_hour) * load_profile::SECONDS_PER_HOUR * 1000 / speedup);
+                let elapsed = start.elapsed();
+                if due > elapsed {
+                    thread::sleep(due - elapsed);
+                }
+            }
+
+            meter.consume(&reading.to_consumption());
+            save_meter_chain_head(meter, chain_head_path);
+        }
+
+        meter.end_batch();
+        save_meter_chain_head(meter, chain_head_path);
+
+        let hours = readings[readings.len() - 1].hour - first_hour + 1;
+        Event::new("replayed", format!("Replayed {} hourly readings covering {} hours", readings.len(), hours))
+            .field("readings", readings.len()).field("hours", hours).print();
+        Ok(())
     }
 
-    shell.register_command("consume", "consume CONS OTHER\t", "Consumer CONS units at time OTHER", Box::new(consume));
-    
-    shell.start();
+    shell.register_command("replay", vec!(ArgSpec::word("FILE"), ArgSpec::unsigned("SPEEDUP")),
+                           "Send the readings in the load profile FILE, SPEEDUP times faster than real time (0 to send them straight away)",
+                           Box::new(move |meter: &mut MeterState<LanStream>, args: &Args| replay(meter, args, &chain_head_path)));
+
+    shell.run(script);
+}
+
+// what the customer needs to check tariffs against the regulator's requirements
+struct RegulatorSettings {
+    sign_pk_path: String,
+    require_countersignature: bool,
+    // a price cap signed by the regulator (see start_regulator)
+    price_cap_path: Option<String>,
 }
 
 fn start_customer(dhparams_path: String, private_coms_key_path: String, public_coms_key_path: String, meter_sign_pk_path: String,
-                  provider_sign_pk_path: String, wan_socket: String, lan_socket: String) -> !{
+                  provider_sign_pk_path: String, sign_key_path: Option<String>, wan_socket: String, lan_socket: String, prices: Prices, regulator: Option<RegulatorSettings>,
+                  min_statement_weights: Option<usize>, script: Option<String>, passphrase: &mut PassphraseSource) -> !{
     assert_file_exists(&private_coms_key_path);
     assert_file_exists(&public_coms_key_path);
     assert_file_exists(&meter_sign_pk_path);
     assert_file_exists(&provider_sign_pk_path);
+    if let Some(ref r) = regulator {
+        assert_file_exists(&r.sign_pk_path);
+        if let Some(ref path) = r.price_cap_path {
+            assert_file_exists(path);
+        }
+    }
+    if let Some(ref path) = sign_key_path {
+        assert_file_exists(path);
+    }
 
-    println!("Starting a customer on on {} (LAN) -> {} (WAN) using communication keys {} and {} and trusting signing keys {} and {}", lan_socket, wan_socket, private_coms_key_path, public_coms_key_path, meter_sign_pk_path, provider_sign_pk_path);
+    Event::new("starting", format!("Starting a customer on on {} (LAN) -> {} (WAN) using communication keys {} and {} and trusting signing keys {} and {}", lan_socket, wan_socket, private_coms_key_path, public_coms_key_path, meter_sign_pk_path, provider_sign_pk_path))
+        .field("role", "customer").field("lan_socket", lan_socket.as_str()).field("wan_socket", wan_socket.as_str()).print();
 
     // get dh-params
     let dh_params = read_or_gen_params(dhparams_path);
 
     // get coms keys
-    let (coms_pks, coms_keys) = get_keys(private_coms_key_path, public_coms_key_path);
+    let (coms_pks, coms_keys) = or_exit(keyfile::read_coms_keys(&private_coms_key_path, &public_coms_key_path, passphrase), &private_coms_key_path);
 
     // get signing public keys
-    let meter_sign_pk = sign::get_pubkey(meter_sign_pk_path);
-    let provider_sign_pk = sign::get_pubkey(provider_sign_pk_path);
+    let meter_sign_pk = or_exit(keyfile::read_sign_pk(&meter_sign_pk_path), &meter_sign_pk_path);
+    let provider_sign_pk = or_exit(keyfile::read_sign_pk(&provider_sign_pk_path), &provider_sign_pk_path);
 
     // start listening for connections from the meter
-    let listener = match TcpListener::bind(lan_socket.as_str()) {
-        Err(e) => { panic!("Error listening for TCP connections: {}.", e); }
-        Ok(l) => {l},
+    let listener = match LanListener::bind(&lan_socket) {
+        Ok(l) => l,
+        Err(e) => {
+            output::error(format!("Error listening on {}: {}.", lan_socket, e));
+            exit(1);
+        },
     };
 
     // start crypto with provider
@@ -306,68 +714,276 @@ fn start_customer(dhparams_path: String, private_coms_key_path: String, public_c
     };
     client.blocking_off(1);
 
-    let meter_stream = listener.incoming().next().unwrap().unwrap();
+    let meter_stream = listener.accept_one().unwrap();
     meter_stream.set_nonblocking(true).expect("set_nonblocking call in start_customer failed");
     
-    let customer = CustomerState::new(meter_stream, client, [1; 24*7], provider_sign_pk, meter_sign_pk, dh_params);
+    let mut customer = CustomerState::new(meter_stream, client, prices, provider_sign_pk, meter_sign_pk, dh_params);
+
+    // countersign dispute transcripts
+    if let Some(path) = sign_key_path {
+        let sk = or_exit(keyfile::read_sign_sk(&path, passphrase), &path);
+        customer.set_signing_key(sk);
+    }
+
+    // only accept tariffs the regulator allows
+    if let Some(r) = regulator {
+        let regulator_pk = or_exit(keyfile::read_sign_pk(&r.sign_pk_path), &r.sign_pk_path);
+        let mut policy = TariffPolicy::new(regulator_pk, r.require_countersignature);
+
+        if let Some(path) = r.price_cap_path {
+            let signed_caps = match read_file(&path) {
+                Ok(b) => b,
+                Err(e) => {
+                    output::error(format!("Error reading {}: {}. Exiting.", path, e));
+                    exit(1);
+                },
+            };
+            if !regulator::set_price_cap::<i32, u64, IntegerConsumption>(&mut policy, &signed_caps) {
+                output::error(format!("{} is not a price cap signed by the regulator. Exiting.", path));
+                exit(1);
+            }
+        }
+
+        customer.set_tariff_policy(0, policy);
+    }
+
+    // by default only bill totals are proven. A minimum number of weights allows more statements, but two of them which differ in one hour reveal that reading
+    if let Some(min) = min_statement_weights {
+        customer.set_statement_policy(StatementPolicy::new(min));
+    }
 
     let mut shell = shell::InteractiveShell::new("customer", customer);
 
     // shell commands
-    fn get_consumption(customer: &mut CustomerState<client::Client, TcpStream>, args: Vec<String>) {
-        shell::complain_arg(&args);
+    fn get_consumption(customer: &mut CustomerState<client::Client, LanStream>, _: &Args) -> Result<(), String> {
         customer.read_meter_messages();
+        Ok(())
     }
 
-    shell.register_command("get_cons", "get_cons\t\t", "Receive consumption messages from the smartmeter", Box::new(get_consumption));
+    shell.register_command("get_cons", vec!(), "Receive consumption messages from the smartmeter", Box::new(get_consumption));
 
-    fn get_prices(customer: &mut CustomerState<client::Client, TcpStream>, args: Vec<String>) {
-        shell::complain_arg(&args);
-        customer.read_provider_messages();
+    fn get_prices(customer: &mut CustomerState<client::Client, LanStream>, _: &Args) -> Result<(), String> {
+        read_provider_messages(customer);
+        Ok(())
     }
 
-    shell.register_command("get_prices", "get_prices\t\t", "Receive new prices from the provider", Box::new(get_prices));
+    shell.register_command("get_prices", vec!(), "Receive new prices and answer dispute and statement requests from the provider", Box::new(get_prices));
 
-    fn send_bill(customer: &mut CustomerState<client::Client, TcpStream>, args: Vec<String>) {
-        shell::complain_arg(&args);
-        println!("Checking for new prices...");
-        customer.read_provider_messages();
-        println!("Checking for new consumption statistics...");
+    fn send_bill(customer: &mut CustomerState<client::Client, LanStream>, _: &Args) -> Result<(), String> {
+        output::info("progress", "Checking for new prices...");
+        read_provider_messages(customer);
+        output::info("progress", "Checking for new consumption statistics...");
         customer.read_meter_messages();
-        println!("Calculating the bill and the proof...");
-        println!("The bill is {}.", customer.send_billing_information());
+        output::info("progress", "Calculating the bill and the proof...");
+        for bill in customer.send_billing_information() {
+            Event::new("bill", format!("The bill is {}.", bill)).field("amount", bill).print();
+        }
+        output::info("note", "The consumption table will be cleared when the provider's receipt arrives. Use receipts to check for it.");
+        Ok(())
     }
 
-    shell.register_command("send_bill", "send_bill\t\t", "Send the bill and proof to the provider", Box::new(send_bill));
+    shell.register_command("send_bill", vec!(), "Send the bill and proof to the provider", Box::new(send_bill));
     
-    fn cons_table(customer: &mut CustomerState<client::Client, TcpStream>, args: Vec<String>) {
-        shell::complain_arg(&args);
+    fn cons_table(customer: &mut CustomerState<client::Client, LanStream>, _: &Args) -> Result<(), String> {
         customer.read_meter_messages();
-        println!("{}", customer.readable_consumption_table());
+
+        let rows: Vec<Value> = customer.preview_bill(0, 0).slots.iter().map(|s| output::object(vec!(("other", Value::from(s.other)), ("units", Value::from(s.units))))).collect();
+        Event::new("consumption_table", customer.readable_consumption_table()).field("rows", rows).print();
+        Ok(())
     }
 
-    shell.register_command("cons_table", "cons_table\t\t", "Display the state of the consumption table", Box::new(cons_table));
+    shell.register_command("cons_table", vec!(), "Display the state of the consumption table", Box::new(cons_table));
+
+    fn preview_bill(customer: &mut CustomerState<client::Client, LanStream>, args: &Args) -> Result<(), String> {
+        read_provider_messages(customer);
+        customer.read_meter_messages();
+
+        let period_hours = if args.len() == 1 { args.integer(0) as u64 } else { 24*7 };
+        let preview = customer.preview_bill(0, period_hours);
 
-    shell.start();
+        let mut text = format!("Calculated with tariff number {} ({})\n", preview.tariff_number, preview.tariff_hash);
+        text += &format!("{:<10}{:>10}{:>10}{:>12}\n", "Slot", "Units", "Price", "Amount");
+        let mut slots = Vec::new();
+        for slot in &preview.slots {
+            text += &format!("{:<10}{:>10}{:>10}{:>12}\n", tariff::slot_name(slot.other as usize), slot.units, slot.price, slot.amount);
+            slots.push(output::object(vec!(("other", Value::from(slot.other)), ("slot", Value::from(tariff::slot_name(slot.other as usize))),
+                                           ("units", Value::from(slot.units)), ("price", Value::from(slot.price)), ("amount", Value::from(slot.amount)))));
+        }
+
+        text += &format!("{:<10}{:>10}{:>22}\n", "Day", "Units", "Amount");
+        let mut days = Vec::new();
+        for day in &preview.days {
+            text += &format!("{:<10}{:>10}{:>22}\n", tariff::day_name(day.day as usize), day.units, day.amount);
+            days.push(output::object(vec!(("day", Value::from(tariff::day_name(day.day as usize))), ("units", Value::from(day.units)), ("amount", Value::from(day.amount)))));
+        }
+
+        text += &format!("The bill would be {}.", preview.total);
+        if let Some(projected) = preview.projected_total {
+            text += &format!("\nIf the rest of a {} hour billing period goes the same way the bill will be about {}.", period_hours, projected);
+        }
+        if preview.unsigned_readings > 0 {
+            text += &format!("\n{} readings are not included because the meter has not signed them yet (see the meter's end_batch).", preview.unsigned_readings);
+        }
+        if customer.awaiting_receipt() {
+            text += "\nThese readings have already been billed and are waiting for the provider's receipt.";
+        }
+
+        Event::new("bill_preview", text)
+            .field("tariff_number", preview.tariff_number)
+            .field("tariff_hash", preview.tariff_hash)
+            .field("slots", slots)
+            .field("days", days)
+            .field("total", preview.total)
+            .field("period_hours", period_hours)
+            .field("projected_total", preview.projected_total.map(Value::from).unwrap_or(Value::Null))
+            .field("unsigned_readings", preview.unsigned_readings)
+            .field("awaiting_receipt", customer.awaiting_receipt())
+            .print();
+        Ok(())
+    }
+
+    shell.register_command("preview_bill", vec!(ArgSpec::unsigned("PERIOD_HOURS").optional()),
+                           "Show what the bill would be and how it is made up, without sending it. The projection assumes a billing period of PERIOD_HOURS (one week by default).",
+                           Box::new(preview_bill));
+
+    fn disputes(customer: &mut CustomerState<client::Client, LanStream>, _: &Args) -> Result<(), String> {
+        read_provider_messages(customer);
+
+        for transcript in customer.dispute_transcripts(0) {
+            let mut text = format!("Dispute {} of bill {}:", transcript.dispute_id, transcript.bill_number);
+            for opening in &transcript.openings {
+                text += &format!("\n\tslot {}: {} units ({})", opening.other, opening.cons, if opening.valid { "accepted" } else { "rejected" });
+            }
+            if !transcript.missing.is_empty() {
+                text += &format!("\n\tnot opened: {:?}", transcript.missing);
+            }
+            if transcript.countersignature.is_empty() {
+                text += "\n\tnot countersigned (there is no signing key or the transcript does not match what was opened)";
+            }
+
+            Event::new("dispute", text)
+                .field("dispute_id", transcript.dispute_id)
+                .field("bill_number", transcript.bill_number)
+                .field("openings", openings_json(&transcript.openings))
+                .field("missing", transcript.missing.clone())
+                .field("countersigned", !transcript.countersignature.is_empty())
+                .print();
+        }
+        Ok(())
+    }
+
+    fn receipts(customer: &mut CustomerState<client::Client, LanStream>, _: &Args) -> Result<(), String> {
+        read_provider_messages(customer);
+
+        for receipt in customer.receipts(0) {
+            Event::new("receipt", format!("Bill {} for slots {} to {}: {} accepted (commitments {}, tariff {})", receipt.bill_number, receipt.period_start,
+                                          receipt.period_end, receipt.amount, receipt.commitments_hash, receipt.tariff_hash))
+                .field("bill_number", receipt.bill_number)
+                .field("period_start", receipt.period_start)
+                .field("period_end", receipt.period_end)
+                .field("amount", receipt.amount)
+                .field("commitments_hash", receipt.commitments_hash.as_str())
+                .field("tariff_hash", receipt.tariff_hash.as_str())
+                .print();
+        }
+
+        if customer.awaiting_receipt() {
+            output::info("awaiting_receipt", "Still waiting for the receipt for the last bill.");
+        }
+        Ok(())
+    }
+
+    shell.register_command("receipts", vec!(), "Receive and display the provider's signed receipts for our bills", Box::new(receipts));
+
+    shell.register_command("disputes", vec!(), "Answer dispute requests and display the signed dispute transcripts", Box::new(disputes));
+
+    fn expect_bill(customer: &mut CustomerState<client::Client, LanStream>, args: &Args) -> Result<(), String> {
+        let expected = args.integer(0);
+
+        if customer.awaiting_receipt() {
+            output::info("progress", "Waiting for the receipt for the last bill...");
+            for problem in customer.wait_for_receipt(Duration::from_secs(RECEIPT_TIMEOUT_SECS)) {
+                output::error(problem);
+            }
+            if customer.awaiting_receipt() {
+                return Err(format!("no valid receipt arrived within {} seconds", RECEIPT_TIMEOUT_SECS));
+            }
+        }
+
+        match customer.receipts(0).last() {
+            Some(receipt) if receipt.amount == expected => Ok(()),
+            Some(receipt) => Err(format!("the provider accepted a bill of {}, not {}", receipt.amount, expected)),
+            None => Err(String::from("no bill has been accepted")),
+        }
+    }
+
+    shell.register_assertion("expect_bill", vec!(ArgSpec::integer("AMOUNT", i64::min_value(), i64::max_value())), format!("Wait (for up to {} seconds) for the receipt for the last bill and check the amount accepted", RECEIPT_TIMEOUT_SECS), Box::new(expect_bill));
+
+    shell.run(script);
+}
+
+// read the providers' messages and report any which were not acted on
+fn read_provider_messages(customer: &mut CustomerState<client::Client, LanStream>) {
+    for problem in customer.read_provider_messages() {
+        output::error(problem);
+    }
+}
+
+// the openings of a dispute transcript for --json
+fn openings_json(openings: &[DisputeOpening]) -> Vec<Value> {
+    openings.iter().map(|o| output::object(vec!(("other", Value::from(o.other)), ("units", Value::from(o.cons)), ("valid", Value::from(o.valid))))).collect()
+}
+
+// a tariff for --json
+fn prices_json(prices: &Prices) -> Vec<Value> {
+    prices.iter().map(|p| Value::from(*p)).collect()
+}
+
+// a tariff in a CSV file, or a JSON one if the name ends in .json
+fn read_tariff_file(path: &str) -> Result<Prices, String> {
+    let mut text = String::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+        return Err(format!("Error reading {}: {}", path, e));
+    }
+
+    let prices = if path.ends_with(".json") {
+        tariff::from_json(&text)
+    } else {
+        tariff::read_csv(text.as_bytes())
+    };
+
+    prices.map_err(|e| format!("Error in the tariff {}: {}", path, e))
+}
+
+// the provider shell's staged tariff changes
+struct TariffDraft {
+    // the draft tariff, if one has been started
+    prices: Option<Prices>,
 }
 
-fn start_provider(dhparams_path: String, private_coms_key_path: String, public_coms_key_path: String, sign_key_path: String, sign_trusted_pk_path: String, wan_socket: String) -> ! {
+fn start_provider(dhparams_path: String, private_coms_key_path: String, public_coms_key_path: String, sign_key_path: String, sign_trusted_pk_path: String,
+                  customer_sign_pk_path: Option<String>, wan_socket: String, chain_head_path: String, prices: Prices,
+                  script: Option<String>, passphrase: &mut PassphraseSource) -> ! {
     assert_file_exists(&private_coms_key_path);
     assert_file_exists(&public_coms_key_path);
     assert_file_exists(&sign_key_path);
     assert_file_exists(&sign_trusted_pk_path);
+    if let Some(ref path) = customer_sign_pk_path {
+        assert_file_exists(path);
+    }
 
-    println!("Starting a provider on {}, using the diffie-hellman parameters at {}, communication keys at {} and {} and signing keys at {} and {}", wan_socket, dhparams_path, private_coms_key_path, public_coms_key_path, sign_key_path, sign_trusted_pk_path);
+    Event::new("starting", format!("Starting a provider on {}, using the diffie-hellman parameters at {}, communication keys at {} and {} and signing keys at {} and {}", wan_socket, dhparams_path, private_coms_key_path, public_coms_key_path, sign_key_path, sign_trusted_pk_path))
+        .field("role", "provider").field("wan_socket", wan_socket.as_str()).print();
 
     // get dh-params
     let dh_params = read_or_gen_params(dhparams_path);
 
-    // get coms keys
-    let (coms_pks, coms_keys) = get_keys(private_coms_key_path, public_coms_key_path);
+    // get coms keys (the passphrase is shared between key files)
+    let (coms_pks, coms_keys) = or_exit(keyfile::read_coms_keys(&private_coms_key_path, &public_coms_key_path, passphrase), &private_coms_key_path);
 
     // get signing keys
-    let (_, sign_sk) = sign::get_keypair(sign_key_path);
-    let sign_pk = sign::get_pubkey(sign_trusted_pk_path);
+    let sign_sk = or_exit(keyfile::read_sign_sk(&sign_key_path, passphrase), &sign_key_path);
+    let sign_pk = or_exit(keyfile::read_sign_pk(&sign_trusted_pk_path), &sign_trusted_pk_path);
 
     // start listening for connections
     let listener = match TcpListener::bind(wan_socket.as_str()) {
@@ -379,54 +995,403 @@ fn start_provider(dhparams_path: String, private_coms_key_path: String, public_c
     let server = server::do_key_exchange(listener.incoming().next().unwrap(), &coms_keys, &coms_pks).unwrap();
 
     // begin billing protocol layer
-    let provider = ProviderState::new(server, [1; 7*24], Keys{ my_sk: sign_sk, their_pk: sign_pk }, dh_params);
+    let mut provider = ProviderState::new(server, prices, Keys{ my_sk: sign_sk, their_pk: sign_pk }, dh_params);
+
+    // check the customer's countersignatures of dispute transcripts
+    if let Some(path) = customer_sign_pk_path {
+        provider.set_customer_key(or_exit(keyfile::read_sign_pk(&path), &path));
+    }
+
+    // bills must continue the meter's hash chain from where the last bill stopped
+    let chain_head = match load_chain_head(&chain_head_path, provider.meter_key()) {
+        Ok(h) => h,
+        Err(e) => {
+            output::error(format!("Error reading {}: {}.", chain_head_path, e));
+            exit(1);
+        },
+    };
+    provider.set_chain_head(chain_head);
 
     let mut shell = shell::InteractiveShell::new("provider", provider);
 
     // shell commands
-    fn get_bill(provider: &mut ProviderState<server::Server>, args: Vec<String>) {
-        shell::complain_arg(&args);
-        provider.receive_billing_information();
+    fn get_bill(provider: &mut ProviderState<server::Server>, chain_head_path: &str) -> Result<(), String> {
+        if let Err(why) = provider.receive_billing_information() {
+            return Err(format!("Rejected the bill: {}. The customer sends it again with the latest tariff, so run get_bill again", why));
+        }
 
-        println!("The bill is {}", provider.pay_bill());
+        let amount = provider.pay_bill();
+        Event::new("bill", format!("The bill is {}", amount)).field("amount", amount).field("bill_number", provider.bills_received() - 1).print();
+
+        if let Err(e) = save_chain_head(chain_head_path, provider.meter_key(), provider.chain_head()) {
+            output::warning(format!("Warning: failed to save the chain head to {}: {}", chain_head_path, e));
+        }
+        Ok(())
     }
 
-    shell.register_command("get_bill", "get_bill\t\t", "Receive billing information from the customer and check that was calculated honestly", Box::new(get_bill));
+    shell.register_command("get_bill", vec!(), "Receive billing information from the customer and check that was calculated honestly",
+                           Box::new(move |provider: &mut ProviderState<server::Server>, _: &Args| get_bill(provider, &chain_head_path)));
+
+    fn change_price(provider: &mut ProviderState<server::Server>, args: &Args) -> Result<(), String> {
+        let new_price = args.integer(0) as i32;
+        let other = args.integer(1);
 
-    fn change_price(provider: &mut ProviderState<server::Server>, args: Vec<String>) {
-        if args.len() != 2 {
-            println!("There should be two integer arguments to this command: new_price and the corresponding hour of the week");
-            return;
+        let mut new_prices = provider.prices;
+        new_prices[other as usize] = new_price;
+        
+        provider.change_prices(&new_prices);
+        Ok(())
+    }
+
+    fn dispute(provider: &mut ProviderState<server::Server>, args: &Args) -> Result<(), String> {
+        let bill_number = args.integer(0) as usize;
+        if bill_number >= provider.bills_received() {
+            return Err(format!("Only {} bills have been received", provider.bills_received()));
         }
 
-        let new_price: i32 = match args[0].parse() {
-            Ok(p) => p,
-            Err(_) => {
-                println!("Error parsing the new price. It should be a 32-bit signed integer.");
-                return;
-            },
+        let others: Vec<u64> = (1..args.len()).map(|i| args.integer(i) as u64).collect();
+
+        output::info("progress", "Waiting for the customer to open the readings...");
+        let transcript = provider.request_dispute(bill_number, &others);
+
+        let mut lines = Vec::new();
+        for opening in &transcript.openings {
+            lines.push(format!("slot {}: {} units ({})", opening.other, opening.cons, if opening.valid { "matches the meter's commitment" } else { "DOES NOT MATCH" }));
+        }
+        if !transcript.missing.is_empty() {
+            lines.push(format!("The customer did not open: {:?}", transcript.missing));
+        }
+        lines.push(String::from(if transcript.countersignature.is_empty() { "The customer did not countersign the transcript" } else { "The customer countersigned the transcript" }));
+
+        Event::new("dispute", lines.join("\n"))
+            .field("dispute_id", transcript.dispute_id)
+            .field("bill_number", transcript.bill_number)
+            .field("openings", openings_json(&transcript.openings))
+            .field("missing", transcript.missing.clone())
+            .field("countersigned", !transcript.countersignature.is_empty())
+            .print();
+        Ok(())
+    }
+
+    shell.register_command("dispute", vec!(ArgSpec::unsigned("BILL"), ArgSpec::unsigned("OTHER").repeated()), "Ask the customer to reveal the readings for some slots of a bill", Box::new(dispute));
+
+    fn kwh(provider: &mut ProviderState<server::Server>, args: &Args) -> Result<(), String> {
+        let bill_number = args.integer(0) as usize;
+        if bill_number >= provider.bills_received() {
+            return Err(format!("Only {} bills have been received", provider.bills_received()));
+        }
+
+        let (first, last) = if args.len() == 3 { (args.integer(1) as usize, args.integer(2) as usize) } else { (0, 7*24 - 1) };
+        if first > last {
+            return Err(String::from("The first hour should not be after the last"));
+        }
+
+        let mut weights = [0; 7*24];
+        for hour in first..(last + 1) {
+            weights[hour] = 1;
+        }
+
+        output::info("progress", "Waiting for the customer's proof...");
+        let statement = provider.request_linear_statement(bill_number, &weights);
+        let value = statement.value;
+        let text = match value {
+            Some(v) => format!("Bill {} includes {} units consumed in hours {} to {} of the week", bill_number, v, first, last),
+            None if statement.refused => format!("The customer refused to reveal its consumption in hours {} to {} of bill {}", first, last, bill_number),
+            None => format!("The customer could not prove the consumption for bill {}", bill_number),
         };
 
-        let other: u8 = match args[1].parse() {
-            Ok(o) => o,
-            Err(_) => {
-                println!("Error parsing other. It should be a unsigned integer lower than 168");
-                return;
-            },
+        Event::new("consumption_statement", text)
+            .field("bill_number", bill_number)
+            .field("first_hour", first)
+            .field("last_hour", last)
+            .field("proven", value.is_some())
+            .field("refused", statement.refused)
+            .field("units", value.map(Value::from).unwrap_or(Value::Null))
+            .print();
+        Ok(())
+    }
+
+    shell.register_command("kwh", vec!(ArgSpec::unsigned("BILL"), ArgSpec::integer("FIRST", 0, 7*24 - 1).optional(), ArgSpec::integer("LAST", 0, 7*24 - 1)), "Ask the customer to prove its consumption in a bill, optionally only between two hours of the week", Box::new(kwh));
+
+    fn below(provider: &mut ProviderState<server::Server>, args: &Args) -> Result<(), String> {
+        let (first, last) = (args.integer(0) as usize, args.integer(1) as usize);
+        let threshold = args.integer(2);
+
+        if first > last || last >= provider.bills_received() {
+            return Err(format!("The bills should be in order and only {} bills have been received", provider.bills_received()));
+        }
+
+        output::info("progress", "Waiting for the customer's proof...");
+        let statement = provider.request_threshold_statement(first, last, threshold);
+        let text = if statement.proven {
+            format!("The customer consumed less than {} units in bills {} to {}", threshold, first, last)
+        } else if statement.refused {
+            format!("The customer refused to say whether it consumed less than {} units in bills {} to {}", threshold, first, last)
+        } else {
+            format!("The customer could not prove that it consumed less than {} units in bills {} to {}", threshold, first, last)
+        };
+
+        Event::new("threshold_statement", text)
+            .field("first_bill", first)
+            .field("last_bill", last)
+            .field("threshold", threshold)
+            .field("proven", statement.proven)
+            .field("refused", statement.refused)
+            .print();
+        Ok(())
+    }
+
+    shell.register_command("below", vec!(ArgSpec::unsigned("FIRST"), ArgSpec::unsigned("LAST"), ArgSpec::integer("THRESHOLD", 1, i64::max_value())), "Ask the customer to prove that its consumption in some bills is below a threshold, without revealing it", Box::new(below));
+
+    shell.register_command("change_price", vec!(ArgSpec::integer("NEW_PRICE", i32::min_value() as i64, i32::max_value() as i64), ArgSpec::integer("HOUR", 0, 7*24 - 1)),
+                           "Change the price for a specified hour and send the new prices to the customer, without a countersignature (see tariff_sign)", Box::new(change_price));
+
+    // tariff changes are staged in a draft and sent all at once
+    let draft = Rc::new(RefCell::new(TariffDraft { prices: None }));
+
+    fn tariff_show(provider: &mut ProviderState<server::Server>, draft: &TariffDraft) -> Result<(), String> {
+        let draft_changes = draft.prices.map(|p| tariff::diff(&provider.prices, &p).len());
+
+        let mut text = format!("Tariff number {} ({}):\n{}", provider.tariff_number(), provider.tariff_hash(), tariff::table(&provider.prices));
+        if let Some(changes) = draft_changes {
+            text += &format!("The draft changes {} hours. Use tariff_diff to see them.\n", changes);
+        }
+
+        Event::new("tariff", text.trim_right())
+            .field("tariff_number", provider.tariff_number())
+            .field("tariff_hash", provider.tariff_hash())
+            .field("prices", prices_json(&provider.prices))
+            .field("draft_changes", draft_changes.map(Value::from).unwrap_or(Value::Null))
+            .print();
+        Ok(())
+    }
+
+    let d = draft.clone();
+    shell.register_command("tariff_show", vec!(), "Display the current tariff and whether there is a draft",
+                           Box::new(move |provider: &mut ProviderState<server::Server>, _: &Args| tariff_show(provider, &d.borrow())));
+
+    fn tariff_load(provider: &mut ProviderState<server::Server>, args: &Args, draft: &mut TariffDraft) -> Result<(), String> {
+        let path = args.word(0);
+        let p = try!(read_tariff_file(path));
+        let changes = tariff::diff(&provider.prices, &p).len();
+        Event::new("tariff_draft", format!("Loaded the draft from {}. It changes {} hours.", path, changes)).field("file", path).field("changes", changes).print();
+        draft.prices = Some(p);
+        Ok(())
+    }
+
+    let d = draft.clone();
+    shell.register_command("tariff_load", vec!(ArgSpec::word("FILE")), "Replace the draft with the tariff in FILE (JSON if it ends in .json, otherwise CSV)",
+                           Box::new(move |provider: &mut ProviderState<server::Server>, args: &Args| tariff_load(provider, args, &mut d.borrow_mut())));
+
+    fn tariff_set(provider: &mut ProviderState<server::Server>, args: &Args, draft: &mut TariffDraft) -> Result<(), String> {
+        let price = args.integer(0) as i32;
+        let first = args.integer(1) as usize;
+        let last = if args.len() == 3 { args.integer(2) as usize } else { first };
+        if first > last {
+            return Err(String::from("The first hour should not be after the last"));
+        }
+
+        let mut prices = draft.prices.unwrap_or(provider.prices);
+        for hour in first..(last + 1) {
+            prices[hour] = price;
+        }
+        draft.prices = Some(prices);
+        Ok(())
+    }
+
+    let d = draft.clone();
+    shell.register_command("tariff_set", vec!(ArgSpec::integer("PRICE", i32::min_value() as i64, i32::max_value() as i64), ArgSpec::integer("FIRST", 0, 7*24 - 1),
+                                              ArgSpec::integer("LAST", 0, 7*24 - 1).optional()),
+                           "Set the price of hour FIRST (or hours FIRST to LAST) of the week in the draft",
+                           Box::new(move |provider: &mut ProviderState<server::Server>, args: &Args| tariff_set(provider, args, &mut d.borrow_mut())));
+
+    fn tariff_diff(provider: &mut ProviderState<server::Server>, draft: &TariffDraft) -> Result<(), String> {
+        let prices = match draft.prices {
+            Some(ref p) => p,
+            None => return Err(String::from("There is no draft. Start one with tariff_load or tariff_set.")),
         };
 
-        if other >= 168 {
-            println!("Other should be lower than 168 (it is an hour in a week)");
-            return;
+        let changes = tariff::diff(&provider.prices, prices);
+        let mut text = String::new();
+        let mut rows = Vec::new();
+        for change in &changes {
+            text += &format!("hour {} ({}): {} -> {}\n", change.hour, tariff::slot_name(change.hour), change.old, change.new);
+            rows.push(output::object(vec!(("hour", Value::from(change.hour)), ("slot", Value::from(tariff::slot_name(change.hour))),
+                                          ("old", Value::from(change.old)), ("new", Value::from(change.new)))));
         }
+        text += &format!("{} hours changed", changes.len());
 
-        let mut new_prices = provider.prices;
-        new_prices[other as usize] = new_price;
-        
-        provider.change_prices(&new_prices);
+        Event::new("tariff_diff", text).field("changes", rows).print();
+        Ok(())
+    }
+
+    let d = draft.clone();
+    shell.register_command("tariff_diff", vec!(), "List the hours whose price the draft changes",
+                           Box::new(move |provider: &mut ProviderState<server::Server>, _: &Args| tariff_diff(provider, &d.borrow())));
+
+    let d = draft.clone();
+    shell.register_command("tariff_discard", vec!(), "Throw the draft away",
+                           Box::new(move |_: &mut ProviderState<server::Server>, _: &Args| {
+                               d.borrow_mut().prices = None;
+                               Ok(())
+                           }));
+
+    fn tariff_publish(provider: &mut ProviderState<server::Server>, draft: &mut TariffDraft) -> Result<(), String> {
+        let prices = match draft.prices.take() {
+            Some(p) => p,
+            None => return Err(String::from("There is no draft to publish")),
+        };
+
+        provider.change_prices(&prices);
+        Event::new("tariff_published", format!("Published tariff number {}", provider.tariff_number())).field("tariff_number", provider.tariff_number()).print();
+        Ok(())
+    }
+
+    let d = draft.clone();
+    shell.register_command("tariff_publish", vec!(), "Sign the draft and send it to the customer, without a countersignature (see tariff_sign)",
+                           Box::new(move |provider: &mut ProviderState<server::Server>, _: &Args| tariff_publish(provider, &mut d.borrow_mut())));
+
+    // customers who require countersignatures only accept tariffs which go through the regulator (see start_regulator)
+    fn tariff_sign(provider: &mut ProviderState<server::Server>, args: &Args, draft: &mut TariffDraft) -> Result<(), String> {
+        let prices = match draft.prices.take() {
+            Some(p) => p,
+            None => return Err(String::from("There is no draft to sign")),
+        };
+
+        let path = args.word(0);
+        let signed_prices = provider.sign_tariff(&prices);
+        if let Err(e) = write_file(path, &signed_prices) {
+            return Err(format!("Error writing {}: {}", path, e));
+        }
+
+        Event::new("tariff_signed", format!("Signed the draft as tariff number {} into {}. Once the regulator has countersigned it, send it with tariff_send_countersigned.",
+                                            provider.tariff_number() + 1, path))
+            .field("tariff_number", provider.tariff_number() + 1).field("file", path).print();
+        Ok(())
+    }
+
+    shell.register_command("tariff_sign", vec!(ArgSpec::word("FILE")), "Sign the draft into FILE for the regulator to countersign, without sending it",
+                           Box::new(move |provider: &mut ProviderState<server::Server>, args: &Args| tariff_sign(provider, args, &mut draft.borrow_mut())));
+
+    fn tariff_send_countersigned(provider: &mut ProviderState<server::Server>, args: &Args) -> Result<(), String> {
+        let path = args.word(0);
+        let countersignature = match read_file(path) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Error reading {}: {}", path, e)),
+        };
+
+        if let Err(e) = provider.send_countersigned_tariff(&countersignature) {
+            return Err(format!("Could not send the tariff: {}", e));
+        }
+
+        Event::new("tariff_published", format!("Published tariff number {} with the regulator's countersignature", provider.tariff_number()))
+            .field("tariff_number", provider.tariff_number()).field("countersigned", true).print();
+        Ok(())
+    }
+
+    shell.register_command("tariff_send_countersigned", vec!(ArgSpec::word("COUNTERSIGNATURE_FILE")),
+                           "Send the tariff signed by tariff_sign with the regulator's countersignature from COUNTERSIGNATURE_FILE", Box::new(tariff_send_countersigned));
+
+    fn expect_bill(provider: &mut ProviderState<server::Server>, args: &Args) -> Result<(), String> {
+        let expected = args.integer(0);
+
+        let received = provider.bills_received();
+        if received == 0 {
+            return Err(String::from("no bill has been received"));
+        }
+
+        match provider.bill_amount(received - 1) {
+            Some(amount) if amount == expected => Ok(()),
+            Some(amount) => Err(format!("the last bill was {}, not {}", amount, expected)),
+            None => unreachable!(),
+        }
+    }
+
+    shell.register_assertion("expect_bill", vec!(ArgSpec::integer("AMOUNT", i64::min_value(), i64::max_value())), "Check the amount of the last bill received", Box::new(expect_bill));
+
+    fn expect_bills(provider: &mut ProviderState<server::Server>, args: &Args) -> Result<(), String> {
+        let expected = args.integer(0);
+
+        if provider.bills_received() as i64 == expected {
+            Ok(())
+        } else {
+            Err(format!("{} bills have been received, not {}", provider.bills_received(), expected))
+        }
+    }
+
+    shell.register_assertion("expect_bills", vec!(ArgSpec::unsigned("NUMBER")), "Check how many bills have been received", Box::new(expect_bills));
+
+    shell.run(script);
+}
+
+// the regulator shell's state
+struct RegulatorState {
+    regulator: Regulator,
+    // the tariffs countersigned must be signed by this key
+    provider_pk: sign::PublicKey,
+}
+
+// The regulator works on files which are passed to and from the provider and customers: it countersigns tariffs the provider has signed
+// (see tariff_sign in the provider's shell) and signs price caps for customers (see --price-cap).
+fn start_regulator(sign_key_path: String, provider_sign_pk_path: String, script: Option<String>, passphrase: &mut PassphraseSource) -> ! {
+    assert_file_exists(&sign_key_path);
+    assert_file_exists(&provider_sign_pk_path);
+
+    Event::new("starting", format!("Starting a regulator with the signing key at {}, countersigning tariffs signed by the key at {}", sign_key_path, provider_sign_pk_path))
+        .field("role", "regulator").print();
+
+    let state = RegulatorState {
+        regulator: Regulator::new(or_exit(keyfile::read_sign_sk(&sign_key_path, passphrase), &sign_key_path)),
+        provider_pk: or_exit(keyfile::read_sign_pk(&provider_sign_pk_path), &provider_sign_pk_path),
+    };
+
+    let mut shell = shell::InteractiveShell::new("regulator", state);
+
+    // shell commands
+    fn countersign(state: &mut RegulatorState, args: &Args) -> Result<(), String> {
+        let (tariff_path, countersignature_path) = (args.word(0), args.word(1));
+        let signed_prices = match read_file(tariff_path) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Error reading {}: {}", tariff_path, e)),
+        };
+
+        let (prices, tariff_number) = match regulator::signed_tariff_prices::<i32, u64, IntegerConsumption>(&signed_prices, &state.provider_pk) {
+            Some(t) => t,
+            None => return Err(format!("{} is not a tariff signed by the provider", tariff_path)),
+        };
+
+        if let Err(e) = write_file(countersignature_path, &state.regulator.countersign(&signed_prices)) {
+            return Err(format!("Error writing {}: {}", countersignature_path, e));
+        }
+
+        Event::new("countersigned", format!("Countersigned tariff number {} into {}:\n{}", tariff_number, countersignature_path, tariff::table(&prices)).trim_right())
+            .field("tariff_number", tariff_number)
+            .field("prices", prices_json(&prices))
+            .field("file", countersignature_path)
+            .print();
+        Ok(())
+    }
+
+    shell.register_command("countersign", vec!(ArgSpec::word("TARIFF_FILE"), ArgSpec::word("COUNTERSIGNATURE_FILE")),
+                           "Check that the tariff in TARIFF_FILE (from tariff_sign) is signed by the provider and write a countersignature of it to COUNTERSIGNATURE_FILE", Box::new(countersign));
+
+    fn sign_cap(state: &mut RegulatorState, args: &Args) -> Result<(), String> {
+        let (prices_path, cap_path) = (args.word(0), args.word(1));
+        let caps = try!(read_tariff_file(prices_path));
+
+        if let Err(e) = write_file(cap_path, &state.regulator.sign_price_cap::<i32, u64, IntegerConsumption>(&caps)) {
+            return Err(format!("Error writing {}: {}", cap_path, e));
+        }
+
+        Event::new("price_cap_signed", format!("Signed the price cap into {}:\n{}", cap_path, tariff::table(&caps)).trim_right())
+            .field("prices", prices_json(&caps))
+            .field("file", cap_path)
+            .print();
+        Ok(())
     }
 
-    shell.register_command("change_price", "change_price NEW_PRICE HOUR", "Change the price for a specified hour and send the new prices to the customer", Box::new(change_price));
+    shell.register_command("sign_cap", vec!(ArgSpec::word("PRICES_FILE"), ArgSpec::word("CAP_FILE")),
+                           "Sign the highest prices allowed, read from PRICES_FILE (JSON if it ends in .json, otherwise CSV), into CAP_FILE for customers' --price-cap", Box::new(sign_cap));
 
-    shell.start();
+    shell.run(script);
 }
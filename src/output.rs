@@ -0,0 +1,145 @@
+//! Output from the interactive demo
+//!
+//! Everything the shells report is an event: a kind (e.g. "bill"), a message for people and some fields for programs.
+//! Normally only the message is printed. With `--json` each event is printed as one JSON object on its own line instead:
+//!
+//! ```text
+//! {"amount":12,"event":"bill","message":"The bill is 12"}
+//! ```
+//!
+//! Errors are events of kind "error". Messages printed by the billing library itself (e.g. refusing a tariff) are not events and are still printed as text.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use serde_json::{Map, Value};
+use std::cell::Cell;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+// set once from the command line before any shell starts
+static JSON: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Print events as JSON from now on
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::SeqCst);
+}
+
+// set when an error is reported, so that a script can stop at the first one. Commands run on the shell's thread.
+thread_local!(static ERROR_REPORTED: Cell<bool> = Cell::new(false));
+
+/// Has an error been reported (see error) on this thread since the last call?
+pub fn take_error() -> bool {
+    ERROR_REPORTED.with(|e| {
+        let reported = e.get();
+        e.set(false);
+        reported
+    })
+}
+
+/// Are events printed as JSON?
+pub fn json() -> bool {
+    JSON.load(Ordering::SeqCst)
+}
+
+/// Something to report
+pub struct Event {
+    kind: String,
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl Event {
+    /// An event of kind kind, described for people by message (which may be several lines, e.g. a table)
+    pub fn new<S1: ToString, S2: ToString>(kind: S1, message: S2) -> Event {
+        Event {
+            kind: kind.to_string(),
+            message: message.to_string(),
+            fields: Map::new(),
+        }
+    }
+
+    /// Add a field to the JSON form of the event
+    pub fn field<V: Into<Value>>(mut self, name: &str, value: V) -> Event {
+        self.fields.insert(name.to_string(), value.into());
+        self
+    }
+
+    // the JSON form: the fields, the kind as "event" and the message as "message"
+    fn to_json(self) -> Value {
+        let mut object = self.fields;
+        object.insert(String::from("event"), Value::String(self.kind));
+        object.insert(String::from("message"), Value::String(self.message));
+        Value::Object(object)
+    }
+
+    /// Print the event in the current format
+    pub fn print(self) {
+        if json() {
+            println!("{}", self.to_json());
+        } else {
+            println!("{}", self.message);
+        }
+
+        io::stdout().flush().unwrap();
+    }
+}
+
+/// Report that command finished. This is only printed as JSON: people can see the prompt come back.
+pub fn done(command: &str) {
+    if json() {
+        Event::new("done", format!("{} finished", command)).field("command", command).print();
+    }
+}
+
+/// Report something with no fields
+pub fn info<S1: ToString, S2: ToString>(kind: S1, message: S2) {
+    Event::new(kind, message).print();
+}
+
+/// Report an error
+pub fn error<S: ToString>(message: S) {
+    ERROR_REPORTED.with(|e| e.set(true));
+    Event::new("error", message).print();
+}
+
+/// Report a problem which did not stop the command
+pub fn warning<S: ToString>(message: S) {
+    Event::new("warning", message).print();
+}
+
+/// A JSON object from (name, value) pairs, e.g. for a row of a table
+pub fn object(fields: Vec<(&str, Value)>) -> Value {
+    let mut map = Map::new();
+    for (name, value) in fields {
+        map.insert(name.to_string(), value);
+    }
+
+    Value::Object(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_shape() {
+        let event = Event::new("bill", "The bill is 12").field("amount", 12);
+        assert_eq!(event.to_json().to_string(), "{\"amount\":12,\"event\":\"bill\",\"message\":\"The bill is 12\"}");
+
+        // tables are lists of objects, and the kind and message cannot be overwritten by a field
+        let rows = vec!(object(vec!(("other", Value::from(1)), ("units", Value::from(5)))));
+        let event = Event::new("consumption_table", "1: 5").field("rows", rows).field("event", "other").field("message", Value::Null);
+        assert_eq!(event.to_json().to_string(), "{\"event\":\"consumption_table\",\"message\":\"1: 5\",\"rows\":[{\"other\":1,\"units\":5}]}");
+    }
+}
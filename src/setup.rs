@@ -0,0 +1,279 @@
+//! Deployment bundles for the interactive demo
+//!
+//! `--setup DIR` generates every key a deployment needs, the commitment parameters and a configuration file (see config.rs) for each role, so that nothing has to be wired up by hand:
+//!
+//! ```text
+//! dhparams.txt                       commitment parameters shared by everyone
+//! provider.comsk(.pub)               the provider's communication keypair
+//! provider.signk(.pub)               the provider's signing key
+//! customers.comsk.pub                every customer's public communication key: the keys the provider trusts
+//! customer-N.comsk(.pub)             customer N's communication keypair
+//! customer-N.signk(.pub)             customer N's signing key, for countersigning dispute transcripts
+//! meter-N.signk(.pub)                the signing key of customer N's meter
+//! provider-N.toml                    the provider serving customer N
+//! customer-N.toml, meter-N.toml      customer N and their meter
+//! ```
+//!
+//! The provider serves one customer at a time, so each customer gets their own provider configuration with their own WAN socket. They share the provider's keys and chain head file.
+//! `--add-customer DIR` adds the next customer and meter to an existing bundle.
+
+/*  This file is part of project-billing.
+    project-billing is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+    project-billing is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+    You should have received a copy of the GNU General Public License
+    along with project-billing.  If not, see http://www.gnu.org/licenses/.*/
+
+use keyfile;
+use keyfile::KeyFileError;
+use proj_billing::billing::three_party::read_or_gen_params;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+const DH_PARAMS: &'static str = "dhparams.txt";
+const PROVIDER_COMS_KEY: &'static str = "provider.comsk";
+const PROVIDER_SIGN_KEY: &'static str = "provider.signk";
+const CUSTOMER_COMS_PKS: &'static str = "customers.comsk.pub";
+const CHAIN_HEADS: &'static str = "provider_chain_heads.txt";
+
+// customer N uses these ports on 127.0.0.1, so customer 1 gets the usual defaults
+const FIRST_WAN_PORT: u32 = 1025;
+const FIRST_LAN_PORT: u32 = 1026;
+
+/// Things which can go wrong creating a deployment
+#[derive(Debug)]
+pub enum SetupError {
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// Writing or reading a key file failed
+    KeyFile(KeyFileError),
+    /// There is already a deployment in the directory
+    Exists(String),
+    /// The directory does not contain a deployment to add to
+    NotADeployment(String),
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SetupError::Io(ref e) => write!(f, "{}", e),
+            SetupError::KeyFile(ref e) => write!(f, "{}", e),
+            SetupError::Exists(ref dir) => write!(f, "{} already contains a deployment. Use --add-customer to add to it", dir),
+            SetupError::NotADeployment(ref dir) => write!(f, "{} does not contain a deployment made with --setup", dir),
+        }
+    }
+}
+
+impl From<io::Error> for SetupError {
+    fn from(e: io::Error) -> SetupError {
+        SetupError::Io(e)
+    }
+}
+
+impl From<KeyFileError> for SetupError {
+    fn from(e: KeyFileError) -> SetupError {
+        SetupError::KeyFile(e)
+    }
+}
+
+// path as a string, for the key file functions
+fn in_dir(dir: &str, file: &str) -> String {
+    Path::new(dir).join(file).to_string_lossy().into_owned()
+}
+
+fn customer_coms_key(n: usize) -> String {
+    format!("customer-{}.comsk", n)
+}
+
+fn customer_sign_key(n: usize) -> String {
+    format!("customer-{}.signk", n)
+}
+
+fn meter_sign_key(n: usize) -> String {
+    format!("meter-{}.signk", n)
+}
+
+fn wan_socket(n: usize) -> String {
+    format!("127.0.0.1:{}", FIRST_WAN_PORT + 2 * (n as u32 - 1))
+}
+
+fn lan_socket(n: usize) -> String {
+    format!("127.0.0.1:{}", FIRST_LAN_PORT + 2 * (n as u32 - 1))
+}
+
+fn write_file(dir: &str, file: &str, contents: &str) -> Result<(), SetupError> {
+    let mut f = try!(File::create(in_dir(dir, file)));
+    try!(f.write_all(contents.as_bytes()));
+    Ok(())
+}
+
+/// Create a deployment with customers customers (and a meter for each) in dir, which is created if it does not exist. Secret keys are encrypted with passphrase.
+/// Refuses to overwrite an existing deployment.
+pub fn create(dir: &str, customers: usize, passphrase: &[u8]) -> Result<(), SetupError> {
+    assert!(customers > 0);
+
+    if Path::new(&in_dir(dir, PROVIDER_SIGN_KEY)).exists() {
+        return Err(SetupError::Exists(dir.to_string()));
+    }
+    try!(fs::create_dir_all(dir));
+
+    println!("Generating the commitment parameters. This can take a while.");
+    read_or_gen_params(in_dir(dir, DH_PARAMS));
+
+    try!(keyfile::coms_key_gen_to_file(&in_dir(dir, PROVIDER_COMS_KEY), passphrase));
+    try!(keyfile::sign_key_gen_to_file(&in_dir(dir, PROVIDER_SIGN_KEY), passphrase));
+    try!(keyfile::write_public_file(in_dir(dir, CUSTOMER_COMS_PKS), &[]));
+
+    for n in 1..(customers + 1) {
+        try!(add_numbered_customer(dir, n, passphrase));
+    }
+
+    Ok(())
+}
+
+/// Add the next customer and meter to the deployment in dir. Returns the customer's number.
+pub fn add_customer(dir: &str, passphrase: &[u8]) -> Result<usize, SetupError> {
+    for file in &[DH_PARAMS, PROVIDER_COMS_KEY, PROVIDER_SIGN_KEY, CUSTOMER_COMS_PKS] {
+        if !Path::new(&in_dir(dir, file)).is_file() {
+            return Err(SetupError::NotADeployment(dir.to_string()));
+        }
+    }
+
+    let mut n = 1;
+    while Path::new(&in_dir(dir, &customer_coms_key(n))).exists() {
+        n += 1;
+    }
+
+    try!(add_numbered_customer(dir, n, passphrase));
+    Ok(n)
+}
+
+// generate customer n's keys and the configuration files which use them
+fn add_numbered_customer(dir: &str, n: usize, passphrase: &[u8]) -> Result<(), SetupError> {
+    let customer_key = customer_coms_key(n);
+    let customer_sign = customer_sign_key(n);
+    let meter_key = meter_sign_key(n);
+
+    try!(keyfile::coms_key_gen_to_file(&in_dir(dir, &customer_key), passphrase));
+    try!(keyfile::sign_key_gen_to_file(&in_dir(dir, &customer_sign), passphrase));
+    try!(keyfile::sign_key_gen_to_file(&in_dir(dir, &meter_key), passphrase));
+
+    // the provider has to trust the new customer
+    let customer_pks = in_dir(dir, CUSTOMER_COMS_PKS);
+    let mut trusted = match try!(keyfile::read_public_file(&customer_pks)) {
+        Some(keys) => keys,
+        None => return Err(SetupError::KeyFile(KeyFileError::Malformed)),
+    };
+    match try!(keyfile::read_public_file(in_dir(dir, &format!("{}.pub", customer_key)))) {
+        Some(mut keys) => trusted.append(&mut keys),
+        None => return Err(SetupError::KeyFile(KeyFileError::Malformed)),
+    }
+    {
+        let trusted: Vec<&[u8]> = trusted.iter().map(|k| k.as_slice()).collect();
+        try!(keyfile::write_public_file(&customer_pks, &trusted));
+    }
+
+    let header = |role: &str| format!("# Generated by --setup. Run with: --config {}\nrole = \"{}\"\n\n", in_dir(dir, &format!("{}-{}.toml", role, n)), role);
+
+    let provider = format!("{}[keys]\ncoms_keypair = \"{}\"\npublic_coms_key = \"{}\"\ndh_params = \"{}\"\nsign_key = \"{}\"\nmeter_sign_pk = \"{}.pub\"\ncustomer_sign_pk = \"{}.pub\"\n\n\
+                            [network]\nwan_socket = \"{}\"\n\n[storage]\nchain_head = \"{}\"\n\n[tariff]\nprices = 1\n",
+                           header("provider"), PROVIDER_COMS_KEY, CUSTOMER_COMS_PKS, DH_PARAMS, PROVIDER_SIGN_KEY, meter_key, customer_sign, wan_socket(n), CHAIN_HEADS);
+
+    let customer = format!("{}[keys]\ncoms_keypair = \"{}\"\npublic_coms_key = \"{}.pub\"\ndh_params = \"{}\"\nsign_key = \"{}\"\nmeter_sign_pk = \"{}.pub\"\nprovider_sign_pk = \"{}.pub\"\n\n\
+                            [network]\nwan_socket = \"{}\"\nlan_socket = \"{}\"\n\n[tariff]\nprices = 1\n",
+                           header("customer"), customer_key, PROVIDER_COMS_KEY, DH_PARAMS, customer_sign, meter_key, PROVIDER_SIGN_KEY, wan_socket(n), lan_socket(n));
+
+    let meter = format!("{}[keys]\ndh_params = \"{}\"\nsign_key = \"{}\"\n\n[network]\nlan_socket = \"{}\"\n\n[storage]\nchain_head = \"meter-{}_chain_head.txt\"\n",
+                        header("meter"), DH_PARAMS, meter_key, lan_socket(n), n);
+
+    try!(write_file(dir, &format!("provider-{}.toml", n), &provider));
+    try!(write_file(dir, &format!("customer-{}.toml", n), &customer));
+    try!(write_file(dir, &format!("meter-{}.toml", n), &meter));
+
+    println!("Customer {}: customer-{}.toml, meter-{}.toml and provider-{}.toml in {}", n, n, n, n, dir);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use proj_billing::billing::to_hex;
+    use proj_billing::billing::three_party::DEFAULT_PARAMS_PATH;
+    use sodiumoxide;
+    use sodiumoxide::randombytes::randombytes;
+    use std::env;
+
+    // an empty directory which no other test run uses, holding the commitment parameters the other tests share so that they are not generated again
+    fn temp_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("proj_billing_setup_{}_{}", to_hex(&randombytes(8)), name)).to_string_lossy().into_owned();
+        fs::create_dir_all(&dir).unwrap();
+        read_or_gen_params(DEFAULT_PARAMS_PATH);
+        fs::copy(DEFAULT_PARAMS_PATH, in_dir(&dir, DH_PARAMS)).unwrap();
+        dir
+    }
+
+    fn trusted_keys(dir: &str) -> Vec<Vec<u8>> {
+        keyfile::read_public_file(in_dir(dir, CUSTOMER_COMS_PKS)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn create_and_add() {
+        sodiumoxide::init();
+        let dir = temp_dir("bundle");
+
+        create(&dir, 2, b"passphrase").unwrap();
+        for n in 1..3 {
+            for role in &["provider", "customer", "meter"] {
+                let config = Config::read(&in_dir(&dir, &format!("{}-{}.toml", role, n))).unwrap();
+                assert_eq!(config.role, *role);
+            }
+        }
+
+        // the provider trusts each customer's key, in order
+        let trusted = trusted_keys(&dir);
+        assert_eq!(trusted.len(), 2);
+        for n in 1..3 {
+            let pk = keyfile::read_public_file(in_dir(&dir, &format!("{}.pub", customer_coms_key(n)))).unwrap().unwrap();
+            assert_eq!(trusted[n - 1], pk[0]);
+        }
+
+        match create(&dir, 1, b"passphrase") {
+            Err(SetupError::Exists(_)) => (),
+            _ => panic!("created a deployment on top of another"),
+        }
+        assert_eq!(trusted_keys(&dir).len(), 2);
+
+        assert_eq!(add_customer(&dir, b"passphrase").unwrap(), 3);
+        assert!(Path::new(&in_dir(&dir, "meter-3.toml")).is_file());
+        let trusted = trusted_keys(&dir);
+        assert_eq!(trusted.len(), 3);
+        let pk = keyfile::read_public_file(in_dir(&dir, &format!("{}.pub", customer_coms_key(3)))).unwrap().unwrap();
+        assert_eq!(trusted[2], pk[0]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn add_without_deployment() {
+        sodiumoxide::init();
+        let dir = temp_dir("empty");
+
+        match add_customer(&dir, b"passphrase") {
+            Err(SetupError::NotADeployment(_)) => (),
+            _ => panic!("added a customer to a directory without a deployment"),
+        }
+        assert!(!Path::new(&in_dir(&dir, &customer_coms_key(1))).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,8 @@
 //! For the interactive shells used in main.rs
+//!
+//! Each command declares its arguments (see ArgSpec). The shell checks them before running the command and generates the help from them.
+//! A command which fails returns an error, which the shell reports. A script stops at the first error (see run_script).
+//! Interactive shells have line editing, tab completion of command names (and file names after a command) and a history which is kept between runs.
 
 /*  This file is part of project-net.
     project-net is free software: you can redistribute it and/or modify
@@ -12,16 +16,191 @@
     You should have received a copy of the GNU General Public License
     along with project-net.  If not, see http://www.gnu.org/licenses/.*/
 
+use output;
+use output::Event;
+use rustyline;
+use rustyline::Editor;
+use rustyline::completion::{Completer, FilenameCompleter};
+use rustyline::error::ReadlineError;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::process::exit;
 use std::mem::drop;
 use std::io;
-use std::io::Write;
+use std::io::{BufRead, BufReader};
+use std::fs::File;
+use std::thread;
+use std::time::Duration;
+
+/// The kind of value an argument takes
+#[derive(Clone, Copy)]
+pub enum ArgKind {
+    /// A whole number between the two bounds (inclusive)
+    Integer(i64, i64),
+    /// Any word, e.g. a file name
+    Word,
+}
+
+/// One argument of a command
+#[derive(Clone)]
+pub struct ArgSpec {
+    name: String,
+    kind: ArgKind,
+    // this argument and the ones after it may be left out
+    optional: bool,
+    // this argument can be given any number of times (at least once unless it is optional). Only the last argument can be repeated.
+    repeated: bool,
+}
+
+impl ArgSpec {
+    /// A whole number between min and max (inclusive)
+    pub fn integer<S: ToString>(name: S, min: i64, max: i64) -> ArgSpec {
+        assert!(min <= max);
+        ArgSpec {
+            name: name.to_string(),
+            kind: ArgKind::Integer(min, max),
+            optional: false,
+            repeated: false,
+        }
+    }
+
+    /// A whole number which is at least 0
+    pub fn unsigned<S: ToString>(name: S) -> ArgSpec {
+        ArgSpec::integer(name, 0, i64::max_value())
+    }
+
+    /// Any word
+    pub fn word<S: ToString>(name: S) -> ArgSpec {
+        ArgSpec {
+            name: name.to_string(),
+            kind: ArgKind::Word,
+            optional: false,
+            repeated: false,
+        }
+    }
+
+    /// This argument and the ones after it may be left out together
+    pub fn optional(mut self) -> ArgSpec {
+        self.optional = true;
+        self
+    }
+
+    /// This argument may be given more than once. It must be the last argument.
+    pub fn repeated(mut self) -> ArgSpec {
+        self.repeated = true;
+        self
+    }
+
+    fn usage(&self) -> String {
+        if self.repeated {
+            format!("{}...", self.name)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    // check one value
+    fn check(&self, value: &str) -> Result<(), String> {
+        match self.kind {
+            ArgKind::Word => Ok(()),
+            ArgKind::Integer(min, max) => {
+                let description = if min == 0 && max == i64::max_value() {
+                    String::from("an unsigned integer")
+                } else if min == i64::min_value() && max == i64::max_value() {
+                    String::from("an integer")
+                } else {
+                    format!("an integer from {} to {}", min, max)
+                };
+
+                match value.parse::<i64>() {
+                    Ok(v) if v >= min && v <= max => Ok(()),
+                    _ => Err(format!("{} should be {}, not {}", self.name, description, value)),
+                }
+            },
+        }
+    }
+}
+
+/// The arguments given to a command, which have already been checked against its ArgSpecs
+pub struct Args {
+    values: Vec<String>,
+}
+
+impl Args {
+    /// The number of arguments given
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Argument i, which must have been declared with ArgSpec::integer or ArgSpec::unsigned
+    pub fn integer(&self, i: usize) -> i64 {
+        self.values[i].parse().unwrap()
+    }
+
+    /// Argument i as it was typed
+    pub fn word(&self, i: usize) -> &str {
+        &self.values[i]
+    }
+}
+
+// the whole usage line for a command, e.g. "kwh BILL [FIRST LAST]"
+fn usage(name: &str, specs: &[ArgSpec]) -> String {
+    let mut ret = name.to_string();
+    let mut in_optional = false;
+    for spec in specs {
+        if spec.optional && !in_optional {
+            ret += " [";
+            in_optional = true;
+        } else {
+            ret += " ";
+        }
+        ret += &spec.usage();
+    }
+    if in_optional {
+        ret += "]";
+    }
+
+    ret
+}
+
+// check the words typed after a command against its specs
+fn check_args(specs: &[ArgSpec], words: Vec<String>) -> Result<Args, String> {
+    let required = specs.iter().take_while(|s| !s.optional).count();
+    let repeated = specs.last().map(|s| s.repeated).unwrap_or(false);
+
+    if words.len() < required || (!repeated && words.len() > specs.len()) {
+        let expected = if specs.is_empty() {
+            String::from("no arguments")
+        } else if repeated {
+            format!("at least {}", required)
+        } else if required == specs.len() {
+            format!("{}", required)
+        } else {
+            format!("{} or {}", required, specs.len())
+        };
+        return Err(format!("expected {} arguments but {} were given", expected, words.len()));
+    }
+
+    if words.len() > required && words.len() < specs.len() {
+        return Err(format!("the optional arguments go together: give all {} of them or none", specs.len() - required));
+    }
+
+    for (i, word) in words.iter().enumerate() {
+        let spec = if i < specs.len() { &specs[i] } else { &specs[specs.len() - 1] };
+        try!(spec.check(word));
+    }
+
+    Ok(Args {
+        values: words,
+    })
+}
 
 struct CommandInfo<T> {
-    closure: Box<Fn(&mut T, Vec<String>)>,
+    closure: Box<Fn(&mut T, &Args) -> Result<(), String>>,
     help_string: String,
-    help_name: String,
+    args: Vec<ArgSpec>,
+    // errors from an assertion are reported as failed assertions
+    assertion: bool,
 }
 
 pub struct InteractiveShell<T> where T: 'static {
@@ -30,9 +209,38 @@ pub struct InteractiveShell<T> where T: 'static {
     commands: HashMap<String, CommandInfo<T>>,
 }
 
-pub fn complain_arg(arg: &Vec<String>) {
-    if !(arg.is_empty()) {
-        println!("This command did not require an argument");
+// what happened when a line was run
+enum Outcome {
+    Done(String),
+    Exit,
+    Unrecognised(String),
+    BadArguments(String, String),
+    // a command returned an error
+    Error(String),
+    // a command succeeded but reported an error on the way (see output::take_error)
+    ErrorReported,
+    // an assertion failed
+    Failed(String),
+}
+
+// completes command names at the start of the line and file names after them
+struct CommandCompleter {
+    names: Vec<String>,
+    files: FilenameCompleter,
+}
+
+impl Completer for CommandCompleter {
+    fn complete(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        if before.trim_left().contains(char::is_whitespace) {
+            return self.files.complete(line, pos);
+        }
+
+        let start = before.len() - before.trim_left().len();
+        let prefix = &before[start..];
+        let candidates = self.names.iter().filter(|n| n.starts_with(prefix)).cloned().collect();
+
+        Ok((start, candidates))
     }
 }
 
@@ -44,78 +252,378 @@ impl<T> InteractiveShell<T> {
             commands: HashMap::new(),
         }
     }
-            
-    pub fn register_command<S1, S2, S3>(&mut self, name: S1, help_name: S2, help_string: S3,
-                                        closure: Box<Fn(&mut T, Vec<String>)>)
-                                        where S1: ToString, S2: ToString, S3: ToString {
+
+    /// Register a command taking the arguments described by args. The closure is only called if the arguments typed match.
+    /// If the closure returns an error the shell reports it and a script is stopped with exit status 1 (see run_script).
+    pub fn register_command<S1, S2>(&mut self, name: S1, args: Vec<ArgSpec>, help_string: S2, closure: Box<Fn(&mut T, &Args) -> Result<(), String>>)
+                                    where S1: ToString, S2: ToString {
+        self.register(name, args, help_string, closure, false);
+    }
+
+    /// Register a command which checks something. If the closure returns an error it is reported as a failed assertion and a script is stopped with exit status 1 (see run_script).
+    pub fn register_assertion<S1, S2>(&mut self, name: S1, args: Vec<ArgSpec>, help_string: S2, closure: Box<Fn(&mut T, &Args) -> Result<(), String>>)
+                                      where S1: ToString, S2: ToString {
+        self.register(name, args, help_string, closure, true);
+    }
+
+    fn register<S1, S2>(&mut self, name: S1, args: Vec<ArgSpec>, help_string: S2, closure: Box<Fn(&mut T, &Args) -> Result<(), String>>, assertion: bool)
+                        where S1: ToString, S2: ToString {
+        // the repeated argument has to be the last one
+        for spec in args.iter().rev().skip(1) {
+            assert!(!spec.repeated);
+        }
+
         let info = CommandInfo {
             closure: closure,
             help_string: help_string.to_string(),
-            help_name: help_name.to_string(),
+            args: args,
+            assertion: assertion,
         };
 
         self.commands.insert(name.to_string(), info);
     }
 
-    pub fn start(&mut self) -> ! {
-        // common commands
-        fn exit_command<T>(shared: &mut T, args: Vec<String>) {
-            complain_arg(&args);
-            println!("Goodbye");
-            drop(shared);
-            exit(0); // success
+    // commands available in every shell. This should be called after every other command is registered so that help knows about them.
+    fn register_common_commands(&mut self) {
+        // run_line quits after this so that the state is dropped (and its secrets wiped) first
+        fn exit_command<T>(_: &mut T, _: &Args) -> Result<(), String> {
+            output::info("exit", "Goodbye");
+            Ok(())
         }
 
-        self.register_command("exit", "exit\t\t\t",  "Closes the program", Box::new(exit_command));
+        self.register_command("exit", vec!(), "Closes the program", Box::new(exit_command));
 
-        // copy suitable to be moved into help_command
-        let mut help_info = HashMap::new();
-        for val in self.commands.values() {
-            help_info.insert(val.help_name.clone(), val.help_string.clone());
+        fn sleep_command<T>(_: &mut T, args: &Args) -> Result<(), String> {
+            thread::sleep(Duration::from_millis(args.integer(0) as u64));
+            Ok(())
         }
 
-        // manually add help because it is not registered as a command yet
-        help_info.insert("help\t\t\t".to_string(), "Display this help message".to_string());
-        
-        let help_command = move |shared: &mut T, args: Vec<String>| {
-            let _ = shared; // suppress unused warning (#[ignore()] does not seem to work on closures)
-            complain_arg(&args);
-            println!("Usage:");
-            println!("Command\t\t\t\tDescription");
-            for (name, help) in help_info.iter() {
-                println!("{}\t{}", name, help);
+        self.register_command("sleep", vec!(ArgSpec::unsigned("MILLISECONDS")), "Wait, e.g. for the other parties to catch up in a script", Box::new(sleep_command));
+
+        // copy suitable to be moved into help_command. help is not registered yet so add it by hand.
+        let mut help_info: Vec<(String, String)> = self.commands.iter().map(|(name, info)| (usage(name, &info.args), info.help_string.clone())).collect();
+        help_info.push((String::from("help"), String::from("Display this help message")));
+        help_info.sort();
+
+        let width = help_info.iter().map(|&(ref u, _)| u.len()).max().unwrap_or(0) + 2;
+
+        let help_command = move |_: &mut T, _: &Args| {
+            let mut text = format!("Usage:\n{:width$}{}", "Command", "Description", width = width);
+            let mut rows = Vec::new();
+            for &(ref usage, ref help) in help_info.iter() {
+                text += &format!("\n{:width$}{}", usage, help, width = width);
+                rows.push(output::object(vec!(("usage", Value::from(usage.as_str())), ("description", Value::from(help.as_str())))));
             }
+
+            Event::new("help", text).field("commands", rows).print();
+            Ok(())
         };
 
-        self.register_command("help", "help\t\t", "Display this help message", Box::new(help_command));
+        self.register_command("help", vec!(), "Display this help message", Box::new(help_command));
+    }
+
+    // run one line of input
+    fn run_line(&mut self, input: &str) -> Outcome {
+        let mut iter = input.split_whitespace();
+        let command_name = iter.next().unwrap_or("");
+        match self.commands.get(command_name) {
+            Some(v) => {
+                let words = iter.map(|s| s.to_string()).collect::<Vec<String>>();
+                let args = match check_args(&v.args, words) {
+                    Ok(a) => a,
+                    Err(e) => return Outcome::BadArguments(e, usage(command_name, &v.args)),
+                };
+
+                // only count errors reported by this command
+                output::take_error();
+                match (v.closure)(&mut self.shared_state, &args) {
+                    Ok(()) if output::take_error() => Outcome::ErrorReported,
+                    Ok(()) if command_name == "exit" => Outcome::Exit,
+                    Ok(()) => Outcome::Done(command_name.to_string()),
+                    Err(e) => if v.assertion { Outcome::Failed(e) } else { Outcome::Error(e) },
+                }
+            },
+            None => Outcome::Unrecognised(command_name.to_string()),
+        }
+    }
+
+    // exit with status, dropping the state first because exit does not run destructors
+    fn quit(self, status: i32) -> ! {
+        drop(self.shared_state);
+        exit(status);
+    }
+
+    /// Run the commands in script (see run_script), or read them interactively if script is None. A script of "-" is read from standard input.
+    pub fn run(self, script: Option<String>) -> ! {
+        match script {
+            None => self.start(),
+            Some(ref path) if path == "-" => {
+                let stdin = io::stdin();
+                let lock = stdin.lock();
+                self.run_script(lock)
+            },
+            Some(path) => match File::open(&path) {
+                Ok(f) => self.run_script(BufReader::new(f)),
+                Err(e) => {
+                    output::error(format!("Error opening the script {}: {}. Exiting.", path, e));
+                    self.quit(2);
+                },
+            },
+        }
+    }
+
+    /// Run the commands in script, one per line, without prompting. Blank lines and lines starting with # are skipped.
+    /// Exits with status 0 at the end of the script, 1 at the first command which fails or reports an error (see output::error) or assertion which fails
+    /// and 2 if a line is not a command, has the wrong arguments or the script cannot be read.
+    pub fn run_script<R: BufRead>(mut self, script: R) -> ! {
+        let status = self.script_status(script);
+        self.quit(status);
+    }
+
+    // run script until it ends or stops and return the exit status (see run_script)
+    fn script_status<R: BufRead>(&mut self, script: R) -> i32 {
+        self.register_common_commands();
+
+        for (i, line) in script.lines().enumerate() {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => {
+                    Event::new("error", format!("Error reading line {} of the script: {}. Exiting.", i + 1, e)).field("line", i + 1).print();
+                    return 2;
+                },
+            };
+
+            let command = line.trim();
+            if command.is_empty() || command.starts_with('#') {
+                continue;
+            }
+
+            // echo so that the output reads like an interactive session
+            Event::new("command", format!("{}> {}", self.my_name, command)).field("line", i + 1).field("command", command).print();
+
+            match self.run_line(command) {
+                Outcome::Done(name) => output::done(&name),
+                Outcome::Exit => return 0,
+                Outcome::Unrecognised(name) => {
+                    Event::new("error", format!("Line {} of the script: unrecognised command {}. Exiting.", i + 1, name)).field("line", i + 1).field("command", name).print();
+                    return 2;
+                },
+                Outcome::BadArguments(e, usage) => {
+                    Event::new("error", format!("Line {} of the script: {} (usage: {}). Exiting.", i + 1, e, usage)).field("line", i + 1).field("usage", usage).print();
+                    return 2;
+                },
+                Outcome::Error(e) => {
+                    Event::new("error", format!("Line {} of the script: {}. Exiting.", i + 1, e)).field("line", i + 1).print();
+                    return 1;
+                },
+                Outcome::ErrorReported => {
+                    Event::new("error", format!("Line {} of the script reported an error. Exiting.", i + 1)).field("line", i + 1).print();
+                    return 1;
+                },
+                Outcome::Failed(e) => {
+                    Event::new("assertion_failed", format!("Line {} of the script: assertion failed: {}. Exiting.", i + 1, e)).field("line", i + 1).field("reason", e).print();
+                    return 1;
+                },
+            }
+        }
+
+        output::info("end_of_script", "End of script");
+        0
+    }
+
+    pub fn start(mut self) -> ! {
+        self.register_common_commands();
+
+        let mut names: Vec<String> = self.commands.keys().cloned().collect();
+        names.sort();
+
+        let mut editor = Editor::<CommandCompleter>::new();
+        editor.set_completer(Some(CommandCompleter {
+            names: names,
+            files: FilenameCompleter::new(),
+        }));
+
+        // history is kept in the working directory, like the chain heads. There is none the first time.
+        let history_path = format!(".{}_history", self.my_name);
+        let _ = editor.load_history(&history_path);
 
         // repl
         loop {
-            print!("{}> ", self.my_name);
-            io::stdout().flush().unwrap();
-
-            let mut input = String::new();
-            if io::stdin().read_line(&mut input).is_err() {
-                println!("Error reading from stdin. Exiting.");
-                drop(&mut self.shared_state);
-                exit(1); // failure
+            let input = match editor.readline(&format!("{}> ", self.my_name)) {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue, // Ctrl-C abandons the line
+                Err(ReadlineError::Eof) => {
+                    output::info("exit", "Goodbye");
+                    self.quit(0);
+                },
+                Err(e) => {
+                    output::error(format!("Error reading from stdin: {}. Exiting.", e));
+                    self.quit(1); // failure
+                },
+            };
+
+            // save before running the line because exit does not return
+            if !input.trim().is_empty() {
+                editor.add_history_entry(input.trim());
+                if let Err(e) = editor.save_history(&history_path) {
+                    output::warning(format!("Warning: failed to save the history to {}: {}", history_path, e));
+                }
             }
 
-            let mut iter = input.split_whitespace();
-            let command_name = iter.next().unwrap_or("");
-            match self.commands.get(command_name) {
-                Some(v) => {
-                    let args = iter.map(|s| s.to_string()).collect::<Vec<String>>();
-                    (v.closure)(&mut self.shared_state, args);
-                },
-                None => {
-                    if command_name == "" {
-                        println!("");
-                    } else {
-                        println!("Ignoring unrecognised command {}. Use help to view available commands", command_name);
+            match self.run_line(&input) {
+                Outcome::Done(name) => output::done(&name),
+                Outcome::Exit => self.quit(0),
+                Outcome::Unrecognised(command_name) => {
+                    if command_name != "" {
+                        Event::new("error", format!("Ignoring unrecognised command {}. Use help to view available commands", command_name)).field("command", command_name).print();
                     }
                 },
+                Outcome::BadArguments(e, usage) => Event::new("error", format!("Error: {}. Usage: {}", e, usage)).field("usage", usage).print(),
+                Outcome::Error(e) => output::error(e),
+                Outcome::ErrorReported => (),
+                Outcome::Failed(e) => Event::new("assertion_failed", format!("Assertion failed: {}", e)).field("reason", e).print(),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use output;
+    use rustyline::completion::{Completer, FilenameCompleter};
+
+    fn words(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|w| w.to_string()).collect()
+    }
+
+    fn kwh_args() -> Vec<ArgSpec> {
+        vec!(ArgSpec::unsigned("BILL"), ArgSpec::integer("FIRST", 0, 7*24 - 1).optional(), ArgSpec::integer("LAST", 0, 7*24 - 1))
+    }
+
+    fn dispute_args() -> Vec<ArgSpec> {
+        vec!(ArgSpec::unsigned("BILL"), ArgSpec::unsigned("OTHER").repeated())
+    }
+
+    #[test]
+    fn usage_lines() {
+        assert_eq!(usage("help", &[]), "help");
+        assert_eq!(usage("replay", &[ArgSpec::word("FILE"), ArgSpec::unsigned("SPEEDUP")]), "replay FILE SPEEDUP");
+        assert_eq!(usage("kwh", &kwh_args()), "kwh BILL [FIRST LAST]");
+        assert_eq!(usage("dispute", &dispute_args()), "dispute BILL OTHER...");
+    }
+
+    #[test]
+    fn typed_args() {
+        let specs = vec!(ArgSpec::integer("CONS", 0, 10), ArgSpec::word("FILE"));
+        let args = check_args(&specs, words("5 load.csv")).ok().unwrap();
+        assert_eq!(args.len(), 2);
+        assert_eq!(args.integer(0), 5);
+        assert_eq!(args.word(1), "load.csv");
+
+        assert_eq!(check_args(&specs, words("11 load.csv")).err(), Some(String::from("CONS should be an integer from 0 to 10, not 11")));
+        assert_eq!(check_args(&specs, words("five load.csv")).err(), Some(String::from("CONS should be an integer from 0 to 10, not five")));
+        assert_eq!(check_args(&[ArgSpec::unsigned("N")], words("-1")).err(), Some(String::from("N should be an unsigned integer, not -1")));
+        assert_eq!(check_args(&[ArgSpec::integer("AMOUNT", i64::min_value(), i64::max_value())], words("x")).err(), Some(String::from("AMOUNT should be an integer, not x")));
+        assert_eq!(check_args(&[ArgSpec::integer("AMOUNT", i64::min_value(), i64::max_value())], words("-5")).ok().unwrap().integer(0), -5);
+    }
+
+    #[test]
+    fn argument_counts() {
+        assert!(check_args(&[], words("")).is_ok());
+        assert_eq!(check_args(&[], words("x")).err(), Some(String::from("expected no arguments but 1 were given")));
+        assert_eq!(check_args(&[ArgSpec::word("A"), ArgSpec::word("B")], words("a")).err(), Some(String::from("expected 2 arguments but 1 were given")));
+
+        // the optional arguments are all given or all left out
+        assert_eq!(check_args(&kwh_args(), words("1")).ok().unwrap().len(), 1);
+        assert_eq!(check_args(&kwh_args(), words("1 2 3")).ok().unwrap().len(), 3);
+        assert_eq!(check_args(&kwh_args(), words("1 2")).err(), Some(String::from("the optional arguments go together: give all 2 of them or none")));
+        assert_eq!(check_args(&kwh_args(), words("1 2 3 4")).err(), Some(String::from("expected 1 or 3 arguments but 4 were given")));
+        assert_eq!(check_args(&kwh_args(), words("1 2 168")).err(), Some(String::from("LAST should be an integer from 0 to 167, not 168")));
+
+        // every repeated value is checked
+        assert_eq!(check_args(&dispute_args(), words("1 2 3 4")).ok().unwrap().len(), 4);
+        assert_eq!(check_args(&dispute_args(), words("1")).err(), Some(String::from("expected at least 2 arguments but 1 were given")));
+        assert_eq!(check_args(&dispute_args(), words("1 2 x")).err(), Some(String::from("OTHER should be an unsigned integer, not x")));
+    }
+
+    #[test]
+    fn completion() {
+        let completer = CommandCompleter {
+            names: vec!(String::from("exit"), String::from("expect_bill"), String::from("help")),
+            files: FilenameCompleter::new(),
+        };
+
+        assert_eq!(completer.complete("ex", 2).unwrap(), (0, vec!(String::from("exit"), String::from("expect_bill"))));
+        assert_eq!(completer.complete("  he", 4).unwrap(), (2, vec!(String::from("help"))));
+        assert_eq!(completer.complete("exp", 3).unwrap(), (0, vec!(String::from("expect_bill"))));
+        assert_eq!(completer.complete("zz", 2).unwrap(), (0, vec!()));
+    }
+
+    // a shell whose state is the names of the commands it has run
+    fn script_shell() -> InteractiveShell<Vec<String>> {
+        let mut shell = InteractiveShell::new("test", Vec::new());
+
+        shell.register_command("ok", vec!(), "Succeed", Box::new(|ran: &mut Vec<String>, _: &Args| {
+            ran.push(String::from("ok"));
+            Ok(())
+        }));
+
+        shell.register_command("fail", vec!(), "Fail", Box::new(|ran: &mut Vec<String>, _: &Args| {
+            ran.push(String::from("fail"));
+            Err(String::from("it failed"))
+        }));
+
+        shell.register_command("report", vec!(), "Report an error but succeed", Box::new(|ran: &mut Vec<String>, _: &Args| {
+            ran.push(String::from("report"));
+            output::error("something went wrong");
+            Ok(())
+        }));
+
+        shell.register_assertion("expect_ran", vec!(ArgSpec::unsigned("NUMBER")), "Check how many commands have run", Box::new(|ran: &mut Vec<String>, args: &Args| {
+            if ran.len() as i64 == args.integer(0) {
+                Ok(())
+            } else {
+                Err(format!("{} commands have run", ran.len()))
+            }
+        }));
+
+        shell
+    }
+
+    // the exit status of script and the commands it ran
+    fn run(script: &str) -> (i32, Vec<String>) {
+        let mut shell = script_shell();
+        let status = shell.script_status(script.as_bytes());
+        (status, shell.shared_state)
+    }
+
+    fn ran(commands: &[&str]) -> Vec<String> {
+        commands.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn script_statuses() {
+        assert_eq!(run("ok\n# a comment\n\n  ok\nsleep 0\nexpect_ran 2\n"), (0, ran(&["ok", "ok"])));
+        assert_eq!(run("ok\nexit\nok\n"), (0, ran(&["ok"])));
+
+        // a script stops at the first error
+        assert_eq!(run("ok\nfail\nok\n"), (1, ran(&["ok", "fail"])));
+        assert_eq!(run("report\nok\n"), (1, ran(&["report"])));
+        assert_eq!(run("ok\nexpect_ran 2\nok\n"), (1, ran(&["ok"])));
+
+        // and at the first line which is not a command it can run
+        assert_eq!(run("ok\nnonsense\nok\n"), (2, ran(&["ok"])));
+        assert_eq!(run("expect_ran\nok\n"), (2, ran(&[])));
+        assert_eq!(run("ok 1\n"), (2, ran(&[])));
+    }
+
+    #[test]
+    fn errors_are_taken_once() {
+        output::take_error();
+        assert!(!output::take_error());
+        output::error("an error");
+        assert!(output::take_error());
+        assert!(!output::take_error());
+    }
+}
@@ -5,3 +5,5 @@ rm customer.comsk*
 rm provider.signk*
 rm provider.comsk*
 
+rm -f meter_chain_head.txt
+rm -f provider_chain_heads.txt
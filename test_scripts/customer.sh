@@ -1,4 +1,6 @@
 #!/bin/sh
 
+export PROJ_BILLING_PASSPHRASE="${PROJ_BILLING_PASSPHRASE:-demo}"
+
 cargo run --release -- --customer customer.comsk --public-coms-key provider.comsk.pub --dh-params dhparams.txt --meter-sign-pk meter.signk.pub --provider-sign-pk provider.signk.pub
 
@@ -2,6 +2,9 @@
 
 set -e 
 
+# the demo passphrase, real deployments should be prompted instead
+export PROJ_BILLING_PASSPHRASE="${PROJ_BILLING_PASSPHRASE:-demo}"
+
 EXEC="cargo run --release -- --keygen"
 
 $EXEC customer.comsk --sign-key meter.signk
@@ -1,4 +1,6 @@
 #!/bin/sh
 
+export PROJ_BILLING_PASSPHRASE="${PROJ_BILLING_PASSPHRASE:-demo}"
+
 cargo run --release -- --meter --dh-params dhparams.txt --sign-key meter.signk
 
@@ -1,4 +1,6 @@
 #!/bin/sh
 
+export PROJ_BILLING_PASSPHRASE="${PROJ_BILLING_PASSPHRASE:-demo}"
+
 cargo run --release -- --provider provider.comsk --public-coms-key customer.comsk.pub --dh-params dhparams.txt --sign-key provider.signk --meter-sign-pk meter.signk.pub 
 
@@ -0,0 +1,39 @@
+#!/bin/sh
+
+# Runs the scripts in ./test_scripts/scripted without any typing. Run ./test_scripts/key_gen.sh first.
+# The exit status is non-zero if any of the three parties fails.
+
+export PROJ_BILLING_PASSPHRASE="${PROJ_BILLING_PASSPHRASE:-demo}"
+
+DIR=$(dirname "$0")/scripted
+
+# start each run at the beginning of the meter's hash chain
+rm -f meter_chain_head.txt
+rm -f provider_chain_heads.txt
+
+cargo build --release || exit 1
+EXEC=./target/release/interactive-demo
+
+$EXEC --provider provider.comsk --public-coms-key customer.comsk.pub --dh-params dhparams.txt --sign-key provider.signk --meter-sign-pk meter.signk.pub --script "$DIR/provider.txt" &
+PROVIDER=$!
+sleep 1
+
+$EXEC --customer customer.comsk --public-coms-key provider.comsk.pub --dh-params dhparams.txt --meter-sign-pk meter.signk.pub --provider-sign-pk provider.signk.pub --script "$DIR/customer.txt" &
+CUSTOMER=$!
+sleep 1
+
+$EXEC --meter --dh-params dhparams.txt --sign-key meter.signk --script "$DIR/meter.txt" &
+METER=$!
+
+STATUS=0
+wait $PROVIDER || STATUS=1
+wait $CUSTOMER || STATUS=1
+wait $METER || STATUS=1
+
+if [ $STATUS -eq 0 ]; then
+    echo "Scripted demo passed"
+else
+    echo "Scripted demo FAILED"
+fi
+
+exit $STATUS